@@ -15,7 +15,7 @@ P4C=p4c-bm2-ss
 RUN_SCRIPT=/home/vagrant/tutorials/utils/run_exercise.py
 
 cd ..
-cargo run --bin sluice $sluice_file_path
+cargo run --bin sluice build $sluice_file_path
 cd bmv2_sim
 
 sudo mn -c
@@ -134,6 +134,9 @@ class ExerciseTopo(Topo):
 
         # max 255 switches supported in dstAddr for now
         for sw in switches:
+            # switches without a snippet run the default program and only forward
+            if sw not in snippet_loc:
+                continue
 
             contents = '''{ \
               "target": "bmv2", 
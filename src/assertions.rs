@@ -0,0 +1,169 @@
+// Compilation of assert(expr); and invariants into the bmv2 programs. The
+// interpreter checks them in any case. With an assertion mode, lower_assertions
+// rewrites every check of a bmv2 snippet into statements: a 64 bit flag is set
+// when the check fails, the flags are or'ed into ASSERT_FAILED, and register
+// ASSERT_VIOLATIONS counts the packets that failed a check. bmv2_gen then drops
+// these packets, or mirrors them to the CPU port, see gen_p4_assertions.
+use grammar::*;
+
+pub const ASSERT_FAILED : &str = "_assert_failed";
+pub const ASSERT_VIOLATIONS : &str = "_assert_violations";
+const ASSERT_FLAG_PREFIX : &str = "_assert_fail_";
+const ASSERT_ANY : &str = "_assert_any";
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone, Copy)]
+pub enum AssertionMode {
+    // drop the packet
+    Drop,
+    // send a copy of the packet to the CPU port, and forward it as usual
+    Mirror,
+}
+
+fn leak_name(name : String) -> Identifier<'static> {
+    Identifier { id_name : Box::leak(name.into_boxed_str()) }
+}
+
+fn scalar<'a> (id : Identifier<'a>) -> Operand<'a> {
+    Operand::LValue(LValue::Scalar(id))
+}
+
+fn decl<'a> (id : Identifier<'a>, bit_width : u64, type_qualifier : TypeQualifier, initial_values : Vec<Value>) -> VariableDecl<'a> {
+    VariableDecl { identifier : id, initial_values, var_type : VarType { var_info : VarInfo::BitArray(bit_width, 1), type_qualifier } }
+}
+
+fn generic_block<'a> (id : u64, stmt_vector : Vec<Statement<'a>>) -> IfBlock<'a> {
+    let condition = Condition { expr : Expr { op1 : Operand::Value(Value { value : 1 }), expr_right : ExprRight::Empty() } };
+    IfBlock { id, condtype : 3, condition, statements : Statements { stmt_vector, assert_vector : Vec::new() } }
+}
+
+// flag = expr; flag = flag == 0;
+fn check_statements<'a> (flag : Identifier<'a>, condition : &Condition<'a>) -> Vec<Statement<'a>> {
+    let negation = Expr { op1 : scalar(flag), expr_right : ExprRight::BinOp(BinOpType::Equal, Operand::Value(Value { value : 0 })) };
+    vec![Statement { lvalue : LValue::Scalar(flag), expr : condition.expr.clone() },
+         Statement { lvalue : LValue::Scalar(flag), expr : negation }]
+}
+
+// Replaces the assertions and invariants of a snippet by the statements computing its ASSERT_FAILED flag
+pub fn lower_snippet_assertions<'a> (my_snippet : &Snippet<'a>) -> Snippet<'a> {
+    let mut new_snippet = my_snippet.clone();
+    let mut flags = Vec::new();
+    for ifblock in &mut new_snippet.ifblocks.ifblock_vector {
+        let mut stmt_vector = Vec::new();
+        for position in 0..ifblock.statements.stmt_vector.len() + 1 {
+            for assertion in ifblock.statements.assert_vector.iter().filter(|a| a.position == position) {
+                let flag = leak_name(format!("{}{}", ASSERT_FLAG_PREFIX, flags.len() + 1));
+                stmt_vector.extend(check_statements(flag, &assertion.condition));
+                flags.push(flag);
+            }
+            if let Some(my_statement) = ifblock.statements.stmt_vector.get(position) { stmt_vector.push(my_statement.clone()) }
+        }
+        ifblock.statements = Statements { stmt_vector, assert_vector : Vec::new() };
+    }
+
+    let mut last_id = new_snippet.ifblocks.ifblock_vector.iter().map(|b| b.id).max().unwrap_or(0);
+    let mut stmt_vector = Vec::new();
+    for invariant in &new_snippet.invariants {
+        let flag = leak_name(format!("{}{}", ASSERT_FLAG_PREFIX, flags.len() + 1));
+        stmt_vector.extend(check_statements(flag, &invariant.condition));
+        flags.push(flag);
+    }
+    new_snippet.invariants = Vec::new();
+    if flags.is_empty() {
+        return new_snippet;
+    }
+
+    // ASSERT_FAILED is written once, so that it keeps its name through SSA
+    let any = Identifier { id_name : ASSERT_ANY };
+    let failed = Identifier { id_name : ASSERT_FAILED };
+    let violations = Identifier { id_name : ASSERT_VIOLATIONS };
+    for flag in &flags {
+        new_snippet.variable_decls.decl_vector.push(decl(*flag, 64, TypeQualifier::Transient, Vec::new()));
+        stmt_vector.push(Statement { lvalue : LValue::Scalar(any),
+                                     expr : Expr { op1 : scalar(any), expr_right : ExprRight::BinOp(BinOpType::BooleanOr, scalar(*flag)) } });
+    }
+    stmt_vector.push(Statement { lvalue : LValue::Scalar(failed), expr : Expr { op1 : scalar(any), expr_right : ExprRight::Empty() } });
+    stmt_vector.push(Statement { lvalue : LValue::Scalar(violations),
+                                 expr : Expr { op1 : scalar(violations), expr_right : ExprRight::BinOp(BinOpType::Plus, scalar(failed)) } });
+    new_snippet.variable_decls.decl_vector.push(decl(any, 1, TypeQualifier::Transient, Vec::new()));
+    new_snippet.variable_decls.decl_vector.push(decl(failed, 1, TypeQualifier::Transient, Vec::new()));
+    new_snippet.variable_decls.decl_vector.push(decl(violations, 32, TypeQualifier::Persistent, vec![Value { value : 0 }]));
+    last_id += 1;
+    new_snippet.ifblocks.ifblock_vector.push(generic_block(last_id, stmt_vector));
+    new_snippet
+}
+
+// Lowers the checks of the bmv2 snippets. Other devices have no way to report violations yet.
+pub fn lower_assertions<'a> (my_snippets : &Snippets<'a>) -> Snippets<'a> {
+    let snippet_vector = my_snippets.snippet_vector.iter().map(|s| {
+        if s.device_annotation.device_type.id_name.contains("bmv2") { lower_snippet_assertions(s) } else { s.clone() }
+    }).collect();
+    Snippets { snippet_vector }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compile::*;
+    use p4_eval::*;
+    use bmv2_gen::ASSERT_CPU_PORT;
+    use interpreter::FieldMap;
+
+    const PROGRAM : &str = r"packet dptp_pkt : ethernet(etherType : 1234) {
+                               now : bit<32>;
+                               owd : bit<32>;
+                             }
+                             @ bmv2 : s1;
+                             snippet dptp() {
+                               transient elapsed : bit<32>;
+                               invariant bounded : elapsed < 100;
+                               if (dptp_pkt.now > 10) {
+                                 elapsed = dptp_pkt.now - 10;
+                                 assert(elapsed != 5);
+                                 dptp_pkt.owd = elapsed;
+                               }
+                             }";
+
+    fn packet(now : u64) -> FieldMap {
+        return vec![("ethernet.etherType", 1234), ("dptp_pkt.now", now), ("dptp_pkt.owd", 0)]
+            .iter().map(|&(name, value)| (name.to_string(), value)).collect();
+    }
+
+    fn switch(mode : AssertionMode) -> Bmv2Switch {
+        let mut options = CompileOptions::new();
+        options.assertions = Some(mode);
+        let output = compile(PROGRAM, &options).unwrap();
+        return Bmv2Switch::new(parse_p4(&output.programs[0].p4_code), output.programs[0].commands.as_ref().unwrap());
+    }
+
+    #[test]
+    fn test_assertions_drop() {
+        let mut switch = switch(AssertionMode::Drop);
+        let output = switch.process(&packet(12));
+        assert!(!output.dropped);
+        assert_eq!(output.fields["dptp_pkt.owd"], 2);
+        // the assertion fails
+        assert!(switch.process(&packet(15)).dropped);
+        // the invariant fails
+        assert!(switch.process(&packet(200)).dropped);
+        assert!(!switch.process(&packet(3)).dropped);
+        assert_eq!(switch.registers()[ASSERT_VIOLATIONS], vec![2]);
+    }
+
+    #[test]
+    fn test_assertions_mirror() {
+        let mut switch = switch(AssertionMode::Mirror);
+        let output = switch.process(&packet(15));
+        assert!(!output.dropped);
+        assert_eq!(output.mirrored, Some(ASSERT_CPU_PORT));
+        assert_eq!(switch.process(&packet(12)).mirrored, None);
+        assert_eq!(switch.registers()[ASSERT_VIOLATIONS], vec![1]);
+    }
+
+    #[test]
+    fn test_assertions_not_compiled() {
+        let output = compile(PROGRAM, &CompileOptions::new()).unwrap();
+        assert!(!output.programs[0].p4_code.contains(ASSERT_FAILED));
+    }
+}
@@ -1,44 +1,57 @@
-extern crate regex;
-use self::regex::Regex;
 use grammar::*;
 use std::collections::HashMap;
-use std::fs::File;
 use std::io::prelude::*;
-use std::error::Error;
-use std::path::Path;
 use trans_snippet::*;
-use std::sync::atomic::AtomicUsize;
-use std::sync::atomic::{AtomicBool, Ordering};
+use p4_ir::*;
+use schedule;
+use schedule::Step;
+use stateful::RegisterUpdate;
+use dependency::get_state_aliases;
+use dot::get_node_label;
+use assertions::{AssertionMode, ASSERT_FAILED};
+use std::cell::Cell;
 use std::process;
 
-const META_HEADER : &str = "mdata";
+pub const META_HEADER : &str = "mdata";
 const TAB : &str = "    ";
+// Mirror session of the packets failing an assertion, and the CPU port it sends them to
+pub const ASSERT_MIRROR_SESSION : u64 = 250;
+pub const ASSERT_CPU_PORT : u64 = 64;
 
 //TODO : Deal with warnings
 #[allow(unused_must_use)]
 #[allow(dead_code)]
 #[allow(unused_imports)]
-static ACTION_COUNT : AtomicUsize = AtomicUsize::new(1);
-static TABLE_COUNT : AtomicUsize = AtomicUsize::new(1);
-static TEMP_COUNT : AtomicUsize = AtomicUsize::new(1);
-static EQ_TABLE_COUNT : AtomicUsize = AtomicUsize::new(1);
-static NEQ_TABLE_COUNT : AtomicUsize = AtomicUsize::new(1);
+thread_local! {
+    // Numbers of the actions and tables of the statement being translated. They are
+    // only unique within the statement, see name_statement_code for the final names.
+    static ACTION_COUNT : Cell<usize> = Cell::new(1);
+    static TABLE_COUNT : Cell<usize> = Cell::new(1);
+    static NEW_ACTION : Cell<bool> = Cell::new(true);
+}
+
+// Next number of an action or table of the current statement
+fn next_count(counter : &'static ::std::thread::LocalKey<Cell<usize>>) -> usize {
+    return counter.with(|c| { let count = c.get(); c.set(count + 1); count });
+}
 
-static NEW_ACTION : AtomicBool = AtomicBool::new(true);
+fn is_new_action() -> bool {
+    return NEW_ACTION.with(|c| c.get());
+}
 
 // from p4-spec, metadata initialized to 0 unless initial value is given
 pub fn handle_transient_decl<'a> (my_decl :  &VariableDecl<'a>) -> P4Header {
-    let mut my_p4_header : P4Header = P4Header {meta:String::new(), meta_init:String::new(), register:String::new(), define:String::new()};
+    let mut my_p4_header : P4Header = P4Header::new();
     match my_decl.var_type.var_info {
         VarInfo::BitArray(bit_width, var_size) => {
             if var_size == 1 {
                 let initial_val_index : usize = 0;
-                my_p4_header.meta = format!("{} : {};\n",my_decl.identifier.id_name, bit_width);
+                my_p4_header.meta.push(MetaField { name : my_decl.identifier.id_name.to_string(), width : bit_width });
                 let my_option = my_decl.initial_values.get(initial_val_index);
                 match my_option {
                     Some (initial_value) => {
-                        my_p4_header.meta_init = format!("set_metadata({}.{},{});\n",
-                            META_HEADER, my_decl.identifier.id_name, initial_value.value);
+                        my_p4_header.meta_init.push(Primitive::new("set_metadata",
+                            vec![format!("{}.{}", META_HEADER, my_decl.identifier.id_name), initial_value.value.to_string()]));
                     }
                     _ => {}
                 }
@@ -55,13 +68,13 @@ pub fn handle_transient_decl<'a> (my_decl :  &VariableDecl<'a>) -> P4Header {
 
 // registers are initalized to 0 in bmv2 ???
 pub fn handle_persistent_decl<'a> (my_decl :  &VariableDecl<'a>) -> P4Header {
-    let mut my_p4_header : P4Header = P4Header {meta:String::new(), meta_init:String::new(), register:String::new(), define:String::new()};
+    let mut my_p4_header : P4Header = P4Header::new();
     match my_decl.var_type.var_info {
         VarInfo::BitArray(bit_width, var_size) => {
             // let initial_val_index : usize = 0;
-            my_p4_header.register = format!("register {} {{\n{} width : {}; \n{} instance_count : {};\n}}\n",
-            my_decl.identifier.id_name, TAB, bit_width, TAB, var_size);
-            my_p4_header.meta = format!("{} : {};\n",my_decl.identifier.id_name, bit_width);
+            my_p4_header.register.push(Register { name : my_decl.identifier.id_name.to_string(), width : bit_width,
+                                                  instance_count : var_size });
+            my_p4_header.meta.push(MetaField { name : my_decl.identifier.id_name.to_string(), width : bit_width });
         }
         _ => { }
     }
@@ -70,7 +83,7 @@ pub fn handle_persistent_decl<'a> (my_decl :  &VariableDecl<'a>) -> P4Header {
 
 // how to handle input/output packet decls?? (see ecn.np)
 pub fn get_p4_header_trans<'a> (node_type : &'a DagNodeType<'a>) -> P4Header {
-    let mut my_p4_header : P4Header = P4Header {meta:String::new(), meta_init:String::new(), register:String::new(), define:String::new()};
+    let mut my_p4_header : P4Header = P4Header::new();
     let mut my_vardecl : VarDecl;
     match &node_type {
         DagNodeType::Decl(my_decl) => {
@@ -96,126 +109,111 @@ pub fn get_p4_header_trans<'a> (node_type : &'a DagNodeType<'a>) -> P4Header {
     }
 }
 
-pub fn get_NEW_ACTION () -> (String, String, String) {
-    let mut my_p4_control : String = String::new();
-    let mut my_p4_actions : String = String::new();
-    let mut my_p4_commons : String = String::new();
-    my_p4_control = my_p4_control + &format!("{}apply(table{:?});\n", TAB, TABLE_COUNT);
-    my_p4_actions = my_p4_actions + &format!("action action{:?} () {{\n", ACTION_COUNT);
-    my_p4_commons = my_p4_commons + &format!("table table{:?} {{\n", TABLE_COUNT);
-    my_p4_commons = my_p4_commons + &format!("{}actions {{\n", TAB);
-    my_p4_commons = my_p4_commons + &format!("{}{}action{:?};\n", TAB, TAB, ACTION_COUNT);
-    my_p4_commons = my_p4_commons + &format!("{}}}\n", TAB);
-    my_p4_commons = my_p4_commons + &format!("}}\n");
-    ACTION_COUNT.fetch_add(1, Ordering::SeqCst);
-    TABLE_COUNT.fetch_add(1, Ordering::SeqCst);
-    return (my_p4_control, my_p4_actions, my_p4_commons);
+// A new empty action, run by a new table without reads
+pub fn get_NEW_ACTION () -> P4Code {
+    let mut my_p4_code = P4Code::new();
+    let table = format!("table{}", next_count(&TABLE_COUNT));
+    let action = format!("action{}", next_count(&ACTION_COUNT));
+    my_p4_code.p4_control.push(ControlStmt::Apply(table.clone()));
+    my_p4_code.p4_actions.push(Action { name : action.clone(), primitives : Vec::new() });
+    my_p4_code.p4_tables.push(Table::new(&table, &action));
+    return my_p4_code;
 }
 
-pub fn handle_read_register (my_decl : &VarDecl, my_index : &str) -> (String, String, String) {
-    let mut my_p4_control : String = String::new();
-    let mut my_p4_actions : String = String::new();
-    let mut my_p4_commons : String = String::new();
-    let (a,b,c) = get_NEW_ACTION();
-    my_p4_control = a; my_p4_actions = b; my_p4_commons = c;
-    my_p4_actions = my_p4_actions + &format!("{}register_read({}.{}, {}, {});\n", TAB,
-        META_HEADER, my_decl.id, my_decl.id, my_index);
-    my_p4_actions = my_p4_actions + &format!("}}\n");
-
-    return (my_p4_control, my_p4_actions, my_p4_commons);
+// Names the actions of a statement after the snippet, the statement and their first
+// primitive, e.g. fun_s3_add, and its tables after their first action, so that the
+// generated code does not depend on what was translated before.
+fn name_statement_code(my_p4_code : &mut P4Code, prefix : &str) {
+    let mut names : HashMap<String, String> = HashMap::new();
+    let mut used : Vec<String> = Vec::new();
+    for action in &my_p4_code.p4_actions {
+        let kind = action.primitives.get(0).map_or("nop", |p| p.name.as_str());
+        let mut name = format!("{}_{}", prefix, kind);
+        let mut n = 2;
+        while used.contains(&name) {
+            name = format!("{}_{}_{}", prefix, kind, n);
+            n += 1;
+        }
+        used.push(name.clone());
+        names.insert(action.name.clone(), name);
+    }
+    for table in &my_p4_code.p4_tables {
+        let name = format!("{}_table", names[&table.actions[0]]);
+        names.insert(table.name.clone(), name);
+    }
+    my_p4_code.rename(&names);
 }
 
-pub fn handle_value_assignment<'a> ( my_lval_decl : &VarDecl, my_lval_index : &str,  val : u64,
-    (a,b,c,d) : (String, String, String, String)) -> (String, String, String, String) {
+// Name of a variable in P4, with its header if it is metadata
+fn get_field_name(prefix : &str, my_decl : &VarDecl) -> String {
+    if prefix.len() != 0 {
+        return format!("{}.{}", prefix, my_decl.id);
+    }
+    return my_decl.id.clone();
+}
 
-    let mut my_p4_control = a;
-    let mut my_p4_actions = b;
-    let mut my_p4_commons = c;
-    let mut my_p4_metadecl = d;
+// Name of a variable that is written, where everything but packet fields is metadata
+fn get_lval_name(my_lval_decl : &VarDecl) -> String {
+    match my_lval_decl.type_qualifier {
+        TypeQualifier::Field => my_lval_decl.id.clone(),
+        _ => format!("{}.{}", META_HEADER, my_lval_decl.id),
+    }
+}
+
+// Adds the primitive to a new action, unless it is part of an action that is being built
+fn push_action_primitive(my_p4_code : &mut P4Code, primitive : Primitive) {
+    if is_new_action() {
+        my_p4_code.append(get_NEW_ACTION());
+    }
+    my_p4_code.push_primitive(primitive);
+}
+
+pub fn handle_read_register (my_decl : &VarDecl, my_index : &str, mut my_p4_code : P4Code) -> P4Code {
+    my_p4_code.append(get_NEW_ACTION());
+    my_p4_code.push_primitive(Primitive::new("register_read",
+        vec![format!("{}.{}", META_HEADER, my_decl.id), my_decl.id.clone(), my_index.to_string()]));
+    return my_p4_code;
+}
+
+pub fn handle_value_assignment<'a> ( my_lval_decl : &VarDecl, my_lval_index : &str,  val : u64,
+    mut my_p4_code : P4Code) -> P4Code {
 
-    println!("handling value assignment for  :{:?}\n", my_lval_decl);
+    debug!("handling value assignment for  :{:?}\n", my_lval_decl);
     match my_lval_decl.type_qualifier {
-        TypeQualifier::Transient => {
+        TypeQualifier::Transient | TypeQualifier::Field => {
             // Metadata
-            if NEW_ACTION.load(Ordering::SeqCst) {
-                let (a, b, c) = get_NEW_ACTION();
-                my_p4_control = my_p4_control + &a;
-                my_p4_actions = my_p4_actions + &b;
-                my_p4_commons = my_p4_commons + &c;
-            }
-            my_p4_actions = my_p4_actions + &format!("{}modify_field({}.{}, {});\n", TAB, META_HEADER, my_lval_decl.id, val);
-            if NEW_ACTION.load(Ordering::SeqCst) {
-                my_p4_actions = my_p4_actions + &format!("}}\n");
-            }
-            return (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl);
+            push_action_primitive(&mut my_p4_code, Primitive::new("modify_field", vec![get_lval_name(my_lval_decl), val.to_string()]));
         }
 
         TypeQualifier::Persistent => {
             // Register
-            if NEW_ACTION.load(Ordering::SeqCst) {
-                let (a, b, c) = get_NEW_ACTION();
-                my_p4_control = my_p4_control + &a;
-                my_p4_actions = my_p4_actions + &b;
-                my_p4_commons = my_p4_commons + &c;
-            }
-            my_p4_actions = my_p4_actions + &format!("{}register_write({}, {}, {});\n", TAB, my_lval_decl.id, my_lval_index, val);
-            if NEW_ACTION.load(Ordering::SeqCst) {
-                my_p4_actions = my_p4_actions + &format!("}}\n");
-            }
-            return (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl);
-        }
-
-        TypeQualifier::Field => {
-            // Metadata
-            if NEW_ACTION.load(Ordering::SeqCst) {
-                let (a, b, c) = get_NEW_ACTION();
-                my_p4_control = my_p4_control + &a;
-                my_p4_actions = my_p4_actions + &b;
-                my_p4_commons = my_p4_commons + &c;
-            }
-            my_p4_actions = my_p4_actions + &format!("{}modify_field({}, {});\n", TAB, my_lval_decl.id, val);
-            if NEW_ACTION.load(Ordering::SeqCst) {
-                my_p4_actions = my_p4_actions + &format!("}}\n");
-            }
-            return (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl);
+            push_action_primitive(&mut my_p4_code, Primitive::new("register_write",
+                vec![my_lval_decl.id.clone(), my_lval_index.to_string(), val.to_string()]));
         }
 
-        _ => {
-            return (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl);
-        }
+        _ => {}
     }
+    return my_p4_code;
 }
 
 
-pub fn handle_read_register_v2 (my_decl : &VarDecl, my_index : &str) -> (String, String, String) {
-    let mut my_p4_control : String = String::new();
-    let mut my_p4_actions : String = String::new();
-    let mut my_p4_commons : String = String::new();
-    my_p4_actions = my_p4_actions + &format!("{}register_read({}.{}, {}, {});\n", TAB,
-        META_HEADER, my_decl.id, my_decl.id, my_index);
-    return (my_p4_control, my_p4_actions, my_p4_commons);
+pub fn handle_read_register_v2 (my_decl : &VarDecl, my_index : &str, mut my_p4_code : P4Code) -> P4Code {
+    my_p4_code.push_primitive(Primitive::new("register_read",
+        vec![format!("{}.{}", META_HEADER, my_decl.id), my_decl.id.clone(), my_index.to_string()]));
+    return my_p4_code;
 }
 
 // reg1 = if_block_tmp_2 ? tmp_0_if_2 : reg1; (see test1.np)
 // handle_ref_assignment(reg1, index, tmp_0_if_2, index, v2)
 pub fn handle_ref_assignment<'a> (my_lval_decl : &VarDecl, my_lval_index : &str, my_rval_decl : &VarDecl, my_rval_index : &str,
-                            read_reg_func : &Fn(&VarDecl, &str) -> (String, String, String),
-                            (a,b,c,d) : (String, String, String, String)) -> (String, String, String, String) {
-
-    let mut my_p4_control = a;
-    let mut my_p4_actions = b;
-    let mut my_p4_commons = c;
-    let mut my_p4_metadecl = d;
-
+                            read_reg_func : &Fn(&VarDecl, &str, P4Code) -> P4Code,
+                            mut my_p4_code : P4Code) -> P4Code {
 
     let mut prefix = "";
     match my_rval_decl.type_qualifier {
         TypeQualifier::Persistent => {
             // If register, then first need to read the register val to meta.
-            let (a,b,c) = read_reg_func(my_rval_decl, my_rval_index);
-            my_p4_control = my_p4_control + &a;
-            my_p4_actions = my_p4_actions + &b;
-            my_p4_commons = my_p4_commons + &c;
+            my_p4_code = read_reg_func(my_rval_decl, my_rval_index, my_p4_code);
             prefix = META_HEADER;
         }
         TypeQualifier::Transient => {
@@ -226,190 +224,121 @@ pub fn handle_ref_assignment<'a> (my_lval_decl : &VarDecl, my_lval_index : &str,
             // For others, nothing to be done.
         }
     }
+    let rval = get_field_name(prefix, my_rval_decl);
 
     match my_lval_decl.type_qualifier {
-        TypeQualifier::Transient => {
+        TypeQualifier::Transient | TypeQualifier::Field => {
             // Metadata
-            if NEW_ACTION.load(Ordering::SeqCst) {
-                let (a, b, c) = get_NEW_ACTION();
-                my_p4_control = my_p4_control + &a;
-                my_p4_actions = my_p4_actions + &b;
-                my_p4_commons = my_p4_commons + &c;
-            }
-            if prefix.len()!= 0 {
-                my_p4_actions = my_p4_actions + &format!("{}modify_field({}.{}, {}.{});\n", TAB,
-                    META_HEADER, my_lval_decl.id, prefix, my_rval_decl.id);
-            } else {
-                my_p4_actions = my_p4_actions + &format!("{}modify_field({}.{}, {});\n", TAB,
-                    META_HEADER, my_lval_decl.id, my_rval_decl.id);
-            }
-            if NEW_ACTION.load(Ordering::SeqCst) {
-                my_p4_actions = my_p4_actions + &format!("}}\n");
-            }
-
-            return (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl);
+            push_action_primitive(&mut my_p4_code, Primitive::new("modify_field", vec![get_lval_name(my_lval_decl), rval]));
         }
         TypeQualifier::Persistent => {
             // Register
-            if NEW_ACTION.load(Ordering::SeqCst) {
-                let (a, b, c) = get_NEW_ACTION();
-                my_p4_control = my_p4_control + &a;
-                my_p4_actions = my_p4_actions + &b;
-                my_p4_commons = my_p4_commons + &c;
-            }
-            if prefix.len()!= 0 {
-                my_p4_actions = my_p4_actions + &format!("{}register_write({}, {}, {}.{});\n", TAB,
-                        my_lval_decl.id, my_lval_index, prefix, my_rval_decl.id);
-            } else {
-                my_p4_actions = my_p4_actions + &format!("{}register_write({}, {}, {});\n", TAB,
-                    my_lval_decl.id, my_rval_index, my_rval_decl.id);
-            }
-            if NEW_ACTION.load(Ordering::SeqCst) {
-                my_p4_actions = my_p4_actions + &format!("}}\n");
-            }
-
-            return (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl);
-        }
-
-        TypeQualifier::Field => {
-            // Metadata
-            if NEW_ACTION.load(Ordering::SeqCst) {
-                let (a, b, c) = get_NEW_ACTION();
-                my_p4_control = my_p4_control + &a;
-                my_p4_actions = my_p4_actions + &b;
-                my_p4_commons = my_p4_commons + &c;
-            }
-
-            if prefix.len()!= 0 {
-                my_p4_actions = my_p4_actions + &format!("{}modify_field({}, {}.{});\n", TAB,
-                    my_lval_decl.id, prefix, my_rval_decl.id);
-            } else {
-                my_p4_actions = my_p4_actions + &format!("{}modify_field({}, {});\n", TAB,
-                    my_lval_decl.id, my_rval_decl.id);
-            }
-            if NEW_ACTION.load(Ordering::SeqCst) {
-                my_p4_actions = my_p4_actions + &format!("}}\n");
-            }
-
-            return (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl);
-        }
-
-        _ => {
-            return (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl);
+            push_action_primitive(&mut my_p4_code, Primitive::new("register_write",
+                vec![my_lval_decl.id.clone(), my_lval_index.to_string(), rval]));
         }
+        _ => {}
     }
+    return my_p4_code;
 }
 
 
+// Sets a one bit variable to the result of a comparison, using p4 control blocks
+// available in pipeline.
+fn handle_condition(bin_op_type : &str, my_lval_decl : &VarDecl, left : String, right : String) -> P4Code {
+    let mut my_p4_code = P4Code::new();
+    let lval = get_lval_name(my_lval_decl);
+    let mut then_code = get_NEW_ACTION();
+    then_code.push_primitive(Primitive::new("modify_field", vec![lval.clone(), String::from("1")]));
+    let mut else_code = get_NEW_ACTION();
+    else_code.push_primitive(Primitive::new("modify_field", vec![lval, String::from("0")]));
+
+    let condition = Comparison { left, op : bin_op_type.to_string(), right };
+    my_p4_code.p4_control.push(ControlStmt::If(condition, then_code.p4_control.drain(..).collect(),
+                                               else_code.p4_control.drain(..).collect()));
+    my_p4_code.append(then_code);
+    my_p4_code.append(else_code);
+    return my_p4_code;
+}
+
 
 // This method is using p4 control blocks available in pipeline.
 pub fn handle_condition_refs_v2<'a> (bin_op_type : &str, my_lval_decl : &VarDecl, prefix1 : &str,
- my_rval1_decl : &VarDecl, prefix2 : &str, my_rval2_decl : &VarDecl) -> (String, String, String, String) {
-     let mut my_p4_control : String = String::new();
-     let mut my_p4_actions : String = String::new();
-     let mut my_p4_commons : String = String::new();
-     let mut my_p4_metadecl : String = String::new();
-
-     if prefix1.len() != 0 {
-         my_p4_control = my_p4_control + &format!("{}if ({}.{} {} ", TAB, prefix1, my_rval1_decl.id, bin_op_type);
-     } else {
-         my_p4_control = my_p4_control + &format!("{}if ({} {} ", TAB, my_rval1_decl.id, bin_op_type);
-     }
-     if prefix2.len() != 0 {
-         my_p4_control = my_p4_control + &format!("{}.{}) {{\n{}", prefix2, my_rval2_decl.id, TAB);
-     } else {
-         my_p4_control = my_p4_control + &format!("{}) {{\n{}", my_rval2_decl.id, TAB);
-     }
-     let (a, b, c) = get_NEW_ACTION();
-     my_p4_control = my_p4_control + &a;
-     my_p4_actions = my_p4_actions + &b;
-     my_p4_commons = my_p4_commons + &c;
-
-     match my_lval_decl.type_qualifier {
-        TypeQualifier::Field => {
-            my_p4_actions = my_p4_actions + &format!("{}modify_field({}, 1); \n}}\n", TAB, my_lval_decl.id);
-        }
-        _ => { my_p4_actions = my_p4_actions + &format!("{}modify_field({}.{}, 1); \n}}\n", TAB, META_HEADER, my_lval_decl.id); }
-     }
-
-     my_p4_control = my_p4_control + &format!("{}}} else {{\n{}", TAB, TAB);
-     let (a, b, c) = get_NEW_ACTION();
-     my_p4_control = my_p4_control + &a;
-     my_p4_actions = my_p4_actions + &b;
-     my_p4_commons = my_p4_commons + &c;
-
-     match my_lval_decl.type_qualifier {
-        TypeQualifier::Field => {
-            my_p4_actions = my_p4_actions + &format!("{}modify_field({}, 0); \n}}\n", TAB, my_lval_decl.id);
-        }
-        _ => { my_p4_actions = my_p4_actions + &format!("{}modify_field({}.{}, 0); \n}}\n", TAB, META_HEADER, my_lval_decl.id); }
-     }
-
-     my_p4_control = my_p4_control + &format!("{}}}\n", TAB);
-     return (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl);
+ my_rval1_decl : &VarDecl, prefix2 : &str, my_rval2_decl : &VarDecl) -> P4Code {
+    return handle_condition(bin_op_type, my_lval_decl, get_field_name(prefix1, my_rval1_decl), get_field_name(prefix2, my_rval2_decl));
 }
 
 
 // This method is using p4 control blocks available in pipeline.
 pub fn handle_condition_refval_v2<'a> (bin_op_type : &str, my_lval_decl : &VarDecl, prefix1 : &str,
- my_rval1_decl : &VarDecl, val : u64) -> (String, String, String, String) {
-     let mut my_p4_control : String = String::new();
-     let mut my_p4_actions : String = String::new();
-     let mut my_p4_commons : String = String::new();
-     let mut my_p4_metadecl : String = String::new();
-     if prefix1.len() != 0 {
-         my_p4_control = my_p4_control + &format!("{}if ({}.{} {} {}) {{\n{}", TAB, prefix1, my_rval1_decl.id, bin_op_type, val, TAB);
-     } else {
-         my_p4_control = my_p4_control + &format!("{}if ({} {} {}) {{\n{}", TAB, my_rval1_decl.id, bin_op_type, val, TAB);
-     }
-     let (a, b, c) = get_NEW_ACTION();
-     my_p4_control = my_p4_control + &a;
-     my_p4_actions = my_p4_actions + &b;
-     my_p4_commons = my_p4_commons + &c;
-
-     match my_lval_decl.type_qualifier {
-        TypeQualifier::Field => {
-            my_p4_actions = my_p4_actions + &format!("{}modify_field({}, 1); \n}}\n", TAB, my_lval_decl.id);
+ my_rval1_decl : &VarDecl, val : u64) -> P4Code {
+    return handle_condition(bin_op_type, my_lval_decl, get_field_name(prefix1, my_rval1_decl), val.to_string());
+}
+
+
+// P4 primitive computing an arithmetic operation, empty for operations it cannot do
+fn get_binop_func(bin_op_type : BinOpType) -> &'static str {
+    match bin_op_type {
+        BinOpType::BooleanAnd => "bit_and",
+        BinOpType::BooleanOr => "bit_or",
+        BinOpType::ShiftLeft => "shift_left",
+        BinOpType::ShiftRight => "shift_right",
+        BinOpType::Plus => "add",
+        BinOpType::Minus => "subtract",
+        _ => "",
+    }
+}
+
+
+// Comparison operator of a binary operation that is a condition
+fn get_condition_op(bin_op_type : BinOpType) -> Option<&'static str> {
+    match bin_op_type {
+        BinOpType::Equal => Some("=="),
+        BinOpType::NotEqual => Some("!="),
+        BinOpType::GreaterThan => Some(">"),
+        BinOpType::LessThan => Some("<"),
+        BinOpType::GTEQOp => Some(">="),
+        BinOpType::LTEQOp => Some("<="),
+        _ => None,
+    }
+}
+
+
+// Emits lval = p4_func(rval1, rval2), writing the result back if lval is a register
+fn handle_binop_primitive(my_lval_decl : &VarDecl, my_lval_index : &str, p4_func : &str, rval1 : String, rval2 : String,
+                          my_p4_code : &mut P4Code) {
+    if p4_func.len() == 0 {
+        return;
+    }
+    match my_lval_decl.type_qualifier {
+        TypeQualifier::Transient | TypeQualifier::Field => {
+            push_action_primitive(my_p4_code, Primitive::new(p4_func, vec![get_lval_name(my_lval_decl), rval1, rval2]));
         }
-        _ => { my_p4_actions = my_p4_actions + &format!("{}modify_field({}.{}, 1); \n}}\n", TAB, META_HEADER, my_lval_decl.id); }
-     }
-
-     my_p4_control = my_p4_control + &format!("{}}} else {{\n{}", TAB, TAB);
-     let (a, b, c) = get_NEW_ACTION();
-     my_p4_control = my_p4_control + &a;
-     my_p4_actions = my_p4_actions + &b;
-     my_p4_commons = my_p4_commons + &c;
-
-     match my_lval_decl.type_qualifier {
-        TypeQualifier::Field => {
-            my_p4_actions = my_p4_actions + &format!("{}modify_field({}, 0); \n}}\n", TAB, my_lval_decl.id);
+        TypeQualifier::Persistent => {
+            // Register
+            let meta = format!("{}.{}", META_HEADER, my_lval_decl.id);
+            if is_new_action() {
+                my_p4_code.append(get_NEW_ACTION());
+            }
+            my_p4_code.push_primitive(Primitive::new(p4_func, vec![meta.clone(), rval1, rval2]));
+            my_p4_code.push_primitive(Primitive::new("register_write", vec![my_lval_decl.id.clone(), my_lval_index.to_string(), meta]));
         }
-        _ => { my_p4_actions = my_p4_actions + &format!("{}modify_field({}.{}, 0); \n}}\n", TAB, META_HEADER, my_lval_decl.id); }
-     }
-
-     my_p4_control = my_p4_control + &format!("{}}}\n", TAB);
-     return (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl);
+        // not handling input, output, const...
+        _ => {}
+    }
 }
 
 
 pub fn handle_binop_refs_assignment<'a> (my_lval_decl : &VarDecl,  my_lval_index : &str, my_rval1_decl : &VarDecl, my_rval1_index : &str,
     bin_op_type : BinOpType, my_rval2_decl : &VarDecl, my_rval2_index : &str, decl_map : &'a  HashMap<String, VarDecl>,
-     (a,b,c,d) : (String, String, String, String)) -> (String, String, String, String) {
+     mut my_p4_code : P4Code) -> P4Code {
 
-        let mut my_p4_control = a;
-        let mut my_p4_actions = b;
-        let mut my_p4_commons = c;
-        let mut my_p4_metadecl = d;
         let mut prefix1 = "";
         let mut prefix2 = "";
 
         match my_rval1_decl.type_qualifier {
             TypeQualifier::Persistent => {
                 // If register, then first need to read the register val to meta.
-                let (a,b,c) = handle_read_register(my_rval1_decl, my_rval1_index);
-                my_p4_control = my_p4_control + &a;
-                my_p4_actions = my_p4_actions + &b;
-                my_p4_commons = my_p4_commons + &c;
+                my_p4_code = handle_read_register(my_rval1_decl, my_rval1_index, my_p4_code);
                 prefix1 = META_HEADER;
             }
             TypeQualifier::Transient => {
@@ -423,10 +352,7 @@ pub fn handle_binop_refs_assignment<'a> (my_lval_decl : &VarDecl,  my_lval_index
         match my_rval2_decl.type_qualifier {
             TypeQualifier::Persistent => {
                 // If register, then first need to read the register val to meta.
-                let (a,b,c) = handle_read_register(my_rval2_decl, my_rval2_index);
-                my_p4_control = my_p4_control + &a;
-                my_p4_actions = my_p4_actions + &b;
-                my_p4_commons = my_p4_commons + &c;
+                my_p4_code = handle_read_register(my_rval2_decl, my_rval2_index, my_p4_code);
                 prefix2 = META_HEADER;
             }
             TypeQualifier::Transient => {
@@ -436,223 +362,38 @@ pub fn handle_binop_refs_assignment<'a> (my_lval_decl : &VarDecl,  my_lval_index
                 // For others, nothing to be done.
             }
         }
-        let mut p4_func = "";
-        match bin_op_type {
-            BinOpType::BooleanAnd => {
-                p4_func = "bit_and";
-            }
-            BinOpType::BooleanOr => {
-                p4_func = "bit_or";
-            }
-            BinOpType::ShiftLeft => {
-                p4_func = "shift_left";
-            }
-            BinOpType::ShiftRight => {
-                p4_func = "shift_right";
-            }
-            BinOpType::Plus => {
-                p4_func = "add";
-            }
-            BinOpType::Minus => {
-                p4_func = "subtract";
-            }
-            BinOpType::Mul => {
-                p4_func = "";
-            }
-            BinOpType::Div => {
-                p4_func = "";
-            }
-            BinOpType::Modulo => {
-                p4_func = "";
-            } // Conditions from here
-            BinOpType::Equal => {
-                p4_func = "";
-                // Eg. a == b
-                let (a,b,c,d) = handle_condition_refs_v2("==", my_lval_decl, prefix1, my_rval1_decl, prefix2, my_rval2_decl);
-                my_p4_control = my_p4_control + &a;
-                my_p4_actions = my_p4_actions + &b;
-                my_p4_commons = my_p4_commons + &c;
-                my_p4_metadecl = my_p4_metadecl + &d;
-            }
-            BinOpType::NotEqual => {
-                p4_func = "";
-                // Eg. a != b
-                let (a,b,c,d) = handle_condition_refs_v2("!=", my_lval_decl, prefix1, my_rval1_decl, prefix2, my_rval2_decl);
-                my_p4_control = my_p4_control + &a;
-                my_p4_actions = my_p4_actions + &b;
-                my_p4_commons = my_p4_commons + &c;
-                my_p4_metadecl = my_p4_metadecl + &d;
-            }
-            BinOpType::GreaterThan => {
-                p4_func = "";
-                // Eg. a != b
-                let (a,b,c,d) = handle_condition_refs_v2(">", my_lval_decl, prefix1, my_rval1_decl, prefix2, my_rval2_decl);
-                my_p4_control = my_p4_control + &a;
-                my_p4_actions = my_p4_actions + &b;
-                my_p4_commons = my_p4_commons + &c;
-                my_p4_metadecl = my_p4_metadecl + &d;
-            }
-            BinOpType::LessThan => {
-                p4_func = "";
-                // Eg. a != b
-                let (a,b,c,d) = handle_condition_refs_v2("<", my_lval_decl, prefix1, my_rval1_decl, prefix2, my_rval2_decl);
-                my_p4_control = my_p4_control + &a;
-                my_p4_actions = my_p4_actions + &b;
-                my_p4_commons = my_p4_commons + &c;
-                my_p4_metadecl = my_p4_metadecl + &d;
-            }
-            BinOpType::GTEQOp => {
-                p4_func = "";
-                // Eg. a != b
-                let (a,b,c,d) = handle_condition_refs_v2(">=", my_lval_decl, prefix1, my_rval1_decl, prefix2, my_rval2_decl);
-                my_p4_control = my_p4_control + &a;
-                my_p4_actions = my_p4_actions + &b;
-                my_p4_commons = my_p4_commons + &c;
-                my_p4_metadecl = my_p4_metadecl + &d;
-            }
-            BinOpType::LTEQOp => {
-                p4_func = "";
-                // Eg. a != b
-                let (a,b,c,d) = handle_condition_refs_v2("<=", my_lval_decl, prefix1, my_rval1_decl, prefix2, my_rval2_decl);
-                my_p4_control = my_p4_control + &a;
-                my_p4_actions = my_p4_actions + &b;
-                my_p4_commons = my_p4_commons + &c;
-                my_p4_metadecl = my_p4_metadecl + &d;
-            }
-            _ => {
-                p4_func = "";
-                // Eg. a >= b
-                panic!("Comparison on references not supported.\n");
-            }
-        }
-        match my_lval_decl.type_qualifier {
-            TypeQualifier::Transient => {
-                // Metadata
-                if p4_func.len() != 0 {
-                    if NEW_ACTION.load(Ordering::SeqCst) {
-                        let (a, b, c) = get_NEW_ACTION();
-                        my_p4_control = my_p4_control + &a;
-                        my_p4_actions = my_p4_actions + &b;
-                        my_p4_commons = my_p4_commons + &c;
-                    }
-                    my_p4_actions = my_p4_actions + &format!("{}{}({}.{}, ", TAB, p4_func, META_HEADER, my_lval_decl.id);
-                    match prefix1.len() {
-                        0 => {
-                            my_p4_actions = my_p4_actions + &format!("{}, ", my_rval1_decl.id);
 
-                        }
-                        _ => {
-                            my_p4_actions = my_p4_actions + &format!("{}.{}, ",prefix1, my_rval1_decl.id);
-                        }
-                    }
-                    match prefix2.len() {
-                        0 => {
-                            my_p4_actions = my_p4_actions + &format!("{});\n", my_rval2_decl.id);
-                        }
-                        _ => {
-                            my_p4_actions = my_p4_actions + &format!("{}.{});\n",prefix2, my_rval2_decl.id);
-                        }
-                    }
-                    if NEW_ACTION.load(Ordering::SeqCst) {
-                        my_p4_actions = my_p4_actions + &format!("}}\n");
-                    }
+        let p4_func = get_binop_func(bin_op_type);
+        if p4_func.len() == 0 {
+            match get_condition_op(bin_op_type) {
+                Some(op) => {
+                    // Eg. a == b
+                    my_p4_code.append(handle_condition_refs_v2(op, my_lval_decl, prefix1, my_rval1_decl, prefix2, my_rval2_decl));
                 }
-            }
-
-            TypeQualifier::Persistent => {
-                // Register
-                if p4_func.len() != 0 {
-                    if NEW_ACTION.load(Ordering::SeqCst) {
-                        let (a, b, c) = get_NEW_ACTION();
-                        my_p4_control = my_p4_control + &a;
-                        my_p4_actions = my_p4_actions + &b;
-                        my_p4_commons = my_p4_commons + &c;
-                    }
-                    my_p4_actions = my_p4_actions + &format!("{}{}({}.{}, ", TAB, p4_func, META_HEADER, my_lval_decl.id);
-                    match prefix1.len() {
-                        0 => {
-                            my_p4_actions = my_p4_actions + &format!("{}, ", my_rval1_decl.id);
-                        }
-                        _ => {
-                            my_p4_actions = my_p4_actions + &format!("{}.{}, ",prefix1, my_rval1_decl.id);
-                        }
-                    }
-                    match prefix2.len() {
-                        0 => {
-                            my_p4_actions = my_p4_actions + &format!("{});\n", my_rval2_decl.id);
-                        }
-                        _ => {
-                            my_p4_actions = my_p4_actions + &format!("{}.{});\n",prefix2, my_rval2_decl.id);
-                        }
-                    }
-                    my_p4_actions = my_p4_actions + &format!("{}register_write({}, {}, {}.{});\n", TAB,
-                            my_lval_decl.id, my_lval_index, META_HEADER, my_lval_decl.id);
-                    if NEW_ACTION.load(Ordering::SeqCst) {
-                        my_p4_actions = my_p4_actions + &format!("}}\n");
-                    }
-                }
-            }
-
-            TypeQualifier::Field => {
-                // packet fields
-                if p4_func.len() != 0 {
-                    if NEW_ACTION.load(Ordering::SeqCst) {
-                        let (a, b, c) = get_NEW_ACTION();
-                        my_p4_control = my_p4_control + &a;
-                        my_p4_actions = my_p4_actions + &b;
-                        my_p4_commons = my_p4_commons + &c;
-                    }
-                    my_p4_actions = my_p4_actions + &format!("{}{}({}, ", TAB, p4_func, my_lval_decl.id);
-                    match prefix1.len() {
-                        0 => {
-                            my_p4_actions = my_p4_actions + &format!("{}, ", my_rval1_decl.id);
-                        }
-                        _ => {
-                            my_p4_actions = my_p4_actions + &format!("{}.{}, ",prefix1, my_rval1_decl.id);
-                        }
-                    }
-                    match prefix2.len() {
-                        0 => {
-                            my_p4_actions = my_p4_actions + &format!("{});\n", my_rval2_decl.id);
-                        }
-                        _ => {
-                            my_p4_actions = my_p4_actions + &format!("{}.{});\n",prefix2, my_rval2_decl.id);
-                        }
-                    }
-                    if NEW_ACTION.load(Ordering::SeqCst) {
-                        my_p4_actions = my_p4_actions + &format!("}}\n");
-                    }
+                None => match bin_op_type {
+                    BinOpType::Mul | BinOpType::Div | BinOpType::Modulo => {}
+                    _ => panic!("Comparison on references not supported.\n"),
                 }
-
             }
-            // not handling input, output, const...
-            _ => {}
         }
-
-        return (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl);
+        handle_binop_primitive(my_lval_decl, my_lval_index, p4_func, get_field_name(prefix1, my_rval1_decl),
+                               get_field_name(prefix2, my_rval2_decl), &mut my_p4_code);
+        return my_p4_code;
 }
 
 //Direction : true  for ref <op> val, false for val <op> ref
 pub fn handle_binop_refval_assignment<'a> (my_lval_decl : &VarDecl,  my_lval_index : &str,
     my_rval_decl : &VarDecl,  my_rval_index : &str, bin_op_type : BinOpType, val2 : u64,
      decl_map : &'a  HashMap<String, VarDecl>, ordering : bool,
-     (a,b,c,d) : (String, String, String, String)) -> (String, String, String, String) {
-
-    println!("Handling binop refval\n");
-    let mut my_p4_control = a;
-    let mut my_p4_actions = b;
-    let mut my_p4_commons = c;
-    let mut my_p4_metadecl = d;
+     mut my_p4_code : P4Code) -> P4Code {
 
+    debug!("Handling binop refval\n");
     let mut prefix1 = "";
 
     match my_rval_decl.type_qualifier {
         TypeQualifier::Persistent => {
             // If register, then first need to read the register val to meta.
-            let (a,b,c) = handle_read_register(my_rval_decl, my_rval_index);
-            my_p4_control = my_p4_control + &a;
-            my_p4_actions = my_p4_actions + &b;
-            my_p4_commons = my_p4_commons + &c;
+            my_p4_code = handle_read_register(my_rval_decl, my_rval_index, my_p4_code);
             prefix1 = META_HEADER;
         }
         TypeQualifier::Transient => {
@@ -663,349 +404,67 @@ pub fn handle_binop_refval_assignment<'a> (my_lval_decl : &VarDecl,  my_lval_ind
         }
     }
 
-    let mut p4_func = "";
-    match bin_op_type {
-        BinOpType::BooleanAnd => {
-            p4_func = "bit_and";
-        }
-        BinOpType::BooleanOr => {
-            p4_func = "bit_or";
-        }
-        BinOpType::ShiftLeft => {
-            p4_func = "shift_left";
-        }
-        BinOpType::ShiftRight => {
-            p4_func = "shift_right";
-        }
-        BinOpType::Plus => {
-            p4_func = "add";
-        }
-        BinOpType::Minus => {
-            p4_func = "subtract";
-        }
-        BinOpType::Mul => {
-            p4_func = "";
-        }
-        BinOpType::Div => {
-            p4_func = "";
-        }
-        BinOpType::Modulo => {
-            p4_func = "";
-        }
-        BinOpType::Equal => {
-            p4_func = "";
-            // Eg. a == 10
-            let (a,b,c,d) = handle_condition_refval_v2("==", my_lval_decl, prefix1, my_rval_decl, val2);
-            println!("Handling Condition.\n");
-            my_p4_control = my_p4_control + &a;
-            my_p4_actions = my_p4_actions + &b;
-            my_p4_commons = my_p4_commons + &c;
-            my_p4_metadecl = my_p4_metadecl + &d;
-            println!("{:?} .. {:?} .. {:?}\n", my_p4_control, my_p4_actions, my_p4_commons);
-        }
-        BinOpType::NotEqual => {
-            p4_func = "";
-            // Eg. a != 10
-            let (a,b,c,d) = handle_condition_refval_v2("!=", my_lval_decl, prefix1, my_rval_decl, val2);
-            my_p4_control = my_p4_control + &a;
-            my_p4_actions = my_p4_actions + &b;
-            my_p4_commons = my_p4_commons + &c;
-            my_p4_metadecl = my_p4_metadecl + &d;
-        }
-        BinOpType::GreaterThan => {
-            p4_func = "";
-            // Eg. a != 10
-            let (a,b,c,d) = handle_condition_refval_v2(">", my_lval_decl, prefix1, my_rval_decl, val2);
-            my_p4_control = my_p4_control + &a;
-            my_p4_actions = my_p4_actions + &b;
-            my_p4_commons = my_p4_commons + &c;
-            my_p4_metadecl = my_p4_metadecl + &d;
-        }
-        BinOpType::LessThan => {
-            p4_func = "";
-            // Eg. a != 10
-            let (a,b,c,d) = handle_condition_refval_v2("<", my_lval_decl, prefix1, my_rval_decl, val2);
-            my_p4_control = my_p4_control + &a;
-            my_p4_actions = my_p4_actions + &b;
-            my_p4_commons = my_p4_commons + &c;
-            my_p4_metadecl = my_p4_metadecl + &d;
-        }
-        BinOpType::GTEQOp => {
-            p4_func = "";
-            // Eg. a != 10
-            let (a,b,c,d) = handle_condition_refval_v2(">=", my_lval_decl, prefix1, my_rval_decl, val2);
-            my_p4_control = my_p4_control + &a;
-            my_p4_actions = my_p4_actions + &b;
-            my_p4_commons = my_p4_commons + &c;
-            my_p4_metadecl = my_p4_metadecl + &d;
-        }
-        BinOpType::LTEQOp => {
-            p4_func = "";
-            // Eg. a != 10
-            let (a,b,c,d) = handle_condition_refval_v2("<=", my_lval_decl, prefix1, my_rval_decl, val2);
-            my_p4_control = my_p4_control + &a;
-            my_p4_actions = my_p4_actions + &b;
-            my_p4_commons = my_p4_commons + &c;
-            my_p4_metadecl = my_p4_metadecl + &d;
-        }
-        _ => {
-            p4_func = "";
-            // Eg. a >= b
-            panic!("Comparison on references not supported.\n");
-        }
-    }
-    match my_lval_decl.type_qualifier {
-        TypeQualifier::Transient => {
-            // Metadata
-            if p4_func.len() != 0 {
-                if NEW_ACTION.load(Ordering::SeqCst) {
-                    let (a, b, c) = get_NEW_ACTION();
-                    my_p4_control = my_p4_control + &a;
-                    my_p4_actions = my_p4_actions + &b;
-                    my_p4_commons = my_p4_commons + &c;
-                }
-                my_p4_actions = my_p4_actions + &format!("{}{}({}.{}, ", TAB, p4_func, META_HEADER, my_lval_decl.id);
-                if ordering {
-                    match prefix1.len() {
-                        0 => {
-                            my_p4_actions = my_p4_actions + &format!("{}, ", my_rval_decl.id);
-                        }
-                        _ => {
-                            my_p4_actions = my_p4_actions + &format!("{}.{}, ",prefix1, my_rval_decl.id);
-                        }
-                    }
-                    my_p4_actions = my_p4_actions + &format!("{});\n", val2);
-                } else {
-                    my_p4_actions = my_p4_actions + &format!("{},\n", val2);
-                    match prefix1.len() {
-                        0 => {
-                            my_p4_actions = my_p4_actions + &format!("{});", my_rval_decl.id);
-                        }
-                        _ => {
-                            my_p4_actions = my_p4_actions + &format!("{}.{});",prefix1, my_rval_decl.id);
-                        }
-                    }
-                }
-                if NEW_ACTION.load(Ordering::SeqCst) {
-                    my_p4_actions = my_p4_actions + &format!("}}\n");
-                }
-            }
-
-        }
-
-        TypeQualifier::Persistent => {
-            // Register
-            if p4_func.len() != 0 {
-                if NEW_ACTION.load(Ordering::SeqCst) {
-                    let (a, b, c) = get_NEW_ACTION();
-                    my_p4_control = my_p4_control + &a;
-                    my_p4_actions = my_p4_actions + &b;
-                    my_p4_commons = my_p4_commons + &c;
-                }
-                my_p4_actions = my_p4_actions + &format!("{}{}({}.{}, ", TAB, p4_func, META_HEADER, my_lval_decl.id);
+    let p4_func = get_binop_func(bin_op_type);
+    if p4_func.len() == 0 {
+        match get_condition_op(bin_op_type) {
+            Some(op) => {
+                // Eg. a == 10, or 1 < b
+                debug!("Handling Condition.\n");
                 if ordering {
-                    match prefix1.len() {
-                        0 => {
-                            my_p4_actions = my_p4_actions + &format!("{}, ", my_rval_decl.id);
-                        }
-                        _ => {
-                            my_p4_actions = my_p4_actions + &format!("{}.{}, ",prefix1, my_rval_decl.id);
-                        }
-                    }
-                    my_p4_actions = my_p4_actions + &format!("{});\n", val2);
+                    my_p4_code.append(handle_condition_refval_v2(op, my_lval_decl, prefix1, my_rval_decl, val2));
                 } else {
-                    my_p4_actions = my_p4_actions + &format!("{}, ", val2);
-                    match prefix1.len() {
-                        0 => {
-                            my_p4_actions = my_p4_actions + &format!("{});", my_rval_decl.id);
-                        }
-                        _ => {
-                            my_p4_actions = my_p4_actions + &format!("{}.{});", prefix1, my_rval_decl.id);
-                        }
-                    }
-                }
-                my_p4_actions = my_p4_actions + &format!("{}register_write({}, {}, {}.{});\n", TAB,
-                        my_lval_decl.id, my_lval_index, META_HEADER, my_lval_decl.id);
-                if NEW_ACTION.load(Ordering::SeqCst) {
-                    my_p4_actions = my_p4_actions + &format!("}}\n");
+                    my_p4_code.append(handle_condition(op, my_lval_decl, val2.to_string(), get_field_name(prefix1, my_rval_decl)));
                 }
             }
-        }
-
-        TypeQualifier::Field => {
-            // Metadata
-            if p4_func.len() != 0 {
-                if NEW_ACTION.load(Ordering::SeqCst) {
-                    let (a, b, c) = get_NEW_ACTION();
-                    my_p4_control = my_p4_control + &a;
-                    my_p4_actions = my_p4_actions + &b;
-                    my_p4_commons = my_p4_commons + &c;
-                }
-
-                my_p4_actions = my_p4_actions + &format!("{}{}({}, ", TAB, p4_func, my_lval_decl.id);
-                if ordering {
-                    match prefix1.len() {
-                        0 => {
-                            my_p4_actions = my_p4_actions + &format!("{}, ", my_rval_decl.id);
-                        }
-                        _ => {
-                            my_p4_actions = my_p4_actions + &format!("{}.{}, ",prefix1, my_rval_decl.id);
-                        }
-                    }
-                    my_p4_actions = my_p4_actions + &format!("{});\n", val2);
-                } else {
-                    my_p4_actions = my_p4_actions + &format!("{},\n", val2);
-                    match prefix1.len() {
-                        0 => {
-                            my_p4_actions = my_p4_actions + &format!("{});", my_rval_decl.id);
-                        }
-                        _ => {
-                            my_p4_actions = my_p4_actions + &format!("{}.{});",prefix1, my_rval_decl.id);
-                        }
-                    }
-                }
-                if NEW_ACTION.load(Ordering::SeqCst) {
-                    my_p4_actions = my_p4_actions + &format!("}}\n");
-                }
+            None => match bin_op_type {
+                BinOpType::Mul | BinOpType::Div | BinOpType::Modulo => {}
+                _ => panic!("Comparison on references not supported.\n"),
             }
         }
-
-        _ => {
-            return (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl);
-        }
     }
-
-    return (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl);
+    let rval = get_field_name(prefix1, my_rval_decl);
+    if ordering {
+        handle_binop_primitive(my_lval_decl, my_lval_index, p4_func, rval, val2.to_string(), &mut my_p4_code);
+    } else {
+        handle_binop_primitive(my_lval_decl, my_lval_index, p4_func, val2.to_string(), rval, &mut my_p4_code);
+    }
+    return my_p4_code;
 }
 
 
 pub fn handle_binop_vals_assignment<'a> (my_lval_decl : &VarDecl, my_lval_index : &str,
         val1 : u64, bin_op_type : BinOpType, val2 : u64, decl_map : &'a  HashMap<String, VarDecl>,
-        (a,b,c,d) : (String, String, String, String)) -> (String, String, String, String) {
-
-    let mut my_p4_control = a;
-    let mut my_p4_actions = b;
-    let mut my_p4_commons = c;
-    let mut my_p4_metadecl = d;
+        mut my_p4_code : P4Code) -> P4Code {
 
-    let mut p4_func = "";
+    let p4_func = get_binop_func(bin_op_type);
     match bin_op_type {
-        BinOpType::BooleanAnd => {
-            p4_func = "bit_and";
-        }
-        BinOpType::BooleanOr => {
-            p4_func = "bit_or";
-        }
-        BinOpType::ShiftLeft => {
-            p4_func = "shift_left";
-        }
-        BinOpType::ShiftRight => {
-            p4_func = "shift_right";
-        }
-        BinOpType::Plus => {
-            p4_func = "add";
-        }
-        BinOpType::Minus => {
-            p4_func = "subtract";
-        }
-        BinOpType::Mul => {
-            p4_func = "";
-        }
-        BinOpType::Div => {
-            p4_func = "";
-        }
-        BinOpType::Modulo => {
-            p4_func = "";
-        }
+        BinOpType::Mul | BinOpType::Div | BinOpType::Modulo => {}
+        _ if p4_func.len() != 0 => {}
         _ => {
             panic!("Not supporting complete value based condition.");
             //Something like z = a < b. This could be a pre-condition. will be handled separately.
         }
     }
-    match my_lval_decl.type_qualifier {
-        TypeQualifier::Transient => {
-            // Metadata
-            if p4_func.len() != 0 {
-                if NEW_ACTION.load(Ordering::SeqCst) {
-                    let (a, b, c) = get_NEW_ACTION();
-                    my_p4_control = my_p4_control + &a;
-                    my_p4_actions = my_p4_actions + &b;
-                    my_p4_commons = my_p4_commons + &c;
-                }
-                my_p4_actions = my_p4_actions + &format!("{}{}({}.{}, {}, {});\n", TAB, p4_func, META_HEADER, my_lval_decl.id, val1, val2);
-
-                if NEW_ACTION.load(Ordering::SeqCst) {
-                    my_p4_actions = my_p4_actions + &format!("}}\n");
-                }
-            }
-
-        }
-
-        TypeQualifier::Persistent => {
-            // Register
-            if p4_func.len() != 0 {
-                if NEW_ACTION.load(Ordering::SeqCst) {
-                    let (a, b, c) = get_NEW_ACTION();
-                    my_p4_control = my_p4_control + &a;
-                    my_p4_actions = my_p4_actions + &b;
-                    my_p4_commons = my_p4_commons + &c;
-                }
-                my_p4_actions = my_p4_actions + &format!("{}{}({}.{}, {}, {});\n", TAB, p4_func, META_HEADER, my_lval_decl.id, val1, val2);
-
-                my_p4_actions = my_p4_actions + &format!("{}register_write({}, {}, {}.{});\n", TAB,
-                        my_lval_decl.id, my_lval_index, META_HEADER, my_lval_decl.id);
-                if NEW_ACTION.load(Ordering::SeqCst) {
-                    my_p4_actions = my_p4_actions + &format!("}}\n");
-                }
-            }
-        }
-
-        TypeQualifier::Field => {
-            // Metadata
-            if p4_func.len() != 0 {
-                if NEW_ACTION.load(Ordering::SeqCst) {
-                    let (a, b, c) = get_NEW_ACTION();
-                    my_p4_control = my_p4_control + &a;
-                    my_p4_actions = my_p4_actions + &b;
-                    my_p4_commons = my_p4_commons + &c;
-                }
-                my_p4_actions = my_p4_actions + &format!("{}{}({}, {}, {});\n", TAB, p4_func, my_lval_decl.id, val1, val2);
-
-                if NEW_ACTION.load(Ordering::SeqCst) {
-                    my_p4_actions = my_p4_actions + &format!("}}\n");
-                }
-            }
-
-        }
-
-        _ => {
-            return (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl);
-        }
-    }
-    return (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl);
+    handle_binop_primitive(my_lval_decl, my_lval_index, p4_func, val1.to_string(), val2.to_string(), &mut my_p4_code);
+    return my_p4_code;
 }
 
 // handle_action_operand(l, index, reg3, decl_map)
 
 pub fn handle_action_operand<'a> (my_lval_decl : &VarDecl,  my_lval_index : &str, operand : &Operand<'a>,
                             decl_map : &'a  HashMap<String, VarDecl>, packet_map : &HashMap<String, String>,
-                            (a,b,c,d) : (String, String, String, String)) -> (String, String, String, String) {
-
-    let mut my_p4_control = a;
-    let mut my_p4_actions = b;
-    let mut my_p4_commons = c;
-    let mut my_p4_metadecl = d;
+                            mut my_p4_code : P4Code) -> P4Code {
 
     let mut my_rval_decl;
     let mut my_rval_index = String::from("0");
-    println!("Handling action operand :{}", my_lval_decl.id);
+    debug!("Handling action operand :{}", my_lval_decl.id);
     match operand {
         Operand::LValue(ref lval) => {
             match lval {
                 LValue::Scalar(ref my_id) => {
                     if my_lval_decl.id == my_id.id_name {
-                        println!("Empty\n");
-                        return (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl);
+                        debug!("Empty\n");
+                        return my_p4_code;
                     }
                     my_rval_decl = get_decl(my_id.id_name, decl_map);
                 }
@@ -1017,14 +476,14 @@ pub fn handle_action_operand<'a> (my_lval_decl : &VarDecl,  my_lval_index : &str
                     match my_lval_option {
                         Some(decl) => {
                             if my_lval_decl.id == *decl {
-                                return (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl);
+                                return my_p4_code;
                             }
                             my_rval_decl = get_decl(&decl, decl_map);
                         }
                         None => {
                             //Could be an imported field?
                             if (my_lval_decl.id == my_id) {
-                                return (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl);
+                                return my_p4_code;
                             }
                             my_rval_decl = get_decl(&my_id, decl_map);
                         }
@@ -1033,22 +492,20 @@ pub fn handle_action_operand<'a> (my_lval_decl : &VarDecl,  my_lval_index : &str
 
                 LValue::Array(ref my_id, ref box_index_op) => {
                     if (my_lval_decl.id == my_id.id_name) {
-                        return (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl);
+                        return my_p4_code;
                     }
                     my_rval_decl = get_decl(my_id.id_name, decl_map);
-                    let (a,b,c,d) = handle_array(box_index_op, decl_map, packet_map, &handle_read_register_v2);
-                    my_p4_control = my_p4_control + &a;
-                    my_p4_actions = my_p4_actions + &b;
-                    my_p4_commons = my_p4_commons + &c;
-                    my_rval_index = d;
+                    let (code, index) = handle_array(box_index_op, decl_map, packet_map, &handle_read_register_v2, my_p4_code);
+                    my_p4_code = code;
+                    my_rval_index = index;
                 }
             }
             return handle_ref_assignment(&my_lval_decl, my_lval_index, &my_rval_decl, &my_rval_index, &handle_read_register_v2,
-                                    (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl));
+                                    my_p4_code);
         }
 
         Operand::Value(ref rval_val) => {
-            return handle_value_assignment(&my_lval_decl, my_lval_index, rval_val.value, (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl));
+            return handle_value_assignment(&my_lval_decl, my_lval_index, rval_val.value, my_p4_code);
         }
     }
 }
@@ -1056,73 +513,35 @@ pub fn handle_action_operand<'a> (my_lval_decl : &VarDecl,  my_lval_index : &str
 // reg1 = if_block_tmp_2 ? tmp_0_if_2 : reg1; (test1.np)
 pub fn handle_ternary_assignment<'a> (my_lval_decl : &VarDecl, my_lval_index : &str,
  my_rval_decl : &VarDecl<'a>, my_rval_index : &str, operand1 : &Operand<'a>, operand2 : &Operand<'a>, decl_map : &'a  HashMap<String, VarDecl>,
-    packet_map : &HashMap<String, String>, (a,b,c,d) : (String, String, String, String)) -> (String, String, String, String) {
+    packet_map : &HashMap<String, String>, mut my_p4_code : P4Code) -> P4Code {
 
-    let mut my_p4_control = a;
-    let mut my_p4_actions = b;
-    let mut my_p4_commons = c;
-    let mut my_p4_metadecl = d;
-
-    println!("Handling Ternary Assigment\n");
+    debug!("Handling Ternary Assigment\n");
     match my_rval_decl.type_qualifier {
         TypeQualifier::Persistent => {
             // If register, then first need to read the register val to meta.
-            let (a,b,c) = handle_read_register(my_rval_decl, my_rval_index);
-            my_p4_control = my_p4_control + &a;
-            my_p4_actions = my_p4_actions + &b;
-            my_p4_commons = my_p4_commons + &c;
+            my_p4_code = handle_read_register(my_rval_decl, my_rval_index, my_p4_code);
         }
         _ => {
             // For others, nothing to be done.
         }
     }
 
-    my_p4_control = my_p4_control + &format!("{}apply(table{:?});\n", TAB, TABLE_COUNT);
-    let action1 = &format!("action{:?}", ACTION_COUNT);
-    ACTION_COUNT.fetch_add(1, Ordering::SeqCst);
-    let action2 = &format!("action{:?}", ACTION_COUNT);
-    ACTION_COUNT.fetch_add(1, Ordering::SeqCst);
-    NEW_ACTION.store(false, Ordering::SeqCst);
-    my_p4_actions = my_p4_actions + &format!("action {} () {{\n", action1.to_string());
-    println!("action before : {:?}\n", my_p4_actions);
+    let table = format!("table{}", next_count(&TABLE_COUNT));
+    my_p4_code.p4_control.push(ControlStmt::Apply(table.clone()));
+    let action1 = format!("action{}", next_count(&ACTION_COUNT));
+    let action2 = format!("action{}", next_count(&ACTION_COUNT));
+    NEW_ACTION.with(|c| c.set(false));
+    my_p4_code.p4_actions.push(Action { name : action1.clone(), primitives : Vec::new() });
     // handle_action_operand(l, index, reg3, decl_map) (see first1.np)
-    let (a,b,c,d) = handle_action_operand(my_lval_decl, my_lval_index, operand1, decl_map, packet_map,
-        (my_p4_control.clone(), my_p4_actions.clone(), my_p4_commons.clone(), my_p4_metadecl.clone()));
-    my_p4_control = a;
-    my_p4_actions = b;
-    my_p4_commons = c;
-    my_p4_metadecl = d;
-
-    my_p4_actions = my_p4_actions + &format!("}}\n");
-    my_p4_actions = my_p4_actions + &format!("action {} () {{\n", action2.to_string());
-    let (a,b,c,d) = handle_action_operand(my_lval_decl, my_lval_index, operand2, decl_map, packet_map,
-        (my_p4_control.clone(), my_p4_actions.clone(), my_p4_commons.clone(), my_p4_metadecl.clone()));
-    my_p4_control = a;
-    my_p4_actions = b;
-    my_p4_commons = c;
-    my_p4_metadecl = d;
-    my_p4_actions = my_p4_actions + &format!("}}\n");
-    my_p4_commons = my_p4_commons + &format!("table table{:?} {{\n", TABLE_COUNT);
-    my_p4_commons = my_p4_commons + &format!("{}reads {{\n", TAB);
+    my_p4_code = handle_action_operand(my_lval_decl, my_lval_index, operand1, decl_map, packet_map, my_p4_code);
+    my_p4_code.p4_actions.push(Action { name : action2.clone(), primitives : Vec::new() });
+    my_p4_code = handle_action_operand(my_lval_decl, my_lval_index, operand2, decl_map, packet_map, my_p4_code);
 
-    match my_rval_decl.type_qualifier {
-        TypeQualifier::Field => {
-            my_p4_commons = my_p4_commons + &format!("{}{}{} : exact;\n{}}}\n", TAB, TAB, my_rval_decl.id, TAB);
-        }
-        _ => {
-            my_p4_commons = my_p4_commons + &format!("{}{}{}.{} : exact;\n{}}}\n", TAB, TAB, META_HEADER, my_rval_decl.id, TAB);
-        }
-    }
-    // my_p4_commons = my_p4_commons + &format!("{}{}{}.{} : exact;\n{}}}\n", TAB, TAB, META_HEADER, my_rval_decl.id, TAB);
-    my_p4_commons = my_p4_commons + &format!("{}actions {{\n", TAB);
-    my_p4_commons = my_p4_commons + &format!("{}{}{};\n", TAB, TAB, action1.to_string());
-    my_p4_commons = my_p4_commons + &format!("{}{}{};\n", TAB, TAB, action2.to_string());
-    my_p4_commons = my_p4_commons + &format!("{}}}\n", TAB);
-    my_p4_commons = my_p4_commons + &format!("}}\n");
-    TABLE_COUNT.fetch_add(1, Ordering::SeqCst);
-    NEW_ACTION.store(true, Ordering::SeqCst);
-
-    return (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl);
+    my_p4_code.p4_tables.push(Table { name : table, reads : vec![(get_lval_name(my_rval_decl), String::from("exact"))],
+                                      actions : vec![action1, action2], default_action : None });
+    NEW_ACTION.with(|c| c.set(true));
+
+    return my_p4_code;
 }
 
 
@@ -1143,15 +562,12 @@ pub fn get_decl<'a> (my_id : &str,  decl_map : &'a  HashMap<String, VarDecl>) ->
 
 pub fn handle_array<'a> (operand :  &Operand<'a>, decl_map : &'a  HashMap<String, VarDecl>,
                         packet_map : &HashMap<String, String>,
-                        read_reg_func : &Fn(&VarDecl, &str) -> (String, String, String)) -> (String, String, String, String) {
+                        read_reg_func : &Fn(&VarDecl, &str, P4Code) -> P4Code, mut my_p4_code : P4Code) -> (P4Code, String) {
 
-    let mut my_p4_control : String = String::new();
-    let mut my_p4_actions : String = String::new();
-    let mut my_p4_commons : String = String::new();
     let mut index : String = String::new();
     let my_decl;
 
-    println!("Handling array\n");
+    debug!("Handling array\n");
     match operand {
         Operand::LValue(ref lval) => {
             match lval {
@@ -1160,10 +576,7 @@ pub fn handle_array<'a> (operand :  &Operand<'a>, decl_map : &'a  HashMap<String
                     match my_decl.type_qualifier {
                         TypeQualifier::Persistent => {
                             // array index can be a register with size 1 (so index 0) but not size > 1 (an array)
-                            let (a,b,c) = read_reg_func(my_decl, "0");
-                            my_p4_control = my_p4_control + &a;
-                            my_p4_actions = my_p4_actions + &b;
-                            my_p4_commons = my_p4_commons + &c;
+                            my_p4_code = read_reg_func(my_decl, "0", my_p4_code);
                             index = format!("{}.{}", META_HEADER, my_decl.id);
                         }
                         TypeQualifier::Transient => {
@@ -1199,17 +612,14 @@ pub fn handle_array<'a> (operand :  &Operand<'a>, decl_map : &'a  HashMap<String
         }
     }
 
-    return (my_p4_control, my_p4_actions, my_p4_commons, index);
+    return (my_p4_code, index);
 }
 
 
 pub fn handle_statement<'a> (my_statement :  &Statement<'a>, node_type : &DagNodeType<'a>,
     pre_condition : &Option<Statement<'a>>, decl_map : &'a  HashMap<String, VarDecl>,
-      import_map : &HashMap<String, String>, packet_map : &HashMap<String, String>) -> (String, String, String, String) {
-        let mut my_p4_control : String = String::new();
-        let mut my_p4_actions : String = String::new();
-        let mut my_p4_commons : String = String::new();
-        let mut my_p4_metadecl : String = String::new();
+      import_map : &HashMap<String, String>, packet_map : &HashMap<String, String>) -> P4Code {
+        let mut my_p4_code = P4Code::new();
         let empty_decl = VarDecl {id : String::new(), var_info : VarInfo::BitArray(0,0), type_qualifier: TypeQualifier::Input};
         let mut my_lval_decl = &empty_decl;
         let mut my_rval_decl1 = &empty_decl;
@@ -1220,8 +630,8 @@ pub fn handle_statement<'a> (my_statement :  &Statement<'a>, node_type : &DagNod
         let mut is_rval1_val = false;
         let mut rval1_val = 0;
         // let mut lval_arr_ind;
-        println!("Handling Statement\n");
-        println!("{:?}\n", my_statement);
+        debug!("Handling Statement\n");
+        debug!("{:?}\n", my_statement);
         // println!("decl_map: {:?}\n", decl_map);
         // process::exit(1);
         // checking that lvalue of statement is declared
@@ -1246,11 +656,9 @@ pub fn handle_statement<'a> (my_statement :  &Statement<'a>, node_type : &DagNod
 
             LValue::Array(ref my_id, ref box_index_op) => {
                 my_lval_decl = get_decl(my_id.id_name, decl_map);
-                let (a,b,c,d) = handle_array(box_index_op, decl_map, packet_map, &handle_read_register);
-                my_p4_control = my_p4_control + &a;
-                my_p4_actions = my_p4_actions + &b;
-                my_p4_commons = my_p4_commons + &c;
-                my_lval_index = d;
+                let (code, index) = handle_array(box_index_op, decl_map, packet_map, &handle_read_register, my_p4_code);
+                my_p4_code = code;
+                my_lval_index = index;
             }
         }
 
@@ -1279,11 +687,9 @@ pub fn handle_statement<'a> (my_statement :  &Statement<'a>, node_type : &DagNod
 
                     LValue::Array(ref my_id, ref box_index_op) => {
                         my_rval_decl1 = get_decl(my_id.id_name, decl_map);
-                        let (a,b,c,d) = handle_array(box_index_op, decl_map, packet_map, &handle_read_register);
-                        my_p4_control = my_p4_control + &a;
-                        my_p4_actions = my_p4_actions + &b;
-                        my_p4_commons = my_p4_commons + &c;
-                        my_rval1_index = d;
+                        let (code, index) = handle_array(box_index_op, decl_map, packet_map, &handle_read_register, my_p4_code);
+                        my_p4_code = code;
+                        my_rval1_index = index;
                     }
                 }
 
@@ -1295,7 +701,6 @@ pub fn handle_statement<'a> (my_statement :  &Statement<'a>, node_type : &DagNod
                 //return handle_value_assignment(&my_lval_decl, val.value);
             }
         }
-        println!("temp : {:?}\n", my_p4_control);
         match my_statement.expr.expr_right {
             ExprRight::BinOp(bin_op_type, ref operand) => {
                 // statements like a = b + c
@@ -1322,31 +727,27 @@ pub fn handle_statement<'a> (my_statement :  &Statement<'a>, node_type : &DagNod
 
                             LValue::Array(ref my_id, ref box_index_op) => {
                                 my_rval_decl2 = get_decl(my_id.id_name, decl_map);
-                                let (a,b,c,d) = handle_array(box_index_op, decl_map, packet_map, &handle_read_register);
-                                my_p4_control = my_p4_control + &a;
-                                my_p4_actions = my_p4_actions + &b;
-                                my_p4_commons = my_p4_commons + &c;
-                                my_rval2_index = d;
+                                let (code, index) = handle_array(box_index_op, decl_map, packet_map, &handle_read_register, my_p4_code);
+                                my_p4_code = code;
+                                my_rval2_index = index;
                             }
                         }
-                        println!("temp : {:?}\n", my_p4_control);
                         if is_rval1_val {
                             return handle_binop_refval_assignment(&my_lval_decl, &my_lval_index, &my_rval_decl2, &my_rval2_index, bin_op_type, rval1_val, decl_map,
-                                                                 false, (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl));
+                                                                 false, my_p4_code);
                         } else {
                             return handle_binop_refs_assignment(&my_lval_decl, &my_lval_index, &my_rval_decl1, &my_rval1_index,
-                                                                bin_op_type, &my_rval_decl2, &my_rval2_index, decl_map,
-                                                                (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl));
+                                                                bin_op_type, &my_rval_decl2, &my_rval2_index, decl_map, my_p4_code);
                         }
                     }
 
                     Operand::Value(ref val2) => {
                         if is_rval1_val {
                             return handle_binop_vals_assignment(&my_lval_decl, &my_lval_index, rval1_val, bin_op_type, val2.value, decl_map,
-                                                                (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl));
+                                                                my_p4_code);
                         } else {
                             return handle_binop_refval_assignment(&my_lval_decl, &my_lval_index, &my_rval_decl1, &my_rval1_index, bin_op_type, val2.value, decl_map, true,
-                                                                (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl));
+                                                                my_p4_code);
                         }
                     }
                 }
@@ -1356,7 +757,7 @@ pub fn handle_statement<'a> (my_statement :  &Statement<'a>, node_type : &DagNod
                 // statements like m = z ? A : B;
                 if !is_rval1_val {
                     return handle_ternary_assignment(&my_lval_decl, &my_lval_index, my_rval_decl1, &my_rval1_index, operand1, operand2, decl_map, packet_map,
-                                                    (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl));
+                                                    my_p4_code);
                 } else {
                     panic!("Static ternary not supported for now.\n");
                 }
@@ -1365,38 +766,29 @@ pub fn handle_statement<'a> (my_statement :  &Statement<'a>, node_type : &DagNod
             ExprRight::Empty() => {
                 // statements like a = b or a = 1
                 if is_rval1_val {
-                    return handle_value_assignment(&my_lval_decl, &my_lval_index, rval1_val, (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl));
+                    return handle_value_assignment(&my_lval_decl, &my_lval_index, rval1_val, my_p4_code);
                 } else {
                     return handle_ref_assignment(&my_lval_decl, &my_lval_index, &my_rval_decl1, &my_rval1_index, &handle_read_register,
-                                                    (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl));
+                                                    my_p4_code);
                 }
             }
         }
-
-
-        return (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl);
     }
 
 
 // Ideally to get both ingress and egress parts of conversion [0] for ingress and [1] for egress and [2] for actions
 pub fn get_p4_body_trans<'a> (node_type : &DagNodeType<'a>, pre_condition : &Option<Statement<'a>>,
- decl_map : &'a HashMap<String, VarDecl>, import_map : &HashMap<String, String>, packet_map : &HashMap<String, String>) -> (String, String, String, String) {
-    let mut my_p4_control : String = String::new();
-    let mut my_p4_actions : String = String::new();
-    let mut my_p4_commons : String = String::new();
-    let mut my_p4_metadecl : String = String::new();
-
+ decl_map : &'a HashMap<String, VarDecl>, import_map : &HashMap<String, String>, packet_map : &HashMap<String, String>) -> P4Code {
     match &node_type {
         // DagNodeType::Cond(my_cond) => {
         //     // TODO : If Statements
         //     panic!("If Conditional not supported yet!");
-        //     //return (my_p4_control, my_p4_actions, my_p4_commons);
         // }
         DagNodeType::Stmt(my_statement) => {
             return handle_statement(&my_statement, node_type, pre_condition, decl_map, import_map, packet_map);
         }
         _ => {
-            return (my_p4_control, my_p4_actions, my_p4_commons, my_p4_metadecl);
+            return P4Code::new();
         }
     }
 }
@@ -1520,9 +912,9 @@ pub fn fill_p4code<'a> (import_map : &HashMap<String, String>, my_globals : &Glo
 
     for mut my_dag_node in &mut my_dag.dag_vector {
         my_dag_node.p4_code.p4_header = get_p4_header_trans(&my_dag_node.node_type);
-        // Insert nodes to decl_map
+        // Insert nodes to decl_map, device fields are already mapped to their bmv2 metadata above
         match my_dag_node.node_type {
-            DagNodeType::Decl(ref my_decl) => {
+            DagNodeType::Decl(ref my_decl) if !import_map.contains_key(my_decl.identifier.id_name) => {
                 let mut my_vardecl : VarDecl;
                 let my_id : String = String::from(my_decl.identifier.id_name);
                 let mut my_varinfo : VarInfo<'a>;
@@ -1541,23 +933,143 @@ pub fn fill_p4code<'a> (import_map : &HashMap<String, String>, my_globals : &Glo
             _ => {}
         }
     }
-    println!("decl_map : {:?}\n", decl_map);
+    debug!("decl_map : {:?}\n", decl_map);
+    let snippet_id = my_dag.snippet_id;
+    let mut stmt_count = 0;
     for mut my_dag_node in &mut my_dag.dag_vector {
-        let (a, b, c, d) = get_p4_body_trans(&my_dag_node.node_type, &my_dag_node.pre_condition, &decl_map, import_map, &packet_map);
-        //println!("meta header : {}\n", d);
-        println!("{:?}\n", a);
-        my_dag_node.p4_code.p4_control = a;
-        my_dag_node.p4_code.p4_actions = b;
-        my_dag_node.p4_code.p4_commons = c;
-        my_dag_node.p4_code.p4_header.meta.push_str(d.as_str());
+        ACTION_COUNT.with(|c| c.set(1));
+        TABLE_COUNT.with(|c| c.set(1));
+        NEW_ACTION.with(|c| c.set(true));
+        let mut my_p4_body = get_p4_body_trans(&my_dag_node.node_type, &my_dag_node.pre_condition, &decl_map, import_map, &packet_map);
+        if let DagNodeType::Stmt(_) = my_dag_node.node_type {
+            name_statement_code(&mut my_p4_body, &format!("{}_s{}", snippet_id, stmt_count));
+            stmt_count += 1;
+        }
+        debug!("{:?}\n", my_p4_body.p4_control);
+        my_dag_node.p4_code.append(my_p4_body);
     }
 }
 
-fn gen_p4_includes<'a> ( p4_file : &mut File) {
+// Lower every group of register updates to a single table. Its action reads each
+// register once, computes the update on the metadata copy of the register and writes
+// the result back at the end, so every register is accessed once per packet.
+// Conditional updates left by branch removal become the two actions of a table
+// reading the condition, which all conditional statements of the group must share.
+pub fn lower_register_updates<'a> (my_dag : &mut Dag<'a>, updates : &[RegisterUpdate], groups : &[Vec<usize>]) {
+    let aliases = get_state_aliases(my_dag);
+
+    for group in groups {
+        let group_updates : Vec<&RegisterUpdate> = updates.iter().filter(|u| u.members.iter().any(|m| group.contains(m))).collect();
+        let registers : Vec<&str> = group_updates.iter().map(|u| u.register.as_str()).collect();
+        // SSA versions of the registers, as plain names and as metadata fields
+        let mut versions : HashMap<String, String> = HashMap::new();
+        for (version, origin) in aliases.iter().filter(|&(_, origin)| registers.contains(&origin.as_str())) {
+            versions.insert(version.clone(), origin.clone());
+            versions.insert(format!("{}.{}", META_HEADER, version), format!("{}.{}", META_HEADER, origin));
+        }
+
+        // bodies of the actions run when the condition is 1 and when it is 0
+        let mut bodies : Vec<Vec<Primitive>> = vec![Vec::new(), Vec::new()];
+        let mut condition : Option<Vec<(String, String)>> = None;
+        let mut names : Option<(String, Vec<String>)> = None;
+        for member in group {
+            let dagnode = &my_dag.dag_vector[*member];
+            for step in schedule::get_steps(&dagnode.p4_code) {
+                match step {
+                    Step::Fusable { table, action, body } => {
+                        if names.is_none() {
+                            names = Some((table, vec![action]));
+                        }
+                        bodies[0].extend(body.clone());
+                        bodies[1].extend(body);
+                    }
+                    Step::Fixed { actions, tables, .. } => {
+                        if tables.len() != 1 || tables[0].reads.is_empty() || tables[0].actions.len() != 2 {
+                            panic!("Snippet {} needs two accesses to register {} in one pass, because {} cannot be part of its stateful action.",
+                                   my_dag.snippet_id, registers.join(", "), get_node_label(dagnode));
+                        }
+                        match condition {
+                            Some(ref previous) if *previous != tables[0].reads => {
+                                panic!("Snippet {} needs two accesses to register {} in one pass, because it is updated under two different conditions.",
+                                       my_dag.snippet_id, registers.join(", "));
+                            }
+                            _ => {}
+                        }
+                        condition = Some(tables[0].reads.clone());
+                        if names.as_ref().is_none_or(|n| n.1.len() == 1) {
+                            names = Some((tables[0].name.clone(), tables[0].actions.clone()));
+                        }
+                        for (body, action_name) in bodies.iter_mut().zip(tables[0].actions.iter()) {
+                            let action = actions.iter().find(|a| a.name == *action_name).unwrap();
+                            body.extend(action.primitives.clone());
+                        }
+                    }
+                }
+            }
+        }
+        let (table, actions) = match names {
+            Some(names) => names,
+            None => continue,
+        };
+
+        // one read of every register at the start and one write at the end, with
+        // all accesses in between going to the metadata copy
+        let mut indices : HashMap<String, String> = HashMap::new();
+        let mut rewritten : Vec<Vec<Primitive>> = Vec::new();
+        for body in &bodies {
+            let mut primitives = Vec::new();
+            for primitive in body {
+                let args : Vec<String> = primitive.args.iter().map(|a| versions.get(a).cloned().unwrap_or(a.clone())).collect();
+                if primitive.name == "register_read" && registers.contains(&args[1].as_str()) {
+                    indices.insert(args[1].clone(), args[2].clone());
+                } else if primitive.name == "register_write" && registers.contains(&args[0].as_str()) {
+                    indices.insert(args[0].clone(), args[1].clone());
+                    let copy = format!("{}.{}", META_HEADER, args[0]);
+                    if args[2] != copy {
+                        primitives.push(Primitive::new("modify_field", vec![copy, args[2].clone()]));
+                    }
+                } else {
+                    primitives.push(Primitive::new(&primitive.name, args));
+                }
+            }
+            rewritten.push(primitives);
+        }
+        let mut reads = Vec::new();
+        let mut writes = Vec::new();
+        for update in &group_updates {
+            let index = indices.get(&update.register).cloned().unwrap_or(update.index.clone());
+            let copy = format!("{}.{}", META_HEADER, update.register);
+            reads.push(Primitive::new("register_read", vec![copy.clone(), update.register.clone(), index.clone()]));
+            if !update.writes.is_empty() {
+                writes.push(Primitive::new("register_write", vec![update.register.clone(), index, copy]));
+            }
+        }
+
+        let leader = *group.last().unwrap();
+        for member in group {
+            let p4_code = &mut my_dag.dag_vector[*member].p4_code;
+            p4_code.p4_control.clear();
+            p4_code.p4_actions.clear();
+            p4_code.p4_tables.clear();
+        }
+        let p4_code = &mut my_dag.dag_vector[leader].p4_code;
+        p4_code.p4_control.push(ControlStmt::Apply(table.clone()));
+        for (action, body) in actions.iter().zip(rewritten) {
+            let primitives = reads.iter().cloned().chain(body).chain(writes.iter().cloned()).collect();
+            p4_code.p4_actions.push(Action { name : action.clone(), primitives });
+        }
+        p4_code.p4_tables.push(match condition {
+            Some(reads) => Table { name : table, reads, actions, default_action : None },
+            None => Table::new(&table, &actions[0]),
+        });
+    }
+}
+
+fn gen_p4_includes<'a> ( p4_file : &mut dyn Write) {
     //p4_file.write(b"#include <core.p4>\n#include <v1model.p4>\n");
 }
 
-fn gen_p4_globals<'a> (my_dag : &Dag<'a>, p4_file : &mut File) {
+fn gen_p4_globals<'a> (my_dag : &Dag<'a>, p4_file : &mut dyn Write) {
     let mut contents : String = String::new();
     for my_dag_node in &my_dag.dag_vector {
         if (my_dag_node.p4_code.p4_header.define.len() != 0) {
@@ -1567,7 +1079,7 @@ fn gen_p4_globals<'a> (my_dag : &Dag<'a>, p4_file : &mut File) {
     p4_file.write(contents.as_bytes());
 }
 
-fn gen_p4_headers<'a> (my_dag : &Dag<'a>, my_packets : &Packets<'a>, p4_file : &mut File) {
+fn gen_p4_headers<'a> (my_dag : &Dag<'a>, my_packets : &Packets<'a>, p4_file : &mut dyn Write) {
     // TODO
     let mut contents : String = String::new();
     contents = contents + "#define ETHERTYPE_IPV4 0x0800\n";
@@ -1619,22 +1131,19 @@ fn gen_p4_headers<'a> (my_dag : &Dag<'a>, my_packets : &Packets<'a>, p4_file : &
     }}\n}}\n");
     //
     for my_packet in &my_packets.packet_vector {
-        if my_packet.packet_fields.field_vector.len() != 0 {
-            contents = contents + &format!("header_type {}_t {{\n", my_packet.packet_id.id_name);
-            contents = contents + &format!("{}fields {{\n", TAB);
-        }
+        let mut header_type = HeaderType { name : format!("{}_t", my_packet.packet_id.id_name), fields : Vec::new() };
         for my_field in &my_packet.packet_fields.field_vector {
             match my_field.var_type.var_info {
                 VarInfo::BitArray(size, no) => {
-                    contents = contents + &format!("{}{}{} : {};\n", TAB, TAB, my_field.identifier.id_name, size)
+                    header_type.fields.push(MetaField { name : my_field.identifier.id_name.to_string(), width : size });
                 }
                 _ => {
-                    println!("Un-supported entry in packet field!");
+                    note!("Un-supported entry in packet field!");
                 }
             }
         }
         if my_packet.packet_fields.field_vector.len() != 0 {
-            contents = contents + &format!("{}}}\n}}\n", TAB);
+            contents = contents + &header_type.to_p4();
             contents = contents + &format!("header {}_t {};\n", my_packet.packet_id.id_name, my_packet.packet_id.id_name);
         }
     }
@@ -1648,7 +1157,7 @@ fn gen_p4_headers<'a> (my_dag : &Dag<'a>, my_packets : &Packets<'a>, p4_file : &
 }
 
 
-fn gen_p4_routing_tables<'a> (p4_file : &mut File) {
+fn gen_p4_routing_tables<'a> (p4_file : &mut dyn Write) {
     let mut contents : String = String::new();
     contents = contents + &format!("
 field_list ipv4_checksum_list {{
@@ -1705,7 +1214,7 @@ table ipv4_lpm {{
 }
 
 
-fn gen_p4_metadata<'a> (my_dag : &Dag<'a>, p4_file : &mut File) {
+fn gen_p4_metadata<'a> (my_dag : &Dag<'a>, p4_file : &mut dyn Write) {
     let mut contents : String = String::new();
 
     contents = contents + &format!("header_type queueing_metadata_t {{
@@ -1720,28 +1229,25 @@ fn gen_p4_metadata<'a> (my_dag : &Dag<'a>, p4_file : &mut File) {
 metadata queueing_metadata_t queueing_metadata;
  \n");
 
-    contents = contents + &format!("header_type metadata_t {{ \n");
-    contents = contents + &format!("{}fields {{\n", TAB);
+    let mut metadata = HeaderType { name : String::from("metadata_t"), fields : Vec::new() };
     for my_dag_node in &my_dag.dag_vector {
-        if my_dag_node.p4_code.p4_header.meta.len() != 0 {
-            contents = contents + &format!("{}{}{}",TAB, TAB,my_dag_node.p4_code.p4_header.meta);
-        }
+        metadata.fields.extend(my_dag_node.p4_code.p4_header.meta.iter().cloned());
     }
-    contents = contents + &format!("{}}}\n}}\nmetadata metadata_t mdata;\n\n", TAB);
+    contents = contents + &metadata.to_p4() + "metadata metadata_t mdata;\n\n";
     p4_file.write(contents.as_bytes());
 }
 
-fn gen_p4_registers<'a> (my_dag : &Dag<'a>, p4_file : &mut File) {
+fn gen_p4_registers<'a> (my_dag : &Dag<'a>, p4_file : &mut dyn Write) {
     let mut contents : String = String::new();
     for my_dag_node in &my_dag.dag_vector {
-        if my_dag_node.p4_code.p4_header.register.len() != 0 {
-            contents = contents + &my_dag_node.p4_code.p4_header.register;
+        for register in &my_dag_node.p4_code.p4_header.register {
+            contents = contents + &register.to_p4();
         }
     }
     p4_file.write(contents.as_bytes());
 }
 
-fn gen_p4_parser<'a> (my_dag : &Dag<'a>, my_packets : &Packets<'a>, p4_file : &mut File) {
+fn gen_p4_parser<'a> (my_dag : &Dag<'a>, my_packets : &Packets<'a>, p4_file : &mut dyn Write) {
     let mut contents : String = String::new();
     // TODO handle multiple user-defined packets. Currently only allowing one
     let my_option  = my_packets.packet_vector.get(0);
@@ -1752,7 +1258,7 @@ fn gen_p4_parser<'a> (my_dag : &Dag<'a>, my_packets : &Packets<'a>, p4_file : &m
 
     match my_option {
         Some(my_packet) => {
-            println!("Header base : {}\n", my_packet.packet_base.id_name);
+            debug!("Header base : {}\n", my_packet.packet_base.id_name);
             let my_base = my_packet.packet_base.id_name;
             let my_condition = &my_packet.packet_parser_condition;
             match my_base {
@@ -1864,13 +1370,13 @@ fn gen_p4_parser<'a> (my_dag : &Dag<'a>, my_packets : &Packets<'a>, p4_file : &m
 
             let mut set_metadata : String = String::new();
             for my_dag_node in &my_dag.dag_vector {
-                if my_dag_node.p4_code.p4_header.meta_init.len() != 0 {
-                    set_metadata = set_metadata + &format!("{}",my_dag_node.p4_code.p4_header.meta_init);
+                for primitive in &my_dag_node.p4_code.p4_header.meta_init {
+                    set_metadata = set_metadata + TAB + &primitive.to_p4();
                 }
             }
             contents = contents + &format!("parser parse_{} {{
-    extract({});\n{}",
-                my_packet.packet_id.id_name, my_packet.packet_id.id_name, TAB);
+    extract({});\n",
+                my_packet.packet_id.id_name, my_packet.packet_id.id_name);
             contents = contents + &set_metadata + &format!("{}return ingress;\n}}\n\n", TAB);
         }
         _ => {}
@@ -1880,20 +1386,66 @@ fn gen_p4_parser<'a> (my_dag : &Dag<'a>, my_packets : &Packets<'a>, p4_file : &m
 }
 
 
-fn gen_p4_body<'a> (my_dag : &Dag<'a>, my_packets : &Packets<'a>, p4_file : &mut File) {
+// ASSERT_FAILED flags of the snippets of a program, prefixed when they share the device
+fn get_assertion_flags<'a> (my_dag : &Dag<'a>) -> Vec<String> {
+    let mut flags = Vec::new();
+    for my_dag_node in &my_dag.dag_vector {
+        if let DagNodeType::Decl(ref my_decl) = my_dag_node.node_type {
+            let name = my_decl.identifier.id_name;
+            if name == ASSERT_FAILED || name.ends_with(&format!("_{}", ASSERT_FAILED)) {
+                flags.push(name.to_string());
+            }
+        }
+    }
+    return flags;
+}
+
+// Action and table run on the packets with a flag set. The checks of the flags are nested,
+// so that a packet failing the checks of several snippets is handled once.
+fn gen_p4_assertions<'a> (my_dag : &Dag<'a>, mode : AssertionMode) -> (Action, Vec<Table>, Vec<ControlStmt>) {
+    let action = match mode {
+        AssertionMode::Drop => Action { name : String::from("_assert_drop"), primitives : vec![Primitive::new("drop", Vec::new())] },
+        AssertionMode::Mirror => Action { name : String::from("_assert_mirror"),
+                                          primitives : vec![Primitive::new("clone_ingress_pkt_to_egress",
+                                                                           vec![ASSERT_MIRROR_SESSION.to_string()])] },
+    };
+    let mut tables = Vec::new();
+    let mut control = Vec::new();
+    for flag in get_assertion_flags(my_dag).iter().rev() {
+        let table = Table::new(&format!("{}_table", flag), &action.name);
+        let comparison = Comparison { left : format!("{}.{}", META_HEADER, flag), op : String::from("=="), right : String::from("1") };
+        control = vec![ControlStmt::If(comparison, vec![ControlStmt::Apply(table.name.clone())], control)];
+        tables.push(table);
+    }
+    return (action, tables, control);
+}
+
+fn gen_p4_body<'a> (my_dag : &Dag<'a>, my_packets : &Packets<'a>, assertions : Option<AssertionMode>, p4_file : &mut dyn Write) {
     let mut contents : String = String::new();
 
     for my_dag_node in &my_dag.dag_vector {
-        if my_dag_node.p4_code.p4_actions.len() != 0 {
-            contents = contents + &my_dag_node.p4_code.p4_actions;
+        for action in &my_dag_node.p4_code.p4_actions {
+            contents = contents + &action.to_p4();
         }
     }
 
+    // bmv2 takes default actions from the control plane commands
     for my_dag_node in &my_dag.dag_vector {
-        if my_dag_node.p4_code.p4_commons.len() != 0 {
-            contents = contents + &my_dag_node.p4_code.p4_commons;
+        for table in &my_dag_node.p4_code.p4_tables {
+            contents = contents + &table.to_p4(false);
         }
     }
+    let assertion_control = match assertions {
+        Some(mode) if !get_assertion_flags(my_dag).is_empty() => {
+            let (action, tables, control) = gen_p4_assertions(my_dag, mode);
+            contents = contents + &action.to_p4();
+            for table in &tables {
+                contents = contents + &table.to_p4(false);
+            }
+            control
+        }
+        _ => Vec::new(),
+    };
 
     // TODO : Identify placement in ingress/egress
     contents = contents + &format!("\ncontrol ingress {{\n");
@@ -1933,10 +1485,9 @@ fn gen_p4_body<'a> (my_dag : &Dag<'a>, my_packets : &Packets<'a>, p4_file : &mut
 
     contents = contents + &parser_conds;
 
+    let indent = if parser_conds.len() != 0 { 2 } else { 1 };
     for my_dag_node in &my_dag.dag_vector {
-        if my_dag_node.p4_code.p4_control.len() != 0 {
-            contents = contents + &my_dag_node.p4_code.p4_control;
-        }
+        contents = contents + &control_to_p4(&my_dag_node.p4_code.p4_control, indent);
     }
 
     if parser_conds.len() != 0 {
@@ -1948,6 +1499,8 @@ fn gen_p4_body<'a> (my_dag : &Dag<'a>, my_packets : &Packets<'a>, p4_file : &mut
     if(valid(ipv4) and ipv4.ttl > 0) {
         apply(ipv4_lpm);
     }\n" ;
+    // after routing, which would undo the drop
+    contents = contents + &control_to_p4(&assertion_control, 1);
     contents = contents + &format!("}}\n");
     contents = contents + &format!("\ncontrol egress {{\n");
     // for my_dag_node in &my_dag.dag_vector {
@@ -1959,17 +1512,9 @@ fn gen_p4_body<'a> (my_dag : &Dag<'a>, my_packets : &Packets<'a>, p4_file : &mut
     p4_file.write(contents.as_bytes());
 }
 
-pub fn gen_p4_code<'a> (snippet_name : &str , my_packets : &Packets<'a>, snippet_dag : &Dag<'a>){
-
-    let p4_filename : String = format!("out/{}.p4", snippet_name);
-    let path = Path::new(p4_filename.as_str());
-    let display  = path.display();
-    let mut p4_file = match File::create(path) {
-        Err(why) => panic!("couldn't create {}: {}",
-                           display,
-                           why.description()),
-        Ok(p4_file) => p4_file,
-    };
+// P4 program for the dag of one device
+pub fn get_p4_code<'a> (my_packets : &Packets<'a>, snippet_dag : &Dag<'a>, assertions : Option<AssertionMode>) -> String {
+    let mut p4_file : Vec<u8> = Vec::new();
     gen_p4_includes(&mut p4_file);
     gen_p4_globals(&snippet_dag, &mut p4_file);
     gen_p4_headers(&snippet_dag, my_packets, &mut p4_file);
@@ -1978,22 +1523,30 @@ pub fn gen_p4_code<'a> (snippet_name : &str , my_packets : &Packets<'a>, snippet
     gen_p4_metadata(&snippet_dag, &mut p4_file);
     gen_p4_registers(&snippet_dag, &mut p4_file);
     //gen_p4_actions(&snippet_dag, &mut p4_file);
-    gen_p4_body(&snippet_dag, my_packets, &mut p4_file);
+    gen_p4_body(&snippet_dag, my_packets, assertions, &mut p4_file);
+    return String::from_utf8(p4_file).unwrap();
+}
+
+
+// Tables with a default action run it. Tables reading a one bit condition run
+// their first action when it is 1 and their second when it is 0.
+fn get_table_commands(tables : &Vec<Table>) -> String {
+    let mut contents : String = String::new();
+    for table in tables {
+        if table.reads.len() == 1 && table.actions.len() == 2 {
+            contents = contents + &format!("table_add {} {} 1 => \n", table.name, table.actions[0]);
+            contents = contents + &format!("table_add {} {} 0 => \n", table.name, table.actions[1]);
+        } else if let Some(ref action) = table.default_action {
+            contents = contents + &format!("table_set_default {} {}\n", table.name, action);
+        }
+    }
+    return contents;
 }
 
 
+// simple_switch_CLI commands that initialize the registers and fill the tables
 // TODO : handle packet fields
-pub fn gen_control_plane_commands<'a> (snippet_name : &str , my_packets : &Packets<'a>, snippet_dag : &Dag<'a>){
-
-    let command_filename : String = format!("bmv2_sim/commands/{}.txt", snippet_name);
-    let path = Path::new(command_filename.as_str());
-    let display  = path.display();
-    let mut command_file = match File::create(path) {
-        Err(why) => panic!("couldn't create {}: {}",
-                           display,
-                           why.description()),
-        Ok(command_file) => command_file,
-    };
+pub fn get_control_plane_commands<'a> (snippet_dag : &Dag<'a>, assertions : Option<AssertionMode>) -> String {
 
     let mut decl_map : HashMap<String, VariableDecl>= HashMap::new();
     let mut contents : String = String::new();
@@ -2031,32 +1584,7 @@ pub fn gen_control_plane_commands<'a> (snippet_name : &str , my_packets : &Packe
                                             Some(my_decl) => {
                                                 match my_decl.var_type.var_info {
                                                     VarInfo::BitArray(1, 1) => {
-                                                        // parse out action and table names from p4_commons
-                                                        let re1 = Regex::new(r"table\d+").unwrap();
-                                                        let re2 = Regex::new(r"action\d+").unwrap();
-
-                                                        let mut table_array = Vec::new();
-                                                        for cap in re1.captures_iter(&dagnode.p4_code.p4_commons) {
-                                                            let ref table_str = cap.get(0).unwrap().as_str();
-                                                            table_array.push(table_str.clone());
-                                                        }
-
-                                                        let mut action_array = Vec::new();
-                                                        for cap in re2.captures_iter(&dagnode.p4_code.p4_commons) {
-                                                            let ref action_str = cap.get(0).unwrap().as_str();
-                                                            action_array.push(action_str.clone());
-                                                        }
-
-                                                        if table_array.len() == 1 && action_array.len() == 2 {
-                                                            contents = contents + &format!("table_add {} {} 1 => \n", table_array[0], action_array[0]);
-                                                            contents = contents + &format!("table_add {} {} 0 => \n", table_array[0], action_array[1]);
-                                                        } else {
-                                                            // If lvalue of statement is an array, there needs to be an extra
-                                                            // control plane entry for reading array index into metadata
-                                                            contents = contents + &format!("table_set_default {} {}\n", table_array[0], action_array[0]);
-                                                            contents = contents + &format!("table_add {} {} 1 => \n", table_array[1], action_array[1]);
-                                                            contents = contents + &format!("table_add {} {} 0 => \n", table_array[1], action_array[2]);
-                                                        }
+                                                        contents = contents + &get_table_commands(&dagnode.p4_code.p4_tables);
                                                     }
                                                     //TODO : add support for 32 bit table indices and tables with multiple read vars
                                                     _ => {panic!("Unsupported table index type!");}
@@ -2064,7 +1592,7 @@ pub fn gen_control_plane_commands<'a> (snippet_name : &str , my_packets : &Packe
                                                 }
                                             }
                                             None => {
-                                                println!("Error: {} not declared?\n",my_id.id_name);
+                                                note!("Error: {} not declared?\n",my_id.id_name);
                                             }
 
                                         }
@@ -2084,28 +1612,7 @@ pub fn gen_control_plane_commands<'a> (snippet_name : &str , my_packets : &Packe
                     }
 
                     _ => {
-                        // parse out action and table names from p4_commons
-                        let re1 = Regex::new(r"table\d+").unwrap();
-                        let re2 = Regex::new(r"action\d+").unwrap();
-
-                        let mut table_array = Vec::new();
-                        for cap in re1.captures_iter(&dagnode.p4_code.p4_commons) {
-                            let ref table_str = cap.get(0).unwrap().as_str();
-                            table_array.push(table_str.clone());
-                        }
-
-                        let mut action_array = Vec::new();
-                        for cap in re2.captures_iter(&dagnode.p4_code.p4_commons) {
-                            let ref action_str = cap.get(0).unwrap().as_str();
-                            action_array.push(action_str.clone());
-                        }
-
-                        // check that exactly 1 action is run per table
-                        if table_array.len() == action_array.len() {
-                            for (x, action) in action_array.iter().enumerate() {
-                                contents = contents + &format!("table_set_default {} {}\n", table_array[x], action);
-                            }
-                        }
+                        contents = contents + &get_table_commands(&dagnode.p4_code.p4_tables);
                     }
                 }
             }
@@ -2114,5 +1621,15 @@ pub fn gen_control_plane_commands<'a> (snippet_name : &str , my_packets : &Packe
         }
     }
 
-    command_file.write(contents.as_bytes());
+    match assertions {
+        Some(mode) if !get_assertion_flags(snippet_dag).is_empty() => {
+            let (_, tables, _) = gen_p4_assertions(snippet_dag, mode);
+            contents = contents + &get_table_commands(&tables);
+            if mode == AssertionMode::Mirror {
+                contents = contents + &format!("mirroring_add {} {}\n", ASSERT_MIRROR_SESSION, ASSERT_CPU_PORT);
+            }
+        }
+        _ => {}
+    }
+    return contents;
 }
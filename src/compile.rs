@@ -0,0 +1,256 @@
+// Library entry point of the compiler. compile takes the source of a sluice
+// program and returns every artifact in memory: the P4 program of each device,
+// its control plane commands, the mininet topology and the reports. Writing
+// them out is left to the caller, see main.rs.
+use grammar::Prog;
+use lexer;
+use parser;
+use imports::{ImportArena, resolve_imports};
+use placement::place_snippets;
+use assertions::{AssertionMode, lower_assertions};
+use trans_snippet::{trans_snippets, lower_packet_variables};
+use dot::DotStage;
+use passes::{DumpFormat, default_passes};
+use serde_json;
+use verbosity;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use std::cell::Cell;
+use std::panic;
+
+pub const INCLUDE_DIR : &str = "net-progs/include/";
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct CompileOptions {
+    // directories searched in order for packet.np, budgets.json and the imported files
+    pub include_dirs  : Vec<String>,
+    // file the source was read from. Its directory is searched first for import "path";
+    pub source_path   : Option<String>,
+    // stage at which the dependency dags are exported as DOT
+    pub dot_stage     : Option<DotStage>,
+    // dag passes run on every snippet, in order, see passes.rs
+    pub passes        : Vec<String>,
+    // pass after which the dags are dumped, and the format of the dump
+    pub dump_after    : Option<String>,
+    pub dump_format   : DumpFormat,
+    // device type to compile, e.g. bmv2. Snippets for other devices are skipped.
+    pub target        : Option<String>,
+    // see verbosity.rs
+    pub verbosity     : usize,
+    // how the bmv2 programs handle packets failing an assertion or invariant.
+    // Without a mode, they are only checked by the interpreter.
+    pub assertions    : Option<AssertionMode>,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CompileOptions {
+    pub fn new() -> CompileOptions {
+        CompileOptions { include_dirs : vec![INCLUDE_DIR.to_string()], source_path : None,
+                         dot_stage : None, passes : default_passes(), dump_after : None, dump_format : DumpFormat::Text,
+                         target : None, verbosity : verbosity::NORMAL, assertions : None }
+    }
+
+    pub fn selects(&self, device_type : &str) -> bool {
+        match self.target {
+            Some(ref target) => device_type.contains(target.as_str()),
+            None => true,
+        }
+    }
+}
+
+// First include directory that contains filename. If none does, the path in the
+// first directory is returned, so that opening it reports the missing file.
+pub fn find_include_file(include_dirs : &Vec<String>, filename : &str) -> String {
+    for include_dir in include_dirs {
+        let path = Path::new(include_dir).join(filename);
+        if path.exists() {
+            return path.to_string_lossy().into_owned();
+        }
+    }
+    match include_dirs.first() {
+        Some(include_dir) => Path::new(include_dir).join(filename).to_string_lossy().into_owned(),
+        None => filename.to_string(),
+    }
+}
+
+pub fn read_include_file(include_dirs : &Vec<String>, filename : &str) -> String {
+    let mut f = File::open(find_include_file(include_dirs, filename)).expect("File not found");
+    let mut contents = String::new();
+    f.read_to_string(&mut contents).expect("Something went wrong reading the file");
+    contents
+}
+
+// Generated code of one device program
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct DeviceCode {
+    pub program_name : String,
+    pub device_type  : String,
+    pub devices      : Vec<String>,
+    pub p4_code      : String,
+    // simple_switch_CLI commands, for bmv2 programs only
+    pub commands     : Option<String>,
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct CompileOutput {
+    pub programs    : Vec<DeviceCode>,
+    pub topology    : serde_json::Value,
+    pub resources   : serde_json::Value,
+    pub schedule    : serde_json::Value,
+    // metadata slots of each bmv2 snippet and the variables they hold
+    pub metadata    : serde_json::Value,
+    // text of the schedule, metadata and resources reports
+    pub reports     : String,
+    pub dot         : Option<String>,
+    // dags after the pass of options.dump_after
+    pub dump        : Option<String>,
+    // listing of the dag of each snippet after code generation
+    pub dags        : String,
+    // warnings and placement decisions
+    pub diagnostics : Vec<String>,
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct CompileError {
+    pub message : String,
+}
+
+// The passes report errors by panicking. compile catches the panic and returns
+// its message, so a bad program never takes down the caller. It leaves no state
+// behind: the verbosity of the options only holds during the call, and the panic
+// hook stays the caller's, see catch_errors.
+pub fn compile(source : &str, options : &CompileOptions) -> Result<CompileOutput, Vec<CompileError>> {
+    verbosity::with_level(options.verbosity, || catch_errors(|| {
+        let mut import_arena = ImportArena::new();
+        let parse_tree = resolve_imports(parse_program(source), options.source_path.as_deref(),
+                                         &options.include_dirs, &mut import_arena);
+        debug!("Parse tree: {:?}\n", parse_tree);
+
+        let contents = read_include_file(&options.include_dirs, "packet.np");
+        let pkt_tokens = &mut lexer::get_tokens(&contents);
+        let pkt_iter = &mut pkt_tokens.iter().peekable();
+        let pkt_tree = parser::parse_import_packets(pkt_iter);
+
+        // assign snippets without a device annotation to switches of the topology
+        let mut diagnostics = Vec::new();
+        let snippets = place_snippets(&parse_tree.topology, &parse_tree.snippets, &parse_tree.connections, &mut diagnostics);
+        let snippets = lower_packet_variables(&snippets);
+        let snippets = match options.assertions {
+            Some(_) => lower_assertions(&snippets),
+            None => snippets,
+        };
+        let mut output = trans_snippets(&parse_tree.imports, &parse_tree.globals, &parse_tree.packets, &parse_tree.topology, &snippets,
+                                        &parse_tree.pipelines, &parse_tree.connections, &pkt_tree, options);
+        diagnostics.append(&mut output.diagnostics);
+        output.diagnostics = diagnostics;
+        output
+    }))
+}
+
+// Parse tree of a program, before its imports are resolved
+pub fn parse_program<'a> (source : &'a str) -> Prog<'a> {
+    let tokens = lexer::get_tokens(source);
+    let token_iter = &mut tokens.iter().peekable();
+    let parse_tree = parser::parse_prog(token_iter);
+    assert!(token_iter.peek().is_none(), "Token iterator is not empty.");
+    parse_tree
+}
+
+thread_local! {
+    // number of catch_errors calls running on this thread
+    static CATCHING : Cell<usize> = const { Cell::new(0) };
+}
+
+// Runs f, returning the message of a panic as an error. The panic hook is left to
+// the caller, which can use catching to keep the panics f raises quiet, see main.rs.
+pub fn catch_errors<T, F : FnOnce() -> T>(f : F) -> Result<T, Vec<CompileError>> {
+    CATCHING.with(|c| c.set(c.get() + 1));
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(f));
+    CATCHING.with(|c| c.set(c.get() - 1));
+    match result {
+        Ok(output) => Ok(output),
+        Err(payload) => {
+            let message = match payload.downcast_ref::<String>() {
+                Some(message) => message.clone(),
+                None => match payload.downcast_ref::<&str>() {
+                    Some(message) => message.to_string(),
+                    None => String::from("Unknown error"),
+                }
+            };
+            Err(vec![CompileError { message }])
+        }
+    }
+}
+
+// Whether this thread is inside catch_errors, where a panic is an error returned to the caller
+pub fn catching() -> bool {
+    CATCHING.with(|c| c.get() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compile_in_memory() {
+        let input = r"@ bmv2 : s1;
+                      snippet fun() {
+                        transient a : bit<32>;
+                        persistent cnt : bit<32> = 0;
+                        a = 1;
+                        cnt = cnt + a;
+                      }";
+        let output = compile(input, &CompileOptions::new()).unwrap();
+        assert_eq!(output.programs.len(), 1);
+        assert_eq!(output.programs[0].program_name, "fun");
+        assert!(output.programs[0].p4_code.contains("register cnt {"));
+        assert!(output.programs[0].commands.as_ref().unwrap().contains("register_write cnt 0 0\n"));
+        assert_eq!(output.topology["snippet_loc"]["s1"], json!("fun"));
+        assert!(output.dot.is_none());
+    }
+
+    #[test]
+    fn test_compile_diagnostics() {
+        let input = r"topology { switch s1 : bmv2; }
+                      snippet fun() {
+                        transient a : bit<32>;
+                        a = 1;
+                      }";
+        let output = compile(input, &CompileOptions::new()).unwrap();
+        assert_eq!(output.diagnostics, vec!["Placed snippet fun on s1", "Placement cost: 0"]);
+        assert!(output.reports.starts_with("Stage maps:\nfun:"));
+    }
+
+    #[test]
+    fn test_compile_error() {
+        let input = r"@ bmv2 : s1;
+                      snippet fun() {
+                        persistent cnt : bit<2> = 4;
+                      }";
+        let errors = compile(input, &CompileOptions::new()).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].message.contains("outside the range [0, 3]"));
+    }
+
+    #[test]
+    fn test_compile_keeps_verbosity() {
+        let mut options = CompileOptions::new();
+        options.verbosity = verbosity::QUIET;
+        assert!(compile("@ bmv2 : s1; snippet fun() { persistent cnt : bit<2> = 4; }", &options).is_err());
+        assert!(verbosity::enabled(verbosity::NORMAL));
+    }
+}
@@ -0,0 +1,243 @@
+// Constant folding and propagation on the SSA dags. SSA writes every version of a transient
+// variable once, so a version assigned a constant holds it for the rest of the snippet, and
+// globals and const variables keep their initial values. fold_constants substitutes these
+// constants into the statements reading them, evaluates operations on constants and replaces
+// a ternary with a constant condition by the operand it selects. Transient versions whose
+// reads were all replaced are removed with their declaration, so they need neither an action
+// nor a metadata field.
+use grammar::*;
+use trans_snippet::*;
+use interpreter::{truncate, eval_bin_op};
+use assertions::ASSERT_FAILED;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+fn value_operand<'a> (value : u64) -> Operand<'a> {
+    Operand::Value(Value { value })
+}
+
+// Replaces a read of a constant by its value
+fn fold_operand<'a> (operand : &Operand<'a>, constants : &HashMap<&'a str, Vec<u64>>) -> Operand<'a> {
+    match *operand {
+        Operand::LValue(LValue::Scalar(ref id)) => match constants.get(id.id_name) {
+            Some(values) if values.len() == 1 => value_operand(values[0]),
+            _ => operand.clone(),
+        },
+        Operand::LValue(LValue::Array(ref id, ref address)) => {
+            let address = fold_operand(address, constants);
+            match (constants.get(id.id_name), &address) {
+                (Some(values), Operand::Value(index)) if index.value < values.len() as u64 => value_operand(values[index.value as usize]),
+                _ => Operand::LValue(LValue::Array(*id, Box::new(address))),
+            }
+        }
+        _ => operand.clone(),
+    }
+}
+
+pub fn fold_expr<'a> (expr : &Expr<'a>, constants : &HashMap<&'a str, Vec<u64>>) -> Expr<'a> {
+    let op1 = fold_operand(&expr.op1, constants);
+    match (op1, &expr.expr_right) {
+        (Operand::Value(value1), &ExprRight::BinOp(bin_op_type, ref op2)) => match fold_operand(op2, constants) {
+            // division by zero is left to the device
+            Operand::Value(ref value2) if value2.value != 0 || (bin_op_type != BinOpType::Div && bin_op_type != BinOpType::Modulo) => {
+                Expr { op1 : value_operand(eval_bin_op(bin_op_type, value1.value, value2.value)), expr_right : ExprRight::Empty() }
+            }
+            op2 => Expr { op1 : Operand::Value(value1), expr_right : ExprRight::BinOp(bin_op_type, op2) },
+        },
+        (op1, &ExprRight::BinOp(bin_op_type, ref op2)) => Expr { op1, expr_right : ExprRight::BinOp(bin_op_type, fold_operand(op2, constants)) },
+        (Operand::Value(condition), ExprRight::Cond(operand_true, operand_false)) => {
+            let selected = if condition.value != 0 { operand_true } else { operand_false };
+            Expr { op1 : fold_operand(selected, constants), expr_right : ExprRight::Empty() }
+        }
+        (op1, ExprRight::Cond(operand_true, operand_false)) => {
+            Expr { op1, expr_right : ExprRight::Cond(fold_operand(operand_true, constants), fold_operand(operand_false, constants)) }
+        }
+        (op1, &ExprRight::Empty()) => Expr { op1, expr_right : ExprRight::Empty() },
+    }
+}
+
+// Variables read by an operand, including array indices
+pub fn read_names<'a> (operand : &Operand<'a>, names : &mut HashSet<&'a str>) {
+    match *operand {
+        Operand::LValue(LValue::Scalar(ref id)) => { names.insert(id.id_name); }
+        Operand::LValue(LValue::Array(ref id, ref address)) => {
+            names.insert(id.id_name);
+            read_names(address, names);
+        }
+        _ => {}
+    }
+}
+
+// Variables read by an expression
+pub fn expr_names<'a> (expr : &Expr<'a>, names : &mut HashSet<&'a str>) {
+    read_names(&expr.op1, names);
+    match expr.expr_right {
+        ExprRight::BinOp(_, ref operand) => read_names(operand, names),
+        ExprRight::Cond(ref operand1, ref operand2) => {
+            read_names(operand1, names);
+            read_names(operand2, names);
+        }
+        ExprRight::Empty() => {}
+    }
+}
+
+// Folds and propagates the constants of an SSA dag, see above
+pub fn fold_constants<'a> (my_dag : &mut Dag<'a>) {
+    let mut decls : HashMap<&'a str, VarType<'a>> = HashMap::new();
+    let mut writes : HashMap<&'a str, usize> = HashMap::new();
+    for my_dag_node in &my_dag.dag_vector {
+        match my_dag_node.node_type {
+            DagNodeType::Decl(ref var_decl) => { decls.insert(var_decl.identifier.id_name, var_decl.var_type.clone()); }
+            DagNodeType::Stmt(Statement { lvalue : LValue::Scalar(ref id), .. }) => { *writes.entry(id.id_name).or_insert(0) += 1; }
+            _ => {}
+        }
+    }
+
+    let mut constants : HashMap<&'a str, Vec<u64>> = HashMap::new();
+    for my_dag_node in &my_dag.dag_vector {
+        if let DagNodeType::Decl(ref var_decl) = my_dag_node.node_type { match var_decl.var_type.type_qualifier {
+            TypeQualifier::Global | TypeQualifier::Const if !writes.contains_key(var_decl.identifier.id_name) => {
+                constants.insert(var_decl.identifier.id_name, var_decl.initial_values.iter().map(|v| v.value).collect());
+            }
+            _ => {}
+        } }
+    }
+
+    // transient versions that are constants. Their values are known from their write onwards.
+    let mut folded : HashSet<&'a str> = HashSet::new();
+    for my_dag_node in &mut my_dag.dag_vector {
+        let node_type = match my_dag_node.node_type {
+            DagNodeType::Stmt(ref my_statement) => {
+                let mut expr = fold_expr(&my_statement.expr, &constants);
+                let lvalue = match my_statement.lvalue {
+                    LValue::Array(ref id, ref address) => LValue::Array(*id, Box::new(fold_operand(address, &constants))),
+                    ref lvalue => lvalue.clone(),
+                };
+                match (&lvalue, &expr.op1, &expr.expr_right) {
+                    (LValue::Scalar(id), Operand::Value(value), &ExprRight::Empty()) if writes[id.id_name] == 1 => {
+                        if let Some(&VarType { var_info : VarInfo::BitArray(bit_width, _), type_qualifier : TypeQualifier::Transient }) = decls.get(id.id_name) {
+                            let value = truncate(value.value, bit_width);
+                            constants.insert(id.id_name, vec![value]);
+                            folded.insert(id.id_name);
+                            expr = Expr { op1 : value_operand(value), expr_right : ExprRight::Empty() };
+                        }
+                    }
+                    _ => {}
+                }
+                DagNodeType::Stmt(Statement { lvalue, expr })
+            }
+            DagNodeType::Cond(ref my_expr) => DagNodeType::Cond(fold_expr(my_expr, &constants)),
+            ref node_type => node_type.clone(),
+        };
+        my_dag_node.node_type = node_type;
+    }
+
+    let mut read : HashSet<&'a str> = HashSet::new();
+    for my_dag_node in &my_dag.dag_vector {
+        match my_dag_node.node_type {
+            DagNodeType::Stmt(ref my_statement) => {
+                expr_names(&my_statement.expr, &mut read);
+                if let LValue::Array(_, ref address) = my_statement.lvalue { read_names(address, &mut read) }
+            }
+            DagNodeType::Cond(ref my_expr) => expr_names(my_expr, &mut read),
+            _ => {}
+        }
+    }
+    // the assertion flag is read by the tables bmv2_gen adds for it
+    let removed : HashSet<&'a str> = folded.into_iter().filter(|name| !read.contains(name) && *name != ASSERT_FAILED).collect();
+    my_dag.dag_vector.retain(|my_dag_node| match my_dag_node.node_type {
+        DagNodeType::Decl(ref var_decl) => !removed.contains(var_decl.identifier.id_name),
+        DagNodeType::Stmt(Statement { lvalue : LValue::Scalar(ref id), .. }) => !removed.contains(id.id_name),
+        _ => true,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compile::*;
+    use differential::check_program;
+    use std::collections::HashMap;
+
+    fn folded_dag(input : &str) -> String {
+        let mut options = CompileOptions::new();
+        options.target = Some(String::from("none"));
+        let output = compile(input, &options).unwrap();
+        return output.dags.lines().filter(|line| !line.contains("Field")).map(|line| line.trim().to_string() + "\n").collect();
+    }
+
+    #[test]
+    fn test_fold_expr() {
+        let mut constants = HashMap::new();
+        constants.insert("a", vec![3]);
+        constants.insert("arr", vec![7, 8]);
+        let a = Operand::LValue(LValue::Scalar(Identifier { id_name : "a" }));
+        let b = Operand::LValue(LValue::Scalar(Identifier { id_name : "b" }));
+        let arr_a = Operand::LValue(LValue::Array(Identifier { id_name : "arr" }, Box::new(value_operand(1))));
+        let expr = |op1 : Operand<'static>, expr_right| Expr { op1, expr_right };
+
+        assert_eq!(fold_expr(&expr(a.clone(), ExprRight::BinOp(BinOpType::Plus, arr_a.clone())), &constants),
+                   expr(value_operand(11), ExprRight::Empty()));
+        assert_eq!(fold_expr(&expr(b.clone(), ExprRight::BinOp(BinOpType::Minus, a.clone())), &constants),
+                   expr(b.clone(), ExprRight::BinOp(BinOpType::Minus, value_operand(3))));
+        assert_eq!(fold_expr(&expr(a.clone(), ExprRight::Cond(b.clone(), value_operand(5))), &constants),
+                   expr(b.clone(), ExprRight::Empty()));
+        assert_eq!(fold_expr(&expr(value_operand(0), ExprRight::Cond(b.clone(), arr_a.clone())), &constants),
+                   expr(value_operand(8), ExprRight::Empty()));
+        // the device decides what a division by zero gives
+        assert_eq!(fold_expr(&expr(a.clone(), ExprRight::BinOp(BinOpType::Div, value_operand(0))), &constants),
+                   expr(value_operand(3), ExprRight::BinOp(BinOpType::Div, value_operand(0))));
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        let input = r"global threshold : bit<32> = 111;
+                      packet n : ethernet(etherType : 1234) {
+                        new_one : bit<32>;
+                      }
+                      @ bmv2 : s1;
+                      snippet fun() {
+                        transient z : bit<1>;
+                        transient q : bit<32>;
+                        transient m : bit<32>;
+                        transient l : bit<8>;
+                        persistent reg1 : bit<32> = 0;
+                        q = 10;
+                        l = q + 250;
+                        z = q >= threshold;
+                        m = z ? 5 : reg1;
+                        reg1 = m + l;
+                        if (n.new_one > threshold) {
+                          n.new_one = q;
+                        }
+                      }";
+        // the global and the transients folded away are removed by dead code elimination
+        assert_eq!(folded_dag(input), "snippet fun @ bmv2\n\
+                                       20: Transient m : bit<32>\n\
+                                       21: Persistent reg1 : bit<32>\n\
+                                       22: Transient if_block_tmp_2 : bit<1>\n\
+                                       23: m = reg1 -> [24]\n\
+                                       24: reg1 = m + 4\n\
+                                       25: if_block_tmp_2 = n . new_one > 111 -> [26]\n\
+                                       26: n . new_one = if_block_tmp_2 ? 10 : n . new_one\n");
+    }
+
+    #[test]
+    fn test_fold_constants_check() {
+        let input = r"global limit : bit<4> = 9;
+                      snippet fun() {
+                        input a : bit<4>;
+                        output b : bit<4>;
+                        transient c : bit<4>;
+                        transient d : bit<1>;
+                        c = limit + 8;
+                        d = c > 0;
+                        if (a > c) {
+                          b = a - c;
+                        } else {
+                          b = d ? limit : a;
+                        }
+                      }";
+        assert_eq!(check_program(input, &CompileOptions::new()).unwrap(), vec![]);
+    }
+}
@@ -0,0 +1,239 @@
+// Common subexpression elimination and copy propagation on the SSA dags. A transient that
+// is written once and assigned the same operation on the same operands as an earlier
+// transient of its width becomes a copy of it. Reads of a copy x = y then read y directly,
+// as long as y is an input or a transient that isn't written any more. Registers and packet
+// fields are never propagated: their SSA versions share one location on the device. The
+// copies left without readers are removed by eliminate_dead_code.
+use grammar::*;
+use trans_snippet::*;
+use assertions::ASSERT_FAILED;
+use std::collections::HashMap;
+
+// Name of a variable or field, keyed p.f for fields
+fn lvalue_key<'a> (my_lval : &LValue<'a>) -> String {
+    match my_lval {
+        &LValue::Scalar(ref id) | &LValue::Array(ref id, _) => id.id_name.to_string(),
+        LValue::Field(id, field) => format!("{}.{}", id.id_name, field.id_name),
+    }
+}
+
+// Variables and fields an operand reads
+fn operand_keys<'a> (operand : &Operand<'a>, keys : &mut Vec<String>) {
+    match operand {
+        Operand::LValue(lval) => {
+            keys.push(lvalue_key(lval));
+            if let LValue::Array(_, address) = lval { operand_keys(address, keys) }
+        }
+        &Operand::Value(_) => {}
+    }
+}
+
+fn expr_keys<'a> (expr : &Expr<'a>) -> Vec<String> {
+    let mut keys = Vec::new();
+    operand_keys(&expr.op1, &mut keys);
+    match expr.expr_right {
+        ExprRight::BinOp(_, ref operand) => operand_keys(operand, &mut keys),
+        ExprRight::Cond(ref operand1, ref operand2) => {
+            operand_keys(operand1, &mut keys);
+            operand_keys(operand2, &mut keys);
+        }
+        ExprRight::Empty() => {}
+    }
+    keys
+}
+
+fn is_commutative(bin_op_type : BinOpType) -> bool {
+    matches!(bin_op_type, BinOpType::Plus | BinOpType::Mul | BinOpType::Equal | BinOpType::NotEqual |
+                          BinOpType::BooleanAnd | BinOpType::BooleanOr)
+}
+
+// Whether two expressions compute the same operation on the same operands
+fn same_computation<'a> (expr1 : &Expr<'a>, expr2 : &Expr<'a>) -> bool {
+    if expr1 == expr2 {
+        return true;
+    }
+    match (&expr1.expr_right, &expr2.expr_right) {
+        (&ExprRight::BinOp(bin_op_type1, ref operand1), &ExprRight::BinOp(bin_op_type2, ref operand2)) => {
+            bin_op_type1 == bin_op_type2 && is_commutative(bin_op_type1) && expr1.op1 == *operand2 && *operand1 == expr2.op1
+        }
+        _ => false,
+    }
+}
+
+// Replaces the reads of propagated copies
+fn propagate_operand<'a> (operand : &Operand<'a>, copies : &HashMap<&'a str, Operand<'a>>) -> Operand<'a> {
+    match *operand {
+        Operand::LValue(LValue::Scalar(ref id)) => copies.get(id.id_name).cloned().unwrap_or(operand.clone()),
+        Operand::LValue(LValue::Array(ref id, ref address)) => Operand::LValue(LValue::Array(*id, Box::new(propagate_operand(address, copies)))),
+        _ => operand.clone(),
+    }
+}
+
+fn propagate_expr<'a> (expr : &Expr<'a>, copies : &HashMap<&'a str, Operand<'a>>) -> Expr<'a> {
+    let expr_right = match expr.expr_right {
+        ExprRight::BinOp(bin_op_type, ref operand) => ExprRight::BinOp(bin_op_type, propagate_operand(operand, copies)),
+        ExprRight::Cond(ref operand1, ref operand2) => ExprRight::Cond(propagate_operand(operand1, copies), propagate_operand(operand2, copies)),
+        ExprRight::Empty() => ExprRight::Empty(),
+    };
+    Expr { op1 : propagate_operand(&expr.op1, copies), expr_right }
+}
+
+// Computes identical operations once and propagates copies, see above
+pub fn eliminate_common_subexpressions<'a> (my_dag : &mut Dag<'a>) {
+    let mut decls : HashMap<&'a str, VarType<'a>> = HashMap::new();
+    // positions of the statements writing each variable or field
+    let mut writes : HashMap<String, Vec<usize>> = HashMap::new();
+    for (i, my_dag_node) in my_dag.dag_vector.iter().enumerate() {
+        match my_dag_node.node_type {
+            DagNodeType::Decl(ref var_decl) => { decls.insert(var_decl.identifier.id_name, var_decl.var_type.clone()); }
+            DagNodeType::Stmt(ref my_statement) => writes.entry(lvalue_key(&my_statement.lvalue)).or_default().push(i),
+            _ => {}
+        }
+    }
+    let qualifier = |name : &str| decls.get(name).map(|var_type| var_type.type_qualifier);
+    let width = |name : &str| match decls.get(name) {
+        Some(&VarType { var_info : VarInfo::BitArray(bit_width, 1), .. }) => Some(bit_width),
+        _ => None,
+    };
+    let written_between = |key : &String, from : usize, to : usize| {
+        writes.get(key).is_some_and(|positions| positions.iter().any(|p| *p > from && *p < to))
+    };
+    // transients written by a single statement, which keep their value after it
+    let single_write = |name : &str| qualifier(name) == Some(TypeQualifier::Transient) && writes.get(name).map_or(0, |w| w.len()) == 1;
+
+    let mut copies : HashMap<&'a str, Operand<'a>> = HashMap::new();
+    // computations available to later statements: the expression, the transient holding it, its position
+    let mut available : Vec<(Expr<'a>, &'a str, usize)> = Vec::new();
+    for i in 0..my_dag.dag_vector.len() {
+        let my_statement = match my_dag.dag_vector[i].node_type {
+            DagNodeType::Stmt(ref my_statement) => my_statement.clone(),
+            DagNodeType::Cond(ref my_expr) => {
+                let my_expr = propagate_expr(my_expr, &copies);
+                my_dag.dag_vector[i].node_type = DagNodeType::Cond(my_expr);
+                continue;
+            }
+            _ => continue,
+        };
+        let mut expr = propagate_expr(&my_statement.expr, &copies);
+        let lvalue = match my_statement.lvalue {
+            LValue::Array(ref id, ref address) => LValue::Array(*id, Box::new(propagate_operand(address, &copies))),
+            ref lvalue => lvalue.clone(),
+        };
+
+        match lvalue {
+            LValue::Scalar(ref id) if single_write(id.id_name) && id.id_name != ASSERT_FAILED => {
+                if expr.expr_right != ExprRight::Empty() {
+                    let keys = expr_keys(&expr);
+                    let previous = available.iter().find(|&&(ref previous_expr, name, position)| {
+                        same_computation(previous_expr, &expr) && width(name) == width(id.id_name) &&
+                            !keys.iter().any(|key| written_between(key, position, i))
+                    }).map(|&(_, name, _)| name);
+                    match previous {
+                        Some(name) => expr = Expr { op1 : Operand::LValue(LValue::Scalar(Identifier { id_name : name })), expr_right : ExprRight::Empty() },
+                        None => available.push((expr.clone(), id.id_name, i)),
+                    }
+                }
+                match (&expr.op1, &expr.expr_right) {
+                    (&Operand::LValue(LValue::Scalar(ref source)), &ExprRight::Empty()) if width(source.id_name) == width(id.id_name) => {
+                        let stable = match qualifier(source.id_name) {
+                            Some(TypeQualifier::Input) => true,
+                            Some(TypeQualifier::Transient) => writes.get(source.id_name).is_none_or(|w| w.iter().all(|p| *p < i)),
+                            _ => false,
+                        };
+                        if stable {
+                            copies.insert(id.id_name, expr.op1.clone());
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+        my_dag.dag_vector[i].node_type = DagNodeType::Stmt(Statement { lvalue, expr });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compile::*;
+    use differential::check_program;
+
+    fn listing(input : &str) -> String {
+        let mut options = CompileOptions::new();
+        options.target = Some(String::from("none"));
+        let output = compile(input, &options).unwrap();
+        return output.dags.lines().filter(|line| !line.contains("Field")).map(|line| line.trim().to_string() + "\n").collect();
+    }
+
+    #[test]
+    fn test_common_subexpressions() {
+        let input = r"packet p : ethernet(etherType : 1234) {
+                        tx : bit<32>;
+                        rx : bit<32>;
+                        delay : bit<32>;
+                        rtt : bit<32>;
+                      }
+                      @ bmv2 : s1;
+                      snippet fun() {
+                        transient d1 : bit<32>;
+                        transient d2 : bit<32>;
+                        transient narrow : bit<8>;
+                        d1 = p.tx - p.rx;
+                        narrow = p.tx - p.rx;
+                        p.delay = d1 + narrow;
+                        d2 = p.tx - p.rx;
+                        p.rtt = d2 + d2;
+                      }";
+        assert_eq!(listing(input), "snippet fun @ bmv2\n\
+                                    23: Transient d1 : bit<32>\n\
+                                    24: Transient narrow : bit<8>\n\
+                                    25: d1 = p . tx - p . rx -> [27, 28]\n\
+                                    26: narrow = p . tx - p . rx -> [27]\n\
+                                    27: p . delay = d1 + narrow\n\
+                                    28: p . rtt = d1 + d1\n");
+    }
+
+    #[test]
+    fn test_copy_propagation() {
+        // branch removal turns the if block into tmp_0_if_1 = a + 1; b = if_block_tmp_1 ? tmp_0_if_1 : b;
+        let input = r"@ bmv2 : s1;
+                      snippet fun() {
+                        input a : bit<8>;
+                        transient b : bit<8>;
+                        transient c : bit<8>;
+                        persistent reg : bit<8> = 0;
+                        if (1) {
+                          b = a + 1;
+                        }
+                        c = b;
+                        reg = c + a;
+                      }";
+        assert_eq!(listing(input), "snippet fun @ bmv2\n\
+                                    0: Input a : bit<8>\n\
+                                    1: Persistent reg : bit<8>\n\
+                                    2: Transient tmp_0_if_1 : bit<8>\n\
+                                    3: tmp_0_if_1 = a + 1 -> [4]\n\
+                                    4: reg = tmp_0_if_1 + a\n");
+    }
+
+    #[test]
+    fn test_common_subexpressions_check() {
+        let input = r"snippet fun() {
+                        input a : bit<3>;
+                        output b : bit<3>;
+                        transient c : bit<3>;
+                        transient d : bit<3>;
+                        transient e : bit<3>;
+                        c = a + 1;
+                        e = c;
+                        if (a > 4) {
+                          c = 1 + a;
+                          d = a + 1;
+                        }
+                        b = c + d;
+                        b = b + e;
+                      }";
+        assert_eq!(check_program(input, &CompileOptions::new()).unwrap(), vec![]);
+    }
+}
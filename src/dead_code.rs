@@ -0,0 +1,207 @@
+// Dead code elimination on the SSA dags. Statements writing registers, packet and device
+// fields and outputs are live, and so is every statement computing a transient that a live
+// statement reads. The other statements are removed, with the declarations of the transients,
+// globals and consts no statement refers to any more, so they need neither an action nor a
+// metadata field. Registers keep their declarations and writes: the control plane can read
+// them, but unread_registers reports the ones the snippet writes and never reads.
+use grammar::*;
+use trans_snippet::*;
+use constant_folding::{read_names, expr_names};
+use dependency::get_state_aliases;
+use assertions::ASSERT_FAILED;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+// Variables read by a statement, including the index of the array it writes
+pub fn statement_reads<'a> (my_statement : &Statement<'a>, names : &mut HashSet<&'a str>) {
+    expr_names(&my_statement.expr, names);
+    if let LValue::Array(_, ref address) = my_statement.lvalue { read_names(address, names) }
+}
+
+fn lvalue_name<'a> (my_lval : &LValue<'a>) -> Option<&'a str> {
+    match my_lval {
+        &LValue::Scalar(ref id) | &LValue::Array(ref id, _) => Some(id.id_name),
+        &LValue::Field(_, _) => None,
+    }
+}
+
+// Removes the statements whose results never reach a register, field or output, see above
+pub fn eliminate_dead_code<'a> (my_dag : &mut Dag<'a>) {
+    let mut qualifiers : HashMap<&'a str, TypeQualifier> = HashMap::new();
+    for my_dag_node in &my_dag.dag_vector {
+        if let DagNodeType::Decl(ref var_decl) = my_dag_node.node_type { qualifiers.insert(var_decl.identifier.id_name, var_decl.var_type.type_qualifier); }
+    }
+    // only writes to transients can be dead. The assertion flag is read by the tables bmv2_gen adds for it.
+    let is_transient = |name : &str| qualifiers.get(name) == Some(&TypeQualifier::Transient) && name != ASSERT_FAILED;
+
+    let mut live = vec![false; my_dag.dag_vector.len()];
+    let mut live_names : HashSet<&'a str> = HashSet::new();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (i, my_dag_node) in my_dag.dag_vector.iter().enumerate().rev() {
+            let my_statement = match my_dag_node.node_type {
+                DagNodeType::Stmt(ref my_statement) if !live[i] => my_statement,
+                _ => continue,
+            };
+            let needed = match lvalue_name(&my_statement.lvalue) {
+                Some(name) => !is_transient(name) || live_names.contains(name),
+                None => true,
+            };
+            if needed {
+                live[i] = true;
+                statement_reads(my_statement, &mut live_names);
+                changed = true;
+            }
+        }
+    }
+
+    let mut referenced : HashSet<&'a str> = HashSet::new();
+    for (i, my_dag_node) in my_dag.dag_vector.iter().enumerate() {
+        match my_dag_node.node_type {
+            DagNodeType::Stmt(ref my_statement) if live[i] => {
+                statement_reads(my_statement, &mut referenced);
+                referenced.extend(lvalue_name(&my_statement.lvalue));
+            }
+            DagNodeType::Cond(ref my_expr) => expr_names(my_expr, &mut referenced),
+            _ => {}
+        }
+    }
+    let mut i = 0;
+    my_dag.dag_vector.retain(|my_dag_node| {
+        let keep = match my_dag_node.node_type {
+            DagNodeType::Stmt(_) => live[i],
+            DagNodeType::Decl(ref var_decl) => match var_decl.var_type.type_qualifier {
+                TypeQualifier::Transient | TypeQualifier::Global | TypeQualifier::Const => referenced.contains(var_decl.identifier.id_name),
+                _ => true,
+            },
+            DagNodeType::Cond(_) => true,
+        };
+        i += 1;
+        keep
+    });
+}
+
+// Registers that a snippet writes and never reads, in declaration order
+pub fn unread_registers<'a> (my_dag : &Dag<'a>) -> Vec<String> {
+    let aliases = get_state_aliases(my_dag);
+    let origin = |name : &str| -> String { aliases.get(name).cloned().unwrap_or(name.to_string()) };
+    let mut read : HashSet<String> = HashSet::new();
+    let mut written : HashSet<String> = HashSet::new();
+    for my_dag_node in &my_dag.dag_vector {
+        if let DagNodeType::Stmt(ref my_statement) = my_dag_node.node_type {
+            let mut names = HashSet::new();
+            statement_reads(my_statement, &mut names);
+            read.extend(names.iter().map(|name| origin(name)));
+            written.extend(lvalue_name(&my_statement.lvalue).map(&origin));
+        }
+    }
+
+    let mut registers = Vec::new();
+    for my_dag_node in &my_dag.dag_vector {
+        match my_dag_node.node_type {
+            DagNodeType::Decl(ref var_decl) if var_decl.var_type.type_qualifier == TypeQualifier::Persistent => {
+                let name = var_decl.identifier.id_name.to_string();
+                if !aliases.contains_key(&name) && written.contains(&name) && !read.contains(&name) {
+                    registers.push(name);
+                }
+            }
+            _ => {}
+        }
+    }
+    registers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compile::*;
+    use differential::check_program;
+
+    fn compile_dag(input : &str) -> CompileOutput {
+        let mut options = CompileOptions::new();
+        options.target = Some(String::from("none"));
+        return compile(input, &options).unwrap();
+    }
+
+    fn listing(output : &CompileOutput) -> String {
+        return output.dags.lines().filter(|line| !line.contains("Field")).map(|line| line.trim().to_string() + "\n").collect();
+    }
+
+    #[test]
+    fn test_eliminate_dead_code() {
+        let input = r"packet n : ethernet(etherType : 1234) {
+                        new_one : bit<32>;
+                      }
+                      @ bmv2 : s1;
+                      snippet fun() {
+                        input a : bit<32>;
+                        output b : bit<32>;
+                        transient z : bit<1>;
+                        transient t : bit<32>;
+                        transient u : bit<32>;
+                        transient v : bit<32>;
+                        persistent reg1 : bit<32> = 0;
+                        persistent reg2 : bit<32> = 0;
+                        z = a > 10;
+                        t = a + 1;
+                        u = t + reg1;
+                        v = u + 2;
+                        if (z) {
+                          n.new_one = u;
+                        }
+                        b = a - 1;
+                        reg2 = t;
+                      }";
+        let output = compile_dag(input);
+        assert_eq!(listing(&output), "snippet fun @ bmv2\n\
+                                      20: Input a : bit<32>\n\
+                                      21: Output b : bit<32>\n\
+                                      22: Transient z : bit<1>\n\
+                                      23: Transient t : bit<32>\n\
+                                      24: Transient u : bit<32>\n\
+                                      25: Persistent reg1 : bit<32>\n\
+                                      26: Persistent reg2 : bit<32>\n\
+                                      27: z = a > 10 -> [30]\n\
+                                      28: t = a + 1 -> [29, 32]\n\
+                                      29: u = t + reg1 -> [30]\n\
+                                      30: n . new_one = z ? u : n . new_one\n\
+                                      31: b = a - 1\n\
+                                      32: reg2 = t\n");
+        assert!(output.diagnostics.contains(&String::from("warning: Register reg2 of snippet fun is written but never read.")));
+    }
+
+    #[test]
+    fn test_eliminate_dead_code_check() {
+        let input = r"snippet fun() {
+                        input a : bit<3>;
+                        output b : bit<3>;
+                        transient c : bit<3>;
+                        transient d : bit<3>;
+                        persistent cnt : bit<2> = 0;
+                        c = a + 1;
+                        d = c + cnt;
+                        if (a > 2) {
+                          cnt = cnt + 1;
+                          b = c;
+                        }
+                      }";
+        assert_eq!(check_program(input, &CompileOptions::new()).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_unread_registers() {
+        let input = r"@ bmv2 : s1;
+                      snippet fun() {
+                        input a : bit<8>;
+                        persistent cnt : bit<8> = 0;
+                        persistent last : bit<8> = 0;
+                        persistent unused : bit<8> = 0;
+                        cnt = cnt + 1;
+                        last = a + 1;
+                      }";
+        let output = compile_dag(input);
+        let warnings : Vec<&String> = output.diagnostics.iter().filter(|d| d.starts_with("warning")).collect();
+        assert_eq!(warnings, vec!["warning: Register last of snippet fun is written but never read."]);
+    }
+}
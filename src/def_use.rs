@@ -93,6 +93,7 @@ impl<'a> TreeFold<'a> for DefUse<'a> {
     }
     self.visit_variable_decls(&tree.variable_decls);
     self.visit_ifblocks(&tree.ifblocks);
+    for invariant in &tree.invariants { self.visit_invariant(invariant); }
   }
 
 
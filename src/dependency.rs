@@ -0,0 +1,271 @@
+// Dependency analysis between the statements of a dag. Every pair of statements
+// that touch the same location, with at least one of them writing it, is ordered
+// by an edge stored in next_nodes of the earlier and prev_nodes of the later statement.
+use grammar::*;
+use std::collections::HashMap;
+use trans_snippet::*;
+
+// Kinds of dependency between two statements of a dag
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone, Copy)]
+pub enum DepKind {
+    RAW,
+    WAR,
+    WAW,
+}
+
+// A storage location read or written by a statement. An array element whose
+// index is not a constant may be any element of the array.
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub enum Location {
+    Whole(String),
+    Element(String, u64),
+    AnyElement(String),
+}
+
+impl Location {
+    pub fn get_base(&self) -> &str {
+        match self {
+            &Location::Whole(ref base) | &Location::Element(ref base, _) | &Location::AnyElement(ref base) => base,
+        }
+    }
+
+    pub fn conflicts(&self, other : &Location) -> bool {
+        if self.get_base() != other.get_base() {
+            return false;
+        }
+        match (self, other) {
+            (&Location::Element(_, a), &Location::Element(_, b)) => a == b,
+            _ => true
+        }
+    }
+}
+
+
+// SSA gives every rewrite of a variable a fresh version and records the variable it
+// was created from. For transient variables that is a genuinely new variable, but
+// persistent state and packet or device fields still live in one place, so map each
+// of their versions back to that variable.
+pub fn get_state_aliases<'a> (my_dag : &Dag<'a>) -> HashMap<String, String> {
+    let mut aliases = HashMap::new();
+    for dagnode in &my_dag.dag_vector {
+        if let DagNodeType::Decl(ref var_decl) = dagnode.node_type {
+            match var_decl.var_type.type_qualifier {
+                TypeQualifier::Persistent | TypeQualifier::Field => {
+                    let name = var_decl.identifier.id_name;
+                    if let Some(origin) = my_dag.ssa_origins.get(name) { aliases.insert(name.to_string(), origin.clone()); }
+                }
+                _ => {}
+            }
+        }
+    }
+    aliases
+}
+
+
+fn get_lvalue_location<'a> (my_lval : &LValue<'a>, aliases : &HashMap<String, String>) -> Location {
+    let canonical = |name : String| -> String { aliases.get(&name).cloned().unwrap_or(name) };
+    match my_lval {
+        LValue::Scalar(id) => Location::Whole(canonical(id.id_name.to_string())),
+        LValue::Array(id, index) => {
+            match **index {
+                Operand::Value(ref value) => Location::Element(canonical(id.id_name.to_string()), value.value),
+                Operand::LValue(_) => Location::AnyElement(canonical(id.id_name.to_string())),
+            }
+        }
+        LValue::Field(id, field_name) => Location::Whole(canonical(format!("{}.{}", id.id_name, field_name.id_name))),
+    }
+}
+
+
+fn add_operand_reads<'a> (my_operand : &Operand<'a>, aliases : &HashMap<String, String>, reads : &mut Vec<Location>) {
+    match my_operand {
+        Operand::LValue(lval) => {
+            reads.push(get_lvalue_location(lval, aliases));
+            if let LValue::Array(_, index) = lval { add_operand_reads(index, aliases, reads) }
+        }
+        &Operand::Value(_) => {}
+    }
+}
+
+
+fn add_expr_reads<'a> (my_expr : &Expr<'a>, aliases : &HashMap<String, String>, reads : &mut Vec<Location>) {
+    add_operand_reads(&my_expr.op1, aliases, reads);
+    match my_expr.expr_right {
+        ExprRight::BinOp(_, ref operand) => add_operand_reads(operand, aliases, reads),
+        ExprRight::Cond(ref operand1, ref operand2) => {
+            add_operand_reads(operand1, aliases, reads);
+            add_operand_reads(operand2, aliases, reads);
+        }
+        ExprRight::Empty() => {}
+    }
+}
+
+
+// Locations (reads, writes) of every statement; other nodes access nothing
+pub fn get_accesses<'a> (my_dag : &Dag<'a>) -> Vec<(Vec<Location>, Vec<Location>)> {
+    let aliases = get_state_aliases(my_dag);
+    let mut accesses = Vec::new();
+    for dagnode in &my_dag.dag_vector {
+        let mut reads = Vec::new();
+        let mut writes = Vec::new();
+        if let DagNodeType::Stmt(ref my_statement) = dagnode.node_type {
+            writes.push(get_lvalue_location(&my_statement.lvalue, &aliases));
+            if let LValue::Array(_, ref index) = my_statement.lvalue { add_operand_reads(index, &aliases, &mut reads) }
+            add_expr_reads(&my_statement.expr, &aliases, &mut reads);
+            if let Some(ref condition) = dagnode.pre_condition { add_expr_reads(&condition.expr, &aliases, &mut reads) }
+        }
+        accesses.push((reads, writes));
+    }
+    accesses
+}
+
+
+fn overlaps(first : &[Location], second : &[Location]) -> bool {
+    first.iter().any(|a| second.iter().any(|b| a.conflicts(b)))
+}
+
+
+// Kinds of dependency of statement j on an earlier statement i
+fn get_dep_kinds(accesses : &[(Vec<Location>, Vec<Location>)], i : usize, j : usize) -> Vec<DepKind> {
+    let (ref reads_i, ref writes_i) = accesses[i];
+    let (ref reads_j, ref writes_j) = accesses[j];
+    let mut dep_kinds = Vec::new();
+    if overlaps(writes_i, reads_j) { dep_kinds.push(DepKind::RAW); }
+    if overlaps(reads_i, writes_j) { dep_kinds.push(DepKind::WAR); }
+    if overlaps(writes_i, writes_j) { dep_kinds.push(DepKind::WAW); }
+    dep_kinds
+}
+
+
+// Replace the edges of the dag with the complete set of RAW, WAR and WAW dependencies
+pub fn add_dependency_edges<'a> (my_dag : &mut Dag<'a>) {
+    let accesses = get_accesses(my_dag);
+    for dagnode in &mut my_dag.dag_vector {
+        dagnode.next_nodes = Vec::new();
+        dagnode.prev_nodes = Vec::new();
+    }
+    for i in 0..my_dag.dag_vector.len() {
+        for j in (i + 1)..my_dag.dag_vector.len() {
+            if !get_dep_kinds(&accesses, i, j).is_empty() {
+                my_dag.dag_vector[i].next_nodes.push(j);
+                my_dag.dag_vector[j].prev_nodes.push(i);
+            }
+        }
+    }
+}
+
+
+// Dependency edges recorded in the dag, as (from, to, kind). An edge may carry several kinds.
+pub fn get_dependencies<'a> (my_dag : &Dag<'a>) -> Vec<(usize, usize, DepKind)> {
+    let accesses = get_accesses(my_dag);
+    let mut dependencies = Vec::new();
+    for (i, dagnode) in my_dag.dag_vector.iter().enumerate() {
+        for j in &dagnode.next_nodes {
+            for dep_kind in get_dep_kinds(&accesses, i, *j) {
+                dependencies.push((i, *j, dep_kind));
+            }
+        }
+    }
+    dependencies
+}
+
+
+// Number of statements on the longest chain of RAW edges, a lower bound on the
+// number of pipeline stages the dag needs.
+pub fn longest_chain<'a> (my_dag : &Dag<'a>) -> usize {
+    let mut raw_edges : HashMap<usize, Vec<usize>> = HashMap::new();
+    for (from, to, dep_kind) in get_dependencies(my_dag) {
+        if dep_kind == DepKind::RAW {
+            raw_edges.entry(from).or_default().push(to);
+        }
+    }
+
+    let mut depth : Vec<usize> = vec![0; my_dag.dag_vector.len()];
+    let mut longest = 0;
+    // edges always point forward, so a single backward pass suffices
+    for i in (0..my_dag.dag_vector.len()).rev() {
+        if let DagNodeType::Stmt(_) = my_dag.dag_vector[i].node_type {
+            let next_depth = raw_edges.get(&i).map_or(0, |next| next.iter().map(|n| depth[*n]).max().unwrap_or(0));
+            depth[i] = next_depth + 1;
+            if depth[i] > longest { longest = depth[i]; }
+        }
+    }
+    longest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::lexer::get_tokens;
+    use super::super::parser::*;
+    use super::super::compile::{INCLUDE_DIR, read_include_file};
+    use super::super::passes::default_passes;
+
+    macro_rules! test_dependencies {
+        ($input_code:expr,$expected:expr,$test_name:ident) => (
+            #[test]
+            fn $test_name() {
+                let input = $input_code;
+                let tokens = &mut get_tokens(input);
+                let token_iter = &mut tokens.iter().peekable();
+                let parse_tree = parse_prog(token_iter);
+                let pkt_contents = read_include_file(&vec![INCLUDE_DIR.to_string()], "packet.np");
+                let pkt_tree = parse_import_packets(&mut get_tokens(&pkt_contents).iter().peekable());
+                let packet_map = create_packet_map(&parse_tree.packets, &pkt_tree);
+                let mut dag_map = create_dag_nodes(&parse_tree.snippets, &parse_tree.globals, &packet_map, &parse_tree.packets, &parse_tree.packets, &Vec::new(), &default_passes(), &mut |_, _| {});
+                let my_dag = dag_map.get_mut("fun").unwrap();
+                add_dependency_edges(my_dag);
+
+                // report edges as pairs of statement numbers, counting from 0
+                let first_stmt = my_dag.dag_vector.iter().position(|n| match n.node_type { DagNodeType::Stmt(_) => true, _ => false }).unwrap();
+                let dependencies : Vec<(usize, usize, DepKind)> = get_dependencies(my_dag).iter()
+                    .map(|&(from, to, dep_kind)| (from - first_stmt, to - first_stmt, dep_kind)).collect();
+                assert_eq!(dependencies, $expected);
+                for (i, dagnode) in my_dag.dag_vector.iter().enumerate() {
+                    for j in &dagnode.next_nodes {
+                        assert!(my_dag.dag_vector[*j].prev_nodes.contains(&i));
+                    }
+                }
+            }
+        )
+    }
+
+    test_dependencies!(r"@ bmv2 : s1;
+                         snippet fun() {
+                           input c : bit<32>;
+                           transient a : bit<32>;
+                           output b : bit<32>;
+                           a = c + 1;
+                           b = a + 2;
+                         }",
+                       vec![(0, 1, DepKind::RAW)], test_dependencies_raw);
+
+    test_dependencies!(r"@ bmv2 : s1;
+                         snippet fun() {
+                           output a : bit<32>;
+                           output b : bit<32>;
+                           transient i : bit<32>;
+                           persistent arr : bit<32>[4] = {0, 0, 0, 0,};
+                           a = arr[1];
+                           arr[2] = 7;
+                           arr[i] = 5;
+                           b = arr[2];
+                         }",
+                       vec![(0, 2, DepKind::WAR), (1, 2, DepKind::WAW), (1, 3, DepKind::RAW), (2, 3, DepKind::RAW)],
+                       test_dependencies_unknown_index);
+
+    test_dependencies!(r"@ bmv2 : s1;
+                         snippet fun() {
+                           transient a : bit<32>;
+                           persistent reg : bit<32> = 0;
+                           a = reg;
+                           reg = 1;
+                           reg = a;
+                         }",
+                       vec![(0, 1, DepKind::WAR), (0, 2, DepKind::RAW), (0, 2, DepKind::WAR), (1, 2, DepKind::WAW)],
+                       test_dependencies_persistent_versions);
+}
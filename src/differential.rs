@@ -0,0 +1,419 @@
+// Differential checker for the dag transformations. A snippet is executed with
+// the interpreter and its dag is executed after every pass of the default
+// pipeline of passes.rs, on the same inputs: the input variables, the packet fields the snippet uses and the
+// initial register state. Inputs are enumerated when they fit in EXHAUSTIVE_BITS
+// bits, and sampled otherwise. The first input on which a dag disagrees with the
+// interpreter is reported.
+use grammar::*;
+use interpreter::*;
+use trans_snippet::*;
+use passes::*;
+use compile::*;
+use dot::DotStage;
+use imports::{ImportArena, resolve_imports};
+use lexer;
+use parser;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+pub const EXHAUSTIVE_BITS : u64 = 12;
+pub const SAMPLES : usize = 1000;
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Divergence {
+    pub snippet_name : String,
+    // transformation after which the dag disagrees with the snippet
+    pub stage        : DotStage,
+    pub packet       : FieldMap,
+    pub registers    : RegisterMap,
+    pub expected     : SnippetOutput,
+    // output of the dag, or the error it failed with
+    pub actual       : Result<SnippetOutput, String>,
+}
+
+// Original variable of a name in a dag, using the versions SSA recorded in it
+fn original_name<'o> (name : &str, ssa_origins : &HashMap<String, String>, originals : &HashSet<&'o str>) -> &'o str {
+    let origin = ssa_origins.get(name).map_or(name, |origin| origin.as_str());
+    match originals.get(origin) {
+        Some(original) => original,
+        None => panic!("{} is not a version of a declared variable.", name),
+    }
+}
+
+// Executes a dag on one packet. originals are the variables declared before SSA, registers the
+// initial register state. Writes to an SSA version of a variable update that variable.
+pub fn run_dag<'a, 'o> (my_dag : &Dag<'a>, originals : &HashSet<&'o str>, my_globals : &Globals<'a>,
+                    registers : &RegisterMap, packet : &FieldMap) -> SnippetOutput {
+    let mut decls : HashMap<&str, &VariableDecl<'a>> = HashMap::new();
+    for my_dag_node in &my_dag.dag_vector {
+        if let DagNodeType::Decl(ref var_decl) = my_dag_node.node_type { decls.insert(var_decl.identifier.id_name, var_decl); }
+    }
+    let width = |name : &str| match decls.get(name) {
+        Some(&&VariableDecl { var_type : VarType { var_info : VarInfo::BitArray(bit_width, _), .. }, .. }) => bit_width,
+        _ => DEFAULT_WIDTH,
+    };
+
+    // scalars are arrays of size 1
+    let mut values : HashMap<String, Vec<u64>> = HashMap::new();
+    let mut latest : HashMap<&'o str, String> = HashMap::new();
+    for my_global in &my_globals.global_vector {
+        values.insert(my_global.identifier.id_name.to_string(), my_global.initial_values.iter().map(|v| v.value).collect());
+    }
+    for &name in originals {
+        let var_decl = match decls.get(name) {
+            Some(var_decl) => var_decl,
+            None => continue,
+        };
+        latest.insert(name, name.to_string());
+        let initial = match var_decl.var_type.type_qualifier {
+            TypeQualifier::Persistent => registers.get(name).cloned(),
+            TypeQualifier::Const => Some(var_decl.initial_values.iter().map(|v| v.value).collect()),
+            TypeQualifier::Transient | TypeQualifier::Output => Some(vec![0]),
+            _ => packet.get(name).map(|value| vec![truncate(*value, width(name))]),
+        };
+        if let Some(initial) = initial { values.insert(name.to_string(), initial); }
+    }
+
+    {
+        let read = |values : &HashMap<String, Vec<u64>>, name : &str, index : usize| -> u64 {
+            match values.get(name) {
+                Some(array) if index < array.len() => array[index],
+                Some(array) => panic!("Index {} is out of bounds for {} of size {}.", index, name, array.len()),
+                None => panic!("{} is read before it is written.", name),
+            }
+        };
+        let eval_operand = |values : &HashMap<String, Vec<u64>>, operand : &Operand<'a>| -> u64 {
+            match operand {
+                Operand::Value(value) => value.value,
+                &Operand::LValue(LValue::Scalar(ref id)) => read(values, id.id_name, 0),
+                &Operand::LValue(LValue::Field(ref id, ref field)) => read(values, &format!("{}.{}", id.id_name, field.id_name), 0),
+                &Operand::LValue(LValue::Array(ref id, ref address)) => {
+                    let index = match **address {
+                        Operand::Value(ref value) => value.value,
+                        Operand::LValue(LValue::Scalar(ref index_id)) => read(values, index_id.id_name, 0),
+                        Operand::LValue(LValue::Field(ref p, ref f)) => read(values, &format!("{}.{}", p.id_name, f.id_name), 0),
+                        _ => panic!("Array index of {} can't be an array.", id.id_name),
+                    };
+                    read(values, id.id_name, index as usize)
+                }
+            }
+        };
+
+        for my_dag_node in &my_dag.dag_vector {
+            let my_statement = match my_dag_node.node_type {
+                DagNodeType::Stmt(ref my_statement) => my_statement,
+                DagNodeType::Cond(_) => panic!("Cond nodes can't be executed."),
+                DagNodeType::Decl(_) => continue,
+            };
+            let value1 = eval_operand(&values, &my_statement.expr.op1);
+            let value = match my_statement.expr.expr_right {
+                ExprRight::BinOp(bin_op_type, ref operand) => eval_bin_op(bin_op_type, value1, eval_operand(&values, operand)),
+                ExprRight::Cond(ref operand_true, ref operand_false) =>
+                    if value1 != 0 { eval_operand(&values, operand_true) } else { eval_operand(&values, operand_false) },
+                ExprRight::Empty() => value1,
+            };
+
+            let (name, index) = match my_statement.lvalue {
+                LValue::Scalar(ref id) => (id.id_name.to_string(), 0),
+                LValue::Field(ref id, ref field) => (format!("{}.{}", id.id_name, field.id_name), 0),
+                LValue::Array(ref id, ref address) => (id.id_name.to_string(), eval_operand(&values, address) as usize),
+            };
+            let original = original_name(&name, &my_dag.ssa_origins, originals);
+            // the first write to an array version starts from the previous version
+            if !values.contains_key(&name) {
+                let previous = match latest.get(original).and_then(|l| values.get(l)) {
+                    Some(previous) => previous.clone(),
+                    None => vec![0],
+                };
+                values.insert(name.clone(), previous);
+            }
+            let value = truncate(value, width(&name));
+            match values.get_mut(&name) {
+                Some(array) if index < array.len() => array[index] = value,
+                Some(array) => panic!("Index {} is out of bounds for {} of size {}.", index, name, array.len()),
+                None => {}
+            }
+            latest.insert(original, name);
+        }
+    }
+
+    let mut output = SnippetOutput { fields : FieldMap::new(), registers : RegisterMap::new(), violations : Vec::new() };
+    for &name in originals {
+        let var_decl = match decls.get(name) {
+            Some(var_decl) => var_decl,
+            None => continue,
+        };
+        let final_value = values.get(&latest[name]);
+        match (var_decl.var_type.type_qualifier, final_value) {
+            (TypeQualifier::Persistent, Some(array)) => { output.registers.insert(name.to_string(), array.clone()); }
+            (TypeQualifier::Output, Some(array)) => { output.fields.insert(name.to_string(), array[0]); }
+            (_, Some(array)) if packet.contains_key(name) && name.contains('.') => { output.fields.insert(name.to_string(), array[0]); }
+            _ => {}
+        }
+    }
+    output
+}
+
+// xorshift, so that the sampled inputs are the same on every run
+struct Random {
+    state : u64,
+}
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    // favours the corner cases 0, 1 and the maximum
+    fn value(&mut self, width : u64) -> u64 {
+        let max = truncate(u64::MAX, width);
+        match self.next() % 8 {
+            0 => 0,
+            1 => 1,
+            2 => max,
+            _ => truncate(self.next(), width),
+        }
+    }
+}
+
+fn collect_fields<'a> (operand : &Operand<'a>, fields : &mut BTreeSet<String>) {
+    match *operand {
+        Operand::LValue(LValue::Field(ref id, ref field)) => { fields.insert(format!("{}.{}", id.id_name, field.id_name)); }
+        Operand::LValue(LValue::Array(_, ref address)) => collect_fields(address, fields),
+        _ => {}
+    }
+}
+
+// Packet fields read or written by a snippet, or read by its assertions and invariants
+pub fn snippet_fields<'a> (my_snippet : &Snippet<'a>) -> BTreeSet<String> {
+    let mut fields = BTreeSet::new();
+    let mut exprs : Vec<&Expr<'a>> = my_snippet.invariants.iter().map(|i| &i.condition.expr).collect();
+    for ifblock in &my_snippet.ifblocks.ifblock_vector {
+        exprs.push(&ifblock.condition.expr);
+        for my_statement in &ifblock.statements.stmt_vector {
+            collect_fields(&Operand::LValue(my_statement.lvalue.clone()), &mut fields);
+            exprs.push(&my_statement.expr);
+        }
+        exprs.extend(ifblock.statements.assert_vector.iter().map(|a| &a.condition.expr));
+    }
+    {
+        for expr in exprs {
+            collect_fields(&expr.op1, &mut fields);
+            match expr.expr_right {
+                ExprRight::BinOp(_, ref operand) => collect_fields(operand, &mut fields),
+                ExprRight::Cond(ref operand1, ref operand2) => {
+                    collect_fields(operand1, &mut fields);
+                    collect_fields(operand2, &mut fields);
+                }
+                ExprRight::Empty() => {}
+            }
+        }
+    }
+    fields
+}
+
+// Packets and initial register states a snippet is run on: the input variables, the packet
+// fields the snippet uses and the elements of its registers. Inputs are enumerated when they
+// fit in EXHAUSTIVE_BITS bits, and sampled otherwise.
+pub fn snippet_inputs<'a> (my_snippet : &Snippet<'a>, field_width : &dyn Fn(&str) -> u64) -> Vec<(FieldMap, RegisterMap)> {
+    let mut widths : Vec<(String, u64)> = Vec::new();
+    // packet inputs, then the elements of the registers
+    for my_decl in &my_snippet.variable_decls.decl_vector {
+        if let (&VarInfo::BitArray(bit_width, _), TypeQualifier::Input) = (&my_decl.var_type.var_info, my_decl.var_type.type_qualifier) { widths.push((my_decl.identifier.id_name.to_string(), bit_width)) }
+    }
+    for field in snippet_fields(my_snippet) {
+        let bit_width = field_width(&field);
+        widths.push((field, bit_width));
+    }
+    let packet_inputs = widths.len();
+    let mut register_sizes = Vec::new();
+    for my_decl in &my_snippet.variable_decls.decl_vector {
+        if let (&VarInfo::BitArray(bit_width, var_size), TypeQualifier::Persistent) = (&my_decl.var_type.var_info, my_decl.var_type.type_qualifier) {
+            register_sizes.push((my_decl.identifier.id_name.to_string(), var_size as usize));
+            for _ in 0..var_size {
+                widths.push((my_decl.identifier.id_name.to_string(), bit_width));
+            }
+        }
+    }
+
+    let total_bits : u64 = widths.iter().map(|&(_, bit_width)| bit_width).sum();
+    let exhaustive = total_bits <= EXHAUSTIVE_BITS;
+    let count = if exhaustive { 1usize << total_bits } else { SAMPLES };
+    let mut random = Random { state : 0x2545f4914f6cdd1d };
+    let mut snippet_inputs = Vec::new();
+    for n in 0..count {
+        let mut bits = n as u64;
+        let inputs : Vec<u64> = widths.iter().map(|&(_, bit_width)| {
+            if exhaustive {
+                let value = truncate(bits, bit_width);
+                bits >>= bit_width;
+                value
+            } else {
+                random.value(bit_width)
+            }
+        }).collect();
+        let packet : FieldMap = widths[..packet_inputs].iter().zip(inputs.iter()).map(|((name, _), value)| (name.clone(), *value)).collect();
+        let mut registers = RegisterMap::new();
+        let mut next_input = packet_inputs;
+        for &(ref name, size) in &register_sizes {
+            registers.insert(name.clone(), inputs[next_input..next_input + size].to_vec());
+            next_input += size;
+        }
+
+        snippet_inputs.push((packet, registers));
+    }
+    snippet_inputs
+}
+
+// Runs the interpreter on one packet, starting from the given register state.
+// The dags and the generated code don't check assertions, so their violations are left out.
+pub fn run_interpreter<'a> (my_snippet : &Snippet<'a>, my_globals : &Globals<'a>, my_packets : &Packets<'a>, pkt_tree : &Packets<'a>,
+                            registers : &RegisterMap, packet : &FieldMap) -> SnippetOutput {
+    let mut interpreter = Interpreter::new(my_snippet, my_globals, my_packets);
+    interpreter.add_headers(pkt_tree);
+    for (name, values) in registers {
+        for (index, value) in values.iter().enumerate() {
+            interpreter.set_register(name, index, *value);
+        }
+    }
+    let mut output = interpreter.process(packet);
+    output.violations.clear();
+    output
+}
+
+// Runs the interpreter and the dags of each stage on every input, and returns the first disagreement
+pub fn check_snippet<'a> (my_snippet : &Snippet<'a>, my_globals : &Globals<'a>, my_packets : &Packets<'a>,
+                          pkt_tree : &Packets<'a>, packet_map : &HashMap<String, String>,
+                          device_decls : &Vec<VariableDecl<'a>>) -> Option<Divergence> {
+    let field_decls = get_field_decls(my_packets, pkt_tree, device_decls);
+    let context = PassContext { my_snippet, my_globals, my_packets, pkt_tree, packet_map, device_decls, field_decls : &field_decls };
+    // the dag after every pass, starting with branch removal
+    let mut stage_dags : Vec<(DotStage, Dag)> = Vec::new();
+    PassManager::new(&default_passes()).run(&context, &mut |pass, my_dag| stage_dags.push((DotStage::from_name(pass), my_dag.clone())));
+    let branch_dag = &stage_dags[0].1;
+
+    let mut originals = HashSet::new();
+    for my_dag_node in &branch_dag.dag_vector {
+        if let DagNodeType::Decl(ref var_decl) = my_dag_node.node_type { originals.insert(var_decl.identifier.id_name); }
+    }
+    let decl_width = |name : &str| -> u64 {
+        for my_dag_node in &branch_dag.dag_vector {
+            match my_dag_node.node_type {
+                DagNodeType::Decl(VariableDecl { identifier, var_type : VarType { var_info : VarInfo::BitArray(bit_width, _), .. }, .. })
+                    if identifier.id_name == name => return bit_width,
+                _ => {}
+            }
+        }
+        DEFAULT_WIDTH
+    };
+
+    for (packet, registers) in snippet_inputs(my_snippet, &decl_width) {
+        // inputs the snippet itself fails on, e.g. with an index out of bounds, are skipped
+        let expected = match catch_errors(|| run_interpreter(my_snippet, my_globals, my_packets, pkt_tree, &registers, &packet)) {
+            Ok(expected) => expected,
+            Err(_) => continue,
+        };
+        for &(stage, ref my_dag) in &stage_dags {
+            let actual = catch_errors(|| run_dag(my_dag, &originals, my_globals, &registers, &packet))
+                .map_err(|errors| errors[0].message.clone());
+            if actual.as_ref() != Ok(&expected) {
+                return Some(Divergence { snippet_name : my_snippet.snippet_id.id_name.to_string(), stage, packet,
+                                         registers, expected, actual });
+            }
+        }
+    }
+    None
+}
+
+// Checks every snippet of a program. Returns the first divergence of each snippet.
+pub fn check_program(source : &str, options : &CompileOptions) -> Result<Vec<Divergence>, Vec<CompileError>> {
+    catch_errors(|| {
+        let tokens = &mut lexer::get_tokens(source);
+        let token_iter = &mut tokens.iter().peekable();
+        let parse_tree = parser::parse_prog(token_iter);
+        assert!(token_iter.peek().is_none(), "Token iterator is not empty.");
+        let mut import_arena = ImportArena::new();
+        let parse_tree = resolve_imports(parse_tree, options.source_path.as_deref(), &options.include_dirs, &mut import_arena);
+
+        let contents = read_include_file(&options.include_dirs, "packet.np");
+        let pkt_tokens = &mut lexer::get_tokens(&contents);
+        let pkt_iter = &mut pkt_tokens.iter().peekable();
+        let pkt_tree = parser::parse_import_packets(pkt_iter);
+        let packet_map = create_packet_map(&parse_tree.packets, &pkt_tree);
+        let device_decls = get_device_decls(&parse_tree.imports, &options.include_dirs);
+
+        lower_packet_variables(&parse_tree.snippets).snippet_vector.iter()
+            .filter_map(|my_snippet| check_snippet(my_snippet, &parse_tree.globals, &parse_tree.packets, &pkt_tree, &packet_map, &device_decls))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::fs::File;
+    use std::io::prelude::*;
+
+    fn check(input : &str) -> Vec<Divergence> {
+        return check_program(input, &CompileOptions::new()).unwrap();
+    }
+
+    #[test]
+    fn test_check_if_else() {
+        let input = r"snippet fun() {
+                        input a : bit<3>;
+                        output b : bit<3>;
+                        persistent cnt : bit<2> = 0;
+                        if (a > 2) {
+                          b = a + 1;
+                          cnt = cnt + 1;
+                        }
+                        else {
+                          b = 2;
+                        }
+                        cnt = cnt + b;
+                      }";
+        assert_eq!(check(input), vec![]);
+    }
+
+    #[test]
+    fn test_check_if_bit_truncated() {
+        // the if bit of branch removal is 1 bit wide, so a condition that isn't a comparison loses its upper bits
+        let input = r"snippet fun() {
+                        input a : bit<2>;
+                        output b : bit<2>;
+                        if (a) {
+                          b = 1;
+                        }
+                      }";
+        let divergences = check(input);
+        assert_eq!(divergences.len(), 1);
+        assert_eq!(divergences[0].stage, DotStage::BranchRemoval);
+        assert_eq!(divergences[0].packet["a"], 2);
+        assert_eq!(divergences[0].expected.fields["b"], 1);
+        assert_eq!(divergences[0].actual.as_ref().unwrap().fields["b"], 0);
+    }
+
+    #[test]
+    fn test_check_net_progs() {
+        let mut paths : Vec<_> = fs::read_dir("net-progs").unwrap().map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().map(|e| e == "np").unwrap_or(false)).collect();
+        paths.sort();
+        for path in paths {
+            let mut contents = String::new();
+            File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+            let file_name = path.file_name().unwrap().to_str().unwrap();
+            match check_program(&contents, &CompileOptions::new()) {
+                Ok(divergences) => assert_eq!(divergences, vec![], "{}", file_name),
+                Err(errors) => panic!("{}: {}", file_name, errors[0].message),
+            }
+        }
+    }
+}
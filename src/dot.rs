@@ -0,0 +1,175 @@
+// Graphviz DOT export of the snippet dags at a chosen compilation stage.
+// Each snippet is drawn as a cluster coloured by the devices it runs on,
+// and each dependency edge is styled by its kind.
+use grammar::*;
+use std::collections::HashMap;
+use trans_snippet::*;
+use dependency::*;
+
+const DEVICE_COLORS : [&str; 6] = ["lightblue", "palegreen", "lightsalmon", "khaki", "plum", "lightgray"];
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone, Copy)]
+pub enum DotStage {
+    BranchRemoval,
+    SSA,
+    ConstantFolding,
+    CSE,
+    DeadCode,
+    RAW,
+}
+
+impl DotStage {
+    pub fn from_name(stage : &str) -> DotStage {
+        match stage {
+            "branch" => DotStage::BranchRemoval,
+            "ssa"    => DotStage::SSA,
+            "fold"   => DotStage::ConstantFolding,
+            "cse"    => DotStage::CSE,
+            "dce"    => DotStage::DeadCode,
+            // raw is the dag after the deps pass
+            "raw" | "deps" => DotStage::RAW,
+            _ => panic!("Unknown DOT stage {}. Expected one of branch, ssa, fold, cse, dce, raw.", stage)
+        }
+    }
+
+    // Pass after which the dags are taken, see passes.rs
+    pub fn get_pass(&self) -> &'static str {
+        match *self {
+            DotStage::BranchRemoval   => "branch",
+            DotStage::SSA             => "ssa",
+            DotStage::ConstantFolding => "fold",
+            DotStage::CSE             => "cse",
+            DotStage::DeadCode        => "dce",
+            DotStage::RAW             => "deps",
+        }
+    }
+
+    pub fn get_string(&self) -> &'static str {
+        match *self {
+            DotStage::BranchRemoval   => "post branch removal",
+            DotStage::SSA             => "post SSA",
+            DotStage::ConstantFolding => "post constant folding",
+            DotStage::CSE             => "post common subexpression elimination",
+            DotStage::DeadCode        => "post dead code elimination",
+            DotStage::RAW             => "post dependency analysis",
+        }
+    }
+}
+
+
+fn get_expr_label<'a> (my_expr : &Expr<'a>) -> String {
+    let mut label = my_expr.op1.get_string();
+    match my_expr.expr_right {
+        ExprRight::BinOp(bin_op_type, ref operand) => {
+            label += &handle_binop(bin_op_type);
+            label += &operand.get_string();
+        }
+        ExprRight::Cond(ref operand1, ref operand2) => {
+            label += &format!(" ? {} : {}", operand1.get_string(), operand2.get_string());
+        }
+        ExprRight::Empty() => {}
+    }
+    label
+}
+
+
+pub fn get_node_label<'a> (dagnode : &DagNode<'a>) -> String {
+    let label = match dagnode.node_type {
+        DagNodeType::Decl(ref var_decl) => {
+            match var_decl.var_type.var_info {
+                VarInfo::BitArray(bit_width, 1) => format!("{:?} {} : bit<{}>", var_decl.var_type.type_qualifier,
+                                                           var_decl.identifier.id_name, bit_width),
+                VarInfo::BitArray(bit_width, var_size) => format!("{:?} {} : bit<{}>[{}]", var_decl.var_type.type_qualifier,
+                                                                  var_decl.identifier.id_name, bit_width, var_size),
+                VarInfo::Packet(ref packet) => format!("{:?} {} : {}", var_decl.var_type.type_qualifier,
+                                                       var_decl.identifier.id_name, packet.id_name),
+            }
+        }
+        DagNodeType::Stmt(ref my_statement) => format!("{} = {}", my_statement.lvalue.get_string(), get_expr_label(&my_statement.expr)),
+        DagNodeType::Cond(ref my_expr) => format!("if {}", get_expr_label(my_expr)),
+    };
+    label.replace('"', "\\\"")
+}
+
+
+// Render the dags in the given order. Snippets on the same devices share a colour.
+pub fn dags_to_dot<'a> (dags : &Vec<Dag<'a>>, stage : DotStage) -> String {
+    let mut device_colors : HashMap<String, &str> = HashMap::new();
+    let mut contents = String::new();
+    contents += "digraph dependency_dag {\n";
+    contents += &format!("    label=\"{}\";\n", stage.get_string());
+    contents += "    node [shape=box, style=filled, fillcolor=white];\n";
+
+    for my_dag in dags {
+        let devices : Vec<&str> = my_dag.device_vector.iter().map(|d| d.id_name).collect();
+        let devices = devices.join(", ");
+        let color_count = device_colors.len();
+        let color = *device_colors.entry(devices.clone()).or_insert(DEVICE_COLORS[color_count % DEVICE_COLORS.len()]);
+
+        contents += &format!("    subgraph \"cluster_{}\" {{\n", my_dag.snippet_id);
+        contents += &format!("        label=\"{} @ {} : {}\";\n", my_dag.snippet_id, my_dag.device_type, devices);
+        contents += &format!("        style=filled;\n        fillcolor={};\n", color);
+        for (i, dagnode) in my_dag.dag_vector.iter().enumerate() {
+            let shape = match dagnode.node_type {
+                DagNodeType::Decl(_) => "ellipse",
+                _ => "box"
+            };
+            contents += &format!("        \"{}_{}\" [label=\"{}\", shape={}];\n", my_dag.snippet_id, i, get_node_label(dagnode), shape);
+        }
+        for (from, to, dep_kind) in get_dependencies(my_dag) {
+            let style = match dep_kind {
+                DepKind::RAW => "solid",
+                DepKind::WAR => "dashed",
+                DepKind::WAW => "dotted",
+            };
+            contents += &format!("        \"{}_{}\" -> \"{}_{}\" [label=\"{:?}\", style={}];\n",
+                                 my_dag.snippet_id, from, my_dag.snippet_id, to, dep_kind, style);
+        }
+        contents += "    }\n";
+    }
+    contents += "}\n";
+    contents
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::lexer::get_tokens;
+    use super::super::parser::*;
+    use super::super::compile::{INCLUDE_DIR, read_include_file};
+    use super::super::passes::default_passes;
+
+    #[test]
+    fn test_dags_to_dot() {
+        let input = r"@ bmv2 : s1;
+                      snippet fun() {
+                        input c : bit<32>;
+                        transient a : bit<32>;
+                        output b : bit<32>;
+                        a = c + 1;
+                        b = a + 2;
+                      }";
+        let tokens = &mut get_tokens(input);
+        let token_iter = &mut tokens.iter().peekable();
+        let parse_tree = parse_prog(token_iter);
+        let pkt_contents = read_include_file(&vec![INCLUDE_DIR.to_string()], "packet.np");
+        let pkt_tree = parse_import_packets(&mut get_tokens(&pkt_contents).iter().peekable());
+        let packet_map = create_packet_map(&parse_tree.packets, &pkt_tree);
+        let dag_map = create_dag_nodes(&parse_tree.snippets, &parse_tree.globals, &packet_map, &parse_tree.packets, &parse_tree.packets, &Vec::new(), &default_passes(), &mut |_, _| {});
+        let dot = dags_to_dot(&vec![dag_map["fun"].clone()], DotStage::RAW);
+        assert!(dot.starts_with("digraph dependency_dag {\n    label=\"post dependency analysis\";\n"));
+        assert!(dot.contains("subgraph \"cluster_fun\" {\n        label=\"fun @ bmv2 : s1\";\n        style=filled;\n        fillcolor=lightblue;\n"));
+        assert!(dot.contains("[label=\"Transient a : bit<32>\", shape=ellipse];"));
+        assert!(dot.contains("[label=\"b = a + 2\", shape=box];"));
+        assert!(dot.contains("[label=\"RAW\", style=solid];"));
+    }
+
+    #[test]
+    #[should_panic(expected="Unknown DOT stage codegen. Expected one of branch, ssa, fold, cse, dce, raw.")]
+    fn test_dot_stage_fail() {
+        DotStage::from_name("codegen");
+    }
+}
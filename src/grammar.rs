@@ -5,7 +5,9 @@ pub struct Prog<'a> {
   pub imports : Imports<'a>,
   pub globals : Globals<'a>,
   pub packets : Packets<'a>,
+  pub topology : Topology<'a>,
   pub snippets : Snippets<'a>,
+  pub pipelines : Pipelines<'a>,
   pub connections : Connections<'a>
 }
 
@@ -67,8 +69,12 @@ pub struct Packet<'a> {
 #[derive(PartialEq)]
 #[derive(Clone)]
 pub struct Import<'a> {
+  // device, or file for import "path";
   pub import_type     : Identifier<'a>,
+  // device name, or path of the imported file
   pub import_id       : Identifier<'a>,
+  // prefix of the names a file import defines, from import "path" as alias;
+  pub alias           : Option<Identifier<'a>>,
 }
 
 #[derive(Debug)]
@@ -96,6 +102,35 @@ pub enum PacketParserCondition<'a> {
 }
 
 
+// Hosts, switches and links of the network the snippets are placed on
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Topology<'a> {
+  pub host_vector   : Vec<Identifier<'a>>,
+  pub switch_vector : Vec<Switch<'a>>,
+  pub link_vector   : Vec<Link<'a>>,
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Switch<'a> {
+  pub switch_id   : Identifier<'a>,
+  pub device_type : Identifier<'a>,
+  // Maximum number of snippet statements the switch can hold, if bounded.
+  // Placement checks the target budget separately, see placement.rs
+  pub capacity    : Option<u64>,
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Link<'a> {
+  pub from_node : Identifier<'a>,
+  pub to_node   : Identifier<'a>,
+}
+
 #[derive(Debug)]
 #[derive(PartialEq)]
 #[derive(Clone)]
@@ -110,9 +145,19 @@ pub struct Snippet<'a> {
   pub snippet_id       : Identifier<'a>,
   pub device_annotation  : DeviceAnnotation<'a>,
   pub variable_decls   : VariableDecls<'a>,
+  pub invariants       : Vec<Invariant<'a>>,
   pub ifblocks         : IfBlocks<'a>,
 }
 
+// invariant name : expr; declared by a snippet, checked after every packet
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Invariant<'a> {
+  pub identifier : Identifier<'a>,
+  pub condition  : Condition<'a>,
+}
+
 #[derive(Debug)]
 #[derive(PartialEq)]
 #[derive(Clone)]
@@ -121,6 +166,22 @@ pub struct DeviceAnnotation<'a> {
   pub device_vector : Vec<Identifier<'a>>,
 }
 
+// Explicit execution order of the snippets that share a device
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Pipelines<'a> {
+  pub pipeline_vector : Vec<Pipeline<'a>>
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Pipeline<'a> {
+  pub device_id      : Identifier<'a>,
+  pub snippet_vector : Vec<Identifier<'a>>,
+}
+
 #[derive(Debug)]
 #[derive(PartialEq)]
 #[derive(Clone)]
@@ -241,7 +302,17 @@ pub enum VarInfo<'a> {
 #[derive(PartialEq)]
 #[derive(Clone)]
 pub struct Statements<'a> {
-  pub stmt_vector : Vec<Statement<'a>>
+  pub stmt_vector   : Vec<Statement<'a>>,
+  pub assert_vector : Vec<Assertion<'a>>
+}
+
+// assert(expr); in a block, checked once the first position statements of the block ran
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Assertion<'a> {
+  pub position  : usize,
+  pub condition : Condition<'a>,
 }
 
 #[derive(Debug)]
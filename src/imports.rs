@@ -0,0 +1,411 @@
+// File imports. import "lib/sketch.np"; adds the packets, globals and snippets
+// of another sluice file to the program, before the program's own. With
+// import "lib/sketch.np" as sketch; every name the file defines is prefixed
+// with sketch_, the same way namespace_dag prefixes the variables of a snippet.
+// A file is searched next to the file importing it, then in the include
+// directories. It is added once per prefix, and import cycles are rejected.
+use grammar::*;
+use lexer;
+use parser;
+use trans_snippet::namespace_statement;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+// Files on the current import chain with their prefixes, and the (file, full prefix)
+// pairs already added
+struct ImportState {
+    stack    : Vec<PathBuf>,
+    prefixes : Vec<String>,
+    loaded   : HashSet<(PathBuf, String)>,
+}
+
+// Sources of the imported files and the prefixed names of what they define. The
+// compilation owns it and the parse tree borrows from it, so an import leaves
+// nothing behind once the compilation ends.
+pub struct ImportArena {
+    sources : Vec<(PathBuf, String)>,
+    names   : Vec<String>,
+}
+
+impl Default for ImportArena {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ImportArena {
+    pub fn new() -> ImportArena {
+        ImportArena { sources : Vec::new(), names : Vec::new() }
+    }
+}
+
+pub fn find_import_file(filename : &str, source_dir : Option<&Path>, include_dirs : &Vec<String>) -> PathBuf {
+    let mut candidates = Vec::new();
+    if let Some(dir) = source_dir { candidates.push(dir.join(filename)) }
+    for include_dir in include_dirs {
+        candidates.push(Path::new(include_dir).join(filename));
+    }
+    match candidates.iter().find(|c| c.is_file()) {
+        Some(path) => path.clone(),
+        None => panic!("Imported file {} not found in {:?}.", filename, candidates),
+    }
+}
+
+fn canonical_path(path : &Path) -> PathBuf {
+    fs::canonicalize(path).expect("Something went wrong resolving the imported file")
+}
+
+fn file_imports<'a> (imports : &Imports<'a>) -> Vec<Import<'a>> {
+    imports.import_vector.iter().filter(|i| i.import_type.id_name == "file").cloned().collect()
+}
+
+// Reads the imported files, and the files they import in turn, into sources
+fn load_sources(imports : Vec<String>, source_dir : Option<&Path>, include_dirs : &Vec<String>, sources : &mut Vec<(PathBuf, String)>) {
+    for filename in imports {
+        let canonical = canonical_path(&find_import_file(&filename, source_dir, include_dirs));
+        if sources.iter().any(|(path, _)| *path == canonical) {
+            continue;
+        }
+        let mut f = File::open(&canonical).expect("File not found");
+        let mut contents = String::new();
+        f.read_to_string(&mut contents).expect("Something went wrong reading the file");
+        let nested : Vec<String> = {
+            let tokens = lexer::get_tokens(&contents);
+            let imports = parser::parse_imports(&mut tokens.iter().peekable());
+            file_imports(&imports).iter().map(|i| i.import_id.id_name.to_string()).collect()
+        };
+        sources.push((canonical.clone(), contents));
+        load_sources(nested, canonical.parent(), include_dirs, sources);
+    }
+}
+
+fn parse_source<'a> (path : &Path, contents : &'a str) -> Prog<'a> {
+    let tokens = lexer::get_tokens(contents);
+    let token_iter = &mut tokens.iter().peekable();
+    let prog = parser::parse_prog(token_iter);
+    assert!(token_iter.peek().is_none(), "Token iterator is not empty in {}.", path.display());
+    prog
+}
+
+fn defined_ids<'a> (prog : &Prog<'a>) -> Vec<&'a str> {
+    let mut ids : Vec<&'a str> = prog.packets.packet_vector.iter().map(|p| p.packet_id.id_name).collect();
+    ids.extend(prog.globals.global_vector.iter().map(|g| g.identifier.id_name));
+    ids.extend(prog.snippets.snippet_vector.iter().map(|s| s.snippet_id.id_name));
+    ids
+}
+
+// Names a program defines once its imports are expanded. The ones an import
+// prefixes are added to names.
+fn defined_names<'a> (prog : &Prog<'a>, source_dir : Option<&Path>, include_dirs : &Vec<String>, progs : &HashMap<PathBuf, Prog<'a>>,
+                      stack : &mut Vec<PathBuf>, names : &mut Vec<String>) -> Vec<String> {
+    let mut defined = Vec::new();
+    for my_import in file_imports(&prog.imports) {
+        let canonical = canonical_path(&find_import_file(my_import.import_id.id_name, source_dir, include_dirs));
+        // cycles are reported by expand_imports
+        if stack.contains(&canonical) {
+            continue;
+        }
+        stack.push(canonical.clone());
+        let mut library = defined_names(&progs[&canonical], canonical.parent(), include_dirs, progs, stack, names);
+        stack.pop();
+        if let Some(ref alias) = my_import.alias {
+            library = library.iter().map(|name| format!("{}_{}", alias.id_name, name)).collect();
+            names.extend(library.iter().cloned());
+        }
+        defined.extend(library);
+    }
+    defined.extend(defined_ids(prog).iter().map(|id| id.to_string()));
+    defined
+}
+
+fn rename_id<'a> (id : &Identifier<'a>, names : &HashMap<String, &'a str>) -> Identifier<'a> {
+    match names.get(id.id_name) {
+        Some(new_name) => Identifier { id_name : new_name },
+        None => *id,
+    }
+}
+
+fn rename_var_type<'a> (var_type : &VarType<'a>, packet_names : &HashMap<String, &'a str>) -> VarType<'a> {
+    let mut new_type = var_type.clone();
+    match var_type.var_info {
+        VarInfo::Packet(ref packet_type) => new_type.var_info = VarInfo::Packet(rename_id(packet_type, packet_names)),
+        VarInfo::BitArray(_, _) => {}
+    }
+    new_type
+}
+
+fn rename_expr<'a> (expr : &Expr<'a>, names : &HashMap<String, &'a str>) -> Expr<'a> {
+    let statement = Statement { lvalue : LValue::Scalar(Identifier { id_name : "" }), expr : expr.clone() };
+    namespace_statement(&statement, names).expr
+}
+
+// Prefixes the packets, globals and snippets defined by an imported file, and
+// every reference to them inside the file. The prefixed names come from names.
+pub fn prefix_prog<'a> (prog : &Prog<'a>, prefix : &str, names : &'a [String]) -> Prog<'a> {
+    let prefixed = |ids : Vec<&str>| -> HashMap<String, &'a str> {
+        ids.iter().map(|id| {
+            let new_name = format!("{}_{}", prefix, id);
+            match names.iter().find(|name| **name == new_name) {
+                Some(name) => (id.to_string(), name.as_str()),
+                None => panic!("Prefixed name {} was not loaded with the imports.", new_name),
+            }
+        }).collect()
+    };
+    let packet_names = prefixed(prog.packets.packet_vector.iter().map(|p| p.packet_id.id_name).collect());
+    let global_names = prefixed(prog.globals.global_vector.iter().map(|g| g.identifier.id_name).collect());
+    let snippet_names = prefixed(prog.snippets.snippet_vector.iter().map(|s| s.snippet_id.id_name).collect());
+
+    let mut new_prog = prog.clone();
+    for my_packet in &mut new_prog.packets.packet_vector {
+        my_packet.packet_id = rename_id(&my_packet.packet_id, &packet_names);
+    }
+    for my_global in &mut new_prog.globals.global_vector {
+        my_global.identifier = rename_id(&my_global.identifier, &global_names);
+    }
+    for my_snippet in &mut new_prog.snippets.snippet_vector {
+        my_snippet.snippet_id = rename_id(&my_snippet.snippet_id, &snippet_names);
+        // variables of the snippet hide the globals with the same name
+        let mut local_names = global_names.clone();
+        for my_decl in &mut my_snippet.variable_decls.decl_vector {
+            local_names.remove(my_decl.identifier.id_name);
+            my_decl.var_type = rename_var_type(&my_decl.var_type, &packet_names);
+        }
+        for invariant in &mut my_snippet.invariants {
+            invariant.condition.expr = rename_expr(&invariant.condition.expr, &local_names);
+        }
+        for ifblock in &mut my_snippet.ifblocks.ifblock_vector {
+            ifblock.condition.expr = rename_expr(&ifblock.condition.expr, &local_names);
+            for my_statement in &mut ifblock.statements.stmt_vector {
+                *my_statement = namespace_statement(my_statement, &local_names);
+            }
+            for assertion in &mut ifblock.statements.assert_vector {
+                assertion.condition.expr = rename_expr(&assertion.condition.expr, &local_names);
+            }
+        }
+    }
+    for my_pipeline in &mut new_prog.pipelines.pipeline_vector {
+        my_pipeline.snippet_vector = my_pipeline.snippet_vector.iter().map(|s| rename_id(s, &snippet_names)).collect();
+    }
+    for my_connection in &mut new_prog.connections.connection_vector {
+        my_connection.from_snippet = rename_id(&my_connection.from_snippet, &snippet_names);
+        my_connection.to_snippet = rename_id(&my_connection.to_snippet, &snippet_names);
+    }
+    new_prog
+}
+
+// Records where each packet, global and snippet is defined, and fails if a name is defined twice
+fn check_names<'a> (prog : &Prog<'a>, origin : &str, origins : &mut HashMap<String, String>) {
+    let mut names = Vec::new();
+    names.extend(prog.packets.packet_vector.iter().map(|p| ("Packet", p.packet_id.id_name)));
+    names.extend(prog.globals.global_vector.iter().map(|g| ("Global", g.identifier.id_name)));
+    names.extend(prog.snippets.snippet_vector.iter().map(|s| ("Snippet", s.snippet_id.id_name)));
+    for (kind, name) in names {
+        let key = format!("{} {}", kind, name);
+        if let Some(other) = origins.get(&key) { panic!("{} is defined in both {} and {}.", key, other, origin) }
+        origins.insert(key, origin.to_string());
+    }
+}
+
+fn append_prog<'a> (merged : &mut Prog<'a>, prog : Prog<'a>) {
+    for my_import in prog.imports.import_vector {
+        if !merged.imports.import_vector.contains(&my_import) {
+            merged.imports.import_vector.push(my_import);
+        }
+    }
+    merged.globals.global_vector.extend(prog.globals.global_vector);
+    merged.packets.packet_vector.extend(prog.packets.packet_vector);
+    merged.snippets.snippet_vector.extend(prog.snippets.snippet_vector);
+    merged.pipelines.pipeline_vector.extend(prog.pipelines.pipeline_vector);
+    merged.connections.connection_vector.extend(prog.connections.connection_vector);
+}
+
+fn expand_imports<'a> (prog : Prog<'a>, source_name : &str, source_dir : Option<&Path>, include_dirs : &Vec<String>,
+                       progs : &HashMap<PathBuf, Prog<'a>>, names : &'a Vec<String>, state : &mut ImportState) -> Prog<'a> {
+    let (file_imports, device_imports) : (Vec<Import<'a>>, Vec<Import<'a>>) =
+        prog.imports.import_vector.iter().cloned().partition(|i| i.import_type.id_name == "file");
+    let mut merged = Prog { imports : Imports { import_vector : device_imports },
+                            globals : Globals { global_vector : Vec::new() },
+                            packets : Packets { packet_vector : Vec::new() },
+                            topology : prog.topology.clone(),
+                            snippets : Snippets { snippet_vector : Vec::new() },
+                            pipelines : Pipelines { pipeline_vector : Vec::new() },
+                            connections : Connections { connection_vector : Vec::new() } };
+    let mut origins = HashMap::new();
+
+    for my_import in file_imports {
+        let path = find_import_file(my_import.import_id.id_name, source_dir, include_dirs);
+        let canonical = canonical_path(&path);
+        if state.stack.contains(&canonical) {
+            let mut chain : Vec<String> = state.stack.iter().skip_while(|p| **p != canonical)
+                .map(|p| p.display().to_string()).collect();
+            chain.push(canonical.display().to_string());
+            panic!("Import cycle: {}.", chain.join(" -> "));
+        }
+        let prefix = match my_import.alias {
+            Some(ref alias) => alias.id_name.to_string(),
+            None => String::new(),
+        };
+        let full_prefix : Vec<&str> = state.prefixes.iter().chain(Some(&prefix)).filter(|p| !p.is_empty()).map(|p| p.as_str()).collect();
+        if !state.loaded.insert((canonical.clone(), full_prefix.join("_"))) {
+            continue;
+        }
+        verbose!("Importing {}", path.display());
+
+        state.stack.push(canonical.clone());
+        state.prefixes.push(prefix.clone());
+        let library = expand_imports(progs[&canonical].clone(), my_import.import_id.id_name, canonical.parent(), include_dirs,
+                                     progs, names, state);
+        state.stack.pop();
+        state.prefixes.pop();
+        if !library.topology.host_vector.is_empty() || !library.topology.switch_vector.is_empty() {
+            panic!("Imported file {} declares a topology. Only the main program can.", my_import.import_id.id_name);
+        }
+        let library = if prefix.is_empty() { library } else { prefix_prog(&library, &prefix, names) };
+        check_names(&library, my_import.import_id.id_name, &mut origins);
+        append_prog(&mut merged, library);
+    }
+
+    check_names(&prog, source_name, &mut origins);
+    let mut own = prog;
+    own.imports.import_vector.clear();
+    append_prog(&mut merged, own);
+    merged
+}
+
+// Expands the file imports of a program. source_path is the file the program was read from, if any.
+// The imported files are kept in arena, which the returned program borrows.
+pub fn resolve_imports<'a> (prog : Prog<'a>, source_path : Option<&str>, include_dirs : &Vec<String>,
+                            arena : &'a mut ImportArena) -> Prog<'a> {
+    let mut state = ImportState { stack : Vec::new(), prefixes : Vec::new(), loaded : HashSet::new() };
+    let source_dir = source_path.and_then(|p| Path::new(p).parent());
+    if let Some(canonical) = source_path.and_then(|p| fs::canonicalize(p).ok()) { state.stack.push(canonical) }
+    let imports = file_imports(&prog.imports).iter().map(|i| i.import_id.id_name.to_string()).collect();
+    load_sources(imports, source_dir, include_dirs, &mut arena.sources);
+
+    let ImportArena { ref sources, ref mut names } = *arena;
+    let progs : HashMap<PathBuf, Prog<'a>> = sources.iter().map(|(path, contents)| (path.clone(), parse_source(path, contents))).collect();
+    defined_names(&prog, source_dir, include_dirs, &progs, &mut state.stack.clone(), names);
+    let names : &'a Vec<String> = names;
+    expand_imports(prog, source_path.unwrap_or("the program"), source_dir, include_dirs, &progs, names, &mut state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    // Writes the files of a test into a fresh directory
+    fn write_files(test_name : &str, files : Vec<(&str, &str)>) -> PathBuf {
+        let dir = env::temp_dir().join(format!("sluice_imports_{}", test_name));
+        let _ = fs::remove_dir_all(&dir);
+        for (name, contents) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+        }
+        return dir;
+    }
+
+    // Resolves the imports of main.np and hands the program to check
+    fn resolve<F : FnOnce(&Prog)> (dir : &Path, check : F) {
+        let main_file = dir.join("main.np").display().to_string();
+        let contents = fs::read_to_string(&main_file).unwrap();
+        let mut arena = ImportArena::new();
+        let prog = resolve_imports(parse_source(Path::new(&main_file), &contents), Some(&main_file), &Vec::new(), &mut arena);
+        check(&prog);
+    }
+
+    #[test]
+    fn test_import_file() {
+        let dir = write_files("file", vec![
+            ("main.np", r#"import "lib/sketch.np";
+                           @ bmv2 : s1;
+                           snippet fun() {
+                             transient a : bit<32>;
+                             a = threshold;
+                           }
+                           (count, fun)"#),
+            ("lib/sketch.np", r#"import "common.np";
+                                 @ bmv2 : s1;
+                                 snippet count() {
+                                   transient b : bit<32>;
+                                   b = threshold + 1;
+                                 }"#),
+            ("lib/common.np", "global threshold : bit<32> = 10;")]);
+        resolve(&dir, |prog| {
+            assert!(prog.imports.import_vector.is_empty());
+            assert_eq!(prog.globals.global_vector[0].identifier.id_name, "threshold");
+            let snippet_names : Vec<&str> = prog.snippets.snippet_vector.iter().map(|s| s.snippet_id.id_name).collect();
+            assert_eq!(snippet_names, vec!["count", "fun"]);
+            assert_eq!(prog.connections.connection_vector.len(), 1);
+        });
+    }
+
+    #[test]
+    fn test_import_file_as() {
+        let dir = write_files("as", vec![
+            ("main.np", r#"import "sketch.np" as sketch;
+                           @ bmv2 : s1;
+                           snippet count() {
+                             transient threshold : bit<32>;
+                             threshold = sketch_threshold;
+                           }
+                           (sketch_count, count)"#),
+            ("sketch.np", r#"global threshold : bit<32> = 10;
+                             packet flow : ipv4 (protocol : 17) { key : bit<32>; }
+                             @ bmv2 : s1;
+                             snippet count() {
+                               input p : flow;
+                               transient b : bit<32>;
+                               b = threshold + p.key;
+                             }"#)]);
+        resolve(&dir, |prog| {
+            assert_eq!(prog.globals.global_vector[0].identifier.id_name, "sketch_threshold");
+            assert_eq!(prog.packets.packet_vector[0].packet_id.id_name, "sketch_flow");
+            let count = &prog.snippets.snippet_vector[0];
+            assert_eq!(count.snippet_id.id_name, "sketch_count");
+            assert_eq!(count.variable_decls.decl_vector[0].var_type.var_info, VarInfo::Packet(Identifier { id_name : "sketch_flow" }));
+            let statement = &count.ifblocks.ifblock_vector[0].statements.stmt_vector[0];
+            assert_eq!(statement.expr.op1.get_string(), "sketch_threshold");
+            assert_eq!(prog.snippets.snippet_vector[1].snippet_id.id_name, "count");
+        });
+    }
+
+    #[test]
+    fn test_import_nested_as() {
+        let dir = write_files("nested_as", vec![
+            ("main.np", r#"import "a.np" as a;
+                           import "b.np" as b;"#),
+            ("a.np", r#"import "b.np" as b;
+                        global threshold : bit<32> = 1;"#),
+            ("b.np", "global threshold : bit<32> = 2;")]);
+        resolve(&dir, |prog| {
+            let global_names : Vec<&str> = prog.globals.global_vector.iter().map(|g| g.identifier.id_name).collect();
+            assert_eq!(global_names, vec!["a_b_threshold", "a_threshold", "b_threshold"]);
+        });
+    }
+
+    #[test]
+    #[should_panic(expected="Import cycle")]
+    fn test_import_cycle_fail() {
+        let dir = write_files("cycle", vec![
+            ("main.np", r#"import "a.np";"#),
+            ("a.np", r#"import "b.np";"#),
+            ("b.np", r#"import "a.np";"#)]);
+        resolve(&dir, |_| {});
+    }
+
+    #[test]
+    #[should_panic(expected="Snippet count is defined in both lib.np and")]
+    fn test_import_name_clash_fail() {
+        let dir = write_files("clash", vec![
+            ("main.np", r#"import "lib.np";
+                           snippet count() {}"#),
+            ("lib.np", "snippet count() {}")]);
+        resolve(&dir, |_| {});
+    }
+}
@@ -0,0 +1,476 @@
+// Reference interpreter for snippets. It executes a snippet over the parse tree,
+// one packet at a time, without going through the compiler: persistent variables
+// keep their values across packets, transient and output variables start at 0
+// for every packet. Every value is an unsigned bit vector, truncated to the width
+// of the variable or field it is written to. Failed assertions and invariants
+// don't stop the snippet, they are reported with its output.
+use grammar::*;
+use pretty_printer::expr_string;
+use lexer;
+use parser;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+
+// Values keyed by name: p.f for the fields of packet (or device) p, the
+// variable name for scalar inputs and outputs
+pub type FieldMap = BTreeMap<String, u64>;
+// Contents of the persistent variables; scalars are registers of size 1
+pub type RegisterMap = BTreeMap<String, Vec<u64>>;
+
+// Width used for fields whose declaration isn't known, e.g. device metadata
+pub const DEFAULT_WIDTH : u64 = 64;
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct SnippetOutput {
+    // packet fields, after the snippet ran, and output variables
+    pub fields    : FieldMap,
+    // register state after the packet
+    pub registers : RegisterMap,
+    // assertions and invariants that failed on the packet
+    pub violations : Vec<String>,
+}
+
+pub fn truncate(value : u64, width : u64) -> u64 {
+    if width >= 64 {
+        return value;
+    }
+    value & ((1u64 << width) - 1)
+}
+
+// Result of a binary operation on two values. Comparisons and boolean operators give 0 or 1.
+pub fn eval_bin_op(bin_op_type : BinOpType, value1 : u64, value2 : u64) -> u64 {
+    match bin_op_type {
+        BinOpType::BooleanAnd  => (value1 != 0 && value2 != 0) as u64,
+        BinOpType::BooleanOr   => (value1 != 0 || value2 != 0) as u64,
+        BinOpType::Plus        => value1.wrapping_add(value2),
+        BinOpType::Minus       => value1.wrapping_sub(value2),
+        BinOpType::Mul         => value1.wrapping_mul(value2),
+        BinOpType::Div         => { if value2 == 0 { panic!("Division by zero."); } value1 / value2 },
+        BinOpType::Modulo      => { if value2 == 0 { panic!("Modulo by zero."); } value1 % value2 },
+        BinOpType::ShiftLeft   => if value2 >= 64 { 0 } else { value1 << value2 },
+        BinOpType::ShiftRight  => if value2 >= 64 { 0 } else { value1 >> value2 },
+        BinOpType::Equal       => (value1 == value2) as u64,
+        BinOpType::NotEqual    => (value1 != value2) as u64,
+        BinOpType::LTEQOp      => (value1 <= value2) as u64,
+        BinOpType::GTEQOp      => (value1 >= value2) as u64,
+        BinOpType::LessThan    => (value1 < value2) as u64,
+        BinOpType::GreaterThan => (value1 > value2) as u64,
+    }
+}
+
+pub struct Interpreter<'a> {
+    snippet   : Snippet<'a>,
+    decls     : HashMap<&'a str, VarType<'a>>,
+    // widths of the packet fields, keyed p.f
+    widths    : HashMap<String, u64>,
+    // globals and const variables
+    constants : HashMap<&'a str, Vec<u64>>,
+    registers : RegisterMap,
+    // user packets, see add_headers
+    packets   : Vec<&'a str>,
+    // variables and fields assigned by the last packet
+    written   : BTreeSet<String>,
+}
+
+// Variables of the packet being processed
+struct PacketState<'a> {
+    scalars : HashMap<&'a str, u64>,
+    fields  : FieldMap,
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(my_snippet : &Snippet<'a>, my_globals : &Globals<'a>, my_packets : &Packets<'a>) -> Interpreter<'a> {
+        let mut packet_widths = HashMap::new();
+        for my_packet in &my_packets.packet_vector {
+            let fields : Vec<(&str, u64)> = my_packet.packet_fields.field_vector.iter().map(|f| match f.var_type.var_info {
+                VarInfo::BitArray(bit_width, _) => (f.identifier.id_name, bit_width),
+                VarInfo::Packet(_) => (f.identifier.id_name, DEFAULT_WIDTH),
+            }).collect();
+            packet_widths.insert(my_packet.packet_id.id_name, fields);
+        }
+        let mut widths = HashMap::new();
+        // fields can be accessed through the packet name, or a variable of the packet type
+        for (packet_id, fields) in &packet_widths {
+            for &(field, bit_width) in fields {
+                widths.insert(format!("{}.{}", packet_id, field), bit_width);
+            }
+        }
+
+        let mut constants = HashMap::new();
+        for my_global in &my_globals.global_vector {
+            constants.insert(my_global.identifier.id_name, my_global.initial_values.iter().map(|v| v.value).collect());
+        }
+
+        let mut decls = HashMap::new();
+        let mut registers = RegisterMap::new();
+        for my_decl in &my_snippet.variable_decls.decl_vector {
+            let name = my_decl.identifier.id_name;
+            decls.insert(name, my_decl.var_type.clone());
+            constants.remove(name);
+            match (&my_decl.var_type.var_info, my_decl.var_type.type_qualifier) {
+                (VarInfo::Packet(packet_type), _) => {
+                    for &(field, bit_width) in packet_widths.get(packet_type.id_name).unwrap_or(&Vec::new()) {
+                        widths.insert(format!("{}.{}", name, field), bit_width);
+                    }
+                }
+                (&VarInfo::BitArray(bit_width, var_size), TypeQualifier::Persistent) => {
+                    let mut values : Vec<u64> = my_decl.initial_values.iter().map(|v| truncate(v.value, bit_width)).collect();
+                    values.resize(var_size as usize, 0);
+                    registers.insert(name.to_string(), values);
+                }
+                (&VarInfo::BitArray(_, _), TypeQualifier::Const) => {
+                    constants.insert(name, my_decl.initial_values.iter().map(|v| v.value).collect());
+                }
+                _ => {}
+            }
+        }
+        let packets = my_packets.packet_vector.iter().map(|p| p.packet_id.id_name).collect();
+        Interpreter { snippet : my_snippet.clone(), decls, widths, constants, registers, packets, written : BTreeSet::new() }
+    }
+
+    // Adds the widths of the standard headers of packet.np. Their fields are accessed
+    // as header.field, or p.headerfield through a user packet p, like insert_packet_decls names them.
+    pub fn add_headers(&mut self, pkt_tree : &Packets<'a>) {
+        for my_pkt in &pkt_tree.packet_vector {
+            for my_pkt_field in &my_pkt.packet_fields.field_vector {
+                let bit_width = match my_pkt_field.var_type.var_info {
+                    VarInfo::BitArray(bit_width, _) => bit_width,
+                    VarInfo::Packet(_) => DEFAULT_WIDTH,
+                };
+                let (header, field) = (my_pkt.packet_id.id_name, my_pkt_field.identifier.id_name);
+                self.widths.insert(format!("{}.{}", header, field), bit_width);
+                for packet_id in &self.packets {
+                    self.widths.insert(format!("{}.{}{}", packet_id, header, field), bit_width);
+                }
+            }
+        }
+    }
+
+    pub fn registers(&self) -> &RegisterMap {
+        &self.registers
+    }
+
+    // Variables and fields, keyed p.f, assigned while processing the last packet
+    pub fn written(&self) -> &BTreeSet<String> {
+        &self.written
+    }
+
+    pub fn set_register(&mut self, name : &str, index : usize, value : u64) {
+        let width = self.width(name);
+        match self.registers.get_mut(name) {
+            Some(ref mut values) if index < values.len() => values[index] = truncate(value, width),
+            _ => panic!("{}[{}] is not a register of snippet {}.", name, index, self.snippet.snippet_id.id_name),
+        }
+    }
+
+    fn width(&self, name : &str) -> u64 {
+        match self.decls.get(name) {
+            Some(&VarType { var_info : VarInfo::BitArray(bit_width, _), .. }) => bit_width,
+            _ => DEFAULT_WIDTH,
+        }
+    }
+
+    // Width of a packet field, keyed p.f
+    pub fn field_width(&self, key : &str) -> u64 {
+        *self.widths.get(key).unwrap_or(&DEFAULT_WIDTH)
+    }
+
+    // Runs the snippet on one packet. Fields of the packet are keyed p.f, inputs by their name.
+    pub fn process(&mut self, packet : &FieldMap) -> SnippetOutput {
+        let mut state = PacketState { scalars : HashMap::new(), fields : FieldMap::new() };
+        self.written.clear();
+        for (name, value) in packet {
+            if name.contains('.') {
+                let width = self.field_width(name);
+                state.fields.insert(name.clone(), truncate(*value, width));
+                continue;
+            }
+            match self.decls.get(name.as_str()) {
+                Some(&VarType { type_qualifier : TypeQualifier::Input, .. }) => {
+                    let (&name, _) = self.decls.get_key_value(name.as_str()).unwrap();
+                    let width = self.width(name);
+                    state.scalars.insert(name, truncate(*value, width));
+                }
+                _ => panic!("{} is not an input of snippet {}.", name, self.snippet.snippet_id.id_name),
+            }
+        }
+        for my_decl in &self.snippet.variable_decls.decl_vector {
+            match my_decl.var_type.type_qualifier {
+                TypeQualifier::Transient | TypeQualifier::Output => { state.scalars.insert(my_decl.identifier.id_name, 0); }
+                TypeQualifier::Input => match my_decl.var_type.var_info {
+                    VarInfo::BitArray(_, _) if !state.scalars.contains_key(my_decl.identifier.id_name) =>
+                        panic!("Packet has no value for input {}.", my_decl.identifier.id_name),
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+
+        // an else block runs if the condition of the if block before it was false
+        let mut last_condition = false;
+        let mut violations = Vec::new();
+        let snippet = self.snippet.clone();
+        for ifblock in &snippet.ifblocks.ifblock_vector {
+            let run = match ifblock.condtype {
+                1 => { last_condition = self.eval_expr(&ifblock.condition.expr, &state) != 0; last_condition }
+                2 => !last_condition,
+                _ => true,
+            };
+            if run {
+                for (position, my_statement) in ifblock.statements.stmt_vector.iter().enumerate() {
+                    self.check_assertions(&ifblock.statements, position, &state, &mut violations);
+                    let value = self.eval_expr(&my_statement.expr, &state);
+                    self.assign(&my_statement.lvalue, value, &mut state);
+                }
+                self.check_assertions(&ifblock.statements, ifblock.statements.stmt_vector.len(), &state, &mut violations);
+            }
+        }
+        for invariant in &snippet.invariants {
+            if self.eval_expr(&invariant.condition.expr, &state) == 0 {
+                violations.push(format!("Invariant {} of snippet {} does not hold.", invariant.identifier.id_name, snippet.snippet_id.id_name));
+            }
+        }
+
+        let mut fields = state.fields;
+        for my_decl in &self.snippet.variable_decls.decl_vector {
+            if my_decl.var_type.type_qualifier == TypeQualifier::Output {
+                fields.insert(my_decl.identifier.id_name.to_string(), state.scalars[my_decl.identifier.id_name]);
+            }
+        }
+        SnippetOutput { fields, registers : self.registers.clone(), violations }
+    }
+
+    // Checks the assertions placed after the first position statements of a block
+    fn check_assertions(&self, statements : &Statements<'a>, position : usize, state : &PacketState<'a>, violations : &mut Vec<String>) {
+        for assertion in statements.assert_vector.iter().filter(|a| a.position == position) {
+            if self.eval_expr(&assertion.condition.expr, state) == 0 {
+                violations.push(format!("Assertion {} failed in snippet {}.", expr_string(&assertion.condition.expr),
+                                        self.snippet.snippet_id.id_name));
+            }
+        }
+    }
+
+    fn index(&self, name : &str, address : &Operand<'a>, state : &PacketState<'a>, size : usize) -> usize {
+        let index = self.eval_operand(address, state);
+        if index >= size as u64 {
+            panic!("Index {} is out of bounds for {} of size {}.", index, name, size);
+        }
+        index as usize
+    }
+
+    fn eval_operand(&self, operand : &Operand<'a>, state : &PacketState<'a>) -> u64 {
+        let lvalue = match operand {
+            Operand::Value(value) => return value.value,
+            Operand::LValue(lvalue) => lvalue,
+        };
+        match lvalue {
+            LValue::Scalar(id) => {
+                if let Some(value) = state.scalars.get(id.id_name) { return *value }
+                match self.registers.get(id.id_name).or(self.constants.get(id.id_name)) {
+                    Some(values) if !values.is_empty() => values[0],
+                    _ => panic!("Variable {} has no value in snippet {}.", id.id_name, self.snippet.snippet_id.id_name),
+                }
+            }
+            LValue::Array(id, address) => {
+                match self.registers.get(id.id_name).or(self.constants.get(id.id_name)) {
+                    Some(values) => values[self.index(id.id_name, address, state, values.len())],
+                    None => panic!("{} is not an array in snippet {}.", id.id_name, self.snippet.snippet_id.id_name),
+                }
+            }
+            LValue::Field(id, field) => {
+                let key = format!("{}.{}", id.id_name, field.id_name);
+                match state.fields.get(&key) {
+                    Some(value) => *value,
+                    None => panic!("Packet has no value for field {}.", key),
+                }
+            }
+        }
+    }
+
+    fn eval_expr(&self, expr : &Expr<'a>, state : &PacketState<'a>) -> u64 {
+        let value1 = self.eval_operand(&expr.op1, state);
+        match expr.expr_right {
+            ExprRight::BinOp(bin_op_type, ref operand) => eval_bin_op(bin_op_type, value1, self.eval_operand(operand, state)),
+            ExprRight::Cond(ref operand_true, ref operand_false) => {
+                if value1 != 0 { self.eval_operand(operand_true, state) } else { self.eval_operand(operand_false, state) }
+            }
+            ExprRight::Empty() => value1,
+        }
+    }
+
+    fn assign(&mut self, lvalue : &LValue<'a>, value : u64, state : &mut PacketState<'a>) {
+        match lvalue {
+            LValue::Field(id, field) => self.written.insert(format!("{}.{}", id.id_name, field.id_name)),
+            &LValue::Scalar(ref id) | &LValue::Array(ref id, _) => self.written.insert(id.id_name.to_string()),
+        };
+        match lvalue {
+            LValue::Scalar(id) => {
+                let value = truncate(value, self.width(id.id_name));
+                if state.scalars.contains_key(id.id_name) {
+                    state.scalars.insert(id.id_name, value);
+                } else if self.registers.contains_key(id.id_name) {
+                    self.registers.get_mut(id.id_name).unwrap()[0] = value;
+                } else if self.constants.contains_key(id.id_name) {
+                    panic!("Cannot assign to constant {}.", id.id_name);
+                } else {
+                    panic!("Variable {} is not declared in snippet {}.", id.id_name, self.snippet.snippet_id.id_name);
+                }
+            }
+            LValue::Array(id, address) => {
+                let size = match self.registers.get(id.id_name) {
+                    Some(values) => values.len(),
+                    None => panic!("{} is not a persistent array in snippet {}.", id.id_name, self.snippet.snippet_id.id_name),
+                };
+                let index = self.index(id.id_name, address, state, size);
+                let value = truncate(value, self.width(id.id_name));
+                self.registers.get_mut(id.id_name).unwrap()[index] = value;
+            }
+            LValue::Field(id, field) => {
+                let key = format!("{}.{}", id.id_name, field.id_name);
+                let value = truncate(value, self.field_width(&key));
+                state.fields.insert(key, value);
+            }
+        }
+    }
+}
+
+// Runs snippet snippet_name of a program on each packet in turn
+pub fn run_snippet(source : &str, snippet_name : &str, packets : &[FieldMap]) -> Vec<SnippetOutput> {
+    let tokens = &mut lexer::get_tokens(source);
+    let token_iter = &mut tokens.iter().peekable();
+    let prog = parser::parse_prog(token_iter);
+    assert!(token_iter.peek().is_none(), "Token iterator is not empty.");
+    let my_snippet = match prog.snippets.snippet_vector.iter().find(|s| s.snippet_id.id_name == snippet_name) {
+        Some(my_snippet) => my_snippet,
+        None => panic!("Snippet {} not found.", snippet_name),
+    };
+    let mut interpreter = Interpreter::new(my_snippet, &prog.globals, &prog.packets);
+    packets.iter().map(|packet| interpreter.process(packet)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(fields : Vec<(&str, u64)>) -> FieldMap {
+        return fields.iter().map(|&(name, value)| (name.to_string(), value)).collect();
+    }
+
+    #[test]
+    fn test_interpreter_state() {
+        let input = r"snippet fun() {
+                        input a : bit<8>;
+                        output b : bit<8>;
+                        transient t : bit<8>;
+                        persistent cnt : bit<4> = 14;
+                        t = t + a;
+                        cnt = cnt + 1;
+                        b = t + cnt;
+                      }";
+        let outputs = run_snippet(input, "fun", &vec![packet(vec![("a", 3)]), packet(vec![("a", 300)])]);
+        // transient t starts at 0 for each packet, cnt wraps around at 4 bits, a is truncated to 8 bits
+        assert_eq!(outputs[0].fields, packet(vec![("b", 18)]));
+        assert_eq!(outputs[0].registers["cnt"], vec![15]);
+        assert_eq!(outputs[1].fields, packet(vec![("b", 44)]));
+        assert_eq!(outputs[1].registers["cnt"], vec![0]);
+    }
+
+    #[test]
+    fn test_interpreter_if_else() {
+        let input = r"snippet fun() {
+                        input a : bit<8>;
+                        output b : bit<8>;
+                        transient big : bit<1>;
+                        persistent arr : bit<8>[4] = {1, 2, 3, 4,};
+                        if (a < 4) {
+                          b = arr[a];
+                          arr[a] = b * 10;
+                        }
+                        else {
+                          big = a > 10;
+                          b = big ? 1 : 2;
+                        }
+                      }";
+        let outputs = run_snippet(input, "fun", &vec![packet(vec![("a", 2)]), packet(vec![("a", 11)]), packet(vec![("a", 5)])]);
+        assert_eq!(outputs[0].fields["b"], 3);
+        assert_eq!(outputs[0].registers["arr"], vec![1, 2, 30, 4]);
+        assert_eq!(outputs[1].fields["b"], 1);
+        assert_eq!(outputs[2].fields["b"], 2);
+    }
+
+    #[test]
+    fn test_interpreter_packet_fields() {
+        let input = r"global DPTP_RESPONSE : bit<8> = 3;
+                      packet dptp_pkt : ethernet (etherType : 1234) {
+                        command : bit<8>;
+                        dptp_now : bit<48>;
+                      }
+                      snippet dptp_switch() {
+                        persistent dptp_ref : bit<48>;
+                        transient elapsed : bit<48>;
+                        if (dptp_pkt.command == 2) {
+                          elapsed = psa.timestamp_tx - psa.timestamp_rx;
+                          dptp_pkt.dptp_now = dptp_ref + elapsed;
+                          dptp_pkt.command = DPTP_RESPONSE;
+                        }
+                        if (dptp_pkt.command == 3) {
+                          dptp_ref = dptp_pkt.dptp_now + 1;
+                        }
+                      }";
+        let request = packet(vec![("dptp_pkt.command", 2), ("dptp_pkt.dptp_now", 0),
+                                  ("psa.timestamp_rx", 5), ("psa.timestamp_tx", 12)]);
+        let outputs = run_snippet(input, "dptp_switch", &vec![request.clone(), request]);
+        assert_eq!(outputs[0].fields["dptp_pkt.command"], 3);
+        assert_eq!(outputs[0].fields["dptp_pkt.dptp_now"], 7);
+        assert_eq!(outputs[0].registers["dptp_ref"], vec![8]);
+        assert_eq!(outputs[1].fields["dptp_pkt.dptp_now"], 15);
+        assert_eq!(outputs[1].registers["dptp_ref"], vec![16]);
+    }
+
+    #[test]
+    #[should_panic(expected="Packet has no value for field p.ttl.")]
+    fn test_interpreter_missing_field_fail() {
+        let input = r"snippet fun() {
+                        transient t : bit<8>;
+                        t = p.ttl;
+                      }";
+        run_snippet(input, "fun", &vec![packet(vec![])]);
+    }
+
+    #[test]
+    #[should_panic(expected="Index 4 is out of bounds for arr of size 4.")]
+    fn test_interpreter_index_fail() {
+        let input = r"snippet fun() {
+                        input a : bit<8>;
+                        persistent arr : bit<8>[4] = {1, 2, 3, 4,};
+                        arr[a] = 1;
+                      }";
+        run_snippet(input, "fun", &vec![packet(vec![("a", 4)])]);
+    }
+
+    #[test]
+    fn test_interpreter_assertions() {
+        let input = r"snippet fun() {
+                        input a : bit<8>;
+                        persistent cnt : bit<8> = 0;
+                        invariant bounded : cnt < 2;
+                        assert(a != 0);
+                        cnt = cnt + 1;
+                        if (a > 5) {
+                          assert(a < 10);
+                        }
+                      }";
+        let outputs = run_snippet(input, "fun", &vec![packet(vec![("a", 7)]), packet(vec![("a", 0)]), packet(vec![("a", 12)])]);
+        assert_eq!(outputs[0].violations, Vec::<String>::new());
+        // the assertion inside the if block is only checked when the block runs
+        assert_eq!(outputs[1].violations, vec![String::from("Assertion a != 0 failed in snippet fun."),
+                                               String::from("Invariant bounded of snippet fun does not hold.")]);
+        // a failed check doesn't stop the snippet
+        assert_eq!(outputs[2].violations, vec![String::from("Assertion a < 10 failed in snippet fun."),
+                                               String::from("Invariant bounded of snippet fun does not hold.")]);
+        assert_eq!(outputs[2].registers["cnt"], vec![3]);
+    }
+}
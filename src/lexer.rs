@@ -3,8 +3,9 @@ use self::regex::Regex;
 
 lazy_static! {
 
-  static ref TOKENS      : Regex = Regex::new(r"[0-9]+|[A-Za-z_][A-Za-z0-9_]*|->|==|!=|>=|<=|>>|<<|>|<|\+|-|/|\*|%|\{|\}|\(|\)|\[|\]|=|;|,|\?|:|\.|\S+").unwrap();
-  static ref KEYWORDS    : Regex = Regex::new(r"^(snippet|and|or|not|persistent|transient|input|output|packet|snippet|const|bit|import|device|global|if|else|@)$").unwrap();
+  static ref TOKENS      : Regex = Regex::new(r#"//[^
+]*|[0-9]+|[A-Za-z_][A-Za-z0-9_]*|->|--|==|!=|>=|<=|>>|<<|>|<|\+|-|/|\*|%|\{|\}|\(|\)|\[|\]|=|;|,|\?|:|\.|"[^"]*"|\S+"#).unwrap();
+  static ref KEYWORDS    : Regex = Regex::new(r"^(snippet|and|or|not|persistent|transient|input|output|packet|snippet|const|bit|import|device|global|if|else|pipeline|topology|assert|invariant|@)$").unwrap();
   static ref IDENTIFIERS : Regex = Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*$").unwrap();
   static ref VALUES      : Regex = Regex::new(r"^([0-9]+)$").unwrap();
 }
@@ -32,18 +33,25 @@ fn get_single_token(tok_str : &str) -> Token {
      "if"     => Token::If,
      "else"   => Token::Else,
      "@"      => Token::Annotation,
+     "pipeline" => Token::Pipeline,
+     "topology" => Token::Topology,
+     "assert" => Token::Assert,
+     "invariant" => Token::Invariant,
      _        => panic!("Unrecognized token string found when looking for keyword: {}", tok_str)
     }
   } else if IDENTIFIERS.is_match(tok_str) {
     return Token::Identifier(tok_str);
   } else if VALUES.is_match(tok_str) {
     return Token::Value(tok_str.parse::<u64>().unwrap());
+  } else if tok_str.len() >= 2 && tok_str.starts_with("\"") && tok_str.ends_with("\"") {
+    return Token::Str(&tok_str[1..tok_str.len() - 1]);
   } else {
     return match tok_str {
       ":" => Token::Colon,
       ";" => Token::SemiColon,
       "," => Token::Comma,
       "->"=> Token::Arrow,
+      "--"=> Token::DoubleDash,
 
       "(" => Token::ParenLeft,
       ")" => Token::ParenRight,
@@ -80,6 +88,10 @@ pub fn get_tokens(input_program : &str) -> Vec<Token> {
   let mut token_array = Vec::new();
   for cap in TOKENS.captures_iter(input_program) {
     let ref tok_str = cap.get(0).unwrap().as_str();
+    // Line comments run from // to the end of the line and produce no token.
+    if tok_str.starts_with("//") {
+      continue;
+    }
     token_array.push(get_single_token(tok_str));
   }
   return token_array;
@@ -114,4 +126,14 @@ mod tests {
                           ";
     println!("{:?}", get_tokens(input_program));
   }
+
+  #[test]
+  fn test_lexer_comments() {
+    let input_program = r"// leading comment
+                          global x : bit<32> = 1; // trailing comment
+                          y = x / 2;";
+    let without_comments = r"global x : bit<32> = 1;
+                             y = x / 2;";
+    assert_eq!(get_tokens(input_program), get_tokens(without_comments));
+  }
 }
@@ -1,6 +1,10 @@
 #[macro_use]
 extern crate lazy_static;
+#[macro_use]
+extern crate serde_json;
 
+#[macro_use]
+pub mod verbosity;
 mod token;
 pub mod lexer;
 pub mod grammar;
@@ -10,5 +14,27 @@ pub mod def_use;
 pub mod code_gen;
 pub mod pretty_printer;
 pub mod trans_snippet;
+pub mod constant_folding;
+pub mod cse;
+pub mod dead_code;
+pub mod passes;
+pub mod imports;
+pub mod assertions;
+pub mod compile;
+pub mod interpreter;
+pub mod pcap;
+pub mod replay;
+pub mod simulator;
+pub mod differential;
+pub mod verify;
+pub mod p4_ir;
+pub mod p4_eval;
 pub mod bmv2_gen;
 pub mod tofino_gen;
+pub mod placement;
+pub mod resources;
+pub mod dot;
+pub mod dependency;
+pub mod schedule;
+pub mod packing;
+pub mod stateful;
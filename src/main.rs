@@ -1,53 +1,355 @@
 extern crate sluice;
-#[macro_use]
 extern crate serde_json;
 use sluice::lexer;
-use sluice::parser;
-use sluice::def_use::DefUse;
+use sluice::imports::{ImportArena, resolve_imports};
+use sluice::compile::*;
+use sluice::dot::DotStage;
+use sluice::passes::DumpFormat;
+use sluice::assertions::AssertionMode;
+use sluice::pretty_printer::PrettyPrinter;
 use sluice::tree_fold::TreeFold;
-use sluice::trans_snippet::*;
+use sluice::verbosity;
+use sluice::pcap;
+use sluice::replay::replay;
+use sluice::simulator::{simulate, parse_scenario};
+use sluice::verify::*;
 
 use std::env;
+use std::fs;
 use std::fs::File;
 use std::io::prelude::*;
+use std::panic;
+use std::path::Path;
 
 use std::process;
-const INCLUDE_DIR : &str = "net-progs/include/";
+const DOT_FILE : &str = "plots/dependency_dag.dot";
+
+const USAGE : &str = "Usage: sluice <command> [options] <program.np>
+
+Commands:
+  build    compile the program and write the P4 programs, commands and topology
+  check    compile the program without writing anything
+  fmt      print the program in canonical form
+  graph    write the dependency dag of the program as DOT
+  replay   run the packets of a pcap file through a snippet with the interpreter
+  simulate run the snippets on their devices with the traffic of a scenario and check its expectations
+  verify   check the assertions, invariants and outputs of the snippets on every input up to a width bound
+
+Options:
+  -o, --out-dir <dir>     root of the generated files (default .)
+  -I, --include <dir>     search dir for packet.np, budgets.json and imports, before net-progs/include
+  -t, --target <device>   only compile the snippets for this device type, e.g. bmv2
+  --emit <kind>           print tokens, ast, dag, p4 or commands instead of writing files
+  --stage <stage>         dag printed by graph: branch, ssa, fold, cse, dce or raw (default raw)
+  --passes <list>         comma-separated dag passes, from branch,ssa to deps (default branch,ssa,fold,cse,dce,deps)
+  --dump-after <pass>     print the dags after this pass instead of writing files
+  --dump-format <format>  format of --dump-after: text, dot or json (default text)
+  --assertions <mode>     drop or mirror the packets failing an assertion in the bmv2 programs
+  --pcap <file>           packets replayed by replay
+  --snippet <name>        snippet replay runs, needed if the program has several
+  --scenario <file>       JSON traffic, latencies and expectations simulated by simulate
+  --width-bound <bits>    variables verify enumerates completely, wider ones are sampled (default 4)
+  -v, --verbose           print intermediate results, -vv also traces code generation
+  -q, --quiet             only print errors";
+
+#[derive(PartialEq)]
+enum Emit {
+  Tokens,
+  Ast,
+  Dag,
+  P4,
+  Commands,
+}
+
+struct Args {
+  command     : String,
+  filename    : String,
+  out_dir     : String,
+  emit        : Option<Emit>,
+  pcap        : Option<String>,
+  snippet     : Option<String>,
+  scenario    : Option<String>,
+  width_bound : u64,
+  options     : CompileOptions,
+}
+
+fn usage_error(message : &str) -> ! {
+  eprintln!("error: {}\n\n{}", message, USAGE);
+  process::exit(2);
+}
+
+// Parses an option value with the from_name of its type, which panics on unknown values
+fn from_name_or_usage<T>(value : &str, from_name : fn(&str) -> T) -> T {
+  match catch_errors(|| from_name(value)) {
+    Ok(parsed) => parsed,
+    Err(errors) => usage_error(&errors[0].message),
+  }
+}
+
+fn parse_args(args : &Vec<String>) -> Args {
+  let mut options = CompileOptions::new();
+  let mut include_dirs = Vec::new();
+  let mut command = None;
+  let mut filename = None;
+  let mut out_dir = String::from(".");
+  let mut emit = None;
+  let mut pcap = None;
+  let mut snippet = None;
+  let mut scenario = None;
+  let mut width_bound = DEFAULT_WIDTH_BOUND;
+  let mut stage = DotStage::RAW;
+
+  let mut arg_iter = args.iter().skip(1);
+  while let Some(arg) = arg_iter.next() {
+    let mut value = |name : &str| -> String {
+      match arg_iter.next() {
+        Some(value) => value.clone(),
+        None => usage_error(&format!("{} needs a value", name)),
+      }
+    };
+    match arg.as_str() {
+      "-h" | "--help" => { println!("{}", USAGE); process::exit(0); }
+      "-o" | "--out-dir" => out_dir = value(arg),
+      "-I" | "--include" => include_dirs.push(value(arg)),
+      "-t" | "--target" => options.target = Some(value(arg)),
+      "--emit" => emit = Some(match value(arg).as_str() {
+        "tokens"   => Emit::Tokens,
+        "ast"      => Emit::Ast,
+        "dag"      => Emit::Dag,
+        "p4"       => Emit::P4,
+        "commands" => Emit::Commands,
+        other      => usage_error(&format!("unknown --emit kind {}, expected tokens, ast, dag, p4 or commands", other)),
+      }),
+      "--stage" => stage = from_name_or_usage(&value(arg), DotStage::from_name),
+      "--passes" => options.passes = value(arg).split(',').map(|pass| pass.trim().to_string()).collect(),
+      "--dump-after" => options.dump_after = Some(value(arg)),
+      "--dump-format" => options.dump_format = from_name_or_usage(&value(arg), DumpFormat::from_name),
+      "--assertions" => options.assertions = Some(match value(arg).as_str() {
+        "drop"   => AssertionMode::Drop,
+        "mirror" => AssertionMode::Mirror,
+        other    => usage_error(&format!("unknown assertion mode {}, expected drop or mirror", other)),
+      }),
+      "--pcap" => pcap = Some(value(arg)),
+      "--snippet" => snippet = Some(value(arg)),
+      "--scenario" => scenario = Some(value(arg)),
+      "--width-bound" => width_bound = match value(arg).parse() {
+        Ok(bits) if bits <= MAX_WIDTH_BOUND => bits,
+        _ => usage_error(&format!("--width-bound needs a number of bits up to {}", MAX_WIDTH_BOUND)),
+      },
+      "-v" | "--verbose" => options.verbosity += 1,
+      "-vv" => options.verbosity += 2,
+      "-q" | "--quiet" => options.verbosity = verbosity::QUIET,
+      _ if arg.starts_with("-") => usage_error(&format!("unknown option {}", arg)),
+      _ if command.is_none() => command = Some(arg.clone()),
+      _ if filename.is_none() => filename = Some(arg.clone()),
+      _ => usage_error(&format!("unexpected argument {}", arg)),
+    }
+  }
+
+  let command = match command {
+    Some(command) => command,
+    None => usage_error("missing command"),
+  };
+  match command.as_str() {
+    "build" | "check" | "fmt" | "graph" | "replay" | "simulate" | "verify" => {}
+    _ => usage_error(&format!("unknown command {}", command)),
+  }
+  let filename = match filename {
+    Some(filename) => filename,
+    None => usage_error("missing input program"),
+  };
+  include_dirs.extend(options.include_dirs.drain(..));
+  options.include_dirs = include_dirs;
+  if command == "replay" && pcap.is_none() {
+    usage_error("replay needs a pcap file, pass it with --pcap");
+  }
+  if command == "simulate" && scenario.is_none() {
+    usage_error("simulate needs a scenario, pass it with --scenario");
+  }
+  options.source_path = Some(filename.clone());
+  if command == "graph" {
+    options.dot_stage = Some(stage);
+  }
+  return Args { command, filename, out_dir, emit, pcap, snippet, scenario, width_bound, options };
+}
+
+fn write_file(out_dir : &str, filename : &str, contents : &[u8]) {
+  let path = Path::new(out_dir).join(filename);
+  match path.parent() {
+    Some(dir) => fs::create_dir_all(dir).expect("Something went wrong creating the output directory"),
+    None => {}
+  }
+  let mut file = match File::create(&path) {
+    Err(why) => panic!("couldn't create {}: {}", path.display(), why),
+    Ok(file) => file,
+  };
+  file.write_all(contents).expect("Something went wrong writing the file");
+  if verbosity::enabled(verbosity::NORMAL) {
+    println!("Wrote {}", path.display());
+  }
+}
+
+fn exit_on_error<T>(result : Result<T, Vec<CompileError>>) -> T {
+  match result {
+    Ok(output) => output,
+    Err(errors) => {
+      for error in errors {
+        eprintln!("error: {}", error.message);
+      }
+      process::exit(1);
+    }
+  }
+}
+
+// Prints one artifact per program, with a header when there are several
+fn print_programs(programs : &Vec<(String, String)>) {
+  for &(ref name, ref contents) in programs {
+    if programs.len() > 1 {
+      println!("==> {} <==", name);
+    }
+    print!("{}", contents);
+  }
+}
 
 // Main compiler binary
 // Takes an input sluice program and produces a P4 program for each network device
 fn main() {
-  let args: Vec<String> = env::args().collect();
-  let filename = &args[1];
-  println!("In file {}", filename);
-  let mut f = File::open(filename).expect("File not found");
+  let args = parse_args(&env::args().collect());
+  verbosity::set_level(args.options.verbosity);
+  // the errors caught by catch_errors are reported by exit_on_error, only print them again with -vv
+  let default_hook = panic::take_hook();
+  panic::set_hook(Box::new(move |info| {
+    if !catching() || verbosity::enabled(verbosity::DEBUG) {
+      default_hook(info);
+    }
+  }));
+  let mut f = File::open(&args.filename).expect("File not found");
   let mut contents = String::new();
   f.read_to_string(&mut contents).expect("Something went wrong reading the file");
 
-  let tokens = & mut lexer::get_tokens(&contents);
-  // parsing
-  let token_iter = & mut tokens.iter().peekable();
-  let parse_tree = parser::parse_prog(token_iter);
-  assert!(token_iter.peek().is_none(), "Token iterator is not empty.");
-  let mut def_use = DefUse::new();
-  // need to fix def_use. Conditional statements in if/else that modify the same lvalue cause error
-  // example error from first.np:  'Redefining variable l that is already defined in fun.'
+  if args.emit == Some(Emit::Tokens) {
+    let tokens = exit_on_error(catch_errors(|| lexer::get_tokens(&contents)));
+    for token in tokens {
+      println!("{:?}", token);
+    }
+    return;
+  }
+  if args.emit == Some(Emit::Ast) || args.command == "fmt" {
+    let result = catch_errors(|| {
+      let parse_tree = parse_program(&contents);
+      let mut import_arena = ImportArena::new();
+      let resolved = resolve_imports(parse_tree.clone(), Some(&args.filename), &args.options.include_dirs, &mut import_arena);
+      // fmt checks the imports but prints the program as written
+      if args.command == "fmt" {
+        let mut pretty_printer = PrettyPrinter::new();
+        pretty_printer.visit_prog(&parse_tree);
+        return pretty_printer.get_str().to_string();
+      }
+      return format!("{:#?}\n", resolved);
+    });
+    print!("{}", exit_on_error(result));
+    return;
+  }
 
-  // def_use.visit_prog(&parse_tree);
-  println!("Parse tree: {:?}\n", parse_tree);
+  if args.command == "replay" {
+    let pcap_file = args.pcap.as_ref().unwrap();
+    let mut pcap_bytes = Vec::new();
+    File::open(pcap_file).expect("File not found").read_to_end(&mut pcap_bytes).expect("Something went wrong reading the file");
+    let result = replay(&contents, args.snippet.as_ref().map(|s| s.as_str()), &pcap::read_pcap(&pcap_bytes), &args.options);
+    let output = exit_on_error(result);
+    let mut pcap_out = Vec::new();
+    pcap::write_pcap(&output.pcap, &mut pcap_out);
+    write_file(&args.out_dir, &format!("out/{}.pcap", output.snippet_name), &pcap_out);
+    let trace : String = output.trace.iter().map(|line| line.to_string() + "\n").collect();
+    write_file(&args.out_dir, &format!("out/{}.trace.jsonl", output.snippet_name), trace.as_bytes());
+    return;
+  }
+  if args.command == "simulate" {
+    let mut scenario_json = String::new();
+    File::open(args.scenario.as_ref().unwrap()).expect("File not found").read_to_string(&mut scenario_json)
+      .expect("Something went wrong reading the file");
+    let result = serde_json::from_str(&scenario_json).map_err(|e| vec![CompileError { message : format!("Invalid scenario: {}", e) }])
+      .and_then(|json| catch_errors(|| parse_scenario(&json)))
+      .and_then(|scenario| simulate(&contents, &scenario, &args.options));
+    let report = exit_on_error(result);
+    write_file(&args.out_dir, "out/simulation.json", report.to_json().to_string().as_bytes());
+    for failure in &report.failures {
+      eprintln!("warning: {} on {} failed at time {}: {}", failure.snippet, failure.device, failure.time, failure.message);
+    }
+    for violation in &report.violations {
+      eprintln!("error: {}", violation);
+    }
+    if !report.violations.is_empty() {
+      process::exit(1);
+    }
+    return;
+  }
+  if args.command == "verify" {
+    let result = verify_program(&contents, args.width_bound, &args.options);
+    let mut failed = false;
+    for verification in exit_on_error(result) {
+      match verification.counterexample {
+        Some(counterexample) => {
+          failed = true;
+          eprintln!("error: {}", counterexample.message);
+          eprintln!("  packet: {}", serde_json::to_string(&counterexample.packet).unwrap());
+          eprintln!("  registers: {}", serde_json::to_string(&counterexample.registers).unwrap());
+        }
+        None if verification.complete => println!("{}: proved on all {} cases", verification.snippet_name, verification.cases),
+        None => println!("{}: no counterexample in {} cases, wider values were sampled", verification.snippet_name, verification.cases),
+      }
+    }
+    if failed {
+      process::exit(1);
+    }
+    return;
+  }
+  let result = compile(&contents, &args.options);
+  let output = exit_on_error(result);
 
-  let packet_file = format!("{}packet.np", INCLUDE_DIR);
-  let mut f = File::open(packet_file).expect("File not found");
-  let mut contents = String::new();
-  f.read_to_string( &mut contents).expect("Something went wrong reading the file");
-  let tokens = & mut lexer::get_tokens(&contents);
-  let token_iter = & mut tokens.iter().peekable();
-  let pkt_tree = parser::parse_import_packets(token_iter);
-  println!("Packet tree: {:?}\n", pkt_tree);
-  // process::exit(1);
+  match args.emit {
+    Some(Emit::Dag) => { print!("{}", output.dags); return; }
+    Some(Emit::P4) => {
+      print_programs(&output.programs.iter().map(|p| (p.program_name.clone(), p.p4_code.clone())).collect());
+      return;
+    }
+    Some(Emit::Commands) => {
+      print_programs(&output.programs.iter().filter(|p| p.commands.is_some())
+                     .map(|p| (p.program_name.clone(), p.commands.clone().unwrap())).collect());
+      return;
+    }
+    _ => {}
+  }
+  match output.dump {
+    Some(ref dump) => { print!("{}", dump); return; }
+    None => {}
+  }
 
-  trans_snippets(&parse_tree.imports,&parse_tree.globals, &parse_tree.packets, &parse_tree.snippets, &pkt_tree);//, &mut my_dag);
-  // Check that identifiers are defined before use
+  if verbosity::enabled(verbosity::NORMAL) {
+    for diagnostic in &output.diagnostics {
+      println!("{}", diagnostic);
+    }
+    print!("{}", output.reports);
+  }
+  match args.command.as_str() {
+    "build" => {
+      for program in &output.programs {
+        write_file(&args.out_dir, &format!("out/{}.p4", program.program_name), program.p4_code.as_bytes());
+        match program.commands {
+          Some(ref commands) => write_file(&args.out_dir, &format!("bmv2_sim/commands/{}.txt", program.program_name), commands.as_bytes()),
+          None => {}
+        }
+      }
+      write_file(&args.out_dir, "bmv2_sim/topology.json", output.topology.to_string().as_bytes());
+      write_file(&args.out_dir, "out/resources.json", output.resources.to_string().as_bytes());
+      write_file(&args.out_dir, "out/schedule.json", output.schedule.to_string().as_bytes());
+      write_file(&args.out_dir, "out/metadata.json", output.metadata.to_string().as_bytes());
+    }
+    "graph" => write_file(&args.out_dir, DOT_FILE, output.dot.as_ref().unwrap().as_bytes()),
+    _ => {
+      if verbosity::enabled(verbosity::NORMAL) {
+        println!("{}: ok", args.filename);
+      }
+    }
+  }
 }
-
-    // let mut field_decls : HashMap<String, VarType> = HashMap::new();
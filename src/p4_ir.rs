@@ -0,0 +1,260 @@
+// Typed representation of the P4-14 code generated for each dag node. Backends
+// populate it, passes such as scheduling and register lowering rewrite it, and
+// the printer functions below render it as P4 source.
+use std::collections::HashMap;
+
+const TAB : &str = "    ";
+
+// Field of the metadata header, or of any other header type
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct MetaField {
+    pub name  : String,
+    pub width : u64,
+}
+
+impl MetaField {
+    pub fn to_p4(&self) -> String {
+        format!("{} : {};\n", self.name, self.width)
+    }
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct HeaderType {
+    pub name   : String,
+    pub fields : Vec<MetaField>,
+}
+
+impl HeaderType {
+    pub fn to_p4(&self) -> String {
+        let mut contents = format!("header_type {} {{\n{}fields {{\n", self.name, TAB);
+        for field in &self.fields {
+            contents = contents + &format!("{}{}{}", TAB, TAB, field.to_p4());
+        }
+        contents + &format!("{}}}\n}}\n", TAB)
+    }
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Register {
+    pub name           : String,
+    pub width          : u64,
+    pub instance_count : u64,
+}
+
+impl Register {
+    pub fn to_p4(&self) -> String {
+        format!("register {} {{\n{}width : {};\n{}instance_count : {};\n}}\n",
+                self.name, TAB, self.width, TAB, self.instance_count)
+    }
+}
+
+// A primitive action call such as modify_field(mdata.a, 1)
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Primitive {
+    pub name : String,
+    pub args : Vec<String>,
+}
+
+impl Primitive {
+    pub fn new(name : &str, args : Vec<String>) -> Primitive {
+        Primitive { name : name.to_string(), args }
+    }
+
+    pub fn to_p4(&self) -> String {
+        format!("{}({});\n", self.name, self.args.join(", "))
+    }
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Action {
+    pub name       : String,
+    pub primitives : Vec<Primitive>,
+}
+
+impl Action {
+    pub fn to_p4(&self) -> String {
+        let mut contents = format!("action {} () {{\n", self.name);
+        for primitive in &self.primitives {
+            contents = contents + TAB + &primitive.to_p4();
+        }
+        contents + "}\n"
+    }
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Table {
+    pub name           : String,
+    // fields matched by the table, with their match kind
+    pub reads          : Vec<(String, String)>,
+    pub actions        : Vec<String>,
+    pub default_action : Option<String>,
+}
+
+impl Table {
+    // A table without reads that always runs its only action
+    pub fn new(name : &str, action : &str) -> Table {
+        Table { name : name.to_string(), reads : Vec::new(), actions : vec![action.to_string()],
+                default_action : Some(action.to_string()) }
+    }
+
+    // bmv2 sets default actions from the control plane, so they are only
+    // printed for targets that take them in the program
+    pub fn to_p4(&self, print_default : bool) -> String {
+        let mut contents = format!("table {} {{\n", self.name);
+        if !self.reads.is_empty() {
+            contents = contents + &format!("{}reads {{\n", TAB);
+            for (field, match_kind) in &self.reads {
+                contents = contents + &format!("{}{}{} : {};\n", TAB, TAB, field, match_kind);
+            }
+            contents = contents + &format!("{}}}\n", TAB);
+        }
+        contents = contents + &format!("{}actions {{\n", TAB);
+        for action in &self.actions {
+            contents = contents + &format!("{}{}{};\n", TAB, TAB, action);
+        }
+        contents = contents + &format!("{}}}\n", TAB);
+        match self.default_action {
+            Some(ref action) if print_default => contents = contents + &format!("{}default_action : {};\n", TAB, action),
+            _ => {}
+        }
+        contents + "}\n"
+    }
+}
+
+// Target specific extern such as a Tofino stateful ALU
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Blackbox {
+    pub kind       : String,
+    pub name       : String,
+    pub attributes : Vec<(String, String)>,
+}
+
+impl Blackbox {
+    pub fn to_p4(&self) -> String {
+        let mut contents = format!("blackbox {} {} {{\n", self.kind, self.name);
+        for (key, value) in &self.attributes {
+            contents = contents + &format!("{}{} : {};\n", TAB, key, value);
+        }
+        contents + "}\n"
+    }
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Comparison {
+    pub left  : String,
+    pub op    : String,
+    pub right : String,
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub enum ControlStmt {
+    Apply(String),
+    If(Comparison, Vec<ControlStmt>, Vec<ControlStmt>),
+}
+
+impl ControlStmt {
+    // Tables applied by the statement, in order
+    pub fn get_tables(&self) -> Vec<String> {
+        match self {
+            ControlStmt::Apply(table) => vec![table.clone()],
+            ControlStmt::If(_, then_block, else_block) => {
+                then_block.iter().chain(else_block.iter()).flat_map(|s| s.get_tables()).collect()
+            }
+        }
+    }
+
+    // Renames the applied tables that appear in names
+    pub fn rename_tables(&mut self, names : &HashMap<String, String>) {
+        match *self {
+            ControlStmt::Apply(ref mut table) => {
+                if let Some(name) = names.get(table) { *table = name.clone() }
+            }
+            ControlStmt::If(_, ref mut then_block, ref mut else_block) => {
+                for stmt in then_block.iter_mut().chain(else_block.iter_mut()) {
+                    stmt.rename_tables(names);
+                }
+            }
+        }
+    }
+
+    // Renames the fields that appear in names in the conditions
+    pub fn rename_fields(&mut self, names : &HashMap<String, String>) {
+        match *self {
+            ControlStmt::Apply(_) => {}
+            ControlStmt::If(ref mut condition, ref mut then_block, ref mut else_block) => {
+                for operand in [&mut condition.left, &mut condition.right] {
+                    if let Some(name) = names.get(operand) { *operand = name.clone() }
+                }
+                for stmt in then_block.iter_mut().chain(else_block.iter_mut()) {
+                    stmt.rename_fields(names);
+                }
+            }
+        }
+    }
+
+    pub fn to_p4(&self, indent : usize) -> String {
+        let tabs = TAB.repeat(indent);
+        match self {
+            ControlStmt::Apply(table) => format!("{}apply({});\n", tabs, table),
+            ControlStmt::If(condition, then_block, else_block) => {
+                let mut contents = format!("{}if ({} {} {}) {{\n", tabs, condition.left, condition.op, condition.right);
+                contents = contents + &control_to_p4(then_block, indent + 1);
+                if !else_block.is_empty() {
+                    contents = contents + &format!("{}}} else {{\n", tabs);
+                    contents = contents + &control_to_p4(else_block, indent + 1);
+                }
+                contents + &format!("{}}}\n", tabs)
+            }
+        }
+    }
+}
+
+
+pub fn control_to_p4(control : &[ControlStmt], indent : usize) -> String {
+    control.iter().map(|s| s.to_p4(indent)).collect::<Vec<String>>().concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_print_table_and_action() {
+        let action = Action { name : String::from("action1"),
+                              primitives : vec![Primitive::new("modify_field", vec![String::from("mdata.a"), String::from("1")])] };
+        assert_eq!(action.to_p4(), "action action1 () {\n    modify_field(mdata.a, 1);\n}\n");
+        let table = Table { name : String::from("table1"), reads : vec![(String::from("mdata.c"), String::from("exact"))],
+                            actions : vec![String::from("action1"), String::from("action2")], default_action : None };
+        assert_eq!(table.to_p4(true), "table table1 {\n    reads {\n        mdata.c : exact;\n    }\n    actions {\n        action1;\n        action2;\n    }\n}\n");
+        assert_eq!(Table::new("table2", "action1").to_p4(true),
+                   "table table2 {\n    actions {\n        action1;\n    }\n    default_action : action1;\n}\n");
+    }
+
+    #[test]
+    fn test_print_control() {
+        let control = vec![ControlStmt::Apply(String::from("table1")),
+                           ControlStmt::If(Comparison { left : String::from("mdata.a"), op : String::from("=="), right : String::from("1") },
+                                           vec![ControlStmt::Apply(String::from("table2"))], vec![ControlStmt::Apply(String::from("table3"))])];
+        assert_eq!(control_to_p4(&control, 1),
+                   "    apply(table1);\n    if (mdata.a == 1) {\n        apply(table2);\n    } else {\n        apply(table3);\n    }\n");
+        assert_eq!(control[1].get_tables(), vec![String::from("table2"), String::from("table3")]);
+    }
+}
@@ -0,0 +1,294 @@
+// Packing of the transient metadata fields of the bmv2 code of a dag. Every SSA version
+// and temporary of a snippet is its own field of metadata_t, so pack_metadata computes the
+// live range of every field on the scheduled control flow, from its first write to its last
+// read, and lets fields whose ranges don't overlap share a slot. A slot is named after its
+// first field and only holds fields of the same width: a wider slot would keep the bits the
+// arithmetic of a narrower field drops. A field read where it may not be written yet, e.g.
+// after a write in only one branch, reads the initial 0 of the metadata, so it is live from
+// the start. The SlotMap of a snippet maps the slots back to the original names.
+use serde_json;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use grammar::*;
+use trans_snippet::*;
+use p4_ir::*;
+use bmv2_gen::META_HEADER;
+use assertions::ASSERT_FAILED;
+
+const TAB : &str = "    ";
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Slot {
+    pub name      : String,
+    pub width     : u64,
+    // variables held by the slot, in the order their live ranges start
+    pub variables : Vec<String>,
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct SlotMap {
+    pub snippet_id : String,
+    pub slots      : Vec<Slot>,
+}
+
+impl SlotMap {
+    pub fn get_string(&self) -> String {
+        let variable_count : usize = self.slots.iter().map(|s| s.variables.len()).sum();
+        let mut contents = format!("{}: {} metadata fields in {} slots\n", self.snippet_id, variable_count, self.slots.len());
+        for slot in &self.slots {
+            contents += &format!("{}{}.{} : {} holds {}\n", TAB, META_HEADER, slot.name, slot.width, slot.variables.join(", "));
+        }
+        contents
+    }
+
+    pub fn get_json(&self) -> serde_json::Value {
+        let slots : Vec<serde_json::Value> = self.slots.iter().map(|slot| json!({
+            "slot" : slot.name,
+            "width" : slot.width,
+            "variables" : slot.variables
+        })).collect();
+        json!({
+            "snippet" : self.snippet_id,
+            "slots" : slots
+        })
+    }
+}
+
+// Fields a primitive reads, and the field it writes. The destination is the first argument,
+// except for the primitives that don't write a field.
+fn primitive_fields(primitive : &Primitive) -> (&[String], Option<&String>) {
+    match primitive.name.as_str() {
+        "register_write" | "drop" | "clone_ingress_pkt_to_egress" => (&primitive.args[..], None),
+        _ if primitive.args.is_empty() => (&primitive.args[..], None),
+        _ => (&primitive.args[1..], primitive.args.first()),
+    }
+}
+
+// Extends the range of a field to the position, and to the start if it may not be written yet
+fn touch(ranges : &mut HashMap<String, (usize, usize)>, field : &str, position : usize, exposed : bool) {
+    let range = ranges.entry(field.to_string()).or_insert((position, position));
+    if exposed {
+        range.0 = 0;
+    }
+    range.1 = position;
+}
+
+// Walks the control flow, numbering the tables and conditions in order. defined holds the
+// fields written on every path to the current position.
+fn walk_control(control : &Vec<ControlStmt>, tables : &HashMap<&str, &Table>, actions : &HashMap<&str, &Action>,
+                position : &mut usize, defined : &mut HashSet<String>, ranges : &mut HashMap<String, (usize, usize)>) {
+    for stmt in control {
+        match stmt {
+            ControlStmt::Apply(name) => {
+                let table = match tables.get(name.as_str()) {
+                    Some(table) => table,
+                    None => panic!("Table {} is applied but not defined.", name),
+                };
+                for (field, _) in &table.reads {
+                    touch(ranges, field, *position, !defined.contains(field));
+                }
+                // a table runs one of its actions, so only the fields all of them write are defined
+                let mut written : Option<HashSet<String>> = None;
+                for action_name in &table.actions {
+                    let mut action_written = HashSet::new();
+                    for primitive in actions.get(action_name.as_str()).map_or(&[][..], |a| &a.primitives[..]) {
+                        let (reads, write) = primitive_fields(primitive);
+                        for field in reads {
+                            touch(ranges, field, *position, !defined.contains(field) && !action_written.contains(field));
+                        }
+                        if let Some(field) = write {
+                            touch(ranges, field, *position, false);
+                            action_written.insert(field.clone());
+                        }
+                    }
+                    written = Some(match written {
+                        Some(written) => written.intersection(&action_written).cloned().collect(),
+                        None => action_written,
+                    });
+                }
+                defined.extend(written.unwrap_or_default());
+                *position += 1;
+            }
+            ControlStmt::If(condition, then_block, else_block) => {
+                for operand in &[&condition.left, &condition.right] {
+                    touch(ranges, operand, *position, !defined.contains(*operand));
+                }
+                *position += 1;
+                let mut then_defined = defined.clone();
+                walk_control(then_block, tables, actions, position, &mut then_defined, ranges);
+                let mut else_defined = defined.clone();
+                walk_control(else_block, tables, actions, position, &mut else_defined, ranges);
+                *defined = then_defined.intersection(&else_defined).cloned().collect();
+            }
+        }
+    }
+}
+
+// Live range of every field in the control flow of the dag, as the positions of its first
+// write and last access
+pub fn get_live_ranges<'a> (my_dag : &Dag<'a>) -> HashMap<String, (usize, usize)> {
+    let mut tables : HashMap<&str, &Table> = HashMap::new();
+    let mut actions : HashMap<&str, &Action> = HashMap::new();
+    let mut control : Vec<ControlStmt> = Vec::new();
+    for my_dag_node in &my_dag.dag_vector {
+        tables.extend(my_dag_node.p4_code.p4_tables.iter().map(|t| (t.name.as_str(), t)));
+        actions.extend(my_dag_node.p4_code.p4_actions.iter().map(|a| (a.name.as_str(), a)));
+        control.extend(my_dag_node.p4_code.p4_control.iter().cloned());
+    }
+    let mut ranges = HashMap::new();
+    walk_control(&control, &tables, &actions, &mut 0, &mut HashSet::new(), &mut ranges);
+    ranges
+}
+
+// Shares the metadata fields of the transients of a scheduled dag, see above
+pub fn pack_metadata<'a> (my_dag : &mut Dag<'a>) -> SlotMap {
+    // transients without an initial value, which the parser would set
+    let mut candidates : Vec<(&'a str, u64)> = Vec::new();
+    for my_dag_node in &my_dag.dag_vector {
+        if let DagNodeType::Decl(VariableDecl { identifier, ref initial_values,
+                                             var_type : VarType { var_info : VarInfo::BitArray(bit_width, 1),
+                                                                  type_qualifier : TypeQualifier::Transient }}) = my_dag_node.node_type {
+            // the assertion flags are read by the tables bmv2_gen adds for them
+            let name = identifier.id_name;
+            if initial_values.is_empty() && name != ASSERT_FAILED && !name.ends_with(&format!("_{}", ASSERT_FAILED)) {
+                candidates.push((name, bit_width));
+            }
+        }
+    }
+    let ranges = get_live_ranges(my_dag);
+    let field = |name : &str| format!("{}.{}", META_HEADER, name);
+    // fields that no table uses keep their own slot
+    let mut live : Vec<(&'a str, u64, (usize, usize))> = candidates.iter()
+        .filter_map(|&(name, width)| ranges.get(&field(name)).map(|range| (name, width, *range))).collect();
+    live.sort_by_key(|&(_, _, range)| range.0);
+
+    // slots with the end of the last range they hold
+    let mut slots : Vec<(Slot, usize)> = Vec::new();
+    for (name, width, (start, end)) in live {
+        match slots.iter_mut().find(|&&mut (ref slot, slot_end)| slot.width == width && slot_end < start) {
+            Some(&mut (ref mut slot, ref mut slot_end)) => {
+                slot.variables.push(name.to_string());
+                *slot_end = end;
+            }
+            None => slots.push((Slot { name : name.to_string(), width, variables : vec![name.to_string()] }, end)),
+        }
+    }
+    for &(name, width) in &candidates {
+        if !ranges.contains_key(&field(name)) {
+            slots.push((Slot { name : name.to_string(), width, variables : vec![name.to_string()] }, 0));
+        }
+    }
+
+    let mut names : HashMap<String, String> = HashMap::new();
+    for (slot, _) in &slots {
+        for variable in slot.variables.iter().skip(1) {
+            names.insert(field(variable), field(&slot.name));
+        }
+    }
+    for my_dag_node in &mut my_dag.dag_vector {
+        my_dag_node.p4_code.rename_fields(&names);
+        my_dag_node.p4_code.p4_header.meta.retain(|meta_field| !names.contains_key(&field(&meta_field.name)));
+    }
+    SlotMap { snippet_id : my_dag.snippet_id.to_string(), slots : slots.into_iter().map(|(slot, _)| slot).collect() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compile::*;
+    use p4_eval::compare_program;
+
+    fn bmv2_output(input : &str) -> CompileOutput {
+        let mut options = CompileOptions::new();
+        options.target = Some(String::from("bmv2"));
+        return compile(input, &options).unwrap();
+    }
+
+    #[test]
+    fn test_live_ranges_branches() {
+        let apply = |table : &str| ControlStmt::Apply(table.to_string());
+        let write = |table : &str, field : &str| {
+            let mut p4_code = P4Code::new();
+            p4_code.p4_actions.push(Action { name : format!("{}_action", table),
+                                             primitives : vec![Primitive::new("modify_field", vec![field.to_string(), String::from("1")])] });
+            p4_code.p4_tables.push(Table::new(table, &format!("{}_action", table)));
+            return DagNode { node_type : DagNodeType::Cond(Expr { op1 : Operand::Value(Value { value : 1 }), expr_right : ExprRight::Empty() }),
+                             p4_code, next_nodes : Vec::new(), prev_nodes : Vec::new(), pre_condition : None };
+        };
+        // a is written in both branches, b in one of them
+        let mut my_dag = Dag { snippet_id : "fun", device_type : "bmv2", device_vector : Vec::new(),
+                               dag_vector : vec![write("t1", "mdata.a"), write("t2", "mdata.a"), write("t3", "mdata.b"),
+                                                 write("t4", "mdata.c"), write("t5", "mdata.d")],
+                               ssa_origins : HashMap::new() };
+        let condition = Comparison { left : String::from("mdata.c"), op : String::from("=="), right : String::from("1") };
+        my_dag.dag_vector[0].p4_code.p4_control = vec![apply("t4"), ControlStmt::If(condition, vec![apply("t1"), apply("t3")], vec![apply("t2")])];
+        let mut read = P4Code::new();
+        read.p4_actions.push(Action { name : String::from("t6_action"),
+                                      primitives : vec![Primitive::new("add", vec![String::from("mdata.d"), String::from("mdata.a"), String::from("mdata.b")])] });
+        read.p4_tables.push(Table::new("t6", "t6_action"));
+        read.p4_control.push(apply("t6"));
+        my_dag.dag_vector[4].p4_code.append(read);
+
+        let ranges = get_live_ranges(&my_dag);
+        assert_eq!(ranges["mdata.c"], (0, 1));
+        assert_eq!(ranges["mdata.a"], (2, 5));
+        assert_eq!(ranges["mdata.b"], (0, 5));
+        assert_eq!(ranges["mdata.d"], (5, 5));
+    }
+
+    #[test]
+    fn test_pack_metadata() {
+        let input = r"packet p : ethernet(etherType : 1234) {
+                        x : bit<32>;
+                        y : bit<32>;
+                      }
+                      @ bmv2 : s1;
+                      snippet fun() {
+                        transient a : bit<32>;
+                        transient b : bit<32>;
+                        transient c : bit<32>;
+                        transient f : bit<8>;
+                        a = p.x + 1;
+                        b = a + 2;
+                        c = b + 3;
+                        f = p.y - 4;
+                        p.x = c + 5;
+                        p.y = f + 6;
+                      }";
+        let output = bmv2_output(input);
+        let p4_code = &output.programs[0].p4_code;
+        assert!(p4_code.contains("header_type metadata_t {\n    fields {\n        a : 32;\n        b : 32;\n        f : 8;\n    }\n}\n"));
+        assert!(p4_code.contains("add(mdata.a, mdata.b, 3);"));
+        assert!(!p4_code.contains("mdata.c"));
+        assert!(output.reports.contains(&String::from("Metadata slots:\nfun: 4 metadata fields in 3 slots\n    \
+                                                            mdata.a : 32 holds a, c\n    mdata.f : 8 holds f\n    mdata.b : 32 holds b\n")));
+        assert_eq!(output.metadata[0]["slots"][0], json!({ "slot" : "a", "width" : 32, "variables" : ["a", "c"] }));
+        assert_eq!(compare_program(input, &CompileOptions::new()).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_pack_metadata_conditional() {
+        // t keeps its initial 0 unless p.a > 2, so it is live from the start and can't take the slot of s
+        let input = r"packet p : ethernet(etherType : 1234) {
+                        a : bit<8>;
+                        b : bit<8>;
+                      }
+                      @ bmv2 : s1;
+                      snippet fun() {
+                        transient s : bit<8>;
+                        transient t : bit<8>;
+                        persistent reg : bit<8> = 0;
+                        s = p.a + 1;
+                        reg = s + 1;
+                        if (p.a > 2) {
+                          t = p.a + 3;
+                        }
+                        p.b = t + 1;
+                      }";
+        assert_eq!(compare_program(input, &CompileOptions::new()).unwrap(), vec![]);
+    }
+}
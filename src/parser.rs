@@ -8,13 +8,14 @@ use token::Token;
 use std::iter::Peekable;
 
 
-type TokenIterator<'a> = Peekable<std::slice::Iter<'a, Token<'a>>>;
+// Tokens are only borrowed while parsing, the parse tree borrows the source they point into
+type TokenIterator<'t, 'a> = Peekable<std::slice::Iter<'t, Token<'a>>>;
 
 // Helper function to consume next token and match it against a specified token
 // Throw an error if either:
 // 1. token_iter is empty
 // 2. the next token does not match
-fn match_token<'a>(token_iter : & mut TokenIterator<'a>, expected : Token<'a>, error_msg : &'static str) {
+fn match_token<'t, 'a>(token_iter : & mut TokenIterator<'t, 'a>, expected : Token<'a>, error_msg : &'static str) {
   if !token_iter.peek().is_some() {
     panic!("token_iter is empty. Can't consume next token");
   } else {
@@ -27,16 +28,18 @@ fn match_token<'a>(token_iter : & mut TokenIterator<'a>, expected : Token<'a>, e
   }
 }
 
-pub fn parse_prog<'a>(token_iter : &mut TokenIterator<'a>) -> Prog<'a> {
+pub fn parse_prog<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> Prog<'a> {
   let imports     = parse_imports(token_iter);
   let globals     = parse_globals(token_iter);
   let packets     = parse_packets(token_iter);
+  let topology    = parse_topology(token_iter);
   let snippets    = parse_snippets(token_iter);
+  let pipelines   = parse_pipelines(token_iter);
   let connections = parse_connections(token_iter);
-  return Prog { imports, globals, packets, snippets, connections };
+  return Prog { imports, globals, packets, topology, snippets, pipelines, connections };
 }
 
-pub fn parse_device<'a>(token_iter : &mut TokenIterator<'a>) -> Device<'a> {
+pub fn parse_device<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> Device<'a> {
     match_token(token_iter, Token::Device, "Device definition must start with the keyword 'device'");
     let device_id = parse_identifier(token_iter);
     match_token(token_iter, Token::BraceLeft, "Device body must begin with a left brace.");
@@ -45,7 +48,7 @@ pub fn parse_device<'a>(token_iter : &mut TokenIterator<'a>) -> Device<'a> {
     return Device {device_id, device_fields};
 }
 
-fn parse_device_fields<'a>(token_iter : &mut TokenIterator<'a>) -> DeviceFields<'a> {
+fn parse_device_fields<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> DeviceFields<'a> {
   // Helper function to determine if the keyword starts a declaration
   let is_ident = |token| { match token { &Token::Identifier(_) => true, _ => false, } };
 
@@ -60,7 +63,7 @@ fn parse_device_fields<'a>(token_iter : &mut TokenIterator<'a>) -> DeviceFields<
   }
 }
 
-fn parse_device_field<'a>(token_iter : &mut TokenIterator<'a>) -> DeviceField<'a> {
+fn parse_device_field<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> DeviceField<'a> {
   let identifier = parse_identifier(token_iter);
   let var_type   = parse_type_annotation(token_iter, TypeQualifier::Field);
   match_token(token_iter, Token::SemiColon, "Last token in a declaration must be a semicolon.");
@@ -68,17 +71,29 @@ fn parse_device_field<'a>(token_iter : &mut TokenIterator<'a>) -> DeviceField<'a
 }
 
 
-fn parse_import<'a>(token_iter : &mut TokenIterator<'a>) -> Import<'a> {
+// import device psa; or import "lib/sketch.np" as sketch;
+// as is only a keyword after the path of a file import.
+fn parse_import<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> Import<'a> {
     match_token(token_iter, Token::Import, "Import definition must start with keyword import");
+    if let Some(&&Token::Str(path)) = token_iter.peek() {
+      token_iter.next();
+      let mut alias = None;
+      if token_iter.peek() == Some(&&Token::Identifier("as")) {
+        token_iter.next();
+        alias = Some(parse_identifier(token_iter));
+      }
+      match_token(token_iter, Token::SemiColon, "Last token in an import must be a semicolon.");
+      return Import{import_type : Identifier{id_name : "file"}, import_id : Identifier{id_name : path}, alias};
+    }
     match_token(token_iter, Token::Device, "Device definition must start with the keyword device");
     let dtype = "device";
     let my_type = Identifier{id_name :dtype};
     let my_import_id = parse_identifier(token_iter);
     match_token(token_iter, Token::SemiColon, "Last token in a declaration must be a semicolon.");
-    return Import{import_type: my_type, import_id : my_import_id};
+    return Import{import_type: my_type, import_id : my_import_id, alias : None};
 }
 
-fn parse_imports<'a>(token_iter : &mut TokenIterator<'a>) -> Imports<'a> {
+pub fn parse_imports<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> Imports<'a> {
     let is_import = |token| { match token { &Token::Import => true, _ => false, } };
     let mut import_vector = Vec::<Import>::new();
     //println!("token_iter.peek ={:?}\n", token_iter.peek());
@@ -93,7 +108,7 @@ fn parse_imports<'a>(token_iter : &mut TokenIterator<'a>) -> Imports<'a> {
     }
 }
 
-fn parse_globals<'a>(token_iter : &mut TokenIterator<'a>) -> Globals<'a> {
+fn parse_globals<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> Globals<'a> {
   let is_global = |token| { match token { &Token::Global => true, _ => false, } };
   let mut global_vector = Vec::<VariableDecl>::new();
   loop {
@@ -106,7 +121,7 @@ fn parse_globals<'a>(token_iter : &mut TokenIterator<'a>) -> Globals<'a> {
   }
 }
 
-pub fn parse_import_packets<'a>(token_iter : &mut TokenIterator<'a>) -> Packets<'a> {
+pub fn parse_import_packets<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> Packets<'a> {
   // Internal helper function to check if it's a snippet or not
   let is_packet = |token| { match token { &Token::Packet => true, _ => false, } };
 
@@ -122,7 +137,7 @@ pub fn parse_import_packets<'a>(token_iter : &mut TokenIterator<'a>) -> Packets<
 }
 
 
-fn parse_import_packet<'a>(token_iter : &mut TokenIterator<'a>) -> Packet<'a> {
+fn parse_import_packet<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> Packet<'a> {
   match_token(token_iter, Token::Packet, "Packet definition must start with the keyword packet");
   let packet_id = parse_identifier(token_iter);
   let packet_base = Identifier{id_name:"none"};
@@ -133,7 +148,7 @@ fn parse_import_packet<'a>(token_iter : &mut TokenIterator<'a>) -> Packet<'a> {
   return Packet {packet_id, packet_base, packet_fields, packet_parser_condition};
 }
 
-fn parse_packets<'a>(token_iter : &mut TokenIterator<'a>) -> Packets<'a> {
+fn parse_packets<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> Packets<'a> {
   // Internal helper function to check if it's a snippet or not
   let is_packet = |token| { match token { &Token::Packet => true, _ => false, } };
 
@@ -149,7 +164,7 @@ fn parse_packets<'a>(token_iter : &mut TokenIterator<'a>) -> Packets<'a> {
 }
 
 
-fn parse_packet<'a>(token_iter : &mut TokenIterator<'a>) -> Packet<'a> {
+fn parse_packet<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> Packet<'a> {
   match_token(token_iter, Token::Packet, "Packet definition must start with the keyword packet");
   let packet_id = parse_identifier(token_iter);
   match_token(token_iter, Token::Colon, "Packet must contain a derivation from eth/ipv4/tcp/udp");
@@ -164,7 +179,7 @@ fn parse_packet<'a>(token_iter : &mut TokenIterator<'a>) -> Packet<'a> {
   return Packet {packet_id, packet_base, packet_fields, packet_parser_condition};
 }
 
-fn parse_packet_parser_condition<'a>(token_iter : &mut TokenIterator<'a>) -> PacketParserCondition<'a> {
+fn parse_packet_parser_condition<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> PacketParserCondition<'a> {
     if !token_iter.peek().is_some() {
       return PacketParserCondition::Empty();
     }
@@ -173,7 +188,7 @@ fn parse_packet_parser_condition<'a>(token_iter : &mut TokenIterator<'a>) -> Pac
     let field_value = parse_value(token_iter);
     return PacketParserCondition::ParserCondition(field_id, field_value);
 }
-fn parse_packet_fields<'a>(token_iter : &mut TokenIterator<'a>) -> PacketFields<'a> {
+fn parse_packet_fields<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> PacketFields<'a> {
   // Helper function to determine if the keyword starts a declaration
   let is_ident = |token| { match token { &Token::Identifier(_) => true, _ => false, } };
 
@@ -189,14 +204,58 @@ fn parse_packet_fields<'a>(token_iter : &mut TokenIterator<'a>) -> PacketFields<
 }
 
 
-fn parse_packet_field<'a>(token_iter : &mut TokenIterator<'a>) -> PacketField<'a> {
+fn parse_packet_field<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> PacketField<'a> {
   let identifier = parse_identifier(token_iter);
   let var_type   = parse_type_annotation(token_iter, TypeQualifier::Field);
   match_token(token_iter, Token::SemiColon, "Last token in a declaration must be a semicolon.");
   return PacketField {identifier, var_type};
 }
 
-fn parse_snippets<'a>(token_iter : &mut TokenIterator<'a> ) -> Snippets<'a> {
+// topology { host h1; switch s1 : bmv2 capacity 10; link h1 -- s1; }
+// host, switch, link and capacity are only keywords inside the topology block.
+fn parse_topology<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> Topology<'a> {
+  let mut host_vector   = Vec::<Identifier>::new();
+  let mut switch_vector = Vec::<Switch>::new();
+  let mut link_vector   = Vec::<Link>::new();
+  if token_iter.peek() != Some(&&Token::Topology) {
+    return Topology{host_vector, switch_vector, link_vector};
+  }
+
+  match_token(token_iter, Token::Topology, "Topology definition must start with the keyword topology.");
+  match_token(token_iter, Token::BraceLeft, "Topology body must begin with a left brace.");
+  loop {
+    match token_iter.next() {
+      Some(&Token::Identifier("host")) => {
+        host_vector.push(parse_identifier(token_iter));
+      }
+      Some(&Token::Identifier("switch")) => {
+        let switch_id = parse_identifier(token_iter);
+        match_token(token_iter, Token::Colon, "Need a colon after the switch name.");
+        let device_type = parse_identifier(token_iter);
+        let mut capacity = None;
+        if token_iter.peek() == Some(&&Token::Identifier("capacity")) {
+          token_iter.next();
+          capacity = Some(parse_value(token_iter).value);
+        }
+        switch_vector.push(Switch{switch_id, device_type, capacity});
+      }
+      Some(&Token::Identifier("link")) => {
+        let from_node = parse_identifier(token_iter);
+        match_token(token_iter, Token::DoubleDash, "Link endpoints must be separated by --.");
+        let to_node = parse_identifier(token_iter);
+        link_vector.push(Link{from_node, to_node});
+      }
+      Some(&Token::BraceRight) => {
+        return Topology{host_vector, switch_vector, link_vector};
+      }
+      Some(other) => panic!("Expected host, switch or link in topology, found {:?}.", other),
+      None => panic!("Topology body must end with a right brace.")
+    }
+    match_token(token_iter, Token::SemiColon, "Last token in a topology entry must be a semicolon.");
+  }
+}
+
+fn parse_snippets<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a> ) -> Snippets<'a> {
   // Internal helper function to check if it's a snippet or not
   let is_snippet = |token| { match token { &Token::Snippet => true, _ => false, } };
   let is_annotation = |token| { match token { &Token::Annotation => true, _ => false, } };
@@ -214,7 +273,7 @@ fn parse_snippets<'a>(token_iter : &mut TokenIterator<'a> ) -> Snippets<'a> {
 }
 
 
-fn parse_snippet<'a>(token_iter : &mut TokenIterator<'a>) -> Snippet<'a> {
+fn parse_snippet<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> Snippet<'a> {
   let mut ifid: u64 = 0;
   let is_snippet = |token| { match token { &Token::Snippet => true, _ => false, } };
   let is_annotation = |token| { match token { &Token::Annotation => true, _ => false, } };
@@ -233,23 +292,44 @@ fn parse_snippet<'a>(token_iter : &mut TokenIterator<'a>) -> Snippet<'a> {
   match_token(token_iter, Token::ParenRight, "Snippet argument list must end with a right parenthesis.");
   match_token(token_iter, Token::BraceLeft, "Snippet body must begin with a left brace.");
   let variable_decls    = parse_variable_decls(token_iter);
+  let invariants        = parse_invariants(token_iter);
   let ifblocks          = parse_ifblocks(token_iter, &mut ifid);
   match_token(token_iter, Token::BraceRight, "Snippet body must end with a right brace.");
-  return Snippet{snippet_id, device_annotation : dev_anno, variable_decls, ifblocks};
+  return Snippet{snippet_id, device_annotation : dev_anno, variable_decls, invariants, ifblocks};
+}
+
+// Invariants follow the variable declarations of a snippet
+fn parse_invariants<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> Vec<Invariant<'a>> {
+  let mut invariants = Vec::<Invariant>::new();
+  while token_iter.peek() == Some(&&Token::Invariant) {
+    match_token(token_iter, Token::Invariant, "Invariant must start with the keyword invariant.");
+    let identifier = parse_identifier(token_iter);
+    match_token(token_iter, Token::Colon, "Need a colon between the invariant name and its condition.");
+    let condition = parse_condition(token_iter);
+    match_token(token_iter, Token::SemiColon, "Last token in an invariant must be a semicolon.");
+    invariants.push(Invariant{identifier, condition});
+  }
+  return invariants;
 }
 
 
-fn parse_device_annotation<'a>(token_iter : &mut TokenIterator<'a>) -> DeviceAnnotation<'a> {
+fn parse_device_annotation<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> DeviceAnnotation<'a> {
   let is_ident = |token| { match token { &Token::Identifier(_) => true, _ => false, } };
   let dev_type = parse_identifier(token_iter);
-  match_token(token_iter, Token::Colon, "Need a colon after declaring device type");
   let mut dev_vec = Vec::<Identifier>::new();
+  // the device list is optional, @ bmv2 alone annotates the type only
+  if token_iter.peek() == Some(&&Token::Snippet) {
+    return DeviceAnnotation{device_type : dev_type, device_vector : dev_vec};
+  }
+  match_token(token_iter, Token::Colon, "Need a colon after declaring device type");
 
   loop {
     if !token_iter.peek().is_some() || (!is_ident(*token_iter.peek().unwrap())) { break; } 
     let dev = parse_identifier(token_iter);
-    match_token(token_iter, Token::Comma, "Expected comma as separator between device names.");
     dev_vec.push(dev);
+    // trailing comma before the semicolon is optional
+    if token_iter.peek() == Some(&&Token::SemiColon) { break; }
+    match_token(token_iter, Token::Comma, "Expected comma as separator between device names.");
   }
 
   match_token(token_iter, Token::SemiColon, "Last token in annotation must be a semicolon.");
@@ -257,7 +337,39 @@ fn parse_device_annotation<'a>(token_iter : &mut TokenIterator<'a>) -> DeviceAnn
 }
 
 
-fn parse_connections<'a>(token_iter : &mut TokenIterator<'a>) -> Connections<'a> {
+fn parse_pipelines<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> Pipelines<'a> {
+  let is_pipeline = |token| { match token { &Token::Pipeline => true, _ => false, } };
+  let mut pipeline_vector = Vec::<Pipeline>::new();
+  loop {
+    if !token_iter.peek().is_some() || !is_pipeline(*token_iter.peek().unwrap()) {
+      return Pipelines{pipeline_vector};
+    } else {
+      pipeline_vector.push(parse_pipeline(token_iter));
+    }
+  }
+}
+
+// pipeline s1 : foo, fun, ;
+fn parse_pipeline<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> Pipeline<'a> {
+  let is_ident = |token| { match token { &Token::Identifier(_) => true, _ => false, } };
+  match_token(token_iter, Token::Pipeline, "Pipeline definition must start with the keyword pipeline.");
+  let device_id = parse_identifier(token_iter);
+  match_token(token_iter, Token::Colon, "Need a colon after the device in a pipeline.");
+  let mut snippet_vector = Vec::<Identifier>::new();
+
+  loop {
+    if !token_iter.peek().is_some() || (!is_ident(*token_iter.peek().unwrap())) { break; }
+    let snippet = parse_identifier(token_iter);
+    snippet_vector.push(snippet);
+    if token_iter.peek() == Some(&&Token::SemiColon) { break; }
+    match_token(token_iter, Token::Comma, "Expected comma as separator between snippet names.");
+  }
+
+  match_token(token_iter, Token::SemiColon, "Last token in a pipeline must be a semicolon.");
+  return Pipeline{device_id, snippet_vector};
+}
+
+fn parse_connections<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> Connections<'a> {
   let mut connection_vector = Vec::<Connection<'a>>::new();
   loop {
     if !token_iter.peek().is_some() {
@@ -268,7 +380,7 @@ fn parse_connections<'a>(token_iter : &mut TokenIterator<'a>) -> Connections<'a>
   }
 }
 
-fn parse_connection<'a>(token_iter : &mut TokenIterator<'a>) -> Connection<'a> {
+fn parse_connection<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> Connection<'a> {
   // Helper function to detect identifiers and colons
   let is_ident = |token| { match token { &Token::Identifier(_) => true, _ => false, } };
   let is_colon = |token| { match token { &Token::Colon => true, _ => false, } };
@@ -293,7 +405,7 @@ fn parse_connection<'a>(token_iter : &mut TokenIterator<'a>) -> Connection<'a> {
   return Connection { from_snippet : id1, to_snippet : id2, variable_pairs : variable_pairs };
 }
 
-fn parse_variable_decls<'a>(token_iter : &mut TokenIterator<'a>) -> VariableDecls<'a> {
+fn parse_variable_decls<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> VariableDecls<'a> {
   // Helper function to determine if the keyword starts a declaration
   let is_decl = |token| { match token { &Token::Persistent | &Token::Transient | &Token::Const | &Token::Input | &Token::Output | &Token::Packet => true, _ => false, } };
 
@@ -309,7 +421,7 @@ fn parse_variable_decls<'a>(token_iter : &mut TokenIterator<'a>) -> VariableDecl
 }
 
 
-fn parse_variable_decl<'a>(token_iter : &mut TokenIterator<'a>) -> VariableDecl<'a> {
+fn parse_variable_decl<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> VariableDecl<'a> {
   let type_qualifier =  parse_type_qualifier(token_iter);
   let identifier = parse_identifier(token_iter);
 
@@ -355,7 +467,7 @@ fn parse_variable_decl<'a>(token_iter : &mut TokenIterator<'a>) -> VariableDecl<
 }
 
 
-fn parse_type_qualifier<'a>(token_iter : &mut TokenIterator<'a>) -> TypeQualifier {
+fn parse_type_qualifier<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> TypeQualifier {
   if token_iter.peek().is_none() {
     panic!("No tokens left to parse in parse_type_qualifier.");
   } else {
@@ -374,7 +486,7 @@ fn parse_type_qualifier<'a>(token_iter : &mut TokenIterator<'a>) -> TypeQualifie
 }
 
 
-fn parse_type_annotation<'a>(token_iter : &mut TokenIterator<'a>, type_qualifier : TypeQualifier) -> VarType<'a> {
+fn parse_type_annotation<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>, type_qualifier : TypeQualifier) -> VarType<'a> {
   match_token(token_iter, Token::Colon, "Type annotation must start with a colon.");
 
   let is_bit = |token| { match token { &Token::Bit => true, _ => false, } };
@@ -414,11 +526,11 @@ fn parse_type_annotation<'a>(token_iter : &mut TokenIterator<'a>, type_qualifier
 }
 
 
-fn parse_ifblocks<'a>(token_iter : &mut TokenIterator<'a>, ifid :&mut u64) -> IfBlocks<'a> {
+fn parse_ifblocks<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>, ifid :&mut u64) -> IfBlocks<'a> {
   // println!("{:?}", token);
   let is_ifblock   = |token| { match token { &Token::If => true, _ => false } };
   let is_elseblock = |token| { match token { &Token::Else => true, _ => false } };
-  let is_ident = |token| { match token { &Token::Identifier(_) => true, _ => false } };
+  let is_ident = |token| { match token { &Token::Identifier(_) | &Token::Assert => true, _ => false } };
   let mut ifblock_vector = Vec::<IfBlock>::new();
   let mut blocktype: u64;
 
@@ -444,7 +556,7 @@ fn parse_ifblocks<'a>(token_iter : &mut TokenIterator<'a>, ifid :&mut u64) -> If
   }
 }
 
-fn parse_ifblock<'a>(token_iter : &mut TokenIterator<'a>, id : u64, condtype : u64) -> IfBlock<'a> {
+fn parse_ifblock<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>, id : u64, condtype : u64) -> IfBlock<'a> {
 
   // dummyCondition causing pretty-printer problem. If the if block is condtype 2 or 3, then the dummy condition has expr with
   // a value of 1 as default. The 1 gets printed after the last statement since visit_condition is called
@@ -479,16 +591,24 @@ fn parse_ifblock<'a>(token_iter : &mut TokenIterator<'a>, id : u64, condtype : u
   }
 }
 
-fn parse_statements<'a>(token_iter : &mut TokenIterator<'a>) -> Statements<'a> {
+fn parse_statements<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> Statements<'a> {
   // Helper function to identify beginning of statements
   let is_ident = |token| { match token { &Token::Identifier(_) => true, _ => false } };
 
   let mut stmt_vector = Vec::<Statement>::new();
+  let mut assert_vector = Vec::<Assertion>::new();
   loop {
     // println!("is ident={}", is_ident(*token_iter.peek().unwrap()));
 
-    if !token_iter.peek().is_some() || (!is_ident(*token_iter.peek().unwrap())) {
-      return Statements{stmt_vector};
+    if token_iter.peek() == Some(&&Token::Assert) {
+      match_token(token_iter, Token::Assert, "Assertion must start with the keyword assert.");
+      match_token(token_iter, Token::ParenLeft, "Assertion condition must begin with a left parenthesis.");
+      let condition = parse_condition(token_iter);
+      match_token(token_iter, Token::ParenRight, "Assertion condition must end with a right parenthesis.");
+      match_token(token_iter, Token::SemiColon, "Last token in an assertion must be a semicolon.");
+      assert_vector.push(Assertion{position : stmt_vector.len(), condition});
+    } else if !token_iter.peek().is_some() || (!is_ident(*token_iter.peek().unwrap())) {
+      return Statements{stmt_vector, assert_vector};
     } else {
       let statement = parse_statement(token_iter);
       stmt_vector.push(statement);
@@ -496,7 +616,7 @@ fn parse_statements<'a>(token_iter : &mut TokenIterator<'a>) -> Statements<'a> {
   }
 }
 
-fn parse_condition<'a>(token_iter : &mut TokenIterator<'a>) -> Condition<'a> {
+fn parse_condition<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> Condition<'a> {
   //let lvalue = parse_lvalue(token_iter);
   //match_token(token_iter, Token::Assign, "Must separate identifier and expression by an assignment symbol.");
   let expr       = parse_expr(token_iter);
@@ -504,7 +624,7 @@ fn parse_condition<'a>(token_iter : &mut TokenIterator<'a>) -> Condition<'a> {
   return Condition{expr};
 }
 
-fn parse_statement<'a>(token_iter : &mut TokenIterator<'a>) -> Statement<'a> {
+fn parse_statement<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> Statement<'a> {
   let lvalue = parse_lvalue(token_iter);
   match_token(token_iter, Token::Assign, "Must separate identifier and expression by an assignment symbol.");
   let expr       = parse_expr(token_iter);
@@ -512,7 +632,7 @@ fn parse_statement<'a>(token_iter : &mut TokenIterator<'a>) -> Statement<'a> {
   return Statement{lvalue, expr};
 }
 
-fn parse_expr<'a>(token_iter : &mut TokenIterator<'a>) -> Expr<'a> {
+fn parse_expr<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> Expr<'a> {
   if !token_iter.peek().is_some() {
     panic!("Insufficient tokens in call to parse_expr.");
   }
@@ -524,7 +644,7 @@ fn parse_expr<'a>(token_iter : &mut TokenIterator<'a>) -> Expr<'a> {
 // Macro to generate parser for ExprRight given a list of binary operations
 macro_rules! expr_right_parser {
   ($($x:ident),*) => {
-    fn parse_expr_right<'a>(token_iter : &mut TokenIterator<'a>) -> ExprRight<'a> {
+    fn parse_expr_right<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> ExprRight<'a> {
       // generate is_operator helper function
       let is_operator = |token| { match token { $(&Token::$x|)* &Token::Cond => true, _ => false, } };
 
@@ -557,7 +677,7 @@ macro_rules! expr_right_parser {
 // generate parser using macro
 expr_right_parser!(BooleanAnd, BooleanOr, Plus, Minus, Mul, Div, Modulo, ShiftLeft, ShiftRight, Equal, NotEqual, LTEQOp, GTEQOp, LessThan, GreaterThan);
 
-fn parse_identifier<'a>(token_iter : &mut TokenIterator<'a>) -> Identifier<'a> {
+fn parse_identifier<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> Identifier<'a> {
   let identifier_token = token_iter.next().unwrap();
   match identifier_token {
     & Token::Identifier(id_name) => Identifier{id_name},
@@ -565,7 +685,7 @@ fn parse_identifier<'a>(token_iter : &mut TokenIterator<'a>) -> Identifier<'a> {
   }
 }
 
-fn parse_lvalue<'a>(token_iter : &mut TokenIterator<'a>) -> LValue<'a> {
+fn parse_lvalue<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> LValue<'a> {
   let lvalue_token = token_iter.next().unwrap();
   let is_square_left = |token| { match token { &Token::SquareLeft => true, _ => false, } };
   let is_dot         = |token| { match token { &Token::Dot        => true, _ => false, } };
@@ -588,7 +708,7 @@ fn parse_lvalue<'a>(token_iter : &mut TokenIterator<'a>) -> LValue<'a> {
   }
 }
 
-fn parse_operand<'a>(token_iter : &mut TokenIterator<'a>) -> Operand<'a> {
+fn parse_operand<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> Operand<'a> {
   match token_iter.peek().unwrap() { // && is required because we are using Peekable iterators
     && Token::Identifier(_) => return Operand::LValue(parse_lvalue(token_iter)),
     && Token::Value(_)      => return Operand::Value(parse_value(token_iter)),
@@ -596,7 +716,7 @@ fn parse_operand<'a>(token_iter : &mut TokenIterator<'a>) -> Operand<'a> {
   }
 }
 
-fn parse_initial_values<'a>(token_iter : &mut TokenIterator<'a>) -> Vec<Value> {
+fn parse_initial_values<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> Vec<Value> {
   match token_iter.peek().unwrap() {
     && Token::Value(_)  => { let mut singleton_vector = Vec::<Value>::new();
                              singleton_vector.push(parse_value(token_iter));
@@ -611,7 +731,7 @@ fn parse_initial_values<'a>(token_iter : &mut TokenIterator<'a>) -> Vec<Value> {
   }
 }
 
-fn parse_value_vector<'a>(token_iter : &mut TokenIterator<'a>) -> Vec<Value> {
+fn parse_value_vector<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> Vec<Value> {
   // Helper function to detect values
   let is_value = |token| { match token { &Token::Value(_) => true, _ => false, } };
 
@@ -627,7 +747,7 @@ fn parse_value_vector<'a>(token_iter : &mut TokenIterator<'a>) -> Vec<Value> {
   }
 }
 
-fn parse_value<'a>(token_iter : &mut TokenIterator<'a>) -> Value {
+fn parse_value<'t, 'a>(token_iter : &mut TokenIterator<'t, 'a>) -> Value {
   let value_token = token_iter.next().unwrap();
   match value_token {
     & Token::Value(value)  => return Value{value},
@@ -679,6 +799,7 @@ mod tests {
   test_parser_success!(r"a", parse_lvalue, test_parse_lvalue2);
   test_parser_success!(r"7%5", parse_expr, test_parse_expr);
   test_parser_success!(r"x=6+5;", parse_statement, test_parse_statement);
+  test_parser_success!(r#"import "lib/sketch.np" as sketch; import device psa;"#, parse_imports, test_parse_imports);
   test_parser_success!(r"x=6+5;y=7*8;", parse_statements, test_parse_statements);
   test_parser_success!(r"transient x : bit<8>;", parse_variable_decls, test_parse_transient_decls);
   test_parser_success!(r"persistent x : bit<3> = 6; persistent y : bit<3> = 7;",
@@ -778,4 +899,45 @@ mod tests {
                           (foo, fun)
                           ", parse_prog, test_parse_prog2);
   test_parser_success!(r"a.x = 1;", parse_statement, test_parse_dot_operator);
+  test_parser_success!(r"pipeline s1 : foo, fun, ; pipeline s2 : fun;", parse_pipelines, test_parse_pipelines);
+  test_parser_fail!   (r"pipeline s1 : foo fun, ;", parse_pipelines, test_parse_pipeline_fail,
+                       "Invalid token: Identifier(\"fun\"), expected Comma.\nError message: \"Expected comma as separator between snippet names.\"");
+  test_parser_success!(r"@ bmv2 : s1, ;
+                          snippet foo() {
+                            transient x : bit<3>;
+                            x = 5;
+                          }
+                          @ bmv2 : s1, ;
+                          snippet fun() {
+                            transient y : bit<3>;
+                            y = 6;
+                          }
+                          pipeline s1 : fun, foo, ;
+                          (foo, fun)
+                          ", parse_prog, test_parse_prog_pipeline);
+  test_parser_success!(r"topology { host h1; host h2; switch s1 : bmv2 capacity 20; switch s2 : tofino;
+                                    link h1 -- s1; link s1 -- s2; link s2 -- h2; }",
+                       parse_topology, test_parse_topology);
+  test_parser_success!(r"@ bmv2 : s1, s2; snippet fun() {}", parse_snippet, test_parse_annotation_no_trailing_comma);
+  test_parser_success!(r"@ bmv2 snippet fun() {}", parse_snippet, test_parse_annotation_no_devices);
+  test_parser_fail!   (r"topology { host h1; router r1; }", parse_topology, test_parse_topology_fail,
+                       "Expected host, switch or link in topology, found Identifier(\"router\").");
+  test_parser_fail!   (r"topology { link h1 - s1; }", parse_topology, test_parse_topology_link_fail,
+                       "Invalid token: Minus, expected DoubleDash.\nError message: \"Link endpoints must be separated by --.\"");
+  test_parser_success!(r"topology { host h1; switch s1 : bmv2; link h1 -- s1; }
+                          @ bmv2 : s1;
+                          snippet fun() {
+                            transient a : bit<32>;
+                            a = 1;
+                          }
+                          ", parse_prog, test_parse_prog_topology);
+  test_parser_success!(r"snippet fun() {
+                            transient a : bit<32>;
+                            invariant small : a < 10;
+                            assert(a == 0);
+                            a = 1;
+                            if (a == 1) { a = 2; assert(a > 1); }
+                          }", parse_snippet, test_parse_assertions);
+  test_parser_fail!   (r"snippet fun() { transient a : bit<32>; assert a == 0; }", parse_snippet, test_parse_assertion_fail,
+                       "Invalid token: Identifier(\"a\"), expected ParenLeft.\nError message: \"Assertion condition must begin with a left parenthesis.\"");
 }
@@ -0,0 +1,348 @@
+// Pass manager for the dag transformations. The dag of a snippet starts with the
+// declarations of its variables, packet fields and globals, and every DagPass of the
+// pipeline then rewrites it in place. The pipeline is configurable, but always starts
+// with branch removal and SSA and ends with the dependency edges, which the code
+// generators rely on. After every pass the manager checks the invariants of the dag and
+// hands it to a hook, which the DOT export, the dumps and the differential checker use
+// to look at the dag after any pass.
+// Register grouping, stage scheduling and metadata packing aren't DagPasses: they work
+// on the P4 code a backend generates from the final dag, and run per backend in
+// trans_snippets.
+use grammar::*;
+use trans_snippet::*;
+use constant_folding::{fold_constants, expr_names};
+use cse::eliminate_common_subexpressions;
+use dead_code::{eliminate_dead_code, statement_reads};
+use dependency::add_dependency_edges;
+use dot;
+use dot::DotStage;
+use serde_json;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+pub const PASS_NAMES : [&str; 6] = ["branch", "ssa", "fold", "cse", "dce", "deps"];
+// passes every pipeline starts with
+const REQUIRED_PASSES : [&str; 2] = ["branch", "ssa"];
+// pass every pipeline ends with, the passes after it would leave its edges stale
+const FINAL_PASS : &str = "deps";
+
+pub fn default_passes() -> Vec<String> {
+    PASS_NAMES.iter().map(|p| p.to_string()).collect()
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone, Copy)]
+pub enum DumpFormat {
+    Text,
+    Dot,
+    Json,
+}
+
+impl DumpFormat {
+    pub fn from_name(format : &str) -> DumpFormat {
+        match format {
+            "text" => DumpFormat::Text,
+            "dot"  => DumpFormat::Dot,
+            "json" => DumpFormat::Json,
+            _ => panic!("Unknown dump format {}. Expected one of text, dot, json.", format)
+        }
+    }
+}
+
+// Everything a pass may need besides the dag
+pub struct PassContext<'a, 'b> {
+    pub my_snippet  : &'b Snippet<'a>,
+    pub my_globals  : &'b Globals<'a>,
+    pub my_packets  : &'b Packets<'a>,
+    pub pkt_tree    : &'b Packets<'a>,
+    pub packet_map  : &'b HashMap<String, String>,
+    // fields of the imported devices, see get_device_decls
+    pub device_decls : &'b Vec<VariableDecl<'a>>,
+    // types of the packet and device fields, keyed p.f
+    pub field_decls : &'b HashMap<String, VarType<'a>>,
+}
+
+pub trait DagPass<'a> {
+    // name of the pass in --passes and --dump-after
+    fn name(&self) -> &'static str;
+    fn run<'b> (&self, my_dag : &mut Dag<'a>, context : &PassContext<'a, 'b>);
+}
+
+pub struct BranchRemoval;
+pub struct StaticSingleAssignment;
+pub struct ConstantFolding;
+pub struct CommonSubexpressions;
+pub struct DeadCode;
+pub struct DependencyEdges;
+
+impl<'a> DagPass<'a> for BranchRemoval {
+    fn name(&self) -> &'static str { "branch" }
+    fn run<'b> (&self, my_dag : &mut Dag<'a>, context : &PassContext<'a, 'b>) {
+        branch_removal(my_dag, context.packet_map, context.my_snippet, context.field_decls);
+    }
+}
+
+impl<'a> DagPass<'a> for StaticSingleAssignment {
+    fn name(&self) -> &'static str { "ssa" }
+    fn run<'b> (&self, my_dag : &mut Dag<'a>, _context : &PassContext<'a, 'b>) {
+        static_single_assignment(my_dag);
+    }
+}
+
+impl<'a> DagPass<'a> for ConstantFolding {
+    fn name(&self) -> &'static str { "fold" }
+    fn run<'b> (&self, my_dag : &mut Dag<'a>, _context : &PassContext<'a, 'b>) {
+        fold_constants(my_dag);
+    }
+}
+
+impl<'a> DagPass<'a> for CommonSubexpressions {
+    fn name(&self) -> &'static str { "cse" }
+    fn run<'b> (&self, my_dag : &mut Dag<'a>, _context : &PassContext<'a, 'b>) {
+        eliminate_common_subexpressions(my_dag);
+    }
+}
+
+impl<'a> DagPass<'a> for DeadCode {
+    fn name(&self) -> &'static str { "dce" }
+    fn run<'b> (&self, my_dag : &mut Dag<'a>, _context : &PassContext<'a, 'b>) {
+        eliminate_dead_code(my_dag);
+    }
+}
+
+impl<'a> DagPass<'a> for DependencyEdges {
+    fn name(&self) -> &'static str { "deps" }
+    fn run<'b> (&self, my_dag : &mut Dag<'a>, _context : &PassContext<'a, 'b>) {
+        add_dependency_edges(my_dag);
+    }
+}
+
+pub fn get_pass<'a> (name : &str) -> Box<dyn DagPass<'a>> {
+    match name {
+        "branch" => Box::new(BranchRemoval),
+        "ssa"    => Box::new(StaticSingleAssignment),
+        "fold"   => Box::new(ConstantFolding),
+        "cse"    => Box::new(CommonSubexpressions),
+        "dce"    => Box::new(DeadCode),
+        "deps"   => Box::new(DependencyEdges),
+        _ => panic!("Unknown pass {}. Expected one of {}.", name, PASS_NAMES.join(", "))
+    }
+}
+
+// Types of the fields of the packets, of the headers they derive from and of the
+// imported devices, keyed p.f
+pub fn get_field_decls<'a> (my_packets : &Packets<'a>, pkt_tree : &Packets<'a>, device_decls : &Vec<VariableDecl<'a>>) -> HashMap<String, VarType<'a>> {
+    let mut field_decls = HashMap::new();
+    for my_pkt in pkt_tree.packet_vector.iter().chain(my_packets.packet_vector.iter()) {
+        for my_pkt_field in &my_pkt.packet_fields.field_vector {
+            field_decls.insert(format!("{}.{}", my_pkt.packet_id.id_name, my_pkt_field.identifier.id_name), my_pkt_field.var_type.clone());
+        }
+    }
+    for device_decl in device_decls {
+        field_decls.insert(device_decl.identifier.id_name.to_string(), device_decl.var_type.clone());
+    }
+    field_decls
+}
+
+// First invariant the dag breaks: every variable is declared once, every variable a
+// statement uses is declared, the edges go both ways, and after SSA every transient is
+// written once. Packet fields are left out, they aren't all declared.
+pub fn get_dag_problem<'a> (my_dag : &Dag<'a>, in_ssa : bool) -> Option<String> {
+    let mut declared : HashMap<&'a str, TypeQualifier> = HashMap::new();
+    for my_dag_node in &my_dag.dag_vector {
+        if let DagNodeType::Decl(ref var_decl) = my_dag_node.node_type {
+            if declared.insert(var_decl.identifier.id_name, var_decl.var_type.type_qualifier).is_some() {
+                return Some(format!("{} is declared twice", var_decl.identifier.id_name));
+            }
+        }
+    }
+
+    let mut written : HashSet<&'a str> = HashSet::new();
+    for (i, my_dag_node) in my_dag.dag_vector.iter().enumerate() {
+        let mut reads = HashSet::new();
+        match my_dag_node.node_type {
+            DagNodeType::Stmt(ref my_statement) => {
+                statement_reads(my_statement, &mut reads);
+                match my_statement.lvalue {
+                    LValue::Scalar(ref id) => match declared.get(id.id_name) {
+                        None => return Some(format!("{} is written but not declared", id.id_name)),
+                        Some(&TypeQualifier::Transient) if in_ssa && !written.insert(id.id_name) => {
+                            return Some(format!("{} is written twice after SSA", id.id_name));
+                        }
+                        _ => {}
+                    },
+                    LValue::Array(ref id, _) if !declared.contains_key(id.id_name) => {
+                        return Some(format!("{} is written but not declared", id.id_name));
+                    }
+                    _ => {}
+                }
+            }
+            DagNodeType::Cond(ref my_expr) => expr_names(my_expr, &mut reads),
+            DagNodeType::Decl(_) => {}
+        }
+        let mut reads : Vec<&str> = reads.into_iter().filter(|name| !declared.contains_key(name)).collect();
+        reads.sort();
+        if let Some(name) = reads.first() {
+            return Some(format!("{} is read but not declared", name));
+        }
+        for next in &my_dag_node.next_nodes {
+            if my_dag.dag_vector.get(*next).is_none_or(|n| !n.prev_nodes.contains(&i)) {
+                return Some(format!("edge {} -> {} has no back edge", i, next));
+            }
+        }
+        for prev in &my_dag_node.prev_nodes {
+            if my_dag.dag_vector.get(*prev).is_none_or(|n| !n.next_nodes.contains(&i)) {
+                return Some(format!("edge {} -> {} has no forward edge", prev, i));
+            }
+        }
+    }
+    None
+}
+
+pub struct PassManager<'a> {
+    pub passes   : Vec<Box<dyn DagPass<'a>>>,
+    // check the invariants of the dag after every pass
+    pub validate : bool,
+}
+
+impl<'a> PassManager<'a> {
+    pub fn new(names : &[String]) -> PassManager<'a> {
+        if names.len() <= REQUIRED_PASSES.len() || names.iter().zip(REQUIRED_PASSES.iter()).any(|(name, required)| name != required)
+            || names.last().unwrap() != FINAL_PASS {
+            panic!("The pipeline must start with {} and end with {}, the code generators rely on them.", REQUIRED_PASSES.join(", "), FINAL_PASS);
+        }
+        PassManager { passes : names.iter().map(|name| get_pass(name)).collect(), validate : true }
+    }
+
+    // Runs the pipeline on the declarations of a snippet, calling after_pass with the dag after every pass
+    pub fn run<'b> (&self, context : &PassContext<'a, 'b>, after_pass : &mut dyn FnMut(&'static str, &Dag<'a>)) -> Dag<'a> {
+        let my_snippet = context.my_snippet;
+        let mut my_dag = Dag { snippet_id : my_snippet.snippet_id.id_name, device_type : my_snippet.device_annotation.device_type.id_name,
+                               device_vector : my_snippet.device_annotation.device_vector.clone(), dag_vector : Vec::new(),
+                               ssa_origins : HashMap::new() };
+        insert_packet_decls(&mut my_dag, context.my_packets, context.pkt_tree);
+        insert_device_decls(&mut my_dag, context.device_decls);
+        insert_global_decls(&mut my_dag, context.my_globals);
+
+        let mut in_ssa = false;
+        for pass in &self.passes {
+            pass.run(&mut my_dag, context);
+            in_ssa |= pass.name() == "ssa";
+            if self.validate {
+                if let Some(problem) = get_dag_problem(&my_dag, in_ssa) { panic!("Dag of snippet {} is invalid after pass {}: {}.", my_dag.snippet_id, pass.name(), problem) }
+            }
+            after_pass(pass.name(), &my_dag);
+        }
+        my_dag
+    }
+}
+
+fn dag_to_json<'a> (my_dag : &Dag<'a>) -> serde_json::Value {
+    let nodes : Vec<serde_json::Value> = my_dag.dag_vector.iter().enumerate().map(|(i, dagnode)| json!({
+        "index" : i,
+        "kind" : match dagnode.node_type {
+            DagNodeType::Decl(_) => "decl",
+            DagNodeType::Cond(_) => "cond",
+            DagNodeType::Stmt(_) => "stmt",
+        },
+        "label" : dot::get_node_label(dagnode),
+        "next" : dagnode.next_nodes
+    })).collect();
+    json!({
+        "snippet" : my_dag.snippet_id,
+        "device_type" : my_dag.device_type,
+        "nodes" : nodes
+    })
+}
+
+// The dags taken after a pass, in the given format
+pub fn dump_dags<'a> (dags : &Vec<Dag<'a>>, pass_name : &str, format : DumpFormat) -> String {
+    match format {
+        DumpFormat::Text => dags.iter().map(|my_dag| get_dag_text(my_dag)).collect(),
+        DumpFormat::Dot => dot::dags_to_dot(dags, DotStage::from_name(pass_name)),
+        DumpFormat::Json => {
+            let dags_json : Vec<serde_json::Value> = dags.iter().map(|my_dag| dag_to_json(my_dag)).collect();
+            json!({ "pass" : pass_name, "dags" : dags_json }).to_string() + "\n"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compile::*;
+
+    fn compile_dump(input : &str, passes : Vec<&str>, dump_after : &str, format : DumpFormat) -> Result<CompileOutput, Vec<CompileError>> {
+        let mut options = CompileOptions::new();
+        options.target = Some(String::from("none"));
+        options.passes = passes.iter().map(|p| p.to_string()).collect();
+        options.dump_after = Some(dump_after.to_string());
+        options.dump_format = format;
+        return compile(input, &options);
+    }
+
+    const INPUT : &str = r"@ bmv2 : s1;
+                           snippet fun() {
+                             input a : bit<8>;
+                             output b : bit<8>;
+                             transient c : bit<8>;
+                             transient d : bit<8>;
+                             c = 3;
+                             d = a + c;
+                             b = d;
+                           }";
+
+    #[test]
+    fn test_dump_after_pass() {
+        let output = compile_dump(INPUT, vec!["branch", "ssa", "fold", "deps"], "fold", DumpFormat::Text).unwrap();
+        assert_eq!(output.dump.unwrap(), "snippet fun @ bmv2\n    0: Input a : bit<8>\n    1: Output b : bit<8>\n    \
+                                          2: Transient d : bit<8>\n    3: d = a + 3\n    4: b = d\n");
+
+        let output = compile_dump(INPUT, vec!["branch", "ssa", "deps"], "ssa", DumpFormat::Json).unwrap();
+        let dump : serde_json::Value = serde_json::from_str(&output.dump.unwrap()).unwrap();
+        assert_eq!(dump["pass"], "ssa");
+        assert_eq!(dump["dags"][0]["nodes"][4], json!({ "index" : 4, "kind" : "stmt", "label" : "c = 3", "next" : [] }));
+
+        let output = compile_dump(INPUT, vec!["branch", "ssa", "dce", "deps"], "dce", DumpFormat::Dot).unwrap();
+        assert!(output.dump.unwrap().starts_with("digraph dependency_dag {\n    label=\"post dead code elimination\";\n"));
+    }
+
+    #[test]
+    fn test_pipeline_errors() {
+        let errors = compile_dump(INPUT, vec!["ssa", "fold", "deps"], "fold", DumpFormat::Text).unwrap_err();
+        assert_eq!(errors[0].message, "The pipeline must start with branch, ssa and end with deps, the code generators rely on them.");
+        let errors = compile_dump(INPUT, vec!["branch", "ssa", "fold"], "fold", DumpFormat::Text).unwrap_err();
+        assert_eq!(errors[0].message, "The pipeline must start with branch, ssa and end with deps, the code generators rely on them.");
+        let errors = compile_dump(INPUT, vec!["branch", "ssa", "inline", "deps"], "ssa", DumpFormat::Text).unwrap_err();
+        assert_eq!(errors[0].message, "Unknown pass inline. Expected one of branch, ssa, fold, cse, dce, deps.");
+        let errors = compile_dump(INPUT, vec!["branch", "ssa", "deps"], "dce", DumpFormat::Text).unwrap_err();
+        assert_eq!(errors[0].message, "Pass dce is not part of the pipeline branch, ssa, deps.");
+    }
+
+    #[test]
+    fn test_get_dag_problem() {
+        let statement = |lval : &'static str, rval : &'static str| DagNode {
+            node_type : DagNodeType::Stmt(Statement { lvalue : LValue::Scalar(Identifier { id_name : lval }),
+                                                      expr : Expr { op1 : Operand::LValue(LValue::Scalar(Identifier { id_name : rval })),
+                                                                    expr_right : ExprRight::Empty() } }),
+            p4_code : P4Code::new(), next_nodes : Vec::new(), prev_nodes : Vec::new(), pre_condition : None };
+        let decl = |name : &'static str| DagNode {
+            node_type : DagNodeType::Decl(VariableDecl { identifier : Identifier { id_name : name }, initial_values : Vec::new(),
+                                                         var_type : VarType { var_info : VarInfo::BitArray(8, 1),
+                                                                              type_qualifier : TypeQualifier::Transient } }),
+            p4_code : P4Code::new(), next_nodes : Vec::new(), prev_nodes : Vec::new(), pre_condition : None };
+        let mut my_dag = Dag { snippet_id : "fun", device_type : "bmv2", device_vector : Vec::new(),
+                               dag_vector : vec![decl("a"), decl("b"), statement("a", "b"), statement("a", "b")],
+                               ssa_origins : HashMap::new() };
+        assert_eq!(get_dag_problem(&my_dag, false), None);
+        assert_eq!(get_dag_problem(&my_dag, true), Some(String::from("a is written twice after SSA")));
+        my_dag.dag_vector[3] = statement("b", "c");
+        assert_eq!(get_dag_problem(&my_dag, true), Some(String::from("c is read but not declared")));
+        my_dag.dag_vector[3] = statement("b", "a");
+        my_dag.dag_vector[2].next_nodes.push(3);
+        assert_eq!(get_dag_problem(&my_dag, true), Some(String::from("edge 2 -> 3 has no back edge")));
+        my_dag.dag_vector.push(decl("b"));
+        assert_eq!(get_dag_problem(&my_dag, true), Some(String::from("b is declared twice")));
+    }
+}
@@ -0,0 +1,135 @@
+// Reading and writing of libpcap capture files, as written by tcpdump and
+// scapy's wrpcap. Only Ethernet captures are supported.
+use std::io::Write;
+
+const MAGIC_MICROS : u32 = 0xa1b2c3d4;
+const MAGIC_NANOS  : u32 = 0xa1b23c4d;
+const LINKTYPE_ETHERNET : u32 = 1;
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct PcapPacket {
+    pub ts_sec   : u32,
+    // microseconds, or nanoseconds if the capture has nanosecond timestamps
+    pub ts_frac  : u32,
+    // length of the packet on the wire, data may be shorter
+    pub orig_len : u32,
+    pub data     : Vec<u8>,
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Pcap {
+    pub nanosecond : bool,
+    pub snaplen    : u32,
+    pub packets    : Vec<PcapPacket>,
+}
+
+impl PcapPacket {
+    // Capture time in microseconds
+    pub fn timestamp(&self, nanosecond : bool) -> u64 {
+        let frac = if nanosecond { self.ts_frac as u64 / 1000 } else { self.ts_frac as u64 };
+        self.ts_sec as u64 * 1_000_000 + frac
+    }
+}
+
+fn read_u32(bytes : &[u8], offset : usize, big_endian : bool) -> u32 {
+    if bytes.len() < offset + 4 {
+        panic!("Truncated pcap file.");
+    }
+    let mut value = 0;
+    for i in 0..4 {
+        let byte = if big_endian { bytes[offset + i] } else { bytes[offset + 3 - i] };
+        value = (value << 8) | byte as u32;
+    }
+    value
+}
+
+pub fn read_pcap(bytes : &[u8]) -> Pcap {
+    let (big_endian, nanosecond) = match read_u32(bytes, 0, true) {
+        MAGIC_MICROS => (true, false),
+        MAGIC_NANOS => (true, true),
+        magic if magic.swap_bytes() == MAGIC_MICROS => (false, false),
+        magic if magic.swap_bytes() == MAGIC_NANOS => (false, true),
+        magic => panic!("Not a pcap file, magic number {:#x}.", magic),
+    };
+    let snaplen = read_u32(bytes, 16, big_endian);
+    let link_type = read_u32(bytes, 20, big_endian);
+    if link_type != LINKTYPE_ETHERNET {
+        panic!("Only Ethernet captures can be replayed, found link type {}.", link_type);
+    }
+
+    let mut packets = Vec::new();
+    let mut offset = 24;
+    while offset < bytes.len() {
+        let ts_sec = read_u32(bytes, offset, big_endian);
+        let ts_frac = read_u32(bytes, offset + 4, big_endian);
+        let incl_len = read_u32(bytes, offset + 8, big_endian) as usize;
+        let orig_len = read_u32(bytes, offset + 12, big_endian);
+        offset += 16;
+        if bytes.len() < offset + incl_len {
+            panic!("Truncated pcap file.");
+        }
+        packets.push(PcapPacket { ts_sec, ts_frac, orig_len, data : bytes[offset..offset + incl_len].to_vec() });
+        offset += incl_len;
+    }
+    Pcap { nanosecond, snaplen, packets }
+}
+
+// Writes a little-endian capture
+pub fn write_pcap(pcap : &Pcap, out : &mut dyn Write) {
+    let write_u32 = |out : &mut dyn Write, value : u32| {
+        out.write_all(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8])
+           .expect("Something went wrong writing the pcap file");
+    };
+    write_u32(out, if pcap.nanosecond { MAGIC_NANOS } else { MAGIC_MICROS });
+    // version 2.4, as two 16-bit fields
+    write_u32(out, 2 | (4 << 16));
+    write_u32(out, 0);
+    write_u32(out, 0);
+    write_u32(out, pcap.snaplen);
+    write_u32(out, LINKTYPE_ETHERNET);
+    for packet in &pcap.packets {
+        write_u32(out, packet.ts_sec);
+        write_u32(out, packet.ts_frac);
+        write_u32(out, packet.data.len() as u32);
+        write_u32(out, packet.orig_len);
+        out.write_all(&packet.data).expect("Something went wrong writing the pcap file");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pcap_round_trip() {
+        let pcap = Pcap { nanosecond : false, snaplen : 65535,
+                          packets : vec![PcapPacket { ts_sec : 1, ts_frac : 500, orig_len : 60, data : vec![1, 2, 3] },
+                                         PcapPacket { ts_sec : 2, ts_frac : 0, orig_len : 2, data : vec![4, 5] }] };
+        let mut bytes = Vec::new();
+        write_pcap(&pcap, &mut bytes);
+        assert_eq!(bytes.len(), 24 + 16 + 3 + 16 + 2);
+        assert_eq!(read_pcap(&bytes), pcap);
+        assert_eq!(pcap.packets[0].timestamp(false), 1_000_500);
+    }
+
+    #[test]
+    fn test_pcap_big_endian() {
+        let mut bytes = vec![0xa1, 0xb2, 0x3c, 0x4d, 0, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0, 0, 0, 1];
+        bytes.extend(vec![0, 0, 0, 3, 0, 0, 0x07, 0xd0, 0, 0, 0, 1, 0, 0, 0, 1, 42]);
+        let pcap = read_pcap(&bytes);
+        assert!(pcap.nanosecond);
+        assert_eq!(pcap.packets[0], PcapPacket { ts_sec : 3, ts_frac : 2000, orig_len : 1, data : vec![42] });
+        assert_eq!(pcap.packets[0].timestamp(true), 3_000_002);
+    }
+
+    #[test]
+    #[should_panic(expected="Only Ethernet captures can be replayed")]
+    fn test_pcap_link_type_fail() {
+        let bytes = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0, 0, 101, 0, 0, 0];
+        read_pcap(&bytes);
+    }
+}
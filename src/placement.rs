@@ -0,0 +1,421 @@
+// Placement of unannotated snippets onto the switches of a declared topology.
+// Snippets with a device annotation are pinned to those devices. Every other
+// snippet is assigned to exactly one switch so that the data carried between
+// connected snippets travels over as few hops as possible, without exceeding
+// the capacity of any switch.
+// Capacity is a statement count, see snippet_size, not a resource estimate: the
+// P4 code of a snippet only exists after translation. A placement is only
+// accepted once the device program on each switch, merged from the snippets
+// placed there, passes the budget of its target, see get_resource_report.
+use grammar::*;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+
+// Largest number of assignments tried exhaustively before falling back to a greedy placement
+const MAX_SEARCH : u64 = 100000;
+// Cost of a connection between snippets on disconnected devices
+const UNREACHABLE : u64 = 1 << 32;
+
+// Room a snippet takes up on a switch, in the unit of the switch capacity: its number of statements
+pub fn snippet_size<'a> (my_snippet : &Snippet<'a>) -> u64 {
+    let mut size = 0;
+    for ifblock in &my_snippet.ifblocks.ifblock_vector {
+        size += ifblock.statements.stmt_vector.len() as u64;
+    }
+    size
+}
+
+
+// Bytes of carrier header needed to send the variables of a connection to another device
+pub fn carrier_bytes<'a> (my_connection : &Connection<'a>, my_snippets : &Snippets<'a>) -> u64 {
+    let from_snippet = my_snippets.snippet_vector.iter()
+        .find(|s| s.snippet_id == my_connection.from_snippet);
+    let mut bits = 0;
+    for variable_pair in &my_connection.variable_pairs {
+        if let Some(my_snippet) = from_snippet {
+            for var_decl in &my_snippet.variable_decls.decl_vector {
+                if var_decl.identifier == variable_pair.from_var {
+                    match var_decl.var_type.var_info {
+                        VarInfo::BitArray(bit_width, var_size) => { bits += bit_width * var_size; }
+                        VarInfo::Packet(_) => {}
+                    }
+                }
+            }
+        }
+    }
+    bits.div_ceil(8)
+}
+
+
+// Hop distance between every pair of topology nodes, by breadth-first search over the links
+pub fn get_distances<'a> (my_topology : &Topology<'a>) -> HashMap<(&'a str, &'a str), u64> {
+    let mut neighbours : HashMap<&'a str, Vec<&'a str>> = HashMap::new();
+    for link in &my_topology.link_vector {
+        neighbours.entry(link.from_node.id_name).or_default().push(link.to_node.id_name);
+        neighbours.entry(link.to_node.id_name).or_default().push(link.from_node.id_name);
+    }
+
+    let mut distances = HashMap::new();
+    let nodes = my_topology.host_vector.iter().chain(my_topology.switch_vector.iter().map(|s| &s.switch_id));
+    for node in nodes {
+        let source = node.id_name;
+        let mut queue = VecDeque::new();
+        distances.insert((source, source), 0);
+        queue.push_back(source);
+        while let Some(node) = queue.pop_front() {
+            let hops = distances[&(source, node)];
+            for next in neighbours.get(node).unwrap_or(&Vec::new()) {
+                if let std::collections::hash_map::Entry::Vacant(e) = distances.entry((source, *next)) {
+                    e.insert(hops + 1);
+                    queue.push_back(*next);
+                }
+            }
+        }
+    }
+    distances
+}
+
+
+// Sum over all connections of hops * (carrier bytes + 1). Connections with an endpoint
+// that is not yet placed are ignored, as are connections within a device.
+pub fn placement_cost<'a> (my_connections : &Connections<'a>, my_snippets : &Snippets<'a>,
+                           placement : &HashMap<&'a str, Vec<&'a str>>,
+                           distances : &HashMap<(&'a str, &'a str), u64>) -> u64 {
+    let mut cost = 0;
+    for connection in &my_connections.connection_vector {
+        let from_devices = placement.get(connection.from_snippet.id_name);
+        let to_devices = placement.get(connection.to_snippet.id_name);
+        if let (Some(from_devices), Some(to_devices)) = (from_devices, to_devices) {
+            let mut hops = UNREACHABLE;
+            for from in from_devices {
+                for to in to_devices {
+                    let distance = *distances.get(&(*from, *to)).unwrap_or(&UNREACHABLE);
+                    if distance < hops { hops = distance; }
+                }
+            }
+            cost += hops * (carrier_bytes(connection, my_snippets) + 1);
+        }
+    }
+    cost
+}
+
+
+struct PlacementSearch<'s, 'a : 's> {
+    my_snippets    : &'s Snippets<'a>,
+    my_connections : &'s Connections<'a>,
+    distances      : &'s HashMap<(&'a str, &'a str), u64>,
+    capacities     : HashMap<&'a str, Option<u64>>,
+    load           : HashMap<&'a str, u64>,
+    placement      : HashMap<&'a str, Vec<&'a str>>,
+    best_cost      : Option<u64>,
+    best_placement : HashMap<&'a str, Vec<&'a str>>,
+}
+
+impl<'s, 'a> PlacementSearch<'s, 'a> {
+    fn fits(&self, device : &'a str, size : u64) -> bool {
+        match self.capacities[device] {
+            Some(capacity) => self.load[device] + size <= capacity,
+            None => true
+        }
+    }
+
+    fn assign(&mut self, snippet : &'a str, device : &'a str, size : u64) {
+        *self.load.get_mut(device).unwrap() += size;
+        self.placement.insert(snippet, vec![device]);
+    }
+
+    fn unassign(&mut self, snippet : &'a str, device : &'a str, size : u64) {
+        *self.load.get_mut(device).unwrap() -= size;
+        self.placement.remove(snippet);
+    }
+
+    fn cost(&self) -> u64 {
+        placement_cost(self.my_connections, self.my_snippets, &self.placement, self.distances)
+    }
+
+    // Try every candidate device for each free snippet in turn, keeping the cheapest placement.
+    // Ties go to the placement found first, i.e., to switches declared earlier.
+    fn search(&mut self, free : &Vec<(&'a str, Vec<&'a str>, u64)>, index : usize) {
+        if index == free.len() {
+            let cost = self.cost();
+            if self.best_cost.is_none_or(|best| cost < best) {
+                self.best_cost = Some(cost);
+                self.best_placement = self.placement.clone();
+            }
+            return;
+        }
+        let (snippet, ref candidates, size) = free[index];
+        for device in candidates {
+            if self.fits(device, size) {
+                self.assign(snippet, device, size);
+                self.search(free, index + 1);
+                self.unassign(snippet, device, size);
+            }
+        }
+    }
+
+    // Place each free snippet in source order on the device that is cheapest so far
+    fn greedy(&mut self, free : &Vec<(&'a str, Vec<&'a str>, u64)>) {
+        for &(snippet, ref candidates, size) in free {
+            let mut best : Option<(u64, &'a str)> = None;
+            for device in candidates {
+                if self.fits(device, size) {
+                    self.assign(snippet, device, size);
+                    let cost = self.cost();
+                    self.unassign(snippet, device, size);
+                    if best.is_none_or(|(best_cost, _)| cost < best_cost) {
+                        best = Some((cost, device));
+                    }
+                }
+            }
+            match best {
+                Some((_, device)) => self.assign(snippet, device, size),
+                None => panic!("No switch has room left for snippet {}.", snippet)
+            }
+        }
+        self.best_cost = Some(self.cost());
+        self.best_placement = self.placement.clone();
+    }
+}
+
+
+// Make sure node names in the topology are unique, links only join declared nodes,
+// and snippets are annotated with switches of their device type. Snippets on hosts
+// are annotated with the device type host. Without a topology, nothing is checked.
+pub fn check_topology<'a> (my_topology : &Topology<'a>, my_snippets : &Snippets<'a>) {
+    let mut node_types : HashMap<&'a str, &'a str> = HashMap::new();
+    let nodes = my_topology.host_vector.iter().map(|h| (h.id_name, "host"))
+        .chain(my_topology.switch_vector.iter().map(|s| (s.switch_id.id_name, s.device_type.id_name)));
+    for (node, node_type) in nodes {
+        if node_types.insert(node, node_type).is_some() {
+            panic!("Can't have two topology nodes named {}.", node);
+        }
+    }
+    if node_types.is_empty() {
+        return;
+    }
+    for link in &my_topology.link_vector {
+        for node in &[link.from_node, link.to_node] {
+            if !node_types.contains_key(node.id_name) {
+                panic!("Link to {}, but {} is not in the topology", node.id_name, node.id_name);
+            }
+        }
+    }
+    for my_snippet in &my_snippets.snippet_vector {
+        let snippet = my_snippet.snippet_id.id_name;
+        let device_type = my_snippet.device_annotation.device_type.id_name;
+        for device in &my_snippet.device_annotation.device_vector {
+            match node_types.get(device.id_name) {
+                None => panic!("{} placed on {}, but {} is not a switch in the topology", snippet, device.id_name, device.id_name),
+                Some(&"host") if device_type != "host" => {
+                    panic!("{} is annotated with {}, but {} is a host", snippet, device_type, device.id_name);
+                }
+                Some(&node_type) if node_type != device_type => {
+                    panic!("{} is annotated with {}, but switch {} is a {}", snippet, device_type, device.id_name, node_type);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+
+// Return a copy of the snippets in which every snippet carries a device annotation.
+// Without a topology, the snippets are returned unchanged. The placement decisions
+// are added to notes.
+pub fn place_snippets<'a> (my_topology : &Topology<'a>, my_snippets : &Snippets<'a>,
+                           my_connections : &Connections<'a>, notes : &mut Vec<String>) -> Snippets<'a> {
+    check_topology(my_topology, my_snippets);
+    if my_topology.switch_vector.is_empty() {
+        return my_snippets.clone();
+    }
+
+    let distances = get_distances(my_topology);
+    let mut search = PlacementSearch { my_snippets, my_connections, distances : &distances,
+        capacities : HashMap::new(), load : HashMap::new(), placement : HashMap::new(),
+        best_cost : None, best_placement : HashMap::new() };
+    for switch in &my_topology.switch_vector {
+        search.capacities.insert(switch.switch_id.id_name, switch.capacity);
+        search.load.insert(switch.switch_id.id_name, 0);
+    }
+
+    let mut free = Vec::new();
+    let mut search_space : u64 = 1;
+    for my_snippet in &my_snippets.snippet_vector {
+        let snippet = my_snippet.snippet_id.id_name;
+        let size = snippet_size(my_snippet);
+        let annotation = &my_snippet.device_annotation;
+        if !annotation.device_vector.is_empty() {
+            // pinned snippets take up room on every switch they are annotated with, hosts have no capacity
+            for device in &annotation.device_vector {
+                if !search.load.contains_key(device.id_name) {
+                    continue;
+                }
+                if !search.fits(device.id_name, size) {
+                    panic!("Snippet {} does not fit on {}.", snippet, device.id_name);
+                }
+                *search.load.get_mut(device.id_name).unwrap() += size;
+            }
+            search.placement.insert(snippet, annotation.device_vector.iter().map(|d| d.id_name).collect());
+        } else {
+            let device_type = annotation.device_type.id_name;
+            let candidates : Vec<&'a str> = my_topology.switch_vector.iter()
+                .filter(|s| device_type.is_empty() || s.device_type.id_name == device_type)
+                .map(|s| s.switch_id.id_name).collect();
+            if candidates.is_empty() {
+                panic!("No {} switch in the topology to place snippet {} on.", device_type, snippet);
+            }
+            search_space = search_space.saturating_mul(candidates.len() as u64);
+            free.push((snippet, candidates, size));
+        }
+    }
+
+    if search_space <= MAX_SEARCH {
+        search.search(&free, 0);
+    } else {
+        search.greedy(&free);
+    }
+    let best_cost = match search.best_cost {
+        Some(cost) => cost,
+        None => panic!("Snippets do not fit on the switches of the topology.")
+    };
+
+    let mut placed_snippets = my_snippets.clone();
+    for my_snippet in &mut placed_snippets.snippet_vector {
+        if my_snippet.device_annotation.device_vector.is_empty() {
+            let device = search.best_placement[my_snippet.snippet_id.id_name][0];
+            let switch = my_topology.switch_vector.iter().find(|s| s.switch_id.id_name == device).unwrap();
+            my_snippet.device_annotation = DeviceAnnotation { device_type : switch.device_type,
+                                                              device_vector : vec![switch.switch_id] };
+            notes.push(format!("Placed snippet {} on {}", my_snippet.snippet_id.id_name, device));
+        }
+    }
+    notes.push(format!("Placement cost: {}", best_cost));
+    placed_snippets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::lexer::get_tokens;
+    use super::super::parser::*;
+    use super::super::compile::*;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::prelude::*;
+
+    fn get_devices<'a> (my_snippets : &Snippets<'a>, snippet : &str) -> Vec<&'a str> {
+        let my_snippet = my_snippets.snippet_vector.iter().find(|s| s.snippet_id.id_name == snippet).unwrap();
+        return my_snippet.device_annotation.device_vector.iter().map(|d| d.id_name).collect();
+    }
+
+    #[test]
+    fn test_place_next_to_pinned() {
+        let input = r"topology { switch s1 : bmv2; switch s2 : bmv2; switch s3 : bmv2;
+                                 link s1 -- s2; link s2 -- s3; }
+                      @ bmv2 : s3;
+                      snippet foo() {
+                        output a : bit<32>;
+                        a = 1;
+                      }
+                      snippet fun() {
+                        input b : bit<32>;
+                        transient c : bit<32>;
+                        c = b;
+                      }
+                      (foo, fun) : a -> b,";
+        let tokens = &mut get_tokens(input);
+        let token_iter = &mut tokens.iter().peekable();
+        let parse_tree = parse_prog(token_iter);
+        let mut notes = Vec::new();
+        let placed = place_snippets(&parse_tree.topology, &parse_tree.snippets, &parse_tree.connections, &mut notes);
+        assert_eq!(get_devices(&placed, "foo"), vec!["s3"]);
+        assert_eq!(get_devices(&placed, "fun"), vec!["s3"]);
+        assert_eq!(placed.snippet_vector[1].device_annotation.device_type.id_name, "bmv2");
+        assert_eq!(notes, vec!["Placed snippet fun on s3", "Placement cost: 0"]);
+    }
+
+    #[test]
+    fn test_place_respects_capacity() {
+        let input = r"topology { switch s1 : bmv2 capacity 2; switch s2 : bmv2; switch s3 : bmv2;
+                                 link s1 -- s2; link s2 -- s3; }
+                      @ bmv2 : s1;
+                      snippet foo() {
+                        output a : bit<32>;
+                        a = 1;
+                        a = 2;
+                      }
+                      snippet fun() {
+                        input b : bit<32>;
+                        transient c : bit<32>;
+                        c = b;
+                      }
+                      (foo, fun) : a -> b,";
+        let tokens = &mut get_tokens(input);
+        let token_iter = &mut tokens.iter().peekable();
+        let parse_tree = parse_prog(token_iter);
+        let placed = place_snippets(&parse_tree.topology, &parse_tree.snippets, &parse_tree.connections, &mut Vec::new());
+        assert_eq!(get_devices(&placed, "fun"), vec!["s2"]);
+    }
+
+    #[test]
+    fn test_place_within_capacity_over_budget() {
+        let dir = env::temp_dir().join("sluice_place_over_budget");
+        fs::create_dir_all(&dir).unwrap();
+        File::create(dir.join("budgets.json")).unwrap().write_all(br#"{"bmv2" : {"tables" : 1}}"#).unwrap();
+        let mut options = CompileOptions::new();
+        options.include_dirs.insert(0, dir.display().to_string());
+        let input = r"topology { host h1; switch s1 : bmv2 capacity 2; link h1 -- s1; }
+                      snippet foo() {
+                        persistent r : bit<32> = 0;
+                        r = r + 1;
+                      }
+                      snippet fun() {
+                        persistent q : bit<32> = 0;
+                        q = q + 2;
+                      }";
+        // both snippets fit the two statements of s1, but not the table budget of bmv2
+        assert_eq!(compile(input, &options).unwrap_err()[0].message,
+                   "Device program foo_fun needs 2 tables, but the bmv2 budget is 1.");
+    }
+
+    #[test]
+    #[should_panic(expected="No tofino switch in the topology to place snippet fun on.")]
+    fn test_place_no_device_type_fail() {
+        let input = r"topology { switch s1 : bmv2; }
+                      @ tofino : ;
+                      snippet fun() {
+                        transient c : bit<32>;
+                        c = 1;
+                      }";
+        let tokens = &mut get_tokens(input);
+        let token_iter = &mut tokens.iter().peekable();
+        let parse_tree = parse_prog(token_iter);
+        place_snippets(&parse_tree.topology, &parse_tree.snippets, &parse_tree.connections, &mut Vec::new());
+    }
+
+    fn topology_error(input : &str) -> String {
+        return compile(input, &CompileOptions::new()).unwrap_err()[0].message.clone();
+    }
+
+    #[test]
+    fn test_check_topology() {
+        let snippet = r"snippet fun() {
+                          transient a : bit<32>;
+                          a = 1;
+                        }";
+        assert_eq!(topology_error(&format!("topology {{ switch s1 : bmv2; }} @ tofino : s9; {}", snippet)),
+                   "fun placed on s9, but s9 is not a switch in the topology");
+        assert_eq!(topology_error(&format!("topology {{ host h1; switch s1 : tofino; link h1 -- s1; }} @ bmv2 : s1; {}", snippet)),
+                   "fun is annotated with bmv2, but switch s1 is a tofino");
+        assert_eq!(topology_error(&format!("topology {{ host h1; switch s1 : bmv2; }} @ bmv2 : h1; {}", snippet)),
+                   "fun is annotated with bmv2, but h1 is a host");
+        assert_eq!(topology_error(&format!("topology {{ host h1; switch s1 : bmv2; link h1 -- s2; }} {}", snippet)),
+                   "Link to s2, but s2 is not in the topology");
+        assert_eq!(topology_error(&format!("topology {{ host s1; switch s1 : bmv2; }} {}", snippet)),
+                   "Can't have two topology nodes named s1.");
+        let input = format!("topology {{ host h1; switch s1 : bmv2; switch s2 : bmv2; link h1 -- s1; link s1 -- s2; }}
+                             @ bmv2 : s1, s2; {}", snippet);
+        assert!(compile(&input, &CompileOptions::new()).is_ok());
+    }
+}
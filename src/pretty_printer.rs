@@ -1,6 +1,11 @@
+// Prints a parse tree back as sluice source in canonical form, one declaration
+// or statement per line, indented by 4 spaces. Reparsing the output gives the
+// same parse tree. This is what sluice fmt prints.
 use grammar::*;
 use tree_fold::TreeFold;
 
+const TAB : &str = "    ";
+
 pub struct PrettyPrinter {
   pretty_print_str : String,
 }
@@ -9,51 +14,235 @@ impl PrettyPrinter {
   pub fn new() -> PrettyPrinter {
     PrettyPrinter{ pretty_print_str : "".to_string() }
   }
+
+  pub fn get_str(&self) -> &str {
+    return &self.pretty_print_str;
+  }
+
+  // Separates the sections of the program by a blank line
+  fn start_section(&mut self) {
+    if !self.pretty_print_str.is_empty() && !self.pretty_print_str.ends_with("\n\n") {
+      self.pretty_print_str.push_str("\n");
+    }
+  }
+
+  fn push_operand(&mut self, operand : &Operand) {
+    match operand {
+      &Operand::Value(ref value) => self.pretty_print_str.push_str(&value.get_string()),
+      &Operand::LValue(ref lvalue) => self.push_lvalue(lvalue),
+    }
+  }
+
+  fn push_lvalue(&mut self, lvalue : &LValue) {
+    match lvalue {
+      &LValue::Scalar(ref id) => self.pretty_print_str.push_str(id.get_str()),
+      &LValue::Array(ref id, ref address) => {
+        self.pretty_print_str.push_str(id.get_str());
+        self.pretty_print_str.push_str("[");
+        self.push_operand(address);
+        self.pretty_print_str.push_str("]");
+      }
+      &LValue::Field(ref id, ref field_name) => {
+        self.pretty_print_str.push_str(id.get_str());
+        self.pretty_print_str.push_str(".");
+        self.pretty_print_str.push_str(field_name.get_str());
+      }
+    }
+  }
+
+  fn push_var_info(&mut self, var_info : &VarInfo) {
+    match var_info {
+      &VarInfo::BitArray(bit_width, 1) => self.pretty_print_str.push_str(&format!("bit<{}>", bit_width)),
+      &VarInfo::BitArray(bit_width, var_size) => self.pretty_print_str.push_str(&format!("bit<{}>[{}]", bit_width, var_size)),
+      &VarInfo::Packet(ref packet_type) => self.pretty_print_str.push_str(packet_type.get_str()),
+    }
+  }
+
+  // Prints the assertions checked after the first position statements of a block
+  fn push_assertions(&mut self, statements : &Statements, position : usize, indent : &str) {
+    for assertion in statements.assert_vector.iter().filter(|a| a.position == position) {
+      self.pretty_print_str.push_str(&format!("{}assert(", indent));
+      self.visit_expr(&assertion.condition.expr);
+      self.pretty_print_str.push_str(");\n");
+    }
+  }
+
+  fn push_statements(&mut self, statements : &Statements, indent : &str) {
+    for (position, stmt) in statements.stmt_vector.iter().enumerate() {
+      self.push_assertions(statements, position, indent);
+      self.pretty_print_str.push_str(indent);
+      self.visit_statement(stmt);
+    }
+    self.push_assertions(statements, statements.stmt_vector.len(), indent);
+  }
+
+  fn push_identifiers(&mut self, ids : &Vec<Identifier>) {
+    let names : Vec<&str> = ids.iter().map(|id| id.get_str()).collect();
+    self.pretty_print_str.push_str(&names.join(", "));
+  }
+}
+
+// Source of an expression, as used in the messages about assertions
+pub fn expr_string(expr : &Expr) -> String {
+  let mut pretty_printer = PrettyPrinter::new();
+  pretty_printer.visit_expr(expr);
+  return pretty_printer.pretty_print_str;
 }
 
 impl<'a> TreeFold<'a> for PrettyPrinter {
+  fn visit_prog(&mut self, tree : &'a Prog) {
+    for import in &tree.imports.import_vector {
+      if import.import_type.get_str() == "file" {
+        self.pretty_print_str.push_str(&format!("import \"{}\"", import.import_id.get_str()));
+        if let Some(ref alias) = import.alias {
+          self.pretty_print_str.push_str(&format!(" as {}", alias.get_str()));
+        }
+        self.pretty_print_str.push_str(";\n");
+      } else {
+        self.pretty_print_str.push_str(&format!("import device {};\n", import.import_id.get_str()));
+      }
+    }
+    self.start_section();
+    self.visit_globals(&tree.globals);
+    self.start_section();
+    self.visit_packets(&tree.packets);
+    self.visit_topology(&tree.topology);
+    self.visit_snippets(&tree.snippets);
+    self.start_section();
+    self.visit_pipelines(&tree.pipelines);
+    self.start_section();
+    self.visit_connections(&tree.connections);
+    while self.pretty_print_str.ends_with("\n\n") {
+      self.pretty_print_str.pop();
+    }
+  }
+
+  fn visit_packet(&mut self, tree : &'a Packet) {
+    self.start_section();
+    self.pretty_print_str.push_str(&format!("packet {} : {} (", tree.packet_id.get_str(), tree.packet_base.get_str()));
+    match &tree.packet_parser_condition {
+      &PacketParserCondition::ParserCondition(ref field, ref value) => {
+        self.pretty_print_str.push_str(&format!("{} : {}", field.get_str(), value.get_string()));
+      }
+      &PacketParserCondition::Empty() => {}
+    }
+    self.pretty_print_str.push_str(") {\n");
+    self.visit_packet_fields(&tree.packet_fields);
+    self.pretty_print_str.push_str("}\n");
+  }
+
+  fn visit_packet_field(&mut self, tree : &'a PacketField) {
+    self.pretty_print_str.push_str(&format!("{}{} : ", TAB, tree.identifier.get_str()));
+    self.push_var_info(&tree.var_type.var_info);
+    self.pretty_print_str.push_str(";\n");
+  }
+
+  fn visit_topology(&mut self, tree : &'a Topology) {
+    if tree.host_vector.is_empty() && tree.switch_vector.is_empty() && tree.link_vector.is_empty() {
+      return;
+    }
+    self.start_section();
+    self.pretty_print_str.push_str("topology {\n");
+    for host in &tree.host_vector {
+      self.pretty_print_str.push_str(&format!("{}host {};\n", TAB, host.get_str()));
+    }
+    for switch in &tree.switch_vector {
+      self.pretty_print_str.push_str(&format!("{}switch {} : {}", TAB, switch.switch_id.get_str(), switch.device_type.get_str()));
+      if let Some(capacity) = switch.capacity {
+        self.pretty_print_str.push_str(&format!(" capacity {}", capacity));
+      }
+      self.pretty_print_str.push_str(";\n");
+    }
+    for link in &tree.link_vector {
+      self.pretty_print_str.push_str(&format!("{}link {} -- {};\n", TAB, link.from_node.get_str(), link.to_node.get_str()));
+    }
+    self.pretty_print_str.push_str("}\n");
+  }
+
   fn visit_snippet(&mut self, tree : &'a Snippet) {
+    self.start_section();
+    if tree.device_annotation.device_vector.is_empty() && !tree.device_annotation.device_type.get_str().is_empty() {
+      self.pretty_print_str.push_str(&format!("@ {}\n", tree.device_annotation.device_type.get_str()));
+    } else if !tree.device_annotation.device_type.get_str().is_empty() {
+      self.pretty_print_str.push_str(&format!("@ {} : ", tree.device_annotation.device_type.get_str()));
+      self.push_identifiers(&tree.device_annotation.device_vector);
+      self.pretty_print_str.push_str(";\n");
+    }
     self.pretty_print_str.push_str("snippet ");
     self.pretty_print_str.push_str(tree.snippet_id.get_str());
-    self.pretty_print_str.push_str("() {");
-    self.visit_variable_decls(&tree.variable_decls);
+    self.pretty_print_str.push_str("() {\n");
+    for decl in &tree.variable_decls.decl_vector {
+      self.pretty_print_str.push_str(TAB);
+      self.visit_variable_decl(decl);
+    }
+    for invariant in &tree.invariants {
+      self.pretty_print_str.push_str(&format!("{}invariant {} : ", TAB, invariant.identifier.get_str()));
+      self.visit_condition(&invariant.condition);
+      self.pretty_print_str.push_str(";\n");
+    }
     self.visit_ifblocks(&tree.ifblocks);
-    self.pretty_print_str.push_str("}");
+    self.pretty_print_str.push_str("}\n");
+  }
+
+  fn visit_ifblock(&mut self, tree : &'a IfBlock) {
+    match tree.condtype {
+      1 => {
+        self.pretty_print_str.push_str(&format!("{}if (", TAB));
+        self.visit_condition(&tree.condition);
+        self.pretty_print_str.push_str(") {\n");
+      }
+      2 => {
+        // else continues the closing brace of the if block
+        self.pretty_print_str.pop();
+        self.pretty_print_str.push_str(" else {\n");
+      }
+      _ => {
+        self.push_statements(&tree.statements, TAB);
+        return;
+      }
+    }
+    self.push_statements(&tree.statements, &format!("{}{}", TAB, TAB));
+    self.pretty_print_str.push_str(&format!("{}}}\n", TAB));
+  }
+
+  fn visit_pipeline(&mut self, tree : &'a Pipeline) {
+    self.pretty_print_str.push_str(&format!("pipeline {} : ", tree.device_id.get_str()));
+    self.push_identifiers(&tree.snippet_vector);
+    self.pretty_print_str.push_str(";\n");
   }
 
   fn visit_connection(&mut self, tree : &'a Connection) {
     self.pretty_print_str.push_str("(");
     self.pretty_print_str.push_str(tree.from_snippet.get_str());
-    self.pretty_print_str.push_str(",");
+    self.pretty_print_str.push_str(", ");
     self.pretty_print_str.push_str(tree.to_snippet.get_str());
+    self.pretty_print_str.push_str(")");
     if !tree.variable_pairs.is_empty() {
-      self.pretty_print_str.push_str(":");
+      self.pretty_print_str.push_str(" : ");
       for pair in &tree.variable_pairs {
-        self.pretty_print_str.push_str("(");
         self.pretty_print_str.push_str(pair.from_var.get_str());
-        self.pretty_print_str.push_str("->");
+        self.pretty_print_str.push_str(" -> ");
         self.pretty_print_str.push_str(pair.to_var.get_str());
-        self.pretty_print_str.push_str("),");
+        self.pretty_print_str.push_str(", ");
       }
+      self.pretty_print_str.pop();
     }
-    self.pretty_print_str.push_str(")");
+    self.pretty_print_str.push_str("\n");
   }
 
   fn visit_statement(&mut self, tree : &'a Statement) {
-    self.pretty_print_str.push_str(&tree.lvalue.get_string());
+    self.push_lvalue(&tree.lvalue);
     self.pretty_print_str.push_str(" = ");
     self.visit_expr(&tree.expr);
-    self.pretty_print_str.push_str(";");
+    self.pretty_print_str.push_str(";\n");
   }
 
   fn visit_condition(&mut self, tree : &'a Condition) {
-    // check that the condition isnt a dummyCondition
-    if tree.expr.expr_right == ExprRight::Empty() && tree.expr.op1 == Operand::Value(Value {value : 1}) { }
-    else {self.visit_expr(&tree.expr);}
+    self.visit_expr(&tree.expr);
   }
 
   fn visit_expr(&mut self, tree : &'a Expr) {
-    self.pretty_print_str.push_str(&tree.op1.get_string());
+    self.push_operand(&tree.op1);
     match &tree.expr_right {
       ExprRight::Empty() => {},
       ExprRight::BinOp(btype, op2) => {
@@ -65,7 +254,7 @@ impl<'a> TreeFold<'a> for PrettyPrinter {
           BinOpType::Mul        => self.pretty_print_str.push_str(" * "),
           BinOpType::Div        => self.pretty_print_str.push_str(" / "),
           BinOpType::Modulo     => self.pretty_print_str.push_str(" % "),
-          BinOpType::Equal      => self.pretty_print_str.push_str(" = "),
+          BinOpType::Equal      => self.pretty_print_str.push_str(" == "),
           BinOpType::NotEqual   => self.pretty_print_str.push_str(" != "),
           BinOpType::LTEQOp     => self.pretty_print_str.push_str(" <= "),
           BinOpType::GTEQOp     => self.pretty_print_str.push_str(" >= "),
@@ -74,13 +263,13 @@ impl<'a> TreeFold<'a> for PrettyPrinter {
           BinOpType::ShiftLeft  => self.pretty_print_str.push_str(" << "),
           BinOpType::ShiftRight => self.pretty_print_str.push_str(" >> "),
         };
-        self.pretty_print_str.push_str(&op2.get_string());
+        self.push_operand(op2);
       },
       ExprRight::Cond(op_true, op_false) => {
         self.pretty_print_str.push_str(" ? ");
-        self.pretty_print_str.push_str(&op_true.get_string());
+        self.push_operand(op_true);
         self.pretty_print_str.push_str(" : ");
-        self.pretty_print_str.push_str(&op_false.get_string());
+        self.push_operand(op_false);
       }
     }
   }
@@ -98,32 +287,22 @@ impl<'a> TreeFold<'a> for PrettyPrinter {
       });
     self.pretty_print_str.push_str(" ");
     self.pretty_print_str.push_str(tree.identifier.get_str());
-    self.pretty_print_str.push_str(" : bit<");
-
-    let varinfo =  &tree.var_type.var_info;
-    match varinfo {
-      VarInfo::BitArray(bit_width, var_size) => {
-        self.pretty_print_str.push_str(&bit_width.to_string());
-        self.pretty_print_str.push_str(">[");
-        self.pretty_print_str.push_str(&var_size.to_string());
-      }
-
-      VarInfo::Packet(_) => {}
-      // VarInfo::Packet(_, _) => {}
-    }
+    self.pretty_print_str.push_str(" : ");
+    self.push_var_info(&tree.var_type.var_info);
 
-    self.pretty_print_str.push_str("]");
-    if tree.initial_values.is_empty() {
-      self.pretty_print_str.push_str(";");
-    } else {
+    if tree.initial_values.len() == 1 {
+      self.pretty_print_str.push_str(" = ");
+      self.pretty_print_str.push_str(&tree.initial_values[0].get_string());
+    } else if !tree.initial_values.is_empty() {
       self.pretty_print_str.push_str(" = {");
       for val in &tree.initial_values {
         self.pretty_print_str.push_str(&val.get_string());
         self.pretty_print_str.push_str(", ");
       }
+      self.pretty_print_str.pop();
       self.pretty_print_str.push_str("}");
-      self.pretty_print_str.push_str(";");
     }
+    self.pretty_print_str.push_str(";\n");
   }
 }
 
@@ -147,10 +326,10 @@ mod tests {
     // Run pretty printer
     let mut pretty_printer = PrettyPrinter::new();
     pretty_printer.visit_prog(&parse_tree);
-    println!("Pretty printed code: {}", pretty_printer.pretty_print_str);
+    println!("Pretty printed code: {}", pretty_printer.get_str());
 
     // Reparse pretty printed code
-    let new_tokens = &mut lexer::get_tokens(&pretty_printer.pretty_print_str);
+    let new_tokens = &mut lexer::get_tokens(pretty_printer.get_str());
     let new_token_iter = &mut new_tokens.iter().peekable();
     let new_parse_tree = parser::parse_prog(new_token_iter);
     assert!(new_token_iter.peek().is_none(), "new_token_iter is not empty.");
@@ -194,4 +373,78 @@ mod tests {
                           ";
     run_pretty_printer_and_reparse(input_program);
   }
+
+  #[test]
+  fn test_pretty_printer_format(){
+    let input_program = r"global threshold:bit<32> =111;
+                          topology { host h1; switch s1 : bmv2 capacity 4; link h1 -- s1; }
+                          @ bmv2 : s1,;
+                          snippet fun() { transient z : bit<1>; persistent cnt : bit<32>[2] = {0, 0,};
+                            z = threshold > 10; if (z == 1) { cnt[0] = cnt[0] + 1; } else { cnt[1] = 7; } }
+                          @ bmv2
+                          snippet foo() { input a : bit<1>; }
+                          pipeline s1 : fun, foo;
+                          (fun, foo):z->a,";
+    let tokens = & mut lexer::get_tokens(input_program);
+    let token_iter = & mut tokens.iter().peekable();
+    let parse_tree = parser::parse_prog(token_iter);
+    let mut pretty_printer = PrettyPrinter::new();
+    pretty_printer.visit_prog(&parse_tree);
+    assert_eq!(pretty_printer.get_str(), "global threshold : bit<32> = 111;
+
+topology {
+    host h1;
+    switch s1 : bmv2 capacity 4;
+    link h1 -- s1;
+}
+
+@ bmv2 : s1;
+snippet fun() {
+    transient z : bit<1>;
+    persistent cnt : bit<32>[2] = {0, 0,};
+    z = threshold > 10;
+    if (z == 1) {
+        cnt[0] = cnt[0] + 1;
+    } else {
+        cnt[1] = 7;
+    }
+}
+
+@ bmv2
+snippet foo() {
+    input a : bit<1>;
+}
+
+pipeline s1 : fun, foo;
+
+(fun, foo) : z -> a,
+");
+    run_pretty_printer_and_reparse(input_program);
+  }
+
+  #[test]
+  fn test_pretty_printer_assertions(){
+    let input_program = r"snippet fun() { input a : bit<8>; transient z : bit<8>; invariant positive : z > 0;
+                            assert(a < 100); z = a + 1; if (z == 1) { assert(a == 0); } else { z = 2; assert(z != a); } }";
+    let tokens = & mut lexer::get_tokens(input_program);
+    let token_iter = & mut tokens.iter().peekable();
+    let parse_tree = parser::parse_prog(token_iter);
+    let mut pretty_printer = PrettyPrinter::new();
+    pretty_printer.visit_prog(&parse_tree);
+    assert_eq!(pretty_printer.get_str(), "snippet fun() {
+    input a : bit<8>;
+    transient z : bit<8>;
+    invariant positive : z > 0;
+    assert(a < 100);
+    z = a + 1;
+    if (z == 1) {
+        assert(a == 0);
+    } else {
+        z = 2;
+        assert(z != a);
+    }
+}
+");
+    run_pretty_printer_and_reparse(input_program);
+  }
 }
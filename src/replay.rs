@@ -0,0 +1,338 @@
+// Offline replay of captured packets through a snippet. Packets are parsed with
+// the headers of packet.np and the user's packet declarations, following the
+// parser bmv2_gen generates, run through the interpreter and written back with
+// the modified field values. Each packet also gets a line of trace with the
+// fields it changed, the register state after it and the assertions it failed.
+use grammar::*;
+use interpreter::*;
+use pcap::*;
+use compile::*;
+use imports::{ImportArena, resolve_imports};
+use lexer;
+use parser;
+use serde_json;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+const ETHERTYPE_IPV4 : u64 = 0x0800;
+const IP_PROTOCOLS_TCP : u64 = 6;
+const IP_PROTOCOLS_UDP : u64 = 17;
+
+// Device fields set for every packet
+pub const TIMESTAMP_FIELD : &str = "standard_metadata.ingress_global_timestamp";
+pub const LENGTH_FIELD : &str = "standard_metadata.packet_length";
+
+struct Transition {
+    header : String,
+    field  : String,
+    value  : u64,
+    next   : String,
+}
+
+pub struct PacketParser {
+    // fields of each header with their widths, in wire order
+    headers     : HashMap<String, Vec<(String, u64)>>,
+    transitions : Vec<Transition>,
+}
+
+fn read_bits(data : &[u8], bit_offset : usize, width : u64) -> u64 {
+    let mut value = 0;
+    for bit in bit_offset..bit_offset + width as usize {
+        value = (value << 1) | ((data[bit / 8] >> (7 - bit % 8)) & 1) as u64;
+    }
+    value
+}
+
+fn write_bits(data : &mut [u8], bit_offset : usize, width : u64, value : u64) {
+    for i in 0..width as usize {
+        let bit = bit_offset + i;
+        let mask = 1 << (7 - bit % 8);
+        if (value >> (width as usize - 1 - i)) & 1 == 1 {
+            data[bit / 8] |= mask;
+        } else {
+            data[bit / 8] &= !mask;
+        }
+    }
+}
+
+// One's complement checksum of an ipv4 header, with the checksum field zeroed
+fn ipv4_checksum(header : &[u8]) -> u16 {
+    let mut sum : u32 = 0;
+    for i in 0..header.len() / 2 {
+        if i != 5 {
+            sum += ((header[2 * i] as u32) << 8) | header[2 * i + 1] as u32;
+        }
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+impl PacketParser {
+    // my_packets are the user's packets, pkt_tree the headers of packet.np
+    pub fn new<'a>(my_packets : &Packets<'a>, pkt_tree : &Packets<'a>) -> PacketParser {
+        let mut headers = HashMap::new();
+        let mut transitions = vec![
+            Transition { header : "ethernet".to_string(), field : "etherType".to_string(), value : ETHERTYPE_IPV4, next : "ipv4".to_string() },
+            Transition { header : "ipv4".to_string(), field : "protocol".to_string(), value : IP_PROTOCOLS_TCP, next : "tcp".to_string() },
+            Transition { header : "ipv4".to_string(), field : "protocol".to_string(), value : IP_PROTOCOLS_UDP, next : "udp".to_string() }];
+        for my_packet in pkt_tree.packet_vector.iter().chain(my_packets.packet_vector.iter()) {
+            let name = my_packet.packet_id.id_name;
+            let mut fields = Vec::new();
+            for my_field in &my_packet.packet_fields.field_vector {
+                match my_field.var_type.var_info {
+                    VarInfo::BitArray(bit_width, _) if bit_width <= 64 => fields.push((my_field.identifier.id_name.to_string(), bit_width)),
+                    _ => panic!("Field {}.{} can't be replayed, only bit vectors of at most 64 bits can.", name, my_field.identifier.id_name),
+                }
+            }
+            if fields.iter().map(|&(_, bit_width)| bit_width).sum::<u64>() % 8 != 0 {
+                panic!("Header {} is not a whole number of bytes.", name);
+            }
+            headers.insert(name.to_string(), fields);
+            match my_packet.packet_parser_condition {
+                PacketParserCondition::ParserCondition(ref field, ref value) =>
+                    transitions.push(Transition { header : my_packet.packet_base.id_name.to_string(), field : field.id_name.to_string(),
+                                                  value : value.value, next : name.to_string() }),
+                PacketParserCondition::Empty() => {}
+            }
+        }
+        PacketParser { headers, transitions }
+    }
+
+    pub fn has_header(&self, name : &str) -> bool {
+        self.headers.contains_key(name)
+    }
+
+    fn header_bytes(&self, name : &str) -> usize {
+        self.headers[name].iter().map(|&(_, bit_width)| bit_width).sum::<u64>() as usize / 8
+    }
+
+    // Extracts the headers at the front of data. Returns their names in order and their fields, keyed header.field.
+    pub fn parse(&self, data : &[u8]) -> (Vec<String>, FieldMap) {
+        let mut header_names = Vec::new();
+        let mut fields = FieldMap::new();
+        let mut offset = 0;
+        let mut next = Some("ethernet".to_string());
+        while let Some(name) = next {
+            if !self.headers.contains_key(&name) || data.len() < offset + self.header_bytes(&name) {
+                break;
+            }
+            let mut bit_offset = offset * 8;
+            for &(ref field, bit_width) in &self.headers[&name] {
+                fields.insert(format!("{}.{}", name, field), read_bits(data, bit_offset, bit_width));
+                bit_offset += bit_width as usize;
+            }
+            offset += self.header_bytes(&name);
+            next = self.transitions.iter()
+                .find(|t| t.header == name && fields.get(&format!("{}.{}", name, t.field)) == Some(&t.value))
+                .map(|t| t.next.clone());
+            header_names.push(name);
+        }
+        (header_names, fields)
+    }
+
+    // Writes the field values of the parsed headers back into data. The ipv4 checksum is
+    // recomputed, like the calculated_field of the generated P4.
+    pub fn deparse(&self, header_names : &Vec<String>, fields : &FieldMap, data : &[u8]) -> Vec<u8> {
+        let mut new_data = data.to_vec();
+        let mut offset = 0;
+        for name in header_names {
+            let mut bit_offset = offset * 8;
+            for &(ref field, bit_width) in &self.headers[name] {
+                write_bits(&mut new_data, bit_offset, bit_width, fields[&format!("{}.{}", name, field)]);
+                bit_offset += bit_width as usize;
+            }
+            let size = self.header_bytes(name);
+            if name == "ipv4" && size == 20 {
+                let checksum = ipv4_checksum(&new_data[offset..offset + size]);
+                new_data[offset + 10] = (checksum >> 8) as u8;
+                new_data[offset + 11] = checksum as u8;
+            }
+            offset += size;
+        }
+        new_data
+    }
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct ReplayOutput {
+    pub snippet_name : String,
+    pub pcap         : Pcap,
+    // one JSON object per packet
+    pub trace        : Vec<serde_json::Value>,
+}
+
+// Headers whose fields the snippet reads or writes
+fn snippet_headers<'a> (my_snippet : &Snippet<'a>, parser : &PacketParser) -> HashSet<&'a str> {
+    fn add_operand<'a> (operand : &Operand<'a>, parser : &PacketParser, headers : &mut HashSet<&'a str>) {
+        match operand {
+            Operand::LValue(lvalue) => add_lvalue(lvalue, parser, headers),
+            &Operand::Value(_) => {}
+        }
+    }
+    fn add_lvalue<'a> (lvalue : &LValue<'a>, parser : &PacketParser, headers : &mut HashSet<&'a str>) {
+        match lvalue {
+            LValue::Field(id, _) if parser.has_header(id.id_name) => { headers.insert(id.id_name); }
+            LValue::Array(_, address) => add_operand(address, parser, headers),
+            _ => {}
+        }
+    }
+    fn add_expr<'a> (expr : &Expr<'a>, parser : &PacketParser, headers : &mut HashSet<&'a str>) {
+        add_operand(&expr.op1, parser, headers);
+        match expr.expr_right {
+            ExprRight::BinOp(_, ref operand) => add_operand(operand, parser, headers),
+            ExprRight::Cond(ref operand1, ref operand2) => {
+                add_operand(operand1, parser, headers);
+                add_operand(operand2, parser, headers);
+            }
+            ExprRight::Empty() => {}
+        }
+    }
+
+    let mut headers = HashSet::new();
+    for ifblock in &my_snippet.ifblocks.ifblock_vector {
+        add_expr(&ifblock.condition.expr, parser, &mut headers);
+        for my_statement in &ifblock.statements.stmt_vector {
+            add_lvalue(&my_statement.lvalue, parser, &mut headers);
+            add_expr(&my_statement.expr, parser, &mut headers);
+        }
+        for assertion in &ifblock.statements.assert_vector {
+            add_expr(&assertion.condition.expr, parser, &mut headers);
+        }
+    }
+    for invariant in &my_snippet.invariants {
+        add_expr(&invariant.condition.expr, parser, &mut headers);
+    }
+    headers
+}
+
+// Runs every packet of the capture through the snippet. Packets without the headers
+// the snippet uses are passed through unchanged, as the generated parser would.
+pub fn replay_snippet<'a> (my_snippet : &Snippet<'a>, my_globals : &Globals<'a>, my_packets : &Packets<'a>,
+                           pkt_tree : &Packets<'a>, input : &Pcap) -> ReplayOutput {
+    let parser = PacketParser::new(my_packets, pkt_tree);
+    let used_headers = snippet_headers(my_snippet, &parser);
+    let mut interpreter = Interpreter::new(my_snippet, my_globals, my_packets);
+    interpreter.add_headers(pkt_tree);
+    let mut packets = Vec::new();
+    let mut trace = Vec::new();
+
+    for (index, packet) in input.packets.iter().enumerate() {
+        let (header_names, fields) = parser.parse(&packet.data);
+        let skipped = used_headers.iter().any(|h| !header_names.iter().any(|n| n == h));
+        let mut modified = serde_json::Map::new();
+        let mut violations = Vec::new();
+        let mut new_packet = packet.clone();
+        if !skipped {
+            let mut input_fields = fields.clone();
+            input_fields.insert(TIMESTAMP_FIELD.to_string(), packet.timestamp(input.nanosecond));
+            input_fields.insert(LENGTH_FIELD.to_string(), packet.orig_len as u64);
+            let output = interpreter.process(&input_fields);
+            new_packet.data = parser.deparse(&header_names, &output.fields, &packet.data);
+            let (_, new_fields) = parser.parse(&new_packet.data);
+            for (field, value) in &fields {
+                match new_fields.get(field) {
+                    Some(new_value) if new_value != value => { modified.insert(field.clone(), json!(new_value)); }
+                    _ => {}
+                }
+            }
+            violations = output.violations;
+        }
+        trace.push(json!({
+            "packet"    : index,
+            "timestamp" : packet.timestamp(input.nanosecond),
+            "headers"   : header_names,
+            "skipped"   : skipped,
+            "modified"  : modified,
+            "registers" : interpreter.registers(),
+            "violations" : violations,
+        }));
+        packets.push(new_packet);
+    }
+    ReplayOutput { snippet_name : my_snippet.snippet_id.id_name.to_string(),
+                   pcap : Pcap { nanosecond : input.nanosecond, snaplen : input.snaplen, packets }, trace }
+}
+
+// Replays a capture through snippet snippet_name of a program, or its only snippet
+pub fn replay(source : &str, snippet_name : Option<&str>, input : &Pcap, options : &CompileOptions) -> Result<ReplayOutput, Vec<CompileError>> {
+    catch_errors(|| {
+        let tokens = &mut lexer::get_tokens(source);
+        let token_iter = &mut tokens.iter().peekable();
+        let parse_tree = parser::parse_prog(token_iter);
+        assert!(token_iter.peek().is_none(), "Token iterator is not empty.");
+        let mut import_arena = ImportArena::new();
+        let parse_tree = resolve_imports(parse_tree, options.source_path.as_deref(), &options.include_dirs, &mut import_arena);
+
+        let contents = read_include_file(&options.include_dirs, "packet.np");
+        let pkt_tokens = &mut lexer::get_tokens(&contents);
+        let pkt_iter = &mut pkt_tokens.iter().peekable();
+        let pkt_tree = parser::parse_import_packets(pkt_iter);
+
+        let snippets = &parse_tree.snippets.snippet_vector;
+        let my_snippet = match snippet_name {
+            Some(name) => match snippets.iter().find(|s| s.snippet_id.id_name == name) {
+                Some(my_snippet) => my_snippet,
+                None => panic!("Snippet {} not found.", name),
+            },
+            None if snippets.len() == 1 => &snippets[0],
+            None => panic!("The program has {} snippets, pick the one to replay with --snippet.", snippets.len()),
+        };
+        replay_snippet(my_snippet, &parse_tree.globals, &parse_tree.packets, &pkt_tree, input)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ethernet header with the given etherType, followed by a payload
+    fn ethernet_packet(ether_type : u16, payload : Vec<u8>) -> PcapPacket {
+        let mut data = vec![0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 1, (ether_type >> 8) as u8, ether_type as u8];
+        data.extend(payload);
+        return PcapPacket { ts_sec : 1, ts_frac : 10, orig_len : data.len() as u32, data };
+    }
+
+    #[test]
+    fn test_replay_user_packet() {
+        let input = r"packet n : ethernet (etherType : 1234) {
+                        new_one : bit<32>;
+                        flag : bit<8>;
+                      }
+                      snippet fun() {
+                        persistent cnt : bit<32> = 0;
+                        cnt = cnt + 1;
+                        n.new_one = cnt;
+                      }";
+        let pcap = Pcap { nanosecond : false, snaplen : 65535,
+                          packets : vec![ethernet_packet(1234, vec![0, 0, 0, 9, 7, 0xaa]),
+                                         ethernet_packet(0x86dd, vec![0, 0, 0, 9, 7, 0xaa]),
+                                         ethernet_packet(1234, vec![0, 0, 0, 9, 7])] };
+        let output = replay(input, None, &pcap, &CompileOptions::new()).unwrap();
+        assert_eq!(output.snippet_name, "fun");
+        // the new_one field is rewritten, the flag and the payload are kept
+        assert_eq!(&output.pcap.packets[0].data[14..], &[0, 0, 0, 1, 7, 0xaa]);
+        assert_eq!(output.pcap.packets[1], pcap.packets[1]);
+        assert_eq!(output.pcap.packets[2].data[14..], [0, 0, 0, 2, 7]);
+        assert_eq!(output.trace[0]["headers"], json!(["ethernet", "n"]));
+        assert_eq!(output.trace[0]["modified"], json!({"n.new_one" : 1}));
+        assert_eq!(output.trace[1]["skipped"], json!(true));
+        assert_eq!(output.trace[2]["registers"], json!({"cnt" : [2]}));
+    }
+
+    #[test]
+    fn test_replay_ipv4_checksum() {
+        let input = r"snippet fun() {
+                        ipv4.ttl = ipv4.ttl - 1;
+                      }";
+        let ipv4 = vec![0x45, 0, 0, 0x1c, 0, 0, 0, 0, 0x40, 0x11, 0x66, 0xcf, 10, 0, 0, 1, 10, 0, 0, 2];
+        let pcap = Pcap { nanosecond : false, snaplen : 65535, packets : vec![ethernet_packet(0x0800, ipv4)] };
+        let output = replay(input, Some("fun"), &pcap, &CompileOptions::new()).unwrap();
+        let new_ipv4 = &output.pcap.packets[0].data[14..];
+        assert_eq!(new_ipv4[8], 0x3f);
+        assert_eq!(ipv4_checksum(new_ipv4), ((new_ipv4[10] as u16) << 8) | new_ipv4[11] as u16);
+        assert_eq!(output.trace[0]["modified"], json!({"ipv4.hdrChecksum" : 0x67cf, "ipv4.ttl" : 0x3f}));
+    }
+}
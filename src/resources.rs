@@ -0,0 +1,178 @@
+// Resource estimates for the P4 code generated for each snippet, and the
+// per-target budgets they and the merged device programs are checked against
+// before deployment.
+use serde_json;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use trans_snippet::*;
+use dependency::*;
+
+const BUDGET_KEYS : [&str; 5] = ["register_bits", "metadata_bits", "tables", "actions", "stages"];
+// settings of the stage scheduler that live next to the budgets, see schedule.rs
+const SCHEDULE_KEYS : [&str; 1] = ["action_width"];
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct ResourceReport {
+    pub snippet_id    : String,
+    pub device_type   : String,
+    pub register_bits : u64,
+    pub metadata_bits : u64,
+    pub tables        : u64,
+    pub actions       : u64,
+    // longest chain of RAW dependencies, a lower bound on the number of stages
+    pub stages        : u64,
+}
+
+impl ResourceReport {
+    pub fn get(&self, key : &str) -> u64 {
+        match key {
+            "register_bits" => self.register_bits,
+            "metadata_bits" => self.metadata_bits,
+            "tables"        => self.tables,
+            "actions"       => self.actions,
+            "stages"        => self.stages,
+            _ => panic!("Unknown resource {}", key)
+        }
+    }
+
+    pub fn get_string(&self) -> String {
+        format!("{} ({}): {} register bits, {} metadata bits, {} tables, {} actions, at least {} stages\n",
+                self.snippet_id, self.device_type, self.register_bits, self.metadata_bits,
+                self.tables, self.actions, self.stages)
+    }
+
+    pub fn get_json(&self) -> serde_json::Value {
+        json!({
+            "snippet" : self.snippet_id,
+            "device_type" : self.device_type,
+            "register_bits" : self.register_bits,
+            "metadata_bits" : self.metadata_bits,
+            "tables" : self.tables,
+            "actions" : self.actions,
+            "stages" : self.stages
+        })
+    }
+}
+
+
+// Estimate the resources used by a dag after its dependencies and P4 code have been filled in
+pub fn estimate_resources<'a> (my_dag : &Dag<'a>) -> ResourceReport {
+    let mut report = ResourceReport { snippet_id : my_dag.snippet_id.to_string(),
+        device_type : my_dag.device_type.to_string(), register_bits : 0, metadata_bits : 0,
+        tables : 0, actions : 0, stages : 0 };
+
+    for dagnode in &my_dag.dag_vector {
+        let p4_code = &dagnode.p4_code;
+        report.metadata_bits += p4_code.p4_header.meta.iter().map(|f| f.width).sum::<u64>();
+        report.register_bits += p4_code.p4_header.register.iter().map(|r| r.width * r.instance_count).sum::<u64>();
+        report.tables += p4_code.p4_tables.len() as u64;
+        report.actions += p4_code.p4_actions.len() as u64;
+    }
+
+    report.stages = longest_chain(my_dag) as u64;
+    report
+}
+
+
+// Budgets per device type, e.g. {"tofino" : {"stages" : 12}}. Resources without a
+// budget are unbounded.
+pub fn load_budgets(budget_file : &str) -> HashMap<String, HashMap<String, u64>> {
+    let mut budgets = HashMap::new();
+    let mut f = match File::open(budget_file) {
+        Err(_) => return budgets,
+        Ok(f) => f,
+    };
+    let mut contents = String::new();
+    f.read_to_string(&mut contents).expect("Something went wrong reading the budget file");
+    let budget_json : serde_json::Value = serde_json::from_str(&contents).expect("Budget file is not valid JSON");
+
+    for (device_type, limits) in budget_json.as_object().expect("Budget file must map device types to budgets") {
+        let mut limit_map = HashMap::new();
+        for (key, limit) in limits.as_object().expect("Budget must map resources to limits") {
+            if !BUDGET_KEYS.contains(&key.as_str()) && !SCHEDULE_KEYS.contains(&key.as_str()) {
+                panic!("Unknown resource {} in budget for {}", key, device_type);
+            }
+            limit_map.insert(key.clone(), limit.as_u64().expect("Budget limits must be non-negative integers"));
+        }
+        budgets.insert(device_type.clone(), limit_map);
+    }
+    budgets
+}
+
+
+// Fail compilation if a snippet uses more of a resource than its target allows
+pub fn check_budget(report : &ResourceReport, budgets : &HashMap<String, HashMap<String, u64>>) {
+    check_limits("Snippet", report, budgets);
+}
+
+// Same for the program merged from the snippets pipelined on one device, whose
+// snippets can fit their budget one by one but not together
+pub fn check_program_budget(report : &ResourceReport, budgets : &HashMap<String, HashMap<String, u64>>) {
+    check_limits("Device program", report, budgets);
+}
+
+fn check_limits(kind : &str, report : &ResourceReport, budgets : &HashMap<String, HashMap<String, u64>>) {
+    if let Some(limits) = budgets.get(&report.device_type) {
+        for key in BUDGET_KEYS.iter() {
+            if let Some(limit) = limits.get(*key) {
+                if report.get(key) > *limit {
+                    panic!("{} {} needs {} {}, but the {} budget is {}.",
+                           kind, report.snippet_id, report.get(key), key, report.device_type, limit);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use grammar::*;
+    use p4_ir::*;
+
+    fn decl_node<'a> (name : &'a str) -> DagNode<'a> {
+        let var_decl = VariableDecl { identifier : Identifier { id_name : name }, initial_values : Vec::new(),
+            var_type : VarType { var_info : VarInfo::BitArray(32, 1), type_qualifier : TypeQualifier::Transient } };
+        return DagNode { node_type : DagNodeType::Decl(var_decl), p4_code : P4Code::new(),
+                         next_nodes : Vec::new(), prev_nodes : Vec::new(), pre_condition : None };
+    }
+
+    fn stmt_node<'a> (write : &'a str, read : &'a str) -> DagNode<'a> {
+        let statement = Statement { lvalue : LValue::Scalar(Identifier { id_name : write }),
+            expr : Expr { op1 : Operand::LValue(LValue::Scalar(Identifier { id_name : read })), expr_right : ExprRight::Empty() } };
+        return DagNode { node_type : DagNodeType::Stmt(statement), p4_code : P4Code::new(),
+                         next_nodes : Vec::new(), prev_nodes : Vec::new(), pre_condition : None };
+    }
+
+    fn test_dag<'a> () -> Dag<'a> {
+        let mut dag_vector = vec![decl_node("a"), decl_node("b"), decl_node("c"), decl_node("d"),
+                                  stmt_node("b", "a"), stmt_node("d", "a"), stmt_node("c", "b")];
+        dag_vector[0].p4_code.p4_header.meta.push(MetaField { name : String::from("a"), width : 32 });
+        dag_vector[1].p4_code.p4_header.register.push(Register { name : String::from("b"), width : 32, instance_count : 4 });
+        dag_vector[4].p4_code.p4_actions.push(Action { name : String::from("action1"), primitives : Vec::new() });
+        dag_vector[4].p4_code.p4_tables.push(Table::new("table1", "action1"));
+        let mut my_dag = Dag { snippet_id : "fun", device_type : "tofino", device_vector : Vec::new(), dag_vector,
+                               ssa_origins : HashMap::new() };
+        add_dependency_edges(&mut my_dag);
+        return my_dag;
+    }
+
+    #[test]
+    fn test_estimate_resources() {
+        let report = estimate_resources(&test_dag());
+        assert_eq!(report, ResourceReport { snippet_id : String::from("fun"), device_type : String::from("tofino"),
+                                            register_bits : 128, metadata_bits : 32, tables : 1, actions : 1, stages : 2 });
+    }
+
+    #[test]
+    #[should_panic(expected="Snippet fun needs 2 stages, but the tofino budget is 1.")]
+    fn test_check_budget_fail() {
+        let report = estimate_resources(&test_dag());
+        let mut budgets = HashMap::new();
+        budgets.insert(String::from("tofino"), [(String::from("stages"), 1)].iter().cloned().collect());
+        check_budget(&report, &budgets);
+    }
+}
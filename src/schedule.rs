@@ -0,0 +1,318 @@
+// Dependency-driven scheduling of the bmv2 code of a dag into pipeline stages.
+// bmv2_gen emits a table with a single action for every primitive. A statement is
+// placed in the first stage after all the statements it depends on, and the
+// single-action tables that end up in the same stage are fused into one action,
+// with at most action_width of them per stage.
+use serde_json;
+use std::collections::HashMap;
+use trans_snippet::*;
+use p4_ir::*;
+use dot::get_node_label;
+
+const TAB : &str = "    ";
+pub const DEFAULT_ACTION_WIDTH : usize = 8;
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone, Copy)]
+pub struct ScheduleConfig {
+    // None leaves the number of stages unbounded
+    pub stage_count  : Option<usize>,
+    // Number of single-action tables that may be fused into the action of one stage
+    pub action_width : usize,
+}
+
+impl ScheduleConfig {
+    // The stage count is the "stages" budget of the device type, and the action
+    // width its "action_width" entry.
+    pub fn from_budgets(budgets : &HashMap<String, HashMap<String, u64>>, device_type : &str) -> ScheduleConfig {
+        let mut config = ScheduleConfig { stage_count : None, action_width : DEFAULT_ACTION_WIDTH };
+        if let Some(limits) = budgets.get(device_type) {
+            config.stage_count = limits.get("stages").map(|s| *s as usize);
+            match limits.get("action_width") {
+                Some(&0) => panic!("The action width of {} must be at least 1.", device_type),
+                Some(width) => config.action_width = *width as usize,
+                None => {}
+            }
+        }
+        config
+    }
+}
+
+
+// Part of a statement that occupies one stage. A single-action table without
+// reads can be fused with others, anything else keeps its own tables.
+#[derive(Debug)]
+#[derive(Clone)]
+pub enum Step {
+    Fusable { table : String, action : String, body : Vec<Primitive> },
+    Fixed { control : Vec<ControlStmt>, actions : Vec<Action>, tables : Vec<Table> },
+}
+
+
+// Steps of a statement in the order they have to run. Code that does more than
+// apply tables one after the other, e.g. branches in the control flow, is kept
+// as a single step.
+pub fn get_steps(p4_code : &P4Code) -> Vec<Step> {
+    let opaque = vec![Step::Fixed { control : p4_code.p4_control.clone(), actions : p4_code.p4_actions.clone(),
+                                    tables : p4_code.p4_tables.clone() }];
+    if p4_code.p4_control.is_empty() {
+        return Vec::new();
+    }
+    let actions : HashMap<&str, &Action> = p4_code.p4_actions.iter().map(|a| (a.name.as_str(), a)).collect();
+    let tables : HashMap<&str, &Table> = p4_code.p4_tables.iter().map(|t| (t.name.as_str(), t)).collect();
+
+    let mut steps = Vec::new();
+    for control in &p4_code.p4_control {
+        let table = match control {
+            ControlStmt::Apply(name) if tables.contains_key(name.as_str()) => tables[name.as_str()],
+            _ => return opaque,
+        };
+        if table.actions.iter().any(|a| !actions.contains_key(a.as_str())) {
+            return opaque;
+        }
+        if table.reads.is_empty() && table.actions.len() == 1 {
+            steps.push(Step::Fusable { table : table.name.clone(), action : table.actions[0].clone(),
+                                       body : actions[table.actions[0].as_str()].primitives.clone() });
+        } else {
+            steps.push(Step::Fixed { control : vec![control.clone()],
+                                     actions : table.actions.iter().map(|a| actions[a.as_str()].clone()).collect(),
+                                     tables : vec![table.clone()] });
+        }
+    }
+    steps
+}
+
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Stage {
+    pub tables     : Vec<String>,
+    pub statements : Vec<String>,
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct StageMap {
+    pub snippet_id : String,
+    pub stages     : Vec<Stage>,
+}
+
+impl StageMap {
+    pub fn get_string(&self) -> String {
+        let mut contents = format!("{}: {} stages\n", self.snippet_id, self.stages.len());
+        for (i, stage) in self.stages.iter().enumerate() {
+            contents += &format!("{}stage {} [{}]: {}\n", TAB, i, stage.tables.join(", "), stage.statements.join("; "));
+        }
+        contents
+    }
+
+    pub fn get_json(&self) -> serde_json::Value {
+        let stages : Vec<serde_json::Value> = self.stages.iter().enumerate().map(|(i, stage)| json!({
+            "stage" : i,
+            "tables" : stage.tables,
+            "statements" : stage.statements
+        })).collect();
+        json!({
+            "snippet" : self.snippet_id,
+            "stages" : stages
+        })
+    }
+}
+
+
+// Place the steps of every statement of a dag with dependency edges into stages,
+// fuse the single-action tables of each stage and rewrite the P4 code of the dag
+// to apply the stages in order. The statements of a group, e.g. a register update,
+// are placed one after the other as a single unit.
+pub fn schedule_dag<'a> (my_dag : &mut Dag<'a>, config : &ScheduleConfig, groups : &Vec<Vec<usize>>) -> StageMap {
+    let node_count = my_dag.dag_vector.len();
+    let node_steps : Vec<Vec<Step>> = my_dag.dag_vector.iter().map(|n| match n.node_type {
+        DagNodeType::Stmt(_) => get_steps(&n.p4_code),
+        _ => Vec::new(),
+    }).collect();
+
+    // every unit is represented by its last statement
+    let mut leader : Vec<usize> = (0..node_count).collect();
+    for group in groups {
+        for member in group {
+            leader[*member] = *group.last().unwrap();
+        }
+    }
+    let mut unit_members : HashMap<usize, Vec<usize>> = HashMap::new();
+    let mut unit_prev : HashMap<usize, Vec<usize>> = HashMap::new();
+    for i in 0..node_count {
+        unit_members.entry(leader[i]).or_default().push(i);
+        let prev = unit_prev.entry(leader[i]).or_default();
+        for p in &my_dag.dag_vector[i].prev_nodes {
+            if leader[*p] != leader[i] && !prev.contains(&leader[*p]) {
+                prev.push(leader[*p]);
+            }
+        }
+    }
+
+    // ready[u] is the first stage a statement depending on unit u can use
+    let mut ready : Vec<usize> = vec![0; node_count];
+    let mut done : Vec<bool> = vec![false; node_count];
+    let mut placement : Vec<Vec<usize>> = vec![Vec::new(); node_count];
+    let mut fused_count : Vec<usize> = Vec::new();
+    for _ in 0..unit_members.len() {
+        // units are placed in dependency order, earliest statement first
+        let unit = *unit_members.keys().filter(|u| !done[**u] && unit_prev[u].iter().all(|p| done[*p])).min()
+            .expect("Groups of statements depend on each other");
+        let mut stage = unit_prev[&unit].iter().map(|p| ready[*p]).max().unwrap_or(0);
+        for i in &unit_members[&unit] {
+            for step in &node_steps[*i] {
+                match *step {
+                    Step::Fusable { .. } => {
+                        while fused_count.get(stage).is_some_and(|c| *c >= config.action_width) {
+                            stage += 1;
+                        }
+                        if fused_count.len() <= stage {
+                            fused_count.resize(stage + 1, 0);
+                        }
+                        fused_count[stage] += 1;
+                    }
+                    Step::Fixed { .. } => {}
+                }
+                placement[*i].push(stage);
+                stage += 1;
+            }
+        }
+        ready[unit] = stage;
+        done[unit] = true;
+    }
+
+    let stage_total = placement.iter().flat_map(|p| p.iter()).map(|s| s + 1).max().unwrap_or(0);
+    match config.stage_count {
+        Some(stage_count) if stage_total > stage_count => {
+            panic!("Snippet {} needs {} stages, but the {} schedule allows {}.",
+                   my_dag.snippet_id, stage_total, my_dag.device_type, stage_count);
+        }
+        _ => {}
+    }
+
+    // rebuild the code stage by stage. Fused tables and actions belong to the
+    // first statement fused into them, all control flow to the first statement.
+    // A table fusing several statements is named after its stage.
+    let mut control : Vec<ControlStmt> = Vec::new();
+    let mut node_actions : Vec<Vec<Action>> = vec![Vec::new(); my_dag.dag_vector.len()];
+    let mut node_tables : Vec<Vec<Table>> = vec![Vec::new(); my_dag.dag_vector.len()];
+    let mut stages = Vec::new();
+    for s in 0..stage_total {
+        let mut stage = Stage { tables : Vec::new(), statements : Vec::new() };
+        let mut fused : Option<(usize, String, String)> = None;
+        let mut fused_body : Vec<Primitive> = Vec::new();
+        let mut fixed_control : Vec<ControlStmt> = Vec::new();
+        for i in 0..my_dag.dag_vector.len() {
+            let mut in_stage = false;
+            for (step, _) in node_steps[i].iter().zip(placement[i].iter()).filter(|&(_, stage)| *stage == s) {
+                in_stage = true;
+                match step {
+                    Step::Fusable { table, action, body } => {
+                        if fused.is_none() {
+                            fused = Some((i, table.clone(), action.clone()));
+                        }
+                        fused_body.extend(body.iter().cloned());
+                    }
+                    Step::Fixed { control : step_control, actions, tables } => {
+                        fixed_control.extend(step_control.iter().cloned());
+                        node_actions[i].extend(actions.iter().cloned());
+                        node_tables[i].extend(tables.iter().cloned());
+                        stage.tables.extend(tables.iter().map(|t| t.name.clone()));
+                    }
+                }
+            }
+            if in_stage {
+                for member in &unit_members[&leader[i]] {
+                    let label = get_node_label(&my_dag.dag_vector[*member]);
+                    if !stage.statements.contains(&label) {
+                        stage.statements.push(label);
+                    }
+                }
+            }
+        }
+        if let Some((owner, mut table, mut action)) = fused {
+            if fused_count.get(s).is_some_and(|c| *c > 1) {
+                action = format!("{}_stage{}", my_dag.snippet_id, s);
+                table = format!("{}_table", action);
+            }
+            control.push(ControlStmt::Apply(table.clone()));
+            node_actions[owner].push(Action { name : action.clone(), primitives : fused_body });
+            node_tables[owner].push(Table::new(&table, &action));
+            stage.tables.insert(0, table);
+        }
+        control.extend(fixed_control);
+        stages.push(stage);
+    }
+
+    let first_stmt = node_steps.iter().position(|steps| !steps.is_empty());
+    for (i, dagnode) in my_dag.dag_vector.iter_mut().enumerate() {
+        if let DagNodeType::Stmt(_) = dagnode.node_type {
+            dagnode.p4_code.p4_control = if Some(i) == first_stmt { control.clone() } else { Vec::new() };
+            dagnode.p4_code.p4_actions = node_actions[i].clone();
+            dagnode.p4_code.p4_tables = node_tables[i].clone();
+        }
+    }
+
+    StageMap { snippet_id : my_dag.snippet_id.to_string(), stages }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use grammar::*;
+    use dependency::*;
+
+    fn stmt_node<'a> (write : &'a str, read : &'a str, table : usize) -> DagNode<'a> {
+        let statement = Statement { lvalue : LValue::Scalar(Identifier { id_name : write }),
+            expr : Expr { op1 : Operand::LValue(LValue::Scalar(Identifier { id_name : read })), expr_right : ExprRight::Empty() } };
+        let mut p4_code = P4Code::new();
+        p4_code.p4_control.push(ControlStmt::Apply(format!("table{}", table)));
+        p4_code.p4_actions.push(Action { name : format!("action{}", table),
+            primitives : vec![Primitive::new("modify_field", vec![format!("mdata.{}", write), format!("mdata.{}", read)])] });
+        p4_code.p4_tables.push(Table::new(&format!("table{}", table), &format!("action{}", table)));
+        return DagNode { node_type : DagNodeType::Stmt(statement), p4_code,
+                         next_nodes : Vec::new(), prev_nodes : Vec::new(), pre_condition : None };
+    }
+
+    fn test_dag<'a> () -> Dag<'a> {
+        // b and d only depend on a, c depends on b
+        let mut my_dag = Dag { snippet_id : "fun", device_type : "bmv2", device_vector : Vec::new(),
+                               dag_vector : vec![stmt_node("b", "a", 1), stmt_node("d", "a", 2), stmt_node("c", "b", 3)],
+                               ssa_origins : HashMap::new() };
+        add_dependency_edges(&mut my_dag);
+        return my_dag;
+    }
+
+    #[test]
+    fn test_schedule_dag() {
+        let mut my_dag = test_dag();
+        let stage_map = schedule_dag(&mut my_dag, &ScheduleConfig { stage_count : None, action_width : DEFAULT_ACTION_WIDTH }, &Vec::new());
+        assert_eq!(stage_map.stages, vec![
+            Stage { tables : vec![String::from("fun_stage0_table")], statements : vec![String::from("b = a"), String::from("d = a")] },
+            Stage { tables : vec![String::from("table3")], statements : vec![String::from("c = b")] }]);
+        assert_eq!(control_to_p4(&my_dag.dag_vector[0].p4_code.p4_control, 1), "    apply(fun_stage0_table);\n    apply(table3);\n");
+        assert_eq!(my_dag.dag_vector[0].p4_code.p4_actions[0].to_p4(),
+                   "action fun_stage0 () {\n    modify_field(mdata.b, mdata.a);\n    modify_field(mdata.d, mdata.a);\n}\n");
+        assert_eq!(my_dag.dag_vector[1].p4_code.p4_tables, Vec::new());
+        assert_eq!(my_dag.dag_vector[2].p4_code.p4_actions[0].to_p4(), "action action3 () {\n    modify_field(mdata.c, mdata.b);\n}\n");
+    }
+
+    #[test]
+    fn test_schedule_action_width() {
+        let mut my_dag = test_dag();
+        let stage_map = schedule_dag(&mut my_dag, &ScheduleConfig { stage_count : None, action_width : 1 }, &Vec::new());
+        let tables : Vec<Vec<String>> = stage_map.stages.iter().map(|s| s.tables.clone()).collect();
+        assert_eq!(tables, vec![vec![String::from("table1")], vec![String::from("table2")], vec![String::from("table3")]]);
+    }
+
+    #[test]
+    #[should_panic(expected="Snippet fun needs 2 stages, but the bmv2 schedule allows 1.")]
+    fn test_schedule_stage_count_fail() {
+        let mut my_dag = test_dag();
+        schedule_dag(&mut my_dag, &ScheduleConfig { stage_count : Some(1), action_width : DEFAULT_ACTION_WIDTH }, &Vec::new());
+    }
+}
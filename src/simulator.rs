@@ -0,0 +1,684 @@
+// Discrete-event simulator for programs whose snippets run on several devices.
+// Every snippet runs with the interpreter on each device it is annotated with,
+// or placed on by place_snippets. The outputs of a snippet are sent along its
+// connections to the nearest device running the destination snippet, arriving
+// link_latency later per hop of the topology (one hop without a topology).
+// A device processes the packets it receives one at a time, service_time each,
+// and holds at most queue_capacity waiting packets: packets arriving at a full
+// queue are dropped. A scenario scripts the packets injected into snippets and
+// the expectations checked on the state at the end of the simulation.
+use grammar::*;
+use interpreter::*;
+use compile::*;
+use imports::{ImportArena, resolve_imports};
+use placement::{place_snippets, get_distances};
+use lexer;
+use parser;
+use serde_json;
+use serde_json::Value;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::collections::BinaryHeap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Traffic {
+    pub time     : u64,
+    pub snippet  : String,
+    // device the packets are injected on, if the snippet runs on several
+    pub device   : Option<String>,
+    pub packet   : FieldMap,
+    pub count    : u64,
+    pub interval : u64,
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub enum Check {
+    // register, index and value
+    Register(String, usize, u64),
+    // field or output variable of the last output of the snippet, and its value
+    Output(String, u64),
+    Processed(u64),
+    Dropped(u64),
+    Failures(u64),
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Expectation {
+    pub snippet : Option<String>,
+    pub device  : Option<String>,
+    pub check   : Check,
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Scenario {
+    pub link_latency   : u64,
+    pub service_time   : u64,
+    pub queue_capacity : usize,
+    // latency of single connections, keyed from->to
+    pub latencies      : HashMap<String, u64>,
+    pub traffic        : Vec<Traffic>,
+    // events after this time are not simulated
+    pub until          : Option<u64>,
+    pub expect         : Vec<Expectation>,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scenario {
+    pub fn new() -> Scenario {
+        Scenario { link_latency : 1, service_time : 1, queue_capacity : 64, latencies : HashMap::new(),
+                   traffic : Vec::new(), until : None, expect : Vec::new() }
+    }
+}
+
+fn get_u64(object : &Value, key : &str, default : Option<u64>) -> u64 {
+    match (object.get(key), default) {
+        (Some(value), _) => match value.as_u64() {
+            Some(value) => value,
+            None => panic!("{} must be a non-negative integer in the scenario, found {}.", key, value),
+        },
+        (None, Some(default)) => default,
+        (None, None) => panic!("Scenario entry {} has no {}.", object, key),
+    }
+}
+
+fn get_string(object : &Value, key : &str) -> Option<String> {
+    match object.get(key) {
+        Some(Value::String(value)) => Some(value.clone()),
+        Some(value) => panic!("{} must be a string in the scenario, found {}.", key, value),
+        None => None,
+    }
+}
+
+// Reads a scenario from JSON:
+// {"link_latency" : 1, "service_time" : 1, "queue_capacity" : 64, "latencies" : {"a->b" : 5}, "until" : 100,
+//  "traffic" : [{"time" : 0, "snippet" : "a", "device" : "h1", "packet" : {"p.f" : 1}, "count" : 1, "interval" : 1}],
+//  "expect" : [{"snippet" : "b", "register" : "r", "index" : 0, "value" : 1}, {"snippet" : "b", "output" : "q.f", "value" : 1},
+//              {"snippet" : "b", "processed" : 1}, {"device" : "s1", "dropped" : 0}, {"failures" : 0}]}
+pub fn parse_scenario(json : &Value) -> Scenario {
+    let mut scenario = Scenario::new();
+    scenario.link_latency = get_u64(json, "link_latency", Some(scenario.link_latency));
+    scenario.service_time = get_u64(json, "service_time", Some(scenario.service_time));
+    scenario.queue_capacity = get_u64(json, "queue_capacity", Some(scenario.queue_capacity as u64)) as usize;
+    if json.get("until").is_some() {
+        scenario.until = Some(get_u64(json, "until", None));
+    }
+    match json.get("latencies") {
+        Some(Value::Object(latencies)) => for (connection, _) in latencies {
+            scenario.latencies.insert(connection.clone(), get_u64(&json["latencies"], connection, None));
+        },
+        Some(value) => panic!("latencies must be an object in the scenario, found {}.", value),
+        None => {}
+    }
+    for traffic in json.get("traffic").and_then(|t| t.as_array()).unwrap_or(&Vec::new()) {
+        let snippet = match get_string(traffic, "snippet") {
+            Some(snippet) => snippet,
+            None => panic!("Traffic {} has no snippet.", traffic),
+        };
+        let mut packet = FieldMap::new();
+        for (name, _) in traffic.get("packet").and_then(|p| p.as_object()).unwrap_or(&serde_json::Map::new()) {
+            packet.insert(name.clone(), get_u64(&traffic["packet"], name, None));
+        }
+        scenario.traffic.push(Traffic { time : get_u64(traffic, "time", Some(0)), snippet, device : get_string(traffic, "device"),
+                                        packet, count : get_u64(traffic, "count", Some(1)),
+                                        interval : get_u64(traffic, "interval", Some(1)) });
+    }
+    for expect in json.get("expect").and_then(|e| e.as_array()).unwrap_or(&Vec::new()) {
+        let check = if let Some(register) = get_string(expect, "register") {
+            Check::Register(register, get_u64(expect, "index", Some(0)) as usize, get_u64(expect, "value", None))
+        } else if let Some(output) = get_string(expect, "output") {
+            Check::Output(output, get_u64(expect, "value", None))
+        } else if expect.get("processed").is_some() {
+            Check::Processed(get_u64(expect, "processed", None))
+        } else if expect.get("dropped").is_some() {
+            Check::Dropped(get_u64(expect, "dropped", None))
+        } else if expect.get("failures").is_some() {
+            Check::Failures(get_u64(expect, "failures", None))
+        } else {
+            panic!("Expectation {} checks nothing, expected register, output, processed, dropped or failures.", expect);
+        };
+        scenario.expect.push(Expectation { snippet : get_string(expect, "snippet"), device : get_string(expect, "device"), check });
+    }
+    scenario
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct InstanceState {
+    pub snippet     : String,
+    pub device      : String,
+    pub registers   : RegisterMap,
+    pub last_output : Option<FieldMap>,
+    pub processed   : u64,
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct DeviceStats {
+    pub received  : u64,
+    pub dropped   : u64,
+    pub max_queue : usize,
+}
+
+// A packet the snippet failed on, e.g. because a field it reads wasn't sent to it,
+// or an assertion or invariant it violated
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Failure {
+    pub time    : u64,
+    pub snippet : String,
+    pub device  : String,
+    pub message : String,
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct SimulationReport {
+    // time of the last event
+    pub time       : u64,
+    pub instances  : Vec<InstanceState>,
+    pub devices    : BTreeMap<String, DeviceStats>,
+    pub failures   : Vec<Failure>,
+    // expectations of the scenario that don't hold
+    pub violations : Vec<String>,
+}
+
+impl SimulationReport {
+    pub fn to_json(&self) -> Value {
+        let instances : Vec<Value> = self.instances.iter().map(|instance| json!({
+            "snippet"     : instance.snippet,
+            "device"      : instance.device,
+            "registers"   : instance.registers,
+            "last_output" : instance.last_output,
+            "processed"   : instance.processed,
+        })).collect();
+        let devices : serde_json::Map<String, Value> = self.devices.iter().map(|(name, stats)| (name.clone(), json!({
+            "received"  : stats.received,
+            "dropped"   : stats.dropped,
+            "max_queue" : stats.max_queue,
+        }))).collect();
+        let failures : Vec<Value> = self.failures.iter().map(|failure| json!({
+            "time"    : failure.time,
+            "snippet" : failure.snippet,
+            "device"  : failure.device,
+            "message" : failure.message,
+        })).collect();
+        json!({
+            "time"       : self.time,
+            "instances"  : instances,
+            "devices"    : devices,
+            "failures"   : failures,
+            "violations" : self.violations,
+        })
+    }
+}
+
+struct Instance<'a> {
+    snippet     : &'a str,
+    device      : &'a str,
+    interpreter : Interpreter<'a>,
+    last_output : Option<FieldMap>,
+    processed   : u64,
+}
+
+struct DeviceState {
+    in_service : Option<(usize, FieldMap)>,
+    queue      : VecDeque<(usize, FieldMap)>,
+    stats      : DeviceStats,
+}
+
+enum Event<'a> {
+    // packet for an instance arrives at its device
+    Arrival(usize, FieldMap),
+    // device finishes the packet in service
+    Done(&'a str),
+}
+
+pub struct Simulator<'a, 's> {
+    scenario    : &'s Scenario,
+    connections : &'s Connections<'a>,
+    inputs      : HashMap<&'a str, Vec<&'a str>>,
+    // variables of a packet type, keyed by snippet
+    packet_vars : HashSet<(&'a str, &'a str)>,
+    distances   : Option<HashMap<(&'a str, &'a str), u64>>,
+    instances   : Vec<Instance<'a>>,
+    devices     : BTreeMap<&'a str, DeviceState>,
+    events      : BinaryHeap<Reverse<(u64, u64)>>,
+    pending     : HashMap<u64, Event<'a>>,
+    next_event  : u64,
+    time        : u64,
+    failures    : Vec<Failure>,
+}
+
+impl<'a, 's> Simulator<'a, 's> {
+    // Instantiates every snippet of snippets, which must all carry a device annotation or a device type
+    pub fn new(snippets : &'s Snippets<'a>, my_globals : &Globals<'a>, my_packets : &Packets<'a>, pkt_tree : &Packets<'a>,
+               my_topology : &Topology<'a>, connections : &'s Connections<'a>, scenario : &'s Scenario) -> Simulator<'a, 's> {
+        let distances = if my_topology.link_vector.is_empty() { None } else { Some(get_distances(my_topology)) };
+        let mut simulator = Simulator { scenario, connections, inputs : HashMap::new(), packet_vars : HashSet::new(), distances, instances : Vec::new(),
+                                        devices : BTreeMap::new(), events : BinaryHeap::new(), pending : HashMap::new(),
+                                        next_event : 0, time : 0, failures : Vec::new() };
+        for my_snippet in &snippets.snippet_vector {
+            let snippet = my_snippet.snippet_id.id_name;
+            let annotation = &my_snippet.device_annotation;
+            // snippets without devices run on a device named after their device type
+            let devices : Vec<&'a str> = if !annotation.device_vector.is_empty() {
+                annotation.device_vector.iter().map(|d| d.id_name).collect()
+            } else if !annotation.device_type.id_name.is_empty() {
+                vec![annotation.device_type.id_name]
+            } else {
+                vec![snippet]
+            };
+            simulator.inputs.insert(snippet, my_snippet.variable_decls.decl_vector.iter()
+                .filter(|d| d.var_type.type_qualifier == TypeQualifier::Input).map(|d| d.identifier.id_name).collect());
+            for my_decl in &my_snippet.variable_decls.decl_vector {
+                if let VarInfo::Packet(_) = my_decl.var_type.var_info { simulator.packet_vars.insert((snippet, my_decl.identifier.id_name)); }
+            }
+            for device in devices {
+                let mut interpreter = Interpreter::new(my_snippet, my_globals, my_packets);
+                interpreter.add_headers(pkt_tree);
+                simulator.instances.push(Instance { snippet, device, interpreter, last_output : None, processed : 0 });
+                simulator.devices.entry(device).or_insert(DeviceState { in_service : None, queue : VecDeque::new(),
+                    stats : DeviceStats { received : 0, dropped : 0, max_queue : 0 } });
+            }
+        }
+        for traffic in &scenario.traffic {
+            let instance = simulator.find_instance(&traffic.snippet, traffic.device.as_deref());
+            for n in 0..traffic.count {
+                simulator.schedule(traffic.time + n * traffic.interval, Event::Arrival(instance, traffic.packet.clone()));
+            }
+        }
+        simulator
+    }
+
+    fn find_instance(&self, snippet : &str, device : Option<&str>) -> usize {
+        let matches : Vec<usize> = (0..self.instances.len())
+            .filter(|&i| self.instances[i].snippet == snippet && device.map(|d| d == self.instances[i].device).unwrap_or(true))
+            .collect();
+        match (matches.len(), device) {
+            (1, _) => matches[0],
+            (0, Some(device)) => panic!("Snippet {} does not run on {}.", snippet, device),
+            (0, None) => panic!("Snippet {} not found.", snippet),
+            _ => panic!("Snippet {} runs on several devices, pick one with device.", snippet),
+        }
+    }
+
+    fn schedule(&mut self, time : u64, event : Event<'a>) {
+        self.events.push(Reverse((time, self.next_event)));
+        self.pending.insert(self.next_event, event);
+        self.next_event += 1;
+    }
+
+    fn hops(&self, from : &str, to : &str) -> u64 {
+        if from == to {
+            return 0;
+        }
+        match self.distances {
+            Some(ref distances) => match distances.get(&(from, to)) {
+                Some(hops) => *hops,
+                None => panic!("No path from {} to {} in the topology.", from, to),
+            },
+            None => 1,
+        }
+    }
+
+    // Runs the events in time order, up to the end of the scenario
+    pub fn run(&mut self) {
+        while let Some(Reverse((time, id))) = self.events.pop() {
+            if self.scenario.until.map(|until| time > until).unwrap_or(false) {
+                break;
+            }
+            self.time = time;
+            match self.pending.remove(&id).unwrap() {
+                Event::Arrival(instance, packet) => self.arrive(instance, packet),
+                Event::Done(device) => self.finish(device),
+            }
+        }
+    }
+
+    fn arrive(&mut self, instance : usize, packet : FieldMap) {
+        let device = self.instances[instance].device;
+        let (time, service_time, capacity) = (self.time, self.scenario.service_time, self.scenario.queue_capacity);
+        let start = {
+            let state = self.devices.get_mut(device).unwrap();
+            state.stats.received += 1;
+            if state.in_service.is_none() {
+                state.in_service = Some((instance, packet));
+                true
+            } else if state.queue.len() >= capacity {
+                state.stats.dropped += 1;
+                false
+            } else {
+                state.queue.push_back((instance, packet));
+                state.stats.max_queue = state.stats.max_queue.max(state.queue.len());
+                false
+            }
+        };
+        if start {
+            self.schedule(time + service_time, Event::Done(device));
+        }
+    }
+
+    fn finish(&mut self, device : &'a str) {
+        let (instance, packet) = self.devices.get_mut(device).unwrap().in_service.take().unwrap();
+        self.process(instance, &packet);
+        let next = self.devices.get_mut(device).unwrap().queue.pop_front();
+        if next.is_some() {
+            self.devices.get_mut(device).unwrap().in_service = next;
+            let time = self.time + self.scenario.service_time;
+            self.schedule(time, Event::Done(device));
+        }
+    }
+
+    fn process(&mut self, instance : usize, packet : &FieldMap) {
+        let output = {
+            let interpreter = &mut self.instances[instance].interpreter;
+            catch_errors(|| interpreter.process(packet))
+        };
+        let (snippet, device) = (self.instances[instance].snippet, self.instances[instance].device);
+        let output = match output {
+            Ok(output) => output,
+            Err(errors) => {
+                self.failures.push(Failure { time : self.time, snippet : snippet.to_string(), device : device.to_string(),
+                                             message : errors[0].message.clone() });
+                return;
+            }
+        };
+        // violated assertions are reported, the packet goes on
+        for violation in &output.violations {
+            self.failures.push(Failure { time : self.time, snippet : snippet.to_string(), device : device.to_string(),
+                                         message : violation.clone() });
+        }
+        self.instances[instance].processed += 1;
+        self.instances[instance].last_output = Some(output.fields.clone());
+
+        let connections = self.connections;
+        for connection in connections.connection_vector.iter().filter(|c| c.from_snippet.id_name == snippet) {
+            let to_snippet = connection.to_snippet.id_name;
+            let target = match (0..self.instances.len()).filter(|&i| self.instances[i].snippet == to_snippet)
+                                                         .min_by_key(|&i| self.hops(device, self.instances[i].device)) {
+                Some(target) => target,
+                None => panic!("Snippet {} is connected to {}, which is not declared.", snippet, to_snippet),
+            };
+            let connection_name = format!("{}->{}", snippet, to_snippet);
+            let latency = match self.scenario.latencies.get(&connection_name) {
+                Some(latency) => *latency,
+                None => self.hops(device, self.instances[target].device) * self.scenario.link_latency,
+            };
+            let sent = self.connection_packet(connection, &output.fields);
+            let time = self.time + latency;
+            self.schedule(time, Event::Arrival(target, sent));
+        }
+    }
+
+    // Packet received over a connection: the paired outputs, renamed to the inputs they are paired with.
+    // Connections without pairs carry the outputs that have the name of an input of the destination.
+    // Packet variables are sent as their fields.
+    fn connection_packet(&self, connection : &Connection<'a>, fields : &FieldMap) -> FieldMap {
+        let mut pairs : Vec<(&str, &str)> = connection.variable_pairs.iter().map(|p| (p.from_var.id_name, p.to_var.id_name)).collect();
+        if pairs.is_empty() {
+            pairs = self.inputs[connection.to_snippet.id_name].iter().map(|&input| (input, input)).collect();
+        }
+        let mut packet = FieldMap::new();
+        for (name, value) in fields {
+            let (root, field) = match name.find('.') {
+                Some(dot) => (&name[..dot], &name[dot..]),
+                None if self.packet_vars.contains(&(connection.from_snippet.id_name, name.as_str())) => continue,
+                None => (name.as_str(), ""),
+            };
+            for &(from_var, to_var) in &pairs {
+                if root == from_var {
+                    packet.insert(format!("{}{}", to_var, field), *value);
+                }
+            }
+        }
+        packet
+    }
+
+    pub fn report(&self) -> SimulationReport {
+        let instances : Vec<InstanceState> = self.instances.iter().map(|instance| InstanceState {
+            snippet : instance.snippet.to_string(), device : instance.device.to_string(),
+            registers : instance.interpreter.registers().clone(), last_output : instance.last_output.clone(),
+            processed : instance.processed }).collect();
+        let devices = self.devices.iter().map(|(device, state)| (device.to_string(), state.stats.clone())).collect();
+        let mut report = SimulationReport { time : self.time, instances, devices, failures : self.failures.clone(), violations : Vec::new() };
+        for expectation in &self.scenario.expect {
+            if let Some(violation) = check_expectation(&report, expectation) { report.violations.push(violation) }
+        }
+        report
+    }
+}
+
+// Message describing how the expectation is violated, if it is
+fn check_expectation(report : &SimulationReport, expectation : &Expectation) -> Option<String> {
+    let device = expectation.device.as_deref();
+    let instance = || -> &InstanceState {
+        let snippet = match expectation.snippet {
+            Some(ref snippet) => snippet,
+            None => panic!("Expectation {:?} needs a snippet.", expectation.check),
+        };
+        let matches : Vec<&InstanceState> = report.instances.iter()
+            .filter(|i| &i.snippet == snippet && device.map(|d| d == i.device).unwrap_or(true)).collect();
+        match matches.len() {
+            1 => matches[0],
+            0 => panic!("Snippet {} does not run on {}.", snippet, device.unwrap_or("any device")),
+            _ => panic!("Snippet {} runs on several devices, pick one with device.", snippet),
+        }
+    };
+    let (name, expected, actual) = match expectation.check {
+        Check::Register(ref register, index, value) => {
+            let instance = instance();
+            let actual = instance.registers.get(register).and_then(|values| values.get(index).cloned());
+            (format!("{}[{}] of {} on {}", register, index, instance.snippet, instance.device), value, actual)
+        }
+        Check::Output(ref field, value) => {
+            let instance = instance();
+            let actual = instance.last_output.as_ref().and_then(|output| output.get(field).cloned());
+            (format!("{} of the last output of {} on {}", field, instance.snippet, instance.device), value, actual)
+        }
+        Check::Processed(count) => {
+            let instance = instance();
+            (format!("Packets processed by {} on {}", instance.snippet, instance.device), count, Some(instance.processed))
+        }
+        Check::Dropped(count) => match device {
+            Some(device) => (format!("Packets dropped by {}", device), count, report.devices.get(device).map(|d| d.dropped)),
+            None => (String::from("Packets dropped"), count, Some(report.devices.values().map(|d| d.dropped).sum())),
+        },
+        Check::Failures(count) => (String::from("Failed packets"), count, Some(report.failures.len() as u64)),
+    };
+    match actual {
+        Some(actual) if actual == expected => None,
+        Some(actual) => Some(format!("{} is {}, expected {}.", name, actual, expected)),
+        None => Some(format!("{} has no value, expected {}.", name, expected)),
+    }
+}
+
+// Simulates a program with the traffic of a scenario, and checks its expectations
+pub fn simulate(source : &str, scenario : &Scenario, options : &CompileOptions) -> Result<SimulationReport, Vec<CompileError>> {
+    catch_errors(|| {
+        let tokens = &mut lexer::get_tokens(source);
+        let token_iter = &mut tokens.iter().peekable();
+        let parse_tree = parser::parse_prog(token_iter);
+        assert!(token_iter.peek().is_none(), "Token iterator is not empty.");
+        let mut import_arena = ImportArena::new();
+        let parse_tree = resolve_imports(parse_tree, options.source_path.as_deref(), &options.include_dirs, &mut import_arena);
+
+        let contents = read_include_file(&options.include_dirs, "packet.np");
+        let pkt_tokens = &mut lexer::get_tokens(&contents);
+        let pkt_iter = &mut pkt_tokens.iter().peekable();
+        let pkt_tree = parser::parse_import_packets(pkt_iter);
+
+        let snippets = place_snippets(&parse_tree.topology, &parse_tree.snippets, &parse_tree.connections, &mut Vec::new());
+        let mut simulator = Simulator::new(&snippets, &parse_tree.globals, &parse_tree.packets, &pkt_tree, &parse_tree.topology,
+                                           &parse_tree.connections, scenario);
+        simulator.run();
+        simulator.report()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::prelude::*;
+
+    const PROGRAM : &str = r"global THRESHOLD : bit<32> = 2;
+                             packet pac : ethernet(etherType : 1234) {
+                               seq  : bit<32>;
+                               mark : bit<1>;
+                             }
+                             TOPOLOGY
+                             @ host : h1;
+                             snippet send() {
+                               input seq : bit<32>;
+                               output a : pac;
+                               a.seq = seq;
+                             }
+                             @ bmv2 : s1;
+                             snippet count() {
+                               input x : pac;
+                               output y : pac;
+                               persistent seen : bit<32> = 0;
+                               seen = seen + 1;
+                               y.seq = x.seq;
+                               y.mark = seen > THRESHOLD;
+                             }
+                             @ host : h2;
+                             snippet receive() {
+                               input p : pac;
+                               persistent marked : bit<32> = 0;
+                               persistent last : bit<32> = 0;
+                               marked = marked + p.mark;
+                               last = p.seq;
+                             }
+                             (send, count):a->x,
+                             (count, receive):y->p,";
+
+    fn run(topology : &str, scenario : Value) -> SimulationReport {
+        let program = PROGRAM.replace("TOPOLOGY", topology);
+        return simulate(&program, &parse_scenario(&scenario), &CompileOptions::new()).unwrap();
+    }
+
+    #[test]
+    fn test_simulate_connections() {
+        let report = run("", json!({
+            "link_latency" : 2,
+            "traffic" : [{"snippet" : "send", "packet" : {"seq" : 7}, "count" : 4}],
+            "expect" : [{"snippet" : "receive", "register" : "marked", "value" : 2},
+                        {"snippet" : "receive", "register" : "last", "value" : 7},
+                        {"snippet" : "count", "processed" : 4},
+                        {"snippet" : "count", "output" : "y.mark", "value" : 1},
+                        {"dropped" : 0},
+                        {"failures" : 0}]}));
+        assert_eq!(report.violations, Vec::<String>::new());
+        // the last packet leaves h1 at 4, s1 at 7 and h2 at 10
+        assert_eq!(report.time, 10);
+        assert_eq!(report.instances[1].last_output.as_ref().unwrap()["y.seq"], 7);
+    }
+
+    #[test]
+    fn test_simulate_topology() {
+        let report = run("topology { host h1; switch s1 : bmv2; switch s2 : bmv2; host h2;
+                                     link h1 -- s1; link s1 -- s2; link s2 -- h2; }",
+                         json!({"link_latency" : 2, "latencies" : {"send->count" : 10},
+                                "traffic" : [{"snippet" : "send", "packet" : {"seq" : 1}}]}));
+        // 10 from h1 to s1, then 2 hops to h2
+        assert_eq!(report.time, 1 + 10 + 1 + 4 + 1);
+        assert_eq!(report.instances[2].registers["last"], vec![1]);
+    }
+
+    #[test]
+    fn test_simulate_queue() {
+        let report = run("", json!({
+            "service_time" : 10, "queue_capacity" : 1, "until" : 25,
+            "traffic" : [{"snippet" : "send", "packet" : {"seq" : 1}, "count" : 4, "interval" : 0}],
+            "expect" : [{"device" : "h1", "dropped" : 1}, {"snippet" : "receive", "processed" : 2}]}));
+        assert_eq!(report.devices["h1"], DeviceStats { received : 4, dropped : 2, max_queue : 1 });
+        assert_eq!(report.violations, vec![String::from("Packets dropped by h1 is 2, expected 1."),
+                                           String::from("Packets processed by receive on h2 is 0, expected 2.")]);
+    }
+
+    #[test]
+    fn test_simulate_failure() {
+        // receive reads p.mark, which send doesn't write
+        let report = run("", json!({"traffic" : [{"snippet" : "count", "packet" : {"x.seq" : 3}},
+                                                 {"snippet" : "receive", "packet" : {"p.seq" : 3}}]}));
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].message, "Packet has no value for field p.mark.");
+        assert_eq!(report.instances[2].processed, 1);
+    }
+
+    fn run_net_prog(file_name : &str, scenario : Value) -> SimulationReport {
+        let mut contents = String::new();
+        File::open(format!("net-progs/{}", file_name)).unwrap().read_to_string(&mut contents).unwrap();
+        return simulate(&contents, &parse_scenario(&scenario), &CompileOptions::new()).unwrap();
+    }
+
+    #[test]
+    fn test_simulate_ecn() {
+        // a packet marked by the congested switch comes back to the sender as cwr, which halves its window
+        let report = run_net_prog("ecn.np", json!({
+            "until" : 7,
+            "traffic" : [{"snippet" : "switch", "packet" : {"x.ipv4diffserv" : 1, "psa.enq_qdepth" : 2000}}],
+            "expect" : [{"snippet" : "switch", "output" : "y.ipv4diffserv", "value" : 3},
+                        {"snippet" : "receive", "output" : "q.tcpEce", "value" : 1},
+                        {"snippet" : "echo", "output" : "c.tcpCwr", "value" : 1},
+                        {"snippet" : "echo", "output" : "check_cwr1", "value" : 1},
+                        {"snippet" : "send", "register" : "window", "value" : 32767},
+                        {"snippet" : "send", "output" : "a.tcpWindow", "value" : 32767},
+                        {"snippet" : "cwr", "output" : "r.tcpEce", "value" : 0},
+                        {"failures" : 0}]}));
+        assert_eq!(report.violations, Vec::<String>::new());
+
+        // below the threshold the packet keeps its ecn capable codepoint
+        let report = run_net_prog("ecn.np", json!({
+            "until" : 1,
+            "traffic" : [{"snippet" : "switch", "packet" : {"x.ipv4diffserv" : 1, "psa.enq_qdepth" : 10}}],
+            "expect" : [{"snippet" : "switch", "output" : "y.ipv4diffserv", "value" : 1}]}));
+        assert_eq!(report.violations, Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_simulate_stream() {
+        // join only passes on the impression and click of the same ad
+        let report = run_net_prog("stream.np", json!({
+            "traffic" : [{"snippet" : "join", "packet" : {"p.adId" : 7, "q.adId" : 7, "p.impr_time" : 10, "q.click_time" : 12}}],
+            "expect" : [{"snippet" : "receive", "processed" : 1},
+                        {"snippet" : "receive", "output" : "x.adId", "value" : 7},
+                        {"snippet" : "receive", "output" : "x.impr_time", "value" : 10},
+                        {"snippet" : "receive", "output" : "x.click_time", "value" : 12},
+                        {"failures" : 0}]}));
+        assert_eq!(report.violations, Vec::<String>::new());
+
+        let report = run_net_prog("stream.np", json!({
+            "traffic" : [{"snippet" : "join", "packet" : {"p.adId" : 7, "q.adId" : 8, "p.impr_time" : 10, "q.click_time" : 12}}],
+            "expect" : [{"snippet" : "receive", "processed" : 1}, {"failures" : 0}]}));
+        assert_eq!(report.violations, Vec::<String>::new());
+        assert_eq!(report.instances[3].last_output, Some(FieldMap::new()));
+    }
+
+    #[test]
+    #[should_panic(expected="Expectation {\"snippet\":\"count\"} checks nothing")]
+    fn test_parse_scenario_fail() {
+        parse_scenario(&json!({"expect" : [{"snippet" : "count"}]}));
+    }
+}
@@ -0,0 +1,271 @@
+// Read-modify-write updates of persistent registers. A register may be accessed
+// only once per packet, so every statement that reads or writes it, together with
+// the statements on dependency paths between them, forms one update that the
+// backends lower to a single stateful unit.
+use grammar::*;
+use std::collections::HashMap;
+use trans_snippet::*;
+use dependency::*;
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct RegisterUpdate {
+    pub register : String,
+    // element of the register that is accessed, "0" for scalar registers
+    pub index    : String,
+    // statements of the update, in dag order
+    pub members  : Vec<usize>,
+    // statements that write the register, including its SSA versions
+    pub writes   : Vec<usize>,
+}
+
+
+// Registers (by the name they were declared with) accessed by a statement, as (register, index, is_write)
+fn get_register_accesses<'a> (my_statement : &Statement<'a>, pre_condition : &Option<Statement<'a>>,
+                              registers : &HashMap<String, String>) -> Vec<(String, String, bool)> {
+    let mut accesses = Vec::new();
+    add_lvalue_accesses(&my_statement.lvalue, true, registers, &mut accesses);
+    add_expr_accesses(&my_statement.expr, registers, &mut accesses);
+    if let Some(condition) = pre_condition { add_expr_accesses(&condition.expr, registers, &mut accesses) }
+    accesses
+}
+
+
+fn add_lvalue_accesses<'a> (my_lval : &LValue<'a>, is_write : bool, registers : &HashMap<String, String>,
+                            accesses : &mut Vec<(String, String, bool)>) {
+    match my_lval {
+        LValue::Scalar(id) => {
+            if let Some(register) = registers.get(id.id_name) { accesses.push((register.clone(), String::from("0"), is_write)) }
+        }
+        LValue::Array(id, index) => {
+            if let Some(register) = registers.get(id.id_name) { accesses.push((register.clone(), index.get_string(), is_write)) }
+            add_operand_accesses(index, registers, accesses);
+        }
+        &LValue::Field(_, _) => {}
+    }
+}
+
+
+fn add_operand_accesses<'a> (my_operand : &Operand<'a>, registers : &HashMap<String, String>,
+                             accesses : &mut Vec<(String, String, bool)>) {
+    match my_operand {
+        Operand::LValue(lval) => add_lvalue_accesses(lval, false, registers, accesses),
+        &Operand::Value(_) => {}
+    }
+}
+
+
+fn add_expr_accesses<'a> (my_expr : &Expr<'a>, registers : &HashMap<String, String>,
+                          accesses : &mut Vec<(String, String, bool)>) {
+    add_operand_accesses(&my_expr.op1, registers, accesses);
+    match my_expr.expr_right {
+        ExprRight::BinOp(_, ref operand) => add_operand_accesses(operand, registers, accesses),
+        ExprRight::Cond(ref operand1, ref operand2) => {
+            add_operand_accesses(operand1, registers, accesses);
+            add_operand_accesses(operand2, registers, accesses);
+        }
+        ExprRight::Empty() => {}
+    }
+}
+
+
+// reachable[i][j] is true if statement j depends on statement i through dependency edges
+fn get_reachability<'a> (my_dag : &Dag<'a>) -> Vec<Vec<bool>> {
+    let node_count = my_dag.dag_vector.len();
+    let mut reachable = vec![vec![false; node_count]; node_count];
+    // edges always point forward, so a single backward pass suffices
+    for i in (0..node_count).rev() {
+        for next in &my_dag.dag_vector[i].next_nodes {
+            reachable[i][*next] = true;
+            let next_reachable = reachable[*next].clone();
+            for (j, reached) in next_reachable.into_iter().enumerate() {
+                if reached {
+                    reachable[i][j] = true;
+                }
+            }
+        }
+    }
+    reachable
+}
+
+
+// Add the statements on dependency paths between members, so that no statement
+// outside the set both depends on and feeds into it
+fn close_members(members : &mut Vec<usize>, reachable : &[Vec<bool>]) {
+    let node_count = reachable.len();
+    let mut closed : Vec<usize> = (0..node_count).filter(|n| members.contains(n) ||
+        (members.iter().any(|a| reachable[*a][*n]) && members.iter().any(|b| reachable[*n][*b]))).collect();
+    closed.sort();
+    *members = closed;
+}
+
+
+// Find the update of every register accessed by the dag. The dependency edges
+// must have been added. Fails if a register is accessed at two different elements,
+// which would need two accesses in one pass.
+pub fn find_register_updates<'a> (my_dag : &Dag<'a>) -> Vec<RegisterUpdate> {
+    let aliases = get_state_aliases(my_dag);
+    // every version of a register maps to the register it was declared as
+    let mut registers : HashMap<String, String> = HashMap::new();
+    for dagnode in &my_dag.dag_vector {
+        match dagnode.node_type {
+            DagNodeType::Decl(ref var_decl) if var_decl.var_type.type_qualifier == TypeQualifier::Persistent => {
+                let name = var_decl.identifier.id_name.to_string();
+                let register = aliases.get(&name).cloned().unwrap_or(name.clone());
+                registers.insert(name, register);
+            }
+            _ => {}
+        }
+    }
+
+    let mut updates : Vec<RegisterUpdate> = Vec::new();
+    for (i, dagnode) in my_dag.dag_vector.iter().enumerate() {
+        if let DagNodeType::Stmt(ref my_statement) = dagnode.node_type {
+            for (register, index, is_write) in get_register_accesses(my_statement, &dagnode.pre_condition, &registers) {
+                let position = match updates.iter().position(|u| u.register == register) {
+                    Some(position) => position,
+                    None => {
+                        updates.push(RegisterUpdate { register : register.clone(), index : index.clone(),
+                                                      members : Vec::new(), writes : Vec::new() });
+                        updates.len() - 1
+                    }
+                };
+                let update = &mut updates[position];
+                if update.index != index {
+                    panic!("Snippet {} accesses register {} at both {} and {}, which needs two accesses to the register in one pass.",
+                           my_dag.snippet_id, register, update.index, index);
+                }
+                if !update.members.contains(&i) {
+                    update.members.push(i);
+                }
+                if is_write && !update.writes.contains(&i) {
+                    update.writes.push(i);
+                }
+            }
+        }
+    }
+
+    let reachable = get_reachability(my_dag);
+    for update in &mut updates {
+        close_members(&mut update.members, &reachable);
+    }
+    updates
+}
+
+
+// Statements that have to be lowered together. Updates that share a statement,
+// e.g. one that reads a register and writes another, are merged into one group.
+pub fn get_update_groups<'a> (my_dag : &Dag<'a>, updates : &Vec<RegisterUpdate>) -> Vec<Vec<usize>> {
+    let reachable = get_reachability(my_dag);
+    let mut groups : Vec<Vec<usize>> = Vec::new();
+    for update in updates {
+        let mut group = update.members.clone();
+        loop {
+            let (overlapping, rest) : (Vec<Vec<usize>>, Vec<Vec<usize>>) = groups.into_iter()
+                .partition(|g| g.iter().any(|n| group.contains(n)));
+            groups = rest;
+            if overlapping.is_empty() {
+                break;
+            }
+            for other in overlapping {
+                group.extend(other);
+            }
+            close_members(&mut group, &reachable);
+        }
+        groups.push(group);
+    }
+    groups.sort();
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::lexer::get_tokens;
+    use super::super::parser::*;
+    use super::super::compile::{INCLUDE_DIR, read_include_file, compile, CompileOptions};
+    use super::super::passes::default_passes;
+
+    fn get_updates(input : &str) -> (Vec<RegisterUpdate>, Vec<Vec<usize>>, usize) {
+        let tokens = &mut get_tokens(input);
+        let token_iter = &mut tokens.iter().peekable();
+        let parse_tree = parse_prog(token_iter);
+        let pkt_contents = read_include_file(&vec![INCLUDE_DIR.to_string()], "packet.np");
+        let pkt_tree = parse_import_packets(&mut get_tokens(&pkt_contents).iter().peekable());
+        let packet_map = create_packet_map(&parse_tree.packets, &pkt_tree);
+        let mut dag_map = create_dag_nodes(&parse_tree.snippets, &parse_tree.globals, &packet_map, &parse_tree.packets, &parse_tree.packets, &Vec::new(), &default_passes(), &mut |_, _| {});
+        let my_dag = dag_map.get_mut("fun").unwrap();
+        let updates = find_register_updates(my_dag);
+        let groups = get_update_groups(my_dag, &updates);
+        let first_stmt = my_dag.dag_vector.iter().position(|n| match n.node_type { DagNodeType::Stmt(_) => true, _ => false }).unwrap();
+        return (updates, groups, first_stmt);
+    }
+
+    #[test]
+    fn test_find_register_updates() {
+        let (updates, groups, first) = get_updates(r"@ bmv2 : s1;
+                                                     snippet fun() {
+                                                       transient t : bit<32>;
+                                                       transient u : bit<32>;
+                                                       transient x : bit<32>;
+                                                       persistent reg : bit<32> = 0;
+                                                       persistent other : bit<32> = 0;
+                                                       t = reg + 1;
+                                                       u = t + 2;
+                                                       x = 5;
+                                                       reg = u;
+                                                       other = x;
+                                                     }");
+        // x = 5 is folded into other = 5
+        assert_eq!(updates, vec![
+            RegisterUpdate { register : String::from("reg"), index : String::from("0"),
+                             members : vec![first, first + 1, first + 2], writes : vec![first + 2] },
+            RegisterUpdate { register : String::from("other"), index : String::from("0"),
+                             members : vec![first + 3], writes : vec![first + 3] }]);
+        assert_eq!(groups, vec![vec![first, first + 1, first + 2], vec![first + 3]]);
+    }
+
+    #[test]
+    fn test_update_groups_merge() {
+        let (_, groups, first) = get_updates(r"@ bmv2 : s1;
+                                               snippet fun() {
+                                                 persistent reg1 : bit<32> = 0;
+                                                 persistent reg2 : bit<32> = 0;
+                                                 reg1 = 1;
+                                                 reg2 = reg1 - 3;
+                                               }");
+        assert_eq!(groups, vec![vec![first, first + 1]]);
+    }
+
+    #[test]
+    #[should_panic(expected="Snippet fun accesses register arr at both 1 and 2, which needs two accesses to the register in one pass.")]
+    fn test_find_register_updates_two_indices_fail() {
+        get_updates(r"@ bmv2 : s1;
+                      snippet fun() {
+                        transient a : bit<32>;
+                        persistent arr : bit<32>[4] = {0, 0, 0, 0,};
+                        a = arr[1];
+                        arr[2] = a;
+                      }");
+    }
+
+    #[test]
+    fn test_update_comparison_fail() {
+        // r1 and r2 form one group, and comparing them can't be part of its stateful action
+        let input = r"@ bmv2 : s1;
+                      snippet fun() {
+                        input a : bit<8>;
+                        persistent r1 : bit<8> = 0;
+                        persistent r2 : bit<8> = 0;
+                        transient z : bit<1>;
+                        r2 = r1 + a;
+                        z = r1 > r2;
+                        r1 = z ? r2 : r1;
+                        z = 1 < r1;
+                        r2 = z ? 5 : r2;
+                      }";
+        assert_eq!(compile(input, &CompileOptions::new()).unwrap_err()[0].message,
+                   "Snippet fun needs two accesses to register r2, r1 in one pass, because z = r1 > r2 cannot be part of its stateful action.");
+    }
+}
@@ -1,11 +1,11 @@
 
 use grammar::*;
 use std::collections::HashMap;
-use std::fs::File;
 use std::io::prelude::*;
-use std::error::Error;
-use std::path::Path;
 use trans_snippet::*;
+use p4_ir::*;
+use stateful::RegisterUpdate;
+use dependency::get_state_aliases;
 
 const META_HEADER : &str = "mdata";
 const TAB : &str = "    ";
@@ -14,17 +14,17 @@ const TAB : &str = "    ";
 #[allow(dead_code)]
 #[allow(unused_imports)]
 pub fn handle_transient_decl<'a> (my_decl :  &VariableDecl<'a>) -> P4Header {
-    let mut my_p4_header : P4Header = P4Header {meta:String::new(), meta_init:String::new(), register:String::new(), define:String::new()};
+    let mut my_p4_header : P4Header = P4Header::new();
     match my_decl.var_type.var_info {
         VarInfo::BitArray(bit_width, var_size) => {
             if var_size == 1 {
                 let initial_val_index : usize = 0;
-                my_p4_header.meta = format!("{} : {};\n",my_decl.identifier.id_name, bit_width);
+                my_p4_header.meta.push(MetaField { name : my_decl.identifier.id_name.to_string(), width : bit_width });
                 let my_option = my_decl.initial_values.get(initial_val_index);
                 match my_option {
                     Some (initial_value) => {
-                        my_p4_header.meta_init = format!("set_metadata({}.{},{});\n",
-                            META_HEADER, my_decl.identifier.id_name, initial_value.value);
+                        my_p4_header.meta_init.push(Primitive::new("set_metadata",
+                            vec![format!("{}.{}", META_HEADER, my_decl.identifier.id_name), initial_value.value.to_string()]));
                     }
                     _ => {}
                 }
@@ -39,17 +39,19 @@ pub fn handle_transient_decl<'a> (my_decl :  &VariableDecl<'a>) -> P4Header {
 }
 
 pub fn handle_persistent_decl<'a> (my_decl :  &VariableDecl<'a>) -> P4Header {
-    let mut my_p4_header : P4Header = P4Header {meta:String::new(), meta_init:String::new(), register:String::new(), define:String::new()};
+    let mut my_p4_header : P4Header = P4Header::new();
     match my_decl.var_type.var_info {
         VarInfo::BitArray(bit_width, var_size) => {
             let initial_val_index : usize = 0;
-            my_p4_header.register = format!("register {} {{\n{} width : {}; \n{} instance_count : {};\n}}\n",
-            my_decl.identifier.id_name, TAB, bit_width, TAB, var_size);
+            my_p4_header.register.push(Register { name : my_decl.identifier.id_name.to_string(), width : bit_width,
+                                                  instance_count : var_size });
+            // stateful ALUs write the value they read into the metadata copy of the register
+            my_p4_header.meta.push(MetaField { name : my_decl.identifier.id_name.to_string(), width : bit_width });
             let my_option = my_decl.initial_values.get(initial_val_index);
             match my_option {
                 Some (initial_value) => {
-                    my_p4_header.meta_init = format!("set_metadata({}.{},{});\n",
-                        META_HEADER, my_decl.identifier.id_name, initial_value.value);
+                    my_p4_header.meta_init.push(Primitive::new("set_metadata",
+                        vec![format!("{}.{}", META_HEADER, my_decl.identifier.id_name), initial_value.value.to_string()]));
                 }
                 _ => {}
             }
@@ -59,7 +61,7 @@ pub fn handle_persistent_decl<'a> (my_decl :  &VariableDecl<'a>) -> P4Header {
     return my_p4_header;
 }
 pub fn get_p4_header_trans<'a> (node_type : &DagNodeType<'a>) -> P4Header {
-    let mut my_p4_header : P4Header = P4Header {meta:String::new(), meta_init:String::new(), register:String::new(), define:String::new()};
+    let mut my_p4_header : P4Header = P4Header::new();
     match &node_type {
         DagNodeType::Decl(my_decl) => {
             // Based on the type, the variable decl should be either a register/meta.
@@ -109,13 +111,121 @@ pub fn fill_p4code<'a> (import_map : &HashMap<String, String>, my_globals : &Glo
     }
 }
 
-fn gen_p4_includes<'a> ( p4_file : &mut File) {
+// Operand of a stateful ALU expression, where the register being updated is register_lo
+fn get_salu_operand<'a> (my_operand : &Operand<'a>, is_register : &Fn(&str) -> bool) -> String {
+    match my_operand {
+        &Operand::Value(ref value) => value.value.to_string(),
+        &Operand::LValue(ref lval) => {
+            match lval {
+                &LValue::Scalar(ref id) | &LValue::Array(ref id, _) if is_register(id.id_name) => String::from("register_lo"),
+                &LValue::Scalar(ref id) => format!("{}.{}", META_HEADER, id.id_name),
+                &LValue::Field(ref id, ref field_name) => format!("{}.{}", id.id_name, field_name.id_name),
+                &LValue::Array(ref id, _) => panic!("A stateful ALU cannot read array {} other than its own register.", id.id_name),
+            }
+        }
+    }
+}
+
+
+fn get_salu_expr<'a> (my_expr : &Expr<'a>, is_register : &Fn(&str) -> bool) -> String {
+    let mut contents = get_salu_operand(&my_expr.op1, is_register);
+    match my_expr.expr_right {
+        ExprRight::BinOp(bin_op_type, ref operand) => {
+            contents = contents + &handle_binop(bin_op_type) + &get_salu_operand(operand, is_register);
+        }
+        ExprRight::Cond(_, _) => panic!("A stateful ALU update cannot nest conditions."),
+        ExprRight::Empty() => {}
+    }
+    return contents;
+}
+
+
+// Lower the update of every register to one stateful ALU, the P4-14 counterpart of a
+// RegisterAction. It reads the register, computes the new value from the statements
+// of the update, and writes the old value to the metadata copy of the register for
+// the statements that read it.
+pub fn lower_register_updates<'a> (my_dag : &mut Dag<'a>, updates : &Vec<RegisterUpdate>) {
+    let aliases = get_state_aliases(my_dag);
+    for update in updates {
+        if update.writes.len() > 1 {
+            panic!("Snippet {} updates register {} more than once, which a single stateful ALU cannot do.",
+                   my_dag.snippet_id, update.register);
+        }
+        let is_register = |name : &str| name == update.register || aliases.get(name) == Some(&update.register);
+        // values of transient variables computed by the update, for substitution into the new value
+        let mut temps : HashMap<&str, &Expr> = HashMap::new();
+        let mut reads_register = false;
+        let mut salu = Blackbox { kind : String::from("stateful_alu"), name : format!("{}_alu", update.register),
+                                  attributes : vec![(String::from("reg"), update.register.clone())] };
+        for member in &update.members {
+            match my_dag.dag_vector[*member].node_type {
+                DagNodeType::Stmt(ref my_statement) if update.writes.contains(member) => {
+                    let resolve = |operand : &Operand<'a>| -> String {
+                        match operand {
+                            &Operand::LValue(LValue::Scalar(ref id)) if temps.contains_key(id.id_name) => {
+                                get_salu_expr(temps[id.id_name], &is_register)
+                            }
+                            _ => get_salu_operand(operand, &is_register)
+                        }
+                    };
+                    match my_statement.expr.expr_right {
+                        ExprRight::Cond(ref operand1, ref operand2) => {
+                            salu.attributes.push((String::from("condition_lo"),
+                                                  format!("{} == 1", get_salu_operand(&my_statement.expr.op1, &is_register))));
+                            let mut branch = 1;
+                            for (predicate, operand) in [("condition_lo", operand1), ("not condition_lo", operand2)] {
+                                if resolve(operand) != "register_lo" {
+                                    salu.attributes.push((format!("update_lo_{}_predicate", branch), predicate.to_string()));
+                                    salu.attributes.push((format!("update_lo_{}_value", branch), resolve(operand)));
+                                    branch += 1;
+                                }
+                            }
+                        }
+                        ExprRight::Empty() => {
+                            salu.attributes.push((String::from("update_lo_1_value"), resolve(&my_statement.expr.op1)));
+                        }
+                        ExprRight::BinOp(_, _) => {
+                            salu.attributes.push((String::from("update_lo_1_value"), get_salu_expr(&my_statement.expr, &is_register)));
+                        }
+                    }
+                }
+                DagNodeType::Stmt(ref my_statement) => {
+                    if let LValue::Scalar(ref id) = my_statement.lvalue {
+                        temps.insert(id.id_name, &my_statement.expr);
+                    }
+                    reads_register = true;
+                }
+                _ => {}
+            }
+        }
+        if reads_register {
+            salu.attributes.push((String::from("output_value"), String::from("register_lo")));
+            salu.attributes.push((String::from("output_dst"), format!("{}.{}", META_HEADER, update.register)));
+        }
+
+        let index = match update.index.parse::<u64>() {
+            Ok(_) => update.index.clone(),
+            Err(_) => format!("{}.{}", META_HEADER, update.index),
+        };
+        let leader = *update.members.last().unwrap();
+        let p4_code = &mut my_dag.dag_vector[leader].p4_code;
+        let action = format!("{}_action", update.register);
+        let table = format!("{}_table", update.register);
+        p4_code.p4_actions.push(Action { name : action.clone(),
+            primitives : vec![Primitive::new(&format!("{}.execute_stateful_alu", salu.name), vec![index])] });
+        p4_code.p4_blackboxes.push(salu);
+        p4_code.p4_tables.push(Table::new(&table, &action));
+        p4_code.p4_control.push(ControlStmt::Apply(table));
+    }
+}
+
+fn gen_p4_includes<'a> ( p4_file : &mut dyn Write) {
     p4_file.write(b"#include <tofino/intrinsic_metadata.p4>\n#include <tofino/constants.p4>\n");
     p4_file.write(b"#include <tofino/primitives.p4>\n#include \"tofino/stateful_alu_blackbox.p4\"\n");
     p4_file.write(b"#include \"tofino/lpf_blackbox.p4\"\n#include \"tofino/wred_blackbox.p4\"\n");
 }
 
-fn gen_p4_globals<'a> (my_dag : &Dag<'a>, p4_file : &mut File) {
+fn gen_p4_globals<'a> (my_dag : &Dag<'a>, p4_file : &mut dyn Write) {
     let mut contents : String = String::new();
     for my_dag_node in &my_dag.dag_vector {
         if (my_dag_node.p4_code.p4_header.define.len() != 0) {
@@ -124,18 +234,18 @@ fn gen_p4_globals<'a> (my_dag : &Dag<'a>, p4_file : &mut File) {
     }
     p4_file.write(contents.as_bytes());
 }
-fn gen_p4_headers<'a> (my_dag : &Dag<'a>, p4_file : &mut File) {
+fn gen_p4_headers<'a> (my_dag : &Dag<'a>, p4_file : &mut dyn Write) {
     // TODO
 }
 
-fn gen_p4_metadata<'a> (my_dag : &Dag<'a>, p4_file : &mut File) {
+fn gen_p4_metadata<'a> (my_dag : &Dag<'a>, p4_file : &mut dyn Write) {
     let mut contents : String = String::new();
     let mut meta_found = 0;
     contents = contents + &format!("header_type metadata_t {{ \n");
     contents = contents + &format!("{}fields {{\n", TAB);
     for my_dag_node in &my_dag.dag_vector {
-        if (my_dag_node.p4_code.p4_header.meta.len() != 0) {
-            contents = contents + &format!("{}{}{}",TAB, TAB,my_dag_node.p4_code.p4_header.meta);
+        for field in &my_dag_node.p4_code.p4_header.meta {
+            contents = contents + &format!("{}{}{}", TAB, TAB, field.to_p4());
             meta_found = 1;
         }
     }
@@ -146,50 +256,50 @@ fn gen_p4_metadata<'a> (my_dag : &Dag<'a>, p4_file : &mut File) {
 
 }
 
-fn gen_p4_registers<'a> (my_dag : &Dag<'a>, p4_file : &mut File) {
+fn gen_p4_registers<'a> (my_dag : &Dag<'a>, p4_file : &mut dyn Write) {
     let mut contents : String = String::new();
     for my_dag_node in &my_dag.dag_vector {
-        if (my_dag_node.p4_code.p4_header.register.len() != 0) {
-            contents = contents + &my_dag_node.p4_code.p4_header.register;
+        for register in &my_dag_node.p4_code.p4_header.register {
+            contents = contents + &register.to_p4();
         }
     }
     p4_file.write(contents.as_bytes());
 }
 
-fn gen_p4_parser<'a> (my_dag : &Dag<'a>, p4_file : &mut File) {
+fn gen_p4_parser<'a> (my_dag : &Dag<'a>, p4_file : &mut dyn Write) {
     // TODO
 }
 
-fn gen_p4_body<'a> (my_dag : &Dag<'a>, p4_file : &mut File) {
+fn gen_p4_body<'a> (my_dag : &Dag<'a>, p4_file : &mut dyn Write) {
     let mut contents : String = String::new();
-    contents = contents + &format!("control ingress {{\n");
     for my_dag_node in &my_dag.dag_vector {
-        if (my_dag_node.p4_code.p4_control.len() != 0) {
-            contents = contents + &my_dag_node.p4_code.p4_control;
+        for blackbox in &my_dag_node.p4_code.p4_blackboxes {
+            contents = contents + &blackbox.to_p4();
+        }
+        for action in &my_dag_node.p4_code.p4_actions {
+            contents = contents + &action.to_p4();
         }
     }
-    contents = contents + &format!("}}\n");
-
-    contents = contents + &format!("control egress {{\n");
     for my_dag_node in &my_dag.dag_vector {
-        if (my_dag_node.p4_code.p4_control.len() != 0) {
-            contents = contents + &my_dag_node.p4_code.p4_control;
+        for table in &my_dag_node.p4_code.p4_tables {
+            contents = contents + &table.to_p4(true);
         }
     }
+    contents = contents + &format!("control ingress {{\n");
+    for my_dag_node in &my_dag.dag_vector {
+        contents = contents + &control_to_p4(&my_dag_node.p4_code.p4_control, 1);
+    }
+    contents = contents + &format!("}}\n");
+
+    // tables can only be applied once, so everything runs in ingress
+    contents = contents + &format!("control egress {{\n");
     contents = contents + &format!("}}\n");
     p4_file.write(contents.as_bytes());
 }
 
-pub fn gen_p4_code<'a> (snippet_name : &str,  my_packets : &Packets<'a>, snippet_dag : &Dag<'a>){
-    let p4_filename : String = format!("out/{}.p4", snippet_name);
-    let path = Path::new(p4_filename.as_str());
-    let display  = path.display();
-    let mut p4_file = match File::create(path) {
-        Err(why) => panic!("couldn't create {}: {}",
-                           display,
-                           why.description()),
-        Ok(p4_file) => p4_file,
-    };
+// P4 program for the dag of one device
+pub fn get_p4_code<'a> (snippet_dag : &Dag<'a>) -> String {
+    let mut p4_file : Vec<u8> = Vec::new();
     gen_p4_includes(&mut p4_file);
     gen_p4_globals(&snippet_dag, &mut p4_file);
     gen_p4_headers(&snippet_dag, &mut p4_file);
@@ -198,4 +308,5 @@ pub fn gen_p4_code<'a> (snippet_name : &str,  my_packets : &Packets<'a>, snippet
     gen_p4_parser(&snippet_dag, &mut p4_file);
     //gen_p4_actions(&snippet_dag, &mut p4_file);
     gen_p4_body(&snippet_dag, &mut p4_file);
+    return String::from_utf8(p4_file).unwrap();
 }
@@ -6,6 +6,8 @@ pub enum Token<'a> {
   // Variants that take an argument
   Identifier(&'a str),
   Value(u64),
+  // String literal, without the quotes
+  Str(&'a str),
 
   // Keywords: input, output, persistent, transient, constant, snippet, and, or, not, bit
   Input,
@@ -24,6 +26,7 @@ pub enum Token<'a> {
   SemiColon,
   Comma,
   Arrow,
+  DoubleDash,
 
   // Grouping operators
   ParenLeft,
@@ -64,6 +67,10 @@ pub enum Token<'a> {
   If,
   Else,
   Annotation,
+  Pipeline,
+  Topology,
+  Assert,
+  Invariant,
 
   // Dot operator
   Dot,
@@ -7,22 +7,26 @@ use grammar::*;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::prelude::*;
-use std::error::Error;
-use std::path::Path;
 use bmv2_gen;
 use tofino_gen;
+use p4_ir::*;
+use resources;
+use schedule;
+use packing;
+use stateful;
+use dead_code::unread_registers;
+use passes::*;
+use dot;
+use compile::*;
+use serde_json;
 
 
-use std::process;
-use std::process::Command;
-use std::env;
 use std::mem;
 
 //use handlebars::Handlebars;
 
 const META_HEADER : &str = "mdata";
 const TAB : &str = "    ";
-const INCLUDE_DIR : &str = "net-progs/include/";
 // natesh edit...removed &'a from VariableDecl
 #[derive(Debug)]
 #[derive(PartialEq)]
@@ -49,22 +53,90 @@ pub struct DagNode<'a> {
 #[derive(Clone)]
 pub struct P4Code {
     pub p4_header : P4Header,
-    pub p4_control : String,
-    pub p4_actions : String,
-    pub p4_commons : String,
+    pub p4_control : Vec<ControlStmt>,
+    pub p4_actions : Vec<Action>,
+    pub p4_tables : Vec<Table>,
+    pub p4_blackboxes : Vec<Blackbox>,
+}
+
+impl P4Code {
+    pub fn new() -> P4Code {
+        return P4Code { p4_header : P4Header::new(), p4_control : Vec::new(), p4_actions : Vec::new(),
+                        p4_tables : Vec::new(), p4_blackboxes : Vec::new() };
+    }
+
+    pub fn append(&mut self, other : P4Code) {
+        self.p4_header.append(other.p4_header);
+        self.p4_control.extend(other.p4_control);
+        self.p4_actions.extend(other.p4_actions);
+        self.p4_tables.extend(other.p4_tables);
+        self.p4_blackboxes.extend(other.p4_blackboxes);
+    }
+
+    // Renames the actions and tables that appear in names, and every reference to them
+    pub fn rename(&mut self, names : &HashMap<String, String>) {
+        let get_name = |name : &String| names.get(name).cloned().unwrap_or(name.clone());
+        for action in &mut self.p4_actions {
+            action.name = get_name(&action.name);
+        }
+        for table in &mut self.p4_tables {
+            table.name = get_name(&table.name);
+            table.actions = table.actions.iter().map(&get_name).collect();
+            table.default_action = table.default_action.as_ref().map(&get_name);
+        }
+        for stmt in &mut self.p4_control {
+            stmt.rename_tables(names);
+        }
+    }
 
+    // Renames the fields that appear in names in the primitives, table reads and conditions
+    pub fn rename_fields(&mut self, names : &HashMap<String, String>) {
+        let get_name = |name : &String| names.get(name).cloned().unwrap_or(name.clone());
+        for action in &mut self.p4_actions {
+            for primitive in &mut action.primitives {
+                primitive.args = primitive.args.iter().map(&get_name).collect();
+            }
+        }
+        for table in &mut self.p4_tables {
+            table.reads = table.reads.iter().map(|&(ref field, ref match_kind)| (get_name(field), match_kind.clone())).collect();
+        }
+        for stmt in &mut self.p4_control {
+            stmt.rename_fields(names);
+        }
+    }
+
+    // Add a primitive to the action that was defined last
+    pub fn push_primitive(&mut self, primitive : Primitive) {
+        match self.p4_actions.last_mut() {
+            Some(action) => action.primitives.push(primitive),
+            None => panic!("Primitive {} outside of an action", primitive.to_p4().trim()),
+        }
+    }
 }
 
 #[derive(Debug)]
 #[derive(PartialEq)]
 #[derive(Clone)]
 pub struct P4Header {
-    pub meta : String,
-    pub meta_init : String,
-    pub register : String,
+    pub meta : Vec<MetaField>,
+    pub meta_init : Vec<Primitive>,
+    pub register : Vec<Register>,
     pub define : String
 }
 
+impl P4Header {
+    pub fn new() -> P4Header {
+        return P4Header { meta : Vec::new(), meta_init : Vec::new(), register : Vec::new(), define : String::new() };
+    }
+
+    pub fn append(&mut self, other : P4Header) {
+        self.meta.extend(other.meta);
+        self.meta_init.extend(other.meta_init);
+        self.register.extend(other.register);
+        self.define += &other.define;
+    }
+}
+
 // For now, using a simplistic DAG dc using vectors.
 #[derive(Debug)]
 #[derive(PartialEq)]
@@ -73,7 +145,9 @@ pub struct Dag<'a> {
     pub snippet_id       : &'a str,
     pub device_type : &'a str,
     pub device_vector : Vec<Identifier<'a>>,
-    pub dag_vector : Vec<DagNode<'a>>
+    pub dag_vector : Vec<DagNode<'a>>,
+    // variable each SSA version was created from, see static_single_assignment
+    pub ssa_origins : HashMap<String, String>,
 }
 
 #[derive(Debug)]
@@ -187,19 +261,19 @@ pub fn check_clone_condition<'a> (my_pre_condition_dag_option : Option<&'a DagNo
 
     match my_pre_condition_dag_option {
         Some (my_pre_condition_dag) => {
-            println!("Condition : {:?}\n",my_pre_condition_dag);
+            debug!("Condition : {:?}\n",my_pre_condition_dag);
             match &my_pre_condition_dag.node_type {
                 DagNodeType::Stmt(my_statement) => {
                     match my_statement.lvalue {
                         LValue::Scalar(ref my_identifier) => {
                             condition_statement_lvalue = LValue::Scalar(Identifier{id_name : my_identifier.id_name});
-                            println!("{:?}\n", condition_statement_lvalue);
+                            debug!("{:?}\n", condition_statement_lvalue);
                             match my_statement.expr.op1 {
                                 Operand::LValue(ref lval) => {
                                     match lval {
                                         LValue::Scalar(ref my_identifier2) => {
                                             let my_lval = LValue::Scalar(Identifier{id_name : my_identifier2.id_name});
-                                            println!("{:?}\n", my_lval);
+                                            debug!("{:?}\n", my_lval);
                                             match my_statement.expr.expr_right {
                                                 ExprRight::BinOp(bin_op_type, ref operand) => {
                                                     match operand {
@@ -207,13 +281,13 @@ pub fn check_clone_condition<'a> (my_pre_condition_dag_option : Option<&'a DagNo
                                                             match lval2 {
                                                                 LValue::Scalar(ref my_identifier3) => {
                                                                     let my_lval2 = LValue::Scalar(Identifier{id_name : my_identifier3.id_name});
-                                                                    println!("{:?}\n", bin_op_type);
-                                                                    println!("{:?}\n", my_lval2);
+                                                                    debug!("{:?}\n", bin_op_type);
+                                                                    debug!("{:?}\n", my_lval2);
                                                                     condition_statement_op1 = Operand::LValue(my_lval);
                                                                     condition_statement_exprright = ExprRight::BinOp(bin_op_type, Operand::LValue(my_lval2));
 
-                                                                    println!("{:?}\n",condition_statement_op1);
-                                                                    println!("{:?}\n",condition_statement_exprright);
+                                                                    debug!("{:?}\n",condition_statement_op1);
+                                                                    debug!("{:?}\n",condition_statement_exprright);
                                                                     condition_statement_expr = Expr{op1: condition_statement_op1, expr_right:condition_statement_exprright};
                                                                     pre_condition = Some(Statement{lvalue: condition_statement_lvalue, expr : condition_statement_expr });
                                                                 }
@@ -294,8 +368,7 @@ pub fn create_connections<'a> (_my_snippet: &'a Snippet<'a>, my_packets : &Packe
         for field in &my_packet.packet_fields.field_vector {
             let field_name  = format!("{}.{}", my_packet.packet_id.id_name.clone(), field.identifier.id_name.clone());
             
-            let dummyheader = P4Header{meta:String::new(), meta_init:String::new(), register:String::new(), define:String::new()};
-            let dummpyp4 = P4Code{p4_header: dummyheader, p4_control:String::new(), p4_actions:String::new(), p4_commons:String::new()};
+            let dummpyp4 = P4Code::new();
             let header_decl = VariableDecl {identifier : Identifier{id_name : Box::leak(field_name.into_boxed_str()) },
                 initial_values : Vec::<Value>::new(), var_type : field.var_type.clone()};
             let packet_decl_node = DagNode {node_type : DagNodeType::Decl(header_decl.clone()),
@@ -310,8 +383,7 @@ pub fn create_connections<'a> (_my_snippet: &'a Snippet<'a>, my_packets : &Packe
                 let my_id = my_pkt_field.identifier.id_name.clone();
                 let field_name  = format!("{}.{}{}", my_packet.packet_id.id_name.clone(), my_pkt.packet_id.id_name.clone(), my_id);
                 
-                let dummyheader = P4Header{meta:String::new(), meta_init:String::new(), register:String::new(), define:String::new()};
-                let dummpyp4 = P4Code{p4_header: dummyheader, p4_control:String::new(), p4_actions:String::new(), p4_commons:String::new()};
+                let dummpyp4 = P4Code::new();
                 let header_decl = VariableDecl {identifier : Identifier{id_name : Box::leak(field_name.into_boxed_str()) },
                     initial_values : Vec::<Value>::new(), var_type : my_pkt_field.var_type.clone()};
                 let packet_decl_node = DagNode {node_type : DagNodeType::Decl(header_decl.clone()),
@@ -574,10 +646,8 @@ pub fn create_connections<'a> (_my_snippet: &'a Snippet<'a>, my_packets : &Packe
         }
     }
 
-    create_dependency_dag(&mut my_dag.clone());
-    println!("new nodes{:?}\n\n", my_dag);
+    debug!("new nodes{:?}\n\n", my_dag);
     create_offload_header(&mut my_dag.clone());
-    process::exit(1);
 }
 
 
@@ -745,88 +815,6 @@ pub fn get_read_vars<'a> (decl_map : &HashMap<String, usize>, my_statement : Sta
 
 
 
-// TODO : create new vardecl nodes for psa.np variables
-pub fn create_RAW_connections<'a> (_my_snippet: &'a Snippet<'a>, my_packets : &Packets<'a>, 
-                                pkt_tree : &Packets<'a>, my_imports : &Imports<'a>, my_dag : &mut Dag<'a>) {
-    // A HashMap to keep track of declarations.
-    let mut decl_map : HashMap<String, usize>= HashMap::new();
-    let mut i : usize = 0;
-    let mut j : usize = 0;
-    let mut write_var : usize = 0;
-    let mut read_vars : Vec<usize> = Vec::new();
-
-    let mut print_write_var : usize = 0;
-    let mut print_read_vars : Vec<usize> = Vec::new();
-
-    // building RAW (read-after-write) dependency dag. Control dependencies were eliminated
-    // after converting if/else to single-line cond statements.
-    for dagnode in my_dag.dag_vector.clone() {
-
-        match &dagnode.node_type {
-            // All vardecls will always be parsed first, before any other lines of code. If/else blocks follow
-            DagNodeType::Decl(var_decl) => {
-                decl_map.insert(var_decl.identifier.id_name.to_string(), i);
-                i += 1;
-            }
-
-            DagNodeType::Stmt(my_statement) => {
-
-                print_write_var = get_write_var(&decl_map, my_statement.lvalue.clone());
-                println!("write_var {:?} {:?}", i, my_dag.dag_vector.get_mut(print_write_var));
-                println!("");
-
-                print_read_vars = get_read_vars(&decl_map, my_statement.clone());
-                for r in print_read_vars {
-                    println!("read_var {:?} {:?}", r, my_dag.dag_vector.get_mut(r));
-                    println!("");
-                }
-
-                j = i + 1;
-                write_var = get_write_var(&decl_map, my_statement.lvalue.clone());
-                
-                while j < my_dag.dag_vector.len() {
-
-                    let next_statement = my_dag.dag_vector.get_mut(j).unwrap();
-
-                    match &next_statement.node_type {
-                        DagNodeType::Stmt(stmt) => {
-                            read_vars = get_read_vars(&decl_map, stmt.clone());
-                        }
-                        _ => {}
-                    }
-                    
-                    for r in read_vars.clone() {
-                        if r == write_var {
-                            let my_parent_dag_option = my_dag.dag_vector.get_mut(i);
-                            match my_parent_dag_option {
-                                Some(mut my_parent_dag_node) => {
-                                    if !&my_parent_dag_node.next_nodes.contains(&j) {
-                                        my_parent_dag_node.next_nodes.push(j);
-                                    }
-                                }
-                                None => {}
-                            }                            
-                        }
-                    }
-
-                    j += 1;
-                }
-
-                i += 1;
-            }
-
-            _ => {}
-        }
-    }
-
-    create_dependency_dag(&mut my_dag.clone());
-    println!("new nodes{:?}\n\n", my_dag);
-    create_offload_header(&mut my_dag.clone());
-    process::exit(1);
-}
-
-
-
 pub fn create_offload_header<'a> (my_dag : &mut Dag<'a>) {
 
     let mut offload_header = Vec::<DagNode>::new();
@@ -858,22 +846,22 @@ pub fn create_offload_header<'a> (my_dag : &mut Dag<'a>) {
     while i < color.len() {
         // let c =  color[i];
         if(color[i] == 0) {
-            println!("HEYYYYYY");
+            debug!("HEYYYYYY");
             DFS_visit(my_dag.dag_vector.clone(), i, &mut color, &mut offload_header);
             // println!("{:?}", i);
         }
-        println!("YOOOO {:?}", i);
+        debug!("YOOOO {:?}", i);
         i += 1;  
     }
 }
 
 
 pub fn DFS_visit<'a> (G : Vec<DagNode>, i : usize, color : &mut Vec<usize>, offload_header : &mut Vec<DagNode> ) {
-    println!("{:?}", color.get_mut(i).unwrap().to_string());
+    debug!("{:?}", color.get_mut(i).unwrap().to_string());
     color[i] = 1;
-    println!("{:?}", color.get_mut(i).unwrap().to_string());
-    println!("{:?}", G.get(i).unwrap());
-    println!();
+    debug!("{:?}", color.get_mut(i).unwrap().to_string());
+    debug!("{:?}", G.get(i).unwrap());
+    debug!();
     // var_map[] = ;
 
     let mut accum : usize = 1;
@@ -889,111 +877,6 @@ pub fn DFS_visit<'a> (G : Vec<DagNode>, i : usize, color : &mut Vec<usize>, offl
 
 
 
-pub fn create_dependency_dag<'a> (my_dag : &mut Dag<'a>) {
-
-    let out_f : String = format!("plots/dependency_dag.txt");
-    let path = Path::new(out_f.as_str());
-    let display  = path.display();
-    let mut out_file1 = match File::create(path) {
-        Err(why) => panic!("couldn't create {}: {}",
-                           display,
-                           why.description()),
-        Ok(out_file1) => out_file1,
-    };
-
-    let out_f : String = format!("plots/next_prev_nodes.txt");
-    let path = Path::new(out_f.as_str());
-    let display  = path.display();
-    let mut out_file2 = match File::create(path) {
-        Err(why) => panic!("couldn't create {}: {}",
-                           display,
-                           why.description()),
-        Ok(out_file2) => out_file2,
-    };
-
-    let mut contents = String::new();
-    let mut next_prev_nodes = String::new();
-
-    for dagnode in my_dag.dag_vector.clone() {
-        match &dagnode.node_type {
-
-            DagNodeType::Decl(var_decl) => {
-                contents += &format!("{:?} {:?} : {:?}", var_decl.var_type.type_qualifier, 
-                    var_decl.identifier.id_name, var_decl.var_type.var_info);
-                next_prev_nodes += &format!("{:?}:{:?}\n", dagnode.next_nodes, dagnode.prev_nodes);
-
-                if(!var_decl.initial_values.is_empty()) {
-                    contents += &format!("[");
-                    for i in var_decl.initial_values.clone() {
-                        contents += &format!("{:?},", i.value);
-                    }
-                    contents += &format!("]");
-                }
-                contents +=  &format!("\n");
-            }
-
-            DagNodeType::Stmt(stmt) => {
-                
-                match &stmt.lvalue {
-                    LValue::Scalar(ref id) => {
-                        contents += &format!("{:?} = ", id.id_name);
-                    }
-
-                    LValue::Array(id1,  id2) => {
-                        contents += &format!("{:?}[{:?}] = ", id1.id_name, handle_array_op(id2));
-                    }
-
-                    LValue::Field(ref p, ref f) => {
-                        contents += &format!("{:?}.{:?} = ", p.id_name, f.id_name);
-                    }
-                }
-
-                contents += &handle_operand(&stmt.expr.op1);
-
-                match &stmt.expr.expr_right {
-                    ExprRight::BinOp(bin_op_type, ref operand) => {
-                        contents += &handle_binop(*bin_op_type);
-                        contents += &handle_operand(operand);                    
-                    }
-
-                    ExprRight::Cond(ref operand1, ref operand2) => {
-                        contents += &format!(" ? ");
-                        contents += &handle_operand(operand1);
-                        contents += &format!(" : ");
-                        contents += &handle_operand(operand2);
-                    }
-
-                    ExprRight::Empty() => {}
-                }
-
-                contents += &format!("\n");
-                next_prev_nodes += &format!("{:?}:{:?}\n", dagnode.next_nodes, dagnode.prev_nodes);
-            }
-
-            _ => {}
-        }
-    }
-
-    let clean_contents : String = contents.replace('"', "").replace('\\',"");
-    out_file1.write(clean_contents.as_bytes());
-    out_file2.write(next_prev_nodes.as_bytes());
-
-    let mut root = Path::new("plots");
-    assert!(env::set_current_dir(&root).is_ok());
-    let _output = Command::new("python3")
-            .arg("gen_dependency_dag.py")
-            .output()
-            .expect("failed to execute process");
-
-    root = Path::new("..");
-    assert!(env::set_current_dir(&root).is_ok());
-
-    // println!("new nodes{:?}", my_dag);
-    // process::exit(1);
-}
-
-
-
 pub fn handle_binop<'a> (bin_op_type : BinOpType) -> String {
     let mut contents = String::new();
     match bin_op_type {
@@ -1116,8 +999,7 @@ pub fn insert_packet_decls<'a> (my_dag : &mut Dag<'a>, my_packets : &Packets<'a>
         for field in &my_packet.packet_fields.field_vector {
             let field_name  = format!("{}.{}", my_packet.packet_id.id_name.clone(), field.identifier.id_name.clone());
             
-            let dummyheader = P4Header{meta:String::new(), meta_init:String::new(), register:String::new(), define:String::new()};
-            let dummpyp4 = P4Code{p4_header: dummyheader, p4_control:String::new(), p4_actions:String::new(), p4_commons:String::new()};
+            let dummpyp4 = P4Code::new();
             let header_decl = VariableDecl {identifier : Identifier{id_name : Box::leak(field_name.into_boxed_str()) },
                 initial_values : Vec::<Value>::new(), var_type : field.var_type.clone()};
             let packet_decl_node = DagNode {node_type : DagNodeType::Decl(header_decl.clone()),
@@ -1132,8 +1014,7 @@ pub fn insert_packet_decls<'a> (my_dag : &mut Dag<'a>, my_packets : &Packets<'a>
                 let my_id = my_pkt_field.identifier.id_name.clone();
                 let field_name  = format!("{}.{}{}", my_packet.packet_id.id_name.clone(), my_pkt.packet_id.id_name.clone(), my_id);
                 
-                let dummyheader = P4Header{meta:String::new(), meta_init:String::new(), register:String::new(), define:String::new()};
-                let dummpyp4 = P4Code{p4_header: dummyheader, p4_control:String::new(), p4_actions:String::new(), p4_commons:String::new()};
+                let dummpyp4 = P4Code::new();
                 let header_decl = VariableDecl {identifier : Identifier{id_name : Box::leak(field_name.into_boxed_str()) },
                     initial_values : Vec::<Value>::new(), var_type : my_pkt_field.var_type.clone()};
                 let packet_decl_node = DagNode {node_type : DagNodeType::Decl(header_decl.clone()),
@@ -1147,14 +1028,36 @@ pub fn insert_packet_decls<'a> (my_dag : &mut Dag<'a>, my_packets : &Packets<'a>
 
 
 
+// Declares the fields of the imported devices, so that statements using them go through SSA
+// like those using packet fields.
+pub fn insert_device_decls<'a> (my_dag : &mut Dag<'a>, device_decls : &Vec<VariableDecl<'a>>) {
+    for device_decl in device_decls {
+        let device_decl_node = DagNode {node_type : DagNodeType::Decl(device_decl.clone()),
+            p4_code : P4Code::new(), next_nodes : Vec::new(), prev_nodes : Vec::new(), pre_condition : None};
+        my_dag.dag_vector.push(device_decl_node);
+    }
+}
+
+
+// Declares the globals of the program, so that statements reading them go through SSA.
+// Their initial values are folded into the statements by fold_constants.
+pub fn insert_global_decls<'a> (my_dag : &mut Dag<'a>, my_globals : &Globals<'a>) {
+    for (insert_ind, my_global) in my_globals.global_vector.iter().enumerate() {
+        let global_decl_node = DagNode {node_type : DagNodeType::Decl(my_global.clone()),
+            p4_code : P4Code::new(), next_nodes : Vec::new(), prev_nodes : Vec::new(), pre_condition : None};
+        my_dag.dag_vector.insert(insert_ind, global_decl_node);
+    }
+}
+
+
+
 pub fn branch_removal<'a> (my_dag : &mut Dag<'a>, packet_map : &HashMap<String, String>, my_snippet : &Snippet<'a>, field_decls : &HashMap<String, VarType>) {
 
     let mut symbol_table : HashMap<&'a str, VarType<'a>> = HashMap::new();
 
     for my_variable_decl in &my_snippet.variable_decls.decl_vector {
 
-        let dummyheader = P4Header{meta:String::new(), meta_init:String::new(), register:String::new(), define:String::new()};
-        let dummpyp4 = P4Code{p4_header: dummyheader, p4_control:String::new(), p4_actions:String::new(), p4_commons:String::new()};
+        let dummpyp4 = P4Code::new();
         let my_dag_start_node = DagNode {node_type : DagNodeType::Decl(my_variable_decl.clone()),
             p4_code : dummpyp4, next_nodes : Vec::new(), prev_nodes : Vec::new(), pre_condition : None};
         my_dag.dag_vector.push(my_dag_start_node);
@@ -1170,8 +1073,7 @@ pub fn branch_removal<'a> (my_dag : &mut Dag<'a>, packet_map : &HashMap<String,
         if my_if_block.condtype == 3 {
 
             for my_statement in &my_if_block.statements.stmt_vector {
-                let dummyheader = P4Header{meta:String::new(), meta_init:String::new(), register:String::new(), define:String::new()};
-                let dummpyp4 = P4Code{p4_header:dummyheader, p4_control:String::new(), p4_actions:String::new(), p4_commons:String::new()};
+                let dummpyp4 = P4Code::new();
                 let mut my_dag_node = DagNode {node_type: DagNodeType::Stmt(my_statement.clone()),
                     p4_code : dummpyp4, next_nodes: Vec::new(), prev_nodes: Vec::new(), pre_condition : None};
                 my_dag.dag_vector.push(my_dag_node);
@@ -1186,8 +1088,7 @@ pub fn branch_removal<'a> (my_dag : &mut Dag<'a>, packet_map : &HashMap<String,
                     // need to change variable names so they are more unique and do not conflict with
                     // variable names in other snippets i.e. include snippet_id, device_id in if_var string
                     let if_var =  format!("if_block_tmp_{}", my_if_block.id);
-                    let dummyheader = P4Header{meta:String::new(), meta_init:String::new(), register:String::new(), define:String::new()};
-                    let dummpyp4 = P4Code{p4_header: dummyheader, p4_control:String::new(), p4_actions:String::new(), p4_commons:String::new()};
+                    let dummpyp4 = P4Code::new();
                     let if_bit_decl = VariableDecl {identifier : Identifier{id_name : Box::leak(if_var.into_boxed_str()) },
                                 initial_values : Vec::<Value>::new(),
                                 var_type : VarType { var_info : VarInfo::BitArray(1, 1), type_qualifier : TypeQualifier::Transient }};
@@ -1202,8 +1103,7 @@ pub fn branch_removal<'a> (my_dag : &mut Dag<'a>, packet_map : &HashMap<String,
                 // adds node for statement of setting if_bit to condition expression
                 {
                     let if_var =  format!("if_block_tmp_{}", my_if_block.id);
-                    let dummyheader = P4Header{meta:String::new(), meta_init:String::new(), register:String::new(), define:String::new()};
-                    let dummpyp4 = P4Code{p4_header: dummyheader, p4_control:String::new(), p4_actions:String::new(), p4_commons:String::new()};
+                    let dummpyp4 = P4Code::new();
                     let if_bit_stmt = Statement {
                                         lvalue : LValue::Scalar(Identifier { id_name : Box::leak(if_var.into_boxed_str()) }),
                                         expr : my_if_block.condition.expr.clone()};
@@ -1223,8 +1123,7 @@ pub fn branch_removal<'a> (my_dag : &mut Dag<'a>, packet_map : &HashMap<String,
 
                     {
                         let tmp_var =  format!("tmp_{}_if_{}", tmp_var_count, my_if_block.id);
-                        let dummyheader = P4Header{meta:String::new(), meta_init:String::new(), register:String::new(), define:String::new()};
-                        let dummpyp4 = P4Code{p4_header: dummyheader, p4_control:String::new(), p4_actions:String::new(), p4_commons:String::new()};
+                        let dummpyp4 = P4Code::new();
                         let mut vinfo : VarInfo = VarInfo::BitArray(1,1); // temp value for vinfo
 
                         // get varinfo's 1st index in bitarray from varinfo field of my_statement.expr.lvalue.scalar.id_name variable(match on scalar/
@@ -1246,7 +1145,8 @@ pub fn branch_removal<'a> (my_dag : &mut Dag<'a>, packet_map : &HashMap<String,
                             // _ => {}
                             LValue::Field(ref p, ref f) => {
                                 let field = format!("{}.{}", p.id_name, f.id_name);
-                                let field_name_map = packet_map.get(&field).unwrap();
+                                // device fields aren't in the packet map
+                                let field_name_map = packet_map.get(&field).unwrap_or(&field);
                                 let vtype = field_decls.get(field_name_map).unwrap();
                                 let width =  match vtype.var_info {
                                               VarInfo::BitArray(bit_width, _var_size) => bit_width,
@@ -1268,8 +1168,7 @@ pub fn branch_removal<'a> (my_dag : &mut Dag<'a>, packet_map : &HashMap<String,
 
                     {
                         let tmp_var =  format!("tmp_{}_if_{}", tmp_var_count, my_if_block.id);
-                        let dummyheader = P4Header{meta:String::new(), meta_init:String::new(), register:String::new(), define:String::new()};
-                        let dummpyp4 = P4Code{p4_header: dummyheader, p4_control:String::new(), p4_actions:String::new(), p4_commons:String::new()};
+                        let dummpyp4 = P4Code::new();
                         let tmp_stmt = Statement {
                                             lvalue : LValue::Scalar(Identifier { id_name : Box::leak(tmp_var.into_boxed_str()) }),
                                             expr : my_statement.expr.clone()};
@@ -1293,7 +1192,7 @@ pub fn branch_removal<'a> (my_dag : &mut Dag<'a>, packet_map : &HashMap<String,
                             tmp_expr = Expr { op1: Operand::LValue(LValue::Scalar(Identifier{id_name: Box::leak(if_var.into_boxed_str()),})),
                                             expr_right: ExprRight::Cond(Operand::LValue(LValue::Scalar(Identifier{id_name: Box::leak(tmp_var.into_boxed_str()),})),
                                             Operand::LValue(my_statement.lvalue.clone())) };
-                            println!("Assigning a cond expr\n");
+                            debug!("Assigning a cond expr\n");
                         } else if my_if_block.condtype == 2 {
                             let if_var =  format!("if_block_tmp_{}", my_if_block.id - 1); // for else condition, use the previous
                                                                                          // if block's condition bit var to set
@@ -1304,8 +1203,7 @@ pub fn branch_removal<'a> (my_dag : &mut Dag<'a>, packet_map : &HashMap<String,
                                             Operand::LValue(LValue::Scalar(Identifier{id_name: Box::leak(tmp_var.into_boxed_str()),}))) };
                         }
 
-                        let dummyheader = P4Header{meta:String::new(), meta_init:String::new(), register:String::new(), define:String::new()};
-                        let dummpyp4 = P4Code{p4_header: dummyheader, p4_control:String::new(), p4_actions:String::new(), p4_commons:String::new()};
+                        let dummpyp4 = P4Code::new();
                         let tmp_stmt = Statement {
                                             lvalue : my_statement.lvalue.clone(),
                                             expr : tmp_expr};
@@ -1331,7 +1229,7 @@ pub fn branch_removal<'a> (my_dag : &mut Dag<'a>, packet_map : &HashMap<String,
                         tmp_expr = Expr { op1: Operand::LValue(LValue::Scalar(Identifier{id_name: Box::leak(if_var.into_boxed_str()),})),
                                         expr_right: ExprRight::Cond(my_statement.expr.op1.clone(),
                                         Operand::LValue(my_statement.lvalue.clone())) };
-                        println!("Assigning a cond expr\n");
+                        debug!("Assigning a cond expr\n");
 
                     } else if my_if_block.condtype == 2 {
                         let if_var =  format!("if_block_tmp_{}", my_if_block.id - 1);
@@ -1339,10 +1237,9 @@ pub fn branch_removal<'a> (my_dag : &mut Dag<'a>, packet_map : &HashMap<String,
                                         expr_right: ExprRight::Cond(Operand::LValue(my_statement.lvalue.clone()),
                                          my_statement.expr.op1.clone()) };
                     }
-                    println!("tmp_expr : {:?}\n", tmp_expr);
+                    debug!("tmp_expr : {:?}\n", tmp_expr);
 
-                    let dummyheader = P4Header{meta:String::new(), meta_init:String::new(), register:String::new(), define:String::new()};
-                    let dummpyp4 = P4Code{p4_header: dummyheader, p4_control:String::new(), p4_actions:String::new(), p4_commons:String::new()};
+                    let dummpyp4 = P4Code::new();
 
                     let tmp_stmt = Statement {
                                         lvalue : my_statement.lvalue.clone(),
@@ -1644,9 +1541,9 @@ pub fn static_single_assignment<'a> (my_dag : &mut Dag<'a>) {
                 
                 if new_name != "" {
                 // if rename_write_operand(&mut assign_table, &mut write_check, &mut name_count, &mut tmp_stmt) {
+                    my_dag.ssa_origins.insert(new_name.clone(), old_name.clone());
                     // create new vardecl node
-                    let dummyheader = P4Header{meta:String::new(), meta_init:String::new(), register:String::new(), define:String::new()};
-                    let dummpyp4 = P4Code{p4_header: dummyheader, p4_control:String::new(), p4_actions:String::new(), p4_commons:String::new()};
+                    let dummpyp4 = P4Code::new();
                     let new_decl = VariableDecl {identifier : Identifier{id_name : Box::leak(new_name.into_boxed_str()) },
                                 initial_values : Vec::<Value>::new(),
                                 var_type : decl_map.get_mut(&old_name).unwrap().clone()};
@@ -1657,8 +1554,7 @@ pub fn static_single_assignment<'a> (my_dag : &mut Dag<'a>) {
                     i += 1;
                 };
 
-                let dummyheader = P4Header{meta:String::new(), meta_init:String::new(), register:String::new(), define:String::new()};
-                let dummpyp4 = P4Code{p4_header: dummyheader, p4_control:String::new(), p4_actions:String::new(), p4_commons:String::new()};
+                let dummpyp4 = P4Code::new();
                 let mut tmp_node = DagNode {node_type : DagNodeType::Stmt(tmp_stmt.clone()),
                     p4_code : dummpyp4, next_nodes : Vec::new(), prev_nodes : Vec::new(), pre_condition : None};
                 let ans = std::mem::replace(&mut my_dag.dag_vector[i], tmp_node.clone());
@@ -1673,66 +1569,381 @@ pub fn static_single_assignment<'a> (my_dag : &mut Dag<'a>) {
 }     
 
 
-// This func creates the snippet dag. It performs branch removal (to convert if/else
-// statements to single line ternary conditionals) and single-static assignment for each snippet 
+// This func creates the snippet dag. It runs the pipeline of dag passes on each snippet,
+// starting with branch removal (to convert if/else statements to single line ternary
+// conditionals) and single-static assignment, see passes.rs. after_pass sees the dag of
+// every snippet after every pass.
 // TODO need to handle packet field nodes
-pub fn create_dag_nodes<'a> (my_snippets : &'a Snippets, packet_map : &HashMap<String, String>,
-    my_packets : &Packets<'a>, pkt_tree : &Packets<'a>,) -> HashMap<&'a str, Dag<'a>>  {
+pub fn create_dag_nodes<'a> (my_snippets : &'a Snippets, my_globals : &Globals<'a>, packet_map : &HashMap<String, String>,
+    my_packets : &Packets<'a>, pkt_tree : &Packets<'a>, device_decls : &Vec<VariableDecl<'a>>, passes : &[String],
+    after_pass : &mut dyn FnMut(&'static str, &Dag<'a>)) -> HashMap<&'a str, Dag<'a>>  {
 
     let mut dag_map : HashMap<&str, Dag>= HashMap::new();
-    let mut field_decls : HashMap<String, VarType> = HashMap::new();
+    let field_decls = get_field_decls(my_packets, pkt_tree, device_decls);
+    let pass_manager = PassManager::new(passes);
+
+    for my_snippet in &my_snippets.snippet_vector {
+        let context = PassContext { my_snippet, my_globals, my_packets, pkt_tree, packet_map, device_decls, field_decls : &field_decls };
+        let my_dag = pass_manager.run(&context, after_pass);
+        dag_map.insert(&my_snippet.snippet_id.id_name, my_dag);
+    }
+
+    return dag_map;
+}
+
+
+
+// All snippets that run on the same set of devices, composed into one P4 program.
+// Snippets are listed in execution order.
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct DeviceProgram<'a> {
+    pub program_name : String,
+    pub device_type : &'a str,
+    pub device_vector : Vec<Identifier<'a>>,
+    pub snippet_vector : Vec<&'a str>
+}
+
 
-    // put all packet fields in hashmap
-    for my_pkt in  &pkt_tree.packet_vector {
-        for my_pkt_field in &my_pkt.packet_fields.field_vector {
-            let f = my_pkt_field.identifier.id_name.clone();
-            let field_name = format!("{}.{}", my_pkt.packet_id.id_name.clone(),f);
-            field_decls.insert(field_name, my_pkt_field.var_type.clone());
+// Order the snippets placed on one device using the connections between them:
+// if (a, b) is a connection, a runs before b. Unconnected snippets keep their source order.
+pub fn order_by_connections<'a> (my_group : &Vec<&'a str>, my_connections : &Connections<'a>, device : &str) -> Vec<&'a str> {
+    let mut in_degree : HashMap<&str, usize> = HashMap::new();
+    for snippet in my_group {
+        in_degree.insert(snippet, 0);
+    }
+
+    let mut edges : Vec<(&str, &str)> = Vec::new();
+    for connection in &my_connections.connection_vector {
+        let from = connection.from_snippet.id_name;
+        let to = connection.to_snippet.id_name;
+        if from != to && in_degree.contains_key(from) && in_degree.contains_key(to) && !edges.contains(&(from, to)) {
+            edges.push((from, to));
+            *in_degree.get_mut(to).unwrap() += 1;
         }
     }
 
-    for my_packet in &my_packets.packet_vector {
-        for field in &my_packet.packet_fields.field_vector {
-            let field_name  = format!("{}.{}", my_packet.packet_id.id_name.clone(), field.identifier.id_name.clone());
-            field_decls.insert(field_name, field.var_type.clone());
+    let mut ordered : Vec<&'a str> = Vec::new();
+    while ordered.len() < my_group.len() {
+        let next = my_group.iter().find(|s| !ordered.contains(s) && in_degree[*s] == 0);
+        match next {
+            Some(snippet) => {
+                ordered.push(snippet);
+                for &(from, to) in &edges {
+                    if from == *snippet {
+                        *in_degree.get_mut(to).unwrap() -= 1;
+                    }
+                }
+            }
+            None => {
+                panic!("Connections between the snippets on {} form a cycle. Declare a pipeline for {}.", device, device);
+            }
         }
     }
+    return ordered;
+}
+
 
+// Group snippets by the devices they are annotated with. Every device runs a single
+// program holding all of its snippets, ordered by an explicit pipeline declaration if
+// there is one, and by the connections between the snippets otherwise. Devices that
+// run the same snippets in the same order share a program.
+pub fn create_device_programs<'a> (my_snippets : &Snippets<'a>, my_pipelines : &Pipelines<'a>,
+                                   my_connections : &Connections<'a>) -> Vec<DeviceProgram<'a>> {
+    // every pipelined snippet must be defined and placed on the pipeline's device
+    let mut pipelined_devices : Vec<&'a str> = Vec::new();
+    for pipeline in &my_pipelines.pipeline_vector {
+        let device = pipeline.device_id.id_name;
+        if pipelined_devices.contains(&device) {
+            panic!("Device {} has more than one pipeline.", device);
+        }
+        pipelined_devices.push(device);
+        for snippet in &pipeline.snippet_vector {
+            match my_snippets.snippet_vector.iter().find(|s| s.snippet_id == *snippet) {
+                None => panic!("{} pipelined on {}, but undefined", snippet.id_name, device),
+                Some(my_snippet) => {
+                    if !my_snippet.device_annotation.device_vector.contains(&pipeline.device_id) {
+                        panic!("{} pipelined on {}, but not annotated with it", snippet.id_name, device);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut device_order : Vec<Identifier<'a>> = Vec::new();
     for my_snippet in &my_snippets.snippet_vector {
+        for device in &my_snippet.device_annotation.device_vector {
+            if !device_order.contains(device) {
+                device_order.push(*device);
+            }
+        }
+    }
 
-        let mut my_dag : Dag = Dag { snippet_id : my_snippet.snippet_id.id_name,
-            device_type : my_snippet.device_annotation.device_type.id_name, 
-            device_vector : my_snippet.device_annotation.device_vector.clone(), dag_vector : Vec::new()};
+    let mut device_programs : Vec<DeviceProgram<'a>> = Vec::new();
+    for device in device_order {
+        let my_group : Vec<&'a str> = my_snippets.snippet_vector.iter()
+            .filter(|s| s.device_annotation.device_vector.contains(&device))
+            .map(|s| s.snippet_id.id_name).collect();
+
+        let my_pipeline = my_pipelines.pipeline_vector.iter().find(|p| p.device_id == device);
+        let snippet_vector = match my_pipeline {
+            Some(pipeline) => {
+                let ordered : Vec<&'a str> = pipeline.snippet_vector.iter().map(|s| s.id_name).collect();
+                if ordered.len() != my_group.len() || my_group.iter().any(|s| !ordered.contains(s)) {
+                    panic!("Pipeline for {} must list each snippet placed on it exactly once.", device.id_name);
+                }
+                ordered
+            }
+            None => order_by_connections(&my_group, my_connections, device.id_name)
+        };
+
+        let mut device_type : &'a str = "";
+        for snippet in &snippet_vector {
+            let my_snippet = my_snippets.snippet_vector.iter().find(|s| s.snippet_id.id_name == *snippet).unwrap();
+            let snippet_type = my_snippet.device_annotation.device_type.id_name;
+            if !device_type.is_empty() && device_type != snippet_type {
+                panic!("Snippets on {} target different device types {} and {}.", device.id_name, device_type, snippet_type);
+            }
+            device_type = snippet_type;
+        }
 
-        insert_packet_decls(&mut my_dag, my_packets, pkt_tree);
-        branch_removal(&mut my_dag, &packet_map, my_snippet, &field_decls);
-        static_single_assignment(&mut my_dag);
-        dag_map.insert(&my_snippet.snippet_id.id_name, my_dag);
+        let my_option = device_programs.iter_mut().find(|p| p.snippet_vector == snippet_vector);
+        match my_option {
+            Some(program) => {
+                program.device_vector.push(device);
+            }
+            None => {
+                device_programs.push(DeviceProgram { program_name : snippet_vector.join("_"), device_type,
+                    device_vector : vec![device], snippet_vector });
+            }
+        }
     }
 
-    return dag_map;
+    // Snippets without devices still get a program of their own
+    for my_snippet in &my_snippets.snippet_vector {
+        if my_snippet.device_annotation.device_vector.is_empty() {
+            device_programs.push(DeviceProgram { program_name : my_snippet.snippet_id.id_name.to_string(),
+                device_type : my_snippet.device_annotation.device_type.id_name,
+                device_vector : Vec::new(), snippet_vector : vec![my_snippet.snippet_id.id_name] });
+        }
+    }
+    return device_programs;
 }
 
 
+pub fn namespace_operand<'a> (my_operand : &Operand<'a>, local_names : &HashMap<String, &'a str>) -> Operand<'a> {
+    match my_operand {
+        Operand::LValue(ref lval) => Operand::LValue(namespace_lvalue(lval, local_names)),
+        Operand::Value(_) => my_operand.clone()
+    }
+}
 
-pub fn gen_code<'a> (my_packets : &Packets<'a>, dag_map : HashMap<&'a str, Dag<'a>>) {
 
-    for (snippet_name, snippet_dag) in dag_map {
-        if snippet_dag.device_type.contains("bmv2") {
-            bmv2_gen::gen_p4_code(&snippet_name, my_packets, &snippet_dag);
-            bmv2_gen::gen_control_plane_commands(&snippet_name, my_packets, &snippet_dag);
-        } else if snippet_dag.device_type.contains("tofino"){
-            tofino_gen::gen_p4_code(&snippet_name, my_packets,  &snippet_dag);
+pub fn namespace_lvalue<'a> (my_lval : &LValue<'a>, local_names : &HashMap<String, &'a str>) -> LValue<'a> {
+    let rename = |id : &Identifier<'a>| -> Identifier<'a> {
+        match local_names.get(id.id_name) {
+            Some(new_name) => Identifier { id_name : new_name },
+            None => *id
         }
+    };
+    match my_lval {
+        LValue::Scalar(ref id) => LValue::Scalar(rename(id)),
+        LValue::Array(ref id, ref index) => LValue::Array(rename(id), Box::new(namespace_operand(index, local_names))),
+        // packet and device fields are shared by all snippets on a device
+        LValue::Field(_, _) => my_lval.clone()
     }
 }
 
 
-pub fn create_import_map<'a> (my_imports : &Imports<'a>) ->HashMap<String, String>  {
-    let mut import_map : HashMap<String, String>= HashMap::new();
-    for my_import in &my_imports.import_vector {
-        let import_file = format!("{}{}.np", INCLUDE_DIR, my_import.import_id.id_name);
-        println!("Importing {}\n", import_file);
+fn packet_operand<'a> (my_operand : &Operand<'a>, packet_vars : &HashMap<&'a str, Identifier<'a>>) -> Operand<'a> {
+    match my_operand {
+        Operand::LValue(ref lval) => Operand::LValue(packet_lvalue(lval, packet_vars)),
+        Operand::Value(_) => my_operand.clone()
+    }
+}
+
+
+fn packet_lvalue<'a> (my_lval : &LValue<'a>, packet_vars : &HashMap<&'a str, Identifier<'a>>) -> LValue<'a> {
+    match my_lval {
+        LValue::Field(ref id, ref field) => match packet_vars.get(id.id_name) {
+            Some(packet_type) => LValue::Field(*packet_type, *field),
+            None => my_lval.clone()
+        },
+        LValue::Array(ref id, ref index) => LValue::Array(*id, Box::new(packet_operand(index, packet_vars))),
+        LValue::Scalar(_) => my_lval.clone()
+    }
+}
+
+
+fn packet_expr<'a> (my_expr : &Expr<'a>, packet_vars : &HashMap<&'a str, Identifier<'a>>) -> Expr<'a> {
+    let expr_right = match my_expr.expr_right {
+        ExprRight::BinOp(bin_op_type, ref operand) => ExprRight::BinOp(bin_op_type, packet_operand(operand, packet_vars)),
+        ExprRight::Cond(ref operand1, ref operand2) => ExprRight::Cond(packet_operand(operand1, packet_vars),
+                                                                        packet_operand(operand2, packet_vars)),
+        ExprRight::Empty() => ExprRight::Empty()
+    };
+    return Expr { op1 : packet_operand(&my_expr.op1, packet_vars), expr_right };
+}
+
+
+// A device processes one packet at a time, so the input and output packet variables of a
+// snippet all name the packet on the device: x.f of input x : pac becomes pac.f. The
+// declarations of the packet variables are dropped, only the connections use them.
+pub fn lower_packet_variables<'a> (my_snippets : &Snippets<'a>) -> Snippets<'a> {
+    let mut new_snippets = my_snippets.clone();
+    for my_snippet in &mut new_snippets.snippet_vector {
+        let mut packet_vars : HashMap<&'a str, Identifier<'a>> = HashMap::new();
+        for my_decl in &my_snippet.variable_decls.decl_vector {
+            match my_decl.var_type.var_info {
+                VarInfo::Packet(packet_type) => { packet_vars.insert(my_decl.identifier.id_name, packet_type); }
+                VarInfo::BitArray(_, _) => {}
+            }
+        }
+        if packet_vars.is_empty() {
+            continue;
+        }
+        my_snippet.variable_decls.decl_vector.retain(|d| !packet_vars.contains_key(d.identifier.id_name));
+        for invariant in &mut my_snippet.invariants {
+            invariant.condition.expr = packet_expr(&invariant.condition.expr, &packet_vars);
+        }
+        for ifblock in &mut my_snippet.ifblocks.ifblock_vector {
+            ifblock.condition.expr = packet_expr(&ifblock.condition.expr, &packet_vars);
+            for my_statement in &mut ifblock.statements.stmt_vector {
+                my_statement.lvalue = packet_lvalue(&my_statement.lvalue, &packet_vars);
+                my_statement.expr = packet_expr(&my_statement.expr, &packet_vars);
+            }
+            for assertion in &mut ifblock.statements.assert_vector {
+                assertion.condition.expr = packet_expr(&assertion.condition.expr, &packet_vars);
+            }
+        }
+    }
+    return new_snippets;
+}
+
+
+pub fn namespace_statement<'a> (my_statement : &Statement<'a>, local_names : &HashMap<String, &'a str>) -> Statement<'a> {
+    let expr_right = match my_statement.expr.expr_right {
+        ExprRight::BinOp(bin_op_type, ref operand) => ExprRight::BinOp(bin_op_type, namespace_operand(operand, local_names)),
+        ExprRight::Cond(ref operand1, ref operand2) => ExprRight::Cond(namespace_operand(operand1, local_names),
+                                                                        namespace_operand(operand2, local_names)),
+        ExprRight::Empty() => ExprRight::Empty()
+    };
+    return Statement { lvalue : namespace_lvalue(&my_statement.lvalue, local_names),
+                       expr : Expr { op1 : namespace_operand(&my_statement.expr.op1, local_names), expr_right } };
+}
+
+
+// Every snippet-local variable (and the temporaries created for it) prefixed with the
+// snippet name, so that metadata and registers of snippets sharing a device do not clash.
+pub fn get_local_names<'a> (my_dag : &Dag<'a>, prefix : &str) -> HashMap<String, String> {
+    let mut local_names = HashMap::new();
+    for dagnode in &my_dag.dag_vector {
+        match &dagnode.node_type {
+            // packet and device fields and globals are shared by all snippets on a device
+            DagNodeType::Decl(var_decl) if var_decl.var_type.type_qualifier != TypeQualifier::Field &&
+                                           var_decl.var_type.type_qualifier != TypeQualifier::Global => {
+                let id_name = var_decl.identifier.id_name;
+                local_names.insert(id_name.to_string(), format!("{}_{}", prefix, id_name));
+            }
+            _ => {}
+        }
+    }
+    return local_names;
+}
+
+
+// Rename the snippet-local variables of a dag, see get_local_names.
+// The dag borrows the new names, so they must outlive it.
+pub fn namespace_dag<'a> (my_dag : &mut Dag<'a>, new_names : &'a HashMap<String, String>) {
+    let local_names : HashMap<String, &'a str> = new_names.iter().map(|(name, new_name)| (name.clone(), new_name.as_str())).collect();
+    for dagnode in &mut my_dag.dag_vector {
+        let node_type = match dagnode.node_type {
+            DagNodeType::Decl(ref var_decl) => {
+                let mut new_decl = var_decl.clone();
+                if let Some(new_name) = local_names.get(var_decl.identifier.id_name) {
+                    new_decl.identifier = Identifier { id_name : new_name };
+                }
+                DagNodeType::Decl(new_decl)
+            }
+            DagNodeType::Stmt(ref my_statement) => DagNodeType::Stmt(namespace_statement(my_statement, &local_names)),
+            DagNodeType::Cond(ref my_expr) => {
+                let my_statement = namespace_statement(&Statement { lvalue : LValue::Scalar(Identifier { id_name : "" }),
+                                                                    expr : my_expr.clone() }, &local_names);
+                DagNodeType::Cond(my_statement.expr)
+            }
+        };
+        dagnode.node_type = node_type;
+        dagnode.pre_condition = dagnode.pre_condition.as_ref().map(|my_statement| namespace_statement(my_statement, &local_names));
+    }
+    let local_name = |name : &String| -> String { local_names.get(name).map_or(name.clone(), |n| n.to_string()) };
+    my_dag.ssa_origins = my_dag.ssa_origins.iter().map(|(version, origin)| (local_name(version), local_name(origin))).collect();
+}
+
+
+// Concatenate the (filled) snippet dags of a device program in execution order.
+// The merged dag is named after, and borrows its name from, the device program.
+pub fn merge_dags<'a, 'b> (my_program : &'b DeviceProgram<'a>, dag_map : &HashMap<&'a str, Dag<'a>>) -> Dag<'b> where 'a : 'b {
+    let mut merged_dag = Dag { snippet_id : &my_program.program_name,
+        device_type : my_program.device_type, device_vector : my_program.device_vector.clone(), dag_vector : Vec::new(),
+        ssa_origins : HashMap::new() };
+    let mut seen_meta : Vec<String> = Vec::new();
+    let mut seen_registers : Vec<String> = Vec::new();
+
+    for snippet in &my_program.snippet_vector {
+        let snippet_dag = dag_map.get(snippet).unwrap();
+        let offset = merged_dag.dag_vector.len();
+        merged_dag.ssa_origins.extend(snippet_dag.ssa_origins.clone());
+        for dagnode in &snippet_dag.dag_vector {
+            let mut new_node = dagnode.clone();
+            new_node.next_nodes = dagnode.next_nodes.iter().map(|n| n + offset).collect();
+            new_node.prev_nodes = dagnode.prev_nodes.iter().map(|n| n + offset).collect();
+            // shared (field) declarations are emitted once per program
+            new_node.p4_code.p4_header.meta.retain(|f| !seen_meta.contains(&f.name));
+            new_node.p4_code.p4_header.register.retain(|r| !seen_registers.contains(&r.name));
+            seen_meta.extend(new_node.p4_code.p4_header.meta.iter().map(|f| f.name.clone()));
+            seen_registers.extend(new_node.p4_code.p4_header.register.iter().map(|r| r.name.clone()));
+            merged_dag.dag_vector.push(new_node);
+        }
+    }
+    return merged_dag;
+}
+
+
+// P4 program and control plane commands of each device program
+pub fn get_device_code<'a> (my_packets : &Packets<'a>, dag_map : &HashMap<&'a str, Dag<'a>>,
+                             device_programs : &Vec<DeviceProgram<'a>>, options : &CompileOptions) -> Vec<DeviceCode> {
+    let mut device_code = Vec::new();
+    for my_program in device_programs {
+        if !options.selects(my_program.device_type) {
+            continue;
+        }
+        let program_dag = merge_dags(my_program, dag_map);
+        let program_name = &my_program.program_name;
+        let (p4_code, commands) = if program_dag.device_type.contains("bmv2") {
+            (bmv2_gen::get_p4_code(my_packets, &program_dag, options.assertions),
+             Some(bmv2_gen::get_control_plane_commands(&program_dag, options.assertions)))
+        } else if program_dag.device_type.contains("tofino") {
+            (tofino_gen::get_p4_code(&program_dag), None)
+        } else {
+            continue;
+        };
+        device_code.push(DeviceCode { program_name : program_name.clone(),
+                                      device_type : my_program.device_type.to_string(),
+                                      devices : my_program.device_vector.iter().map(|d| d.id_name.to_string()).collect(),
+                                      p4_code, commands });
+    }
+    return device_code;
+}
+
+
+// Declarations of the fields of the imported devices, e.g. psa.timestamp_rx
+pub fn get_device_decls<'a> (my_imports : &Imports<'a>, include_dirs : &Vec<String>) -> Vec<VariableDecl<'a>> {
+    let mut device_decls = Vec::new();
+    // file imports are expanded into the program before translation, see imports.rs
+    for my_import in my_imports.import_vector.iter().filter(|i| i.import_type.id_name == "device") {
+        let import_file = find_include_file(include_dirs, &format!("{}.np", my_import.import_id.id_name));
+        verbose!("Importing {}\n", import_file);
         let mut f = File::open(import_file).expect("File not found");
         let mut contents = String::new();
         f.read_to_string(&mut contents).expect("Something went wrong reading the file");
@@ -1740,36 +1951,45 @@ pub fn create_import_map<'a> (my_imports : &Imports<'a>) ->HashMap<String, Strin
         let token_iter = &mut tokens.iter().peekable();
         let dev_tree = parser::parse_device(token_iter);
         for my_dev_field in dev_tree.device_fields.field_vector {
-            let field_name = format!("{}.{}", dev_tree.device_id.id_name.clone(), my_dev_field.identifier.id_name.clone());
-            let identifier = format!("{}", my_dev_field.identifier.id_name.clone());
-            import_map.insert(field_name, identifier);
+            let field_name = format!("{}.{}", dev_tree.device_id.id_name, my_dev_field.identifier.id_name);
+            let var_info = match my_dev_field.var_type.var_info {
+                VarInfo::BitArray(bit_width, var_size) => VarInfo::BitArray(bit_width, var_size),
+                _ => panic!("Device field {} must be a bit array.", field_name),
+            };
+            device_decls.push(VariableDecl { identifier : Identifier { id_name : Box::leak(field_name.into_boxed_str()) },
+                                             initial_values : Vec::new(),
+                                             var_type : VarType { var_info, type_qualifier : TypeQualifier::Field } });
         }
     }
-    println!("Import Map:{:?}\n", import_map);
+    return device_decls;
+}
+
+
+// Maps each device field, e.g. psa.timestamp_rx, to its name in the device
+pub fn create_import_map<'a> (device_decls : &Vec<VariableDecl<'a>>) ->HashMap<String, String>  {
+    let mut import_map : HashMap<String, String>= HashMap::new();
+    for device_decl in device_decls {
+        let field_name = device_decl.identifier.id_name;
+        import_map.insert(field_name.to_string(), field_name[field_name.find('.').unwrap() + 1..].to_string());
+    }
+    verbose!("Import Map:{:?}\n", import_map);
     return import_map;
 }
 
 
-pub fn create_packet_map<'a> (my_packets : &Packets<'a>) ->HashMap<String, String>  {
+// pkt_tree holds the standard headers every user packet is derived from, see packet.np
+pub fn create_packet_map<'a> (my_packets : &Packets<'a>, pkt_tree : &Packets<'a>) ->HashMap<String, String>  {
     let mut packet_map : HashMap<String, String>= HashMap::new();
     for my_packet in &my_packets.packet_vector {
-        println!("my Packet : {:?}\n", my_packet);
-        let packet_file = format!("{}packet.np", INCLUDE_DIR);
-        println!("Importing Packet{}\n", packet_file);
-        let mut f = File::open(packet_file).expect("File not found");
-        let mut contents = String::new();
-        f.read_to_string(&mut contents).expect("Something went wrong reading the file");
-        let tokens = & mut lexer::get_tokens(&contents);
-        let token_iter = & mut tokens.iter().peekable();
-        let pkt_tree = parser::parse_import_packets(token_iter);
-        for my_pkt in  pkt_tree.packet_vector {
+        debug!("my Packet : {:?}\n", my_packet);
+        for my_pkt in &pkt_tree.packet_vector {
             for my_pkt_field in &my_pkt.packet_fields.field_vector {
                 let my_id = my_pkt_field.identifier.id_name.clone();
                 let field_name  = format!("{}.{}{}", my_packet.packet_id.id_name.clone(), my_pkt.packet_id.id_name.clone(), my_id);
                 let identifier = format!("{}.{}", my_pkt.packet_id.id_name.clone(),my_id);
                 packet_map.insert(field_name, identifier);
             }
-            println!("Packet : {:?}\n", my_pkt);
+            debug!("Packet : {:?}\n", my_pkt);
         }
 
         for field in &my_packet.packet_fields.field_vector {
@@ -1778,80 +1998,251 @@ pub fn create_packet_map<'a> (my_packets : &Packets<'a>) ->HashMap<String, Strin
             packet_map.insert(field_name, identifier);
         }
     }
-    println!("Packet Map:{:?}\n", packet_map);
+    verbose!("Packet Map:{:?}\n", packet_map);
     return packet_map;
 }
 
 
-// Currently, if there is only a single snippet, then the program
-// installs that single snippet on all devices, regardless of any annotations
-pub fn gen_topology_json<'a> (dag_map : &HashMap<&'a str, Dag<'a>>) {
+// Hosts, switches and links of the network. Without a topology block, every device a
+// snippet is annotated with becomes a switch with a host of its own, h1 for the first
+// switch and so on, and the switches are linked in a chain. If no snippet names a
+// device, the network is the single switch s1 with host h1.
+pub fn get_topology_nodes<'a> (my_topology : &Topology<'a>, device_programs : &Vec<DeviceProgram<'a>>) -> (Vec<String>, Vec<String>, Vec<(String, String)>) {
+    if !my_topology.switch_vector.is_empty() || !my_topology.host_vector.is_empty() {
+        let hosts = my_topology.host_vector.iter().map(|h| h.id_name.to_string()).collect();
+        let switches = my_topology.switch_vector.iter().map(|s| s.switch_id.id_name.to_string()).collect();
+        let links = my_topology.link_vector.iter()
+            .map(|l| (l.from_node.id_name.to_string(), l.to_node.id_name.to_string())).collect();
+        return (hosts, switches, links);
+    }
 
-    let topo : String = format!("bmv2_sim/topology.json");
-    let path = Path::new(topo.as_str());
-    let display  = path.display();
-    let mut topo_file = match File::create(path) {
-        Err(why) => panic!("couldn't create {}: {}",
-                           display,
-                           why.description()),
-        Ok(topo_file) => topo_file,
-    };
+    let mut switches : Vec<String> = Vec::new();
+    for my_program in device_programs {
+        for device in &my_program.device_vector {
+            if !switches.iter().any(|s| s == device.id_name) {
+                switches.push(device.id_name.to_string());
+            }
+        }
+    }
+    if switches.is_empty() {
+        switches.push(String::from("s1"));
+    }
+    let hosts : Vec<String> = (1..switches.len() + 1).map(|i| format!("h{}", i)).collect();
+    let mut links : Vec<(String, String)> = hosts.iter().cloned().zip(switches.iter().cloned()).collect();
+    for pair in switches.windows(2) {
+        links.push((pair[0].clone(), pair[1].clone()));
+    }
+    return (hosts, switches, links);
+}
 
-    let mut contents = String::new();
 
-    if dag_map.len() == 1 {
-        for key in dag_map.keys() {
-            contents += &format!("{{\"snippet_loc\" : \"{}\"}}", key);
+// Mininet topology consumed by run_exercise.py. Without a topology block, a single
+// program is installed on all switches. Otherwise every program runs on the switches
+// its snippets are annotated with or placed on. Switches that run no snippet only
+// forward, with the default program of run_exercise.py and no commands.
+pub fn create_topology_json<'a> (my_topology : &Topology<'a>, hosts : &[String], switches : &[String], links : &[(String, String)],
+                                 device_programs : &[DeviceProgram<'a>]) -> serde_json::Value {
+    let mut snippet_loc = serde_json::Map::new();
+    if device_programs.len() == 1 && my_topology.switch_vector.is_empty() && my_topology.host_vector.is_empty() {
+        for switch in switches {
+            snippet_loc.insert(switch.clone(), json!(device_programs[0].program_name));
         }
     } else {
-
-        contents += "{\"snippet_loc\" : {";
-        for (snippet_name, snippet_dag) in dag_map {
-            for d in &snippet_dag.device_vector {
-                contents += &format!("\"{}\" : \"{}\",", d.id_name, snippet_name);
+        for my_program in device_programs {
+            for d in &my_program.device_vector {
+                if !switches.contains(&d.id_name.to_string()) {
+                    panic!("{} runs {}, but is not a switch in the topology.", d.id_name, my_program.program_name);
+                }
+                snippet_loc.insert(d.id_name.to_string(), json!(my_program.program_name));
             }
         }
+    }
+
+    let mut switch_map = serde_json::Map::new();
+    for switch in switches {
+        let switch_json = match snippet_loc.get(switch) {
+            Some(program_name) => json!({
+                "runtime_json" : format!("{}-runtime.json", switch),
+                "cli_input" : format!("commands/{}.txt", program_name.as_str().unwrap())
+            }),
+            None => json!({})
+        };
+        switch_map.insert(switch.clone(), switch_json);
+    }
 
-        assert_eq!(contents.pop(), Some(','));
-        contents += "}}";
+    let link_vector : Vec<serde_json::Value> = links.iter().map(|(a, b)| json!([a, b])).collect();
+    return json!({
+        "hosts" : hosts,
+        "switches" : switch_map,
+        "snippet_loc" : snippet_loc,
+        "links" : link_vector
+    });
+}
+
+
+// Resources used by each snippet, as text and as JSON.
+// Fails if a snippet, or the program merged from the snippets of one device, exceeds the budget of its target.
+pub fn get_resource_report<'a> (my_snippets : &Snippets<'a>, dag_map : &HashMap<&'a str, Dag<'a>>, device_programs : &Vec<DeviceProgram<'a>>,
+                                budgets : &HashMap<String, HashMap<String, u64>>, options : &CompileOptions) -> (String, serde_json::Value) {
+    let mut reports = Vec::new();
+    for my_snippet in &my_snippets.snippet_vector {
+        if !options.selects(my_snippet.device_annotation.device_type.id_name) {
+            continue;
+        }
+        if let Some(snippet_dag) = dag_map.get(my_snippet.snippet_id.id_name) {
+            reports.push(resources::estimate_resources(snippet_dag));
+        }
     }
 
-    topo_file.write(contents.as_bytes());
-    let mut root = Path::new("bmv2_sim");
-    assert!(env::set_current_dir(&root).is_ok());
-    let _output = Command::new("python")
-            .arg("gen_topo.py")
-            .output()
-            .expect("failed to execute process");
+    let mut contents = String::new();
+    for report in &reports {
+        contents += &report.get_string();
+    }
+    for report in &reports {
+        resources::check_budget(report, budgets);
+    }
+    for my_program in device_programs {
+        if my_program.snippet_vector.len() > 1 && options.selects(my_program.device_type) {
+            resources::check_program_budget(&resources::estimate_resources(&merge_dags(my_program, dag_map)), budgets);
+        }
+    }
 
-    root = Path::new("..");
-    assert!(env::set_current_dir(&root).is_ok());
+    let report_json : Vec<serde_json::Value> = reports.iter().map(|r| r.get_json()).collect();
+    return (contents, serde_json::Value::Array(report_json));
 }
 
 
 
+// Stage map of each scheduled snippet, as text and as JSON
+pub fn get_schedule_report(stage_maps : &Vec<schedule::StageMap>) -> (String, serde_json::Value) {
+    let mut contents = String::new();
+    for stage_map in stage_maps {
+        contents += &stage_map.get_string();
+    }
+
+    let schedule_json : Vec<serde_json::Value> = stage_maps.iter().map(|m| m.get_json()).collect();
+    return (contents, serde_json::Value::Array(schedule_json));
+}
+
+
+pub fn get_packing_report(slot_maps : &Vec<packing::SlotMap>) -> (String, serde_json::Value) {
+    let mut contents = String::new();
+    for slot_map in slot_maps {
+        contents += &slot_map.get_string();
+    }
+
+    let metadata_json : Vec<serde_json::Value> = slot_maps.iter().map(|m| m.get_json()).collect();
+    return (contents, serde_json::Value::Array(metadata_json));
+}
+
+
 // need to use either 'bmv2' or 'tofino' for device annotation
-pub fn trans_snippets<'a> (my_imports : &Imports<'a>, my_globals : &Globals<'a>, my_packets : &Packets<'a>, my_snippets : &Snippets<'a>, pkt_tree : &Packets<'a>) {
+// Nodes of each snippet dag with their successors and the tables they apply
+pub fn get_dag_listing<'a> (my_snippets : &Snippets<'a>, dag_map : &HashMap<&'a str, Dag<'a>>) -> String {
+    let mut contents = String::new();
+    for my_snippet in &my_snippets.snippet_vector {
+        contents += &get_dag_text(&dag_map[my_snippet.snippet_id.id_name]);
+    }
+    return contents;
+}
+
+// Nodes of one dag with their successors and the tables they apply
+pub fn get_dag_text<'a> (my_dag : &Dag<'a>) -> String {
+    let mut contents = format!("snippet {} @ {}\n", my_dag.snippet_id, my_dag.device_type);
+    for (i, dagnode) in my_dag.dag_vector.iter().enumerate() {
+        contents += &format!("{}{}: {}", TAB, i, dot::get_node_label(dagnode));
+        if !dagnode.next_nodes.is_empty() {
+            contents += &format!(" -> {:?}", dagnode.next_nodes);
+        }
+        let tables : Vec<String> = dagnode.p4_code.p4_control.iter().flat_map(|c| c.get_tables()).collect();
+        if !tables.is_empty() {
+            contents += &format!(" [{}]", tables.join(", "));
+        }
+        contents += "\n";
+    }
+    return contents;
+}
+
+
+
+pub fn trans_snippets<'a> (my_imports : &Imports<'a>, my_globals : &Globals<'a>, my_packets : &Packets<'a>, my_topology : &Topology<'a>,
+                           my_snippets : &Snippets<'a>, my_pipelines : &Pipelines<'a>, my_connections : &Connections<'a>, pkt_tree : &Packets<'a>,
+                           options : &CompileOptions) -> CompileOutput {
     // TODO : Deal with mutability of my_dag
-    let import_map = create_import_map(my_imports);
-    let packet_map = create_packet_map(my_packets);
-    let mut dag_map = create_dag_nodes(&my_snippets, &packet_map, my_packets, pkt_tree);
-    println!("\n\n\n Empty Dag Map: {:?}\n\n\n\n", dag_map);
+    let device_decls = get_device_decls(my_imports, &options.include_dirs);
+    let import_map = create_import_map(&device_decls);
+    let packet_map = create_packet_map(my_packets, pkt_tree);
+    let mut dot_dags = Vec::new();
+    let mut dumped_dags = Vec::new();
+    match options.dump_after {
+        Some(ref pass) if !options.passes.contains(pass) => {
+            panic!("Pass {} is not part of the pipeline {}.", pass, options.passes.join(", "));
+        }
+        _ => {}
+    }
+    let dot_pass = options.dot_stage.map(|stage| stage.get_pass());
+    let mut dag_map = create_dag_nodes(&my_snippets, my_globals, &packet_map, my_packets, pkt_tree, &device_decls, &options.passes, &mut |pass, my_dag| {
+        if dot_pass == Some(pass) {
+            dot_dags.push(my_dag.clone());
+        }
+        if options.dump_after.as_deref() == Some(pass) {
+            dumped_dags.push(my_dag.clone());
+        }
+    });
+    let device_programs = create_device_programs(my_snippets, my_pipelines, my_connections);
+    verbose!("\n\n\n Empty Dag Map: {:?}\n\n\n\n", dag_map);
+
+    let dot = options.dot_stage.map(|stage| dot::dags_to_dot(&dot_dags, stage));
+    let dump = options.dump_after.as_ref().map(|pass| dump_dags(&dumped_dags, pass, options.dump_format));
+
+    let mut warnings = Vec::new();
+    for my_snippet in &my_snippets.snippet_vector {
+        for register in unread_registers(&dag_map[my_snippet.snippet_id.id_name]) {
+            warnings.push(format!("warning: Register {} of snippet {} is written but never read.", register, my_snippet.snippet_id.id_name));
+        }
+    }
+
+    // the local names of snippets sharing a device are prefixed, the dags borrow the new names from here
+    let mut local_names = HashMap::new();
+    for my_program in device_programs.iter().filter(|p| p.snippet_vector.len() > 1) {
+        for snippet in &my_program.snippet_vector {
+            if let Some(snippet_dag) = dag_map.get(snippet) {
+                local_names.insert(*snippet, get_local_names(snippet_dag, snippet));
+            }
+        }
+    }
 
+    let budgets = resources::load_budgets(&find_include_file(&options.include_dirs, "budgets.json"));
+    let mut stage_maps = Vec::new();
+    let mut slot_maps = Vec::new();
 
     for my_snippet in &my_snippets.snippet_vector {
 
         let mut my_option = dag_map.get_mut(&my_snippet.snippet_id.id_name);
         let device_type : String = String::from(my_snippet.device_annotation.device_type.id_name);
+        if !options.selects(&device_type) {
+            continue;
+        }
         match my_option {
            Some(mut snippet_dag) => {
-                // create_connections(&my_snippet, &my_packets, &pkt_tree, &my_imports, &mut snippet_dag.clone());
-                create_RAW_connections(&my_snippet, &my_packets, &pkt_tree, &my_imports, &mut snippet_dag.clone());
+                if let Some(new_names) = local_names.get(my_snippet.snippet_id.id_name) {
+                    namespace_dag(&mut snippet_dag, new_names);
+                }
+                // every register is read and written by a single stateful unit
+                let updates = stateful::find_register_updates(&snippet_dag);
+                let groups = stateful::get_update_groups(&snippet_dag, &updates);
                 // println!("Snippet DAG with connections: {:?}\n", snippet_dag);
                 if device_type.contains("bmv2") {
                     bmv2_gen::fill_p4code(&import_map, &my_globals, &packet_map, &mut snippet_dag, &pkt_tree,  &my_packets);
+                    bmv2_gen::lower_register_updates(&mut snippet_dag, &updates, &groups);
+                    // fuse the one-primitive tables of independent statements into shared stages
+                    let config = schedule::ScheduleConfig::from_budgets(&budgets, "bmv2");
+                    stage_maps.push(schedule::schedule_dag(&mut snippet_dag, &config, &groups));
+                    // transients whose live ranges don't overlap share a metadata field
+                    slot_maps.push(packing::pack_metadata(&mut snippet_dag));
                 } else if device_type.contains("tofino") {
                     tofino_gen::fill_p4code(&import_map, &my_globals, &packet_map, &mut snippet_dag, &pkt_tree, &my_packets);
+                    tofino_gen::lower_register_updates(&mut snippet_dag, &updates);
                 }
                 // println!("Snippet DAG: {:?}\n", snippet_dag);
            }
@@ -1859,12 +2250,18 @@ pub fn trans_snippets<'a> (my_imports : &Imports<'a>, my_globals : &Globals<'a>,
         }
     }
 
-    gen_topology_json(&dag_map);    
+    let (stage_text, schedule) = get_schedule_report(&stage_maps);
+    let (slot_text, metadata) = get_packing_report(&slot_maps);
+    let (resource_text, resources) = get_resource_report(my_snippets, &dag_map, &device_programs, &budgets, options);
+    let (hosts, switches, links) = get_topology_nodes(my_topology, &device_programs);
+    let topology = create_topology_json(my_topology, &hosts, &switches, &links, &device_programs);
     // dag_map now contains p4 code and connection information (next/prev node)
-    println!("\n\n\n Filled Dag Map: {:?}\n\n\n\n", dag_map);
-    // process::exit(1);
-    gen_code(&my_packets, dag_map);
+    verbose!("\n\n\n Filled Dag Map: {:?}\n\n\n\n", dag_map);
+    let programs = get_device_code(&my_packets, &dag_map, &device_programs, options);
+    let dags = get_dag_listing(my_snippets, &dag_map);
     //init_handlebars(dag_map);
+    let reports = format!("Stage maps:\n{}Metadata slots:\n{}Resource estimates:\n{}", stage_text, slot_text, resource_text);
+    return CompileOutput { programs, topology, resources, schedule, metadata, reports, dot, dump, dags, diagnostics : warnings };
 }
 
 #[cfg(test)]
@@ -1872,6 +2269,9 @@ mod tests {
   use super::*;
   use super::super::lexer::get_tokens;
   use super::super::parser::*;
+  use super::super::compile::*;
+  use std::env;
+  use std::fs;
 
   macro_rules! test_trans_success {
     ($input_code:expr,$trans_snippet_routine:ident,$test_name:ident) => (
@@ -1883,7 +2283,8 @@ mod tests {
         let parse_tree = parse_prog(token_iter);
         let pkt_tree = parser::parse_import_packets(token_iter);
         // TODO : need to replace &parse_tree.packets (the 4th func input) with the actual pkt_tree
-        $trans_snippet_routine(&parse_tree.imports, &parse_tree.globals, &parse_tree.packets, &parse_tree.snippets, &pkt_tree);
+        $trans_snippet_routine(&parse_tree.imports, &parse_tree.globals, &parse_tree.packets, &parse_tree.topology, &parse_tree.snippets,
+                               &parse_tree.pipelines, &parse_tree.connections, &pkt_tree, &CompileOptions::new());
         assert!(token_iter.peek().is_none(), "token iterator is not empty");
       }
     )
@@ -1976,4 +2377,310 @@ mod tests {
                             }
                         ", trans_snippets, test_trans_snippets_ternary_cond);
 
+    #[test]
+    fn test_device_programs_pipeline_order() {
+        let input = r"@ bmv2 : s1, s2, ;
+                      snippet foo() {
+                        transient a : bit<32>;
+                        a = 1;
+                      }
+                      @ bmv2 : s1, ;
+                      snippet fun() {
+                        transient a : bit<32>;
+                        a = 2;
+                      }
+                      pipeline s1 : fun, foo, ;
+                      (foo, fun)";
+        let tokens = &mut get_tokens(input);
+        let token_iter = &mut tokens.iter().peekable();
+        let parse_tree = parse_prog(token_iter);
+        let device_programs = create_device_programs(&parse_tree.snippets, &parse_tree.pipelines, &parse_tree.connections);
+        assert_eq!(device_programs.len(), 2);
+        assert_eq!(device_programs[0].program_name, "fun_foo");
+        assert_eq!(device_programs[0].snippet_vector, vec!["fun", "foo"]);
+        assert_eq!(device_programs[0].device_vector, vec![Identifier { id_name : "s1" }]);
+        assert_eq!(device_programs[1].program_name, "foo");
+        assert_eq!(device_programs[1].device_vector, vec![Identifier { id_name : "s2" }]);
+    }
+
+    #[test]
+    fn test_device_programs_connection_order() {
+        let input = r"@ bmv2 : s1, s2, ;
+                      snippet foo() {
+                        transient a : bit<32>;
+                        a = 1;
+                      }
+                      @ bmv2 : s1, s2, ;
+                      snippet fun() {
+                        transient a : bit<32>;
+                        a = 2;
+                      }
+                      (fun, foo)";
+        let tokens = &mut get_tokens(input);
+        let token_iter = &mut tokens.iter().peekable();
+        let parse_tree = parse_prog(token_iter);
+        let device_programs = create_device_programs(&parse_tree.snippets, &parse_tree.pipelines, &parse_tree.connections);
+        assert_eq!(device_programs.len(), 1);
+        assert_eq!(device_programs[0].program_name, "fun_foo");
+        assert_eq!(device_programs[0].device_vector, vec![Identifier { id_name : "s1" }, Identifier { id_name : "s2" }]);
+    }
+
+    #[test]
+    #[should_panic(expected="form a cycle")]
+    fn test_device_programs_cycle_fail() {
+        let input = r"@ bmv2 : s1, ;
+                      snippet foo() {
+                        transient a : bit<32>;
+                        a = 1;
+                      }
+                      @ bmv2 : s1, ;
+                      snippet fun() {
+                        transient a : bit<32>;
+                        a = 2;
+                      }
+                      (fun, foo)
+                      (foo, fun)";
+        let tokens = &mut get_tokens(input);
+        let token_iter = &mut tokens.iter().peekable();
+        let parse_tree = parse_prog(token_iter);
+        create_device_programs(&parse_tree.snippets, &parse_tree.pipelines, &parse_tree.connections);
+    }
+
+    fn pipeline_error(pipelines : &str) -> String {
+        let input = format!(r"@ bmv2 : s1, ;
+                              snippet foo() {{
+                                transient a : bit<32>;
+                                a = 1;
+                              }}
+                              @ bmv2 : s2, ;
+                              snippet fun() {{
+                                transient a : bit<32>;
+                                a = 2;
+                              }}
+                              {}", pipelines);
+        return compile(&input, &CompileOptions::new()).unwrap_err()[0].message.clone();
+    }
+
+    #[test]
+    fn test_device_programs_pipeline_checks() {
+        assert_eq!(pipeline_error("pipeline s1 : foo, bar, ;"), "bar pipelined on s1, but undefined");
+        assert_eq!(pipeline_error("pipeline s1 : foo, fun, ;"), "fun pipelined on s1, but not annotated with it");
+        assert_eq!(pipeline_error("pipeline s3 : fun, ;"), "fun pipelined on s3, but not annotated with it");
+        assert_eq!(pipeline_error("pipeline s1 : foo, ; pipeline s1 : foo, ;"), "Device s1 has more than one pipeline.");
+    }
+
+    #[test]
+    fn test_device_program_budget() {
+        let dir = env::temp_dir().join("sluice_device_program_budget");
+        fs::create_dir_all(&dir).unwrap();
+        File::create(dir.join("budgets.json")).unwrap().write_all(br#"{"bmv2" : {"tables" : 1}}"#).unwrap();
+        let mut options = CompileOptions::new();
+        options.include_dirs.insert(0, dir.display().to_string());
+        let input = r"@ bmv2 : s1, ;
+                      snippet foo() {
+                        persistent r : bit<32> = 0;
+                        r = r + 1;
+                      }
+                      @ bmv2 : s1, ;
+                      snippet fun() {
+                        persistent q : bit<32> = 0;
+                        q = q + 2;
+                      }
+                      (foo, fun)";
+        // each snippet needs a single table, the merged program two
+        assert_eq!(compile(input, &options).unwrap_err()[0].message,
+                   "Device program foo_fun needs 2 tables, but the bmv2 budget is 1.");
+    }
+
+    #[test]
+    fn test_merge_namespaced_dags() {
+        let input = r"@ bmv2 : s1, ;
+                      snippet foo() {
+                        transient a : bit<32>;
+                        a = 1;
+                      }
+                      @ bmv2 : s1, ;
+                      snippet fun() {
+                        transient a : bit<32>;
+                        a = 2;
+                      }
+                      (foo, fun)";
+        let tokens = &mut get_tokens(input);
+        let token_iter = &mut tokens.iter().peekable();
+        let parse_tree = parse_prog(token_iter);
+        let pkt_contents = read_include_file(&vec![INCLUDE_DIR.to_string()], "packet.np");
+        let pkt_tree = parse_import_packets(&mut get_tokens(&pkt_contents).iter().peekable());
+        let packet_map = create_packet_map(&parse_tree.packets, &pkt_tree);
+        let mut dag_map = create_dag_nodes(&parse_tree.snippets, &parse_tree.globals, &packet_map, &parse_tree.packets, &parse_tree.packets, &Vec::new(), &default_passes(), &mut |_, _| {});
+        let device_programs = create_device_programs(&parse_tree.snippets, &parse_tree.pipelines, &parse_tree.connections);
+        let foo_names = get_local_names(&dag_map["foo"], "foo");
+        let fun_names = get_local_names(&dag_map["fun"], "fun");
+        namespace_dag(dag_map.get_mut("foo").unwrap(), &foo_names);
+        namespace_dag(dag_map.get_mut("fun").unwrap(), &fun_names);
+
+        let merged_dag = merge_dags(&device_programs[0], &dag_map);
+        assert_eq!(merged_dag.snippet_id, "foo_fun");
+        let foo_len = dag_map.get("foo").unwrap().dag_vector.len();
+        assert_eq!(merged_dag.dag_vector.len(), foo_len + dag_map.get("fun").unwrap().dag_vector.len());
+        for (i, dagnode) in merged_dag.dag_vector.iter().enumerate() {
+            let lower = if i < foo_len { 0 } else { foo_len };
+            let upper = if i < foo_len { foo_len } else { merged_dag.dag_vector.len() };
+            assert!(dagnode.next_nodes.iter().chain(dagnode.prev_nodes.iter()).all(|n| *n >= lower && *n < upper));
+            match dagnode.node_type {
+                DagNodeType::Decl(ref var_decl) => {
+                    assert!(var_decl.identifier.id_name.starts_with(if i < foo_len { "foo_" } else { "fun_" }));
+                }
+                DagNodeType::Stmt(ref my_statement) => {
+                    assert!(my_statement.lvalue.get_string().starts_with(if i < foo_len { "foo_" } else { "fun_" }));
+                }
+                _ => {}
+            }
+        }
+    }
+
+ 
+    #[test]
+    fn test_bmv2_names_are_stable() {
+        let input = r"@ bmv2 : s1;
+                      snippet fun() {
+                        input c : bit<32>;
+                        transient a : bit<32>;
+                        persistent b : bit<32> = 0;
+                        a = c + 1;
+                        b = a + 2;
+                      }";
+        let tokens = &mut get_tokens(input);
+        let token_iter = &mut tokens.iter().peekable();
+        let parse_tree = parse_prog(token_iter);
+        let device_decls = get_device_decls(&parse_tree.imports, &vec![INCLUDE_DIR.to_string()]);
+        let import_map = create_import_map(&device_decls);
+        let pkt_contents = read_include_file(&vec![INCLUDE_DIR.to_string()], "packet.np");
+        let pkt_tree = parse_import_packets(&mut get_tokens(&pkt_contents).iter().peekable());
+        let packet_map = create_packet_map(&parse_tree.packets, &pkt_tree);
+        let get_names = || -> Vec<String> {
+            let mut dag_map = create_dag_nodes(&parse_tree.snippets, &parse_tree.globals, &packet_map, &parse_tree.packets, &parse_tree.packets, &device_decls, &default_passes(), &mut |_, _| {});
+            let my_dag = dag_map.get_mut("fun").unwrap();
+            bmv2_gen::fill_p4code(&import_map, &parse_tree.globals, &packet_map, my_dag, &parse_tree.packets, &parse_tree.packets);
+            my_dag.dag_vector.iter().flat_map(|n| n.p4_code.p4_actions.iter().map(|a| a.name.clone())
+                .chain(n.p4_code.p4_tables.iter().map(|t| t.name.clone()))).collect()
+        };
+        let names = get_names();
+        assert_eq!(names, vec!["fun_s0_add", "fun_s0_add_table", "fun_s1_add", "fun_s1_add_table"]);
+        assert_eq!(get_names(), names);
+    }
+
+    #[test]
+    fn test_topology_json() {
+        let input = r"topology { host h1; switch s1 : bmv2; switch s2 : bmv2; link h1 -- s1; link s1 -- s2; }
+                      @ bmv2 : s1;
+                      snippet foo() {
+                        transient a : bit<32>;
+                        a = 1;
+                      }
+                      @ bmv2 : s2;
+                      snippet fun() {
+                        transient a : bit<32>;
+                        a = 2;
+                      }";
+        let tokens = &mut get_tokens(input);
+        let token_iter = &mut tokens.iter().peekable();
+        let parse_tree = parse_prog(token_iter);
+        let device_programs = create_device_programs(&parse_tree.snippets, &parse_tree.pipelines, &parse_tree.connections);
+        let (hosts, switches, links) = get_topology_nodes(&parse_tree.topology, &device_programs);
+        let topo = create_topology_json(&parse_tree.topology, &hosts, &switches, &links, &device_programs);
+        assert_eq!(topo, json!({
+            "hosts" : ["h1"],
+            "switches" : {
+                "s1" : { "runtime_json" : "s1-runtime.json", "cli_input" : "commands/foo.txt" },
+                "s2" : { "runtime_json" : "s2-runtime.json", "cli_input" : "commands/fun.txt" }
+            },
+            "snippet_loc" : { "s1" : "foo", "s2" : "fun" },
+            "links" : [["h1", "s1"], ["s1", "s2"]]
+        }));
+    }
+
+    #[test]
+    fn test_topology_json_idle_switch() {
+        let input = r"topology { switch s1 : bmv2; switch s2 : bmv2; switch s3 : bmv2; }
+                      @ bmv2 : s1;
+                      snippet foo() {
+                        transient a : bit<32>;
+                        a = 1;
+                      }
+                      @ bmv2 : s3;
+                      snippet fun() {
+                        transient a : bit<32>;
+                        a = 2;
+                      }";
+        let tokens = &mut get_tokens(input);
+        let token_iter = &mut tokens.iter().peekable();
+        let parse_tree = parse_prog(token_iter);
+        let device_programs = create_device_programs(&parse_tree.snippets, &parse_tree.pipelines, &parse_tree.connections);
+        let (hosts, switches, links) = get_topology_nodes(&parse_tree.topology, &device_programs);
+        let topo = create_topology_json(&parse_tree.topology, &hosts, &switches, &links, &device_programs);
+        assert_eq!(topo["switches"]["s2"], json!({}));
+        assert_eq!(topo["snippet_loc"], json!({ "s1" : "foo", "s3" : "fun" }));
+        assert_eq!(topo["switches"]["s3"]["cli_input"], "commands/fun.txt");
+    }
+
+    #[test]
+    fn test_topology_json_single_program() {
+        let topology = |input : &str| {
+            let tokens = &mut get_tokens(input);
+            let token_iter = &mut tokens.iter().peekable();
+            let parse_tree = parse_prog(token_iter);
+            let device_programs = create_device_programs(&parse_tree.snippets, &parse_tree.pipelines, &parse_tree.connections);
+            let (hosts, switches, links) = get_topology_nodes(&parse_tree.topology, &device_programs);
+            return create_topology_json(&parse_tree.topology, &hosts, &switches, &links, &device_programs);
+        };
+        let snippet = r"@ bmv2 : s1;
+                        snippet fun() {
+                          transient a : bit<32>;
+                          a = 1;
+                        }";
+        // the tofino switch stays idle
+        let topo = topology(&format!("topology {{ switch s1 : bmv2; switch s2 : tofino; }} {}", snippet));
+        assert_eq!(topo["snippet_loc"], json!({ "s1" : "fun" }));
+        assert_eq!(topo["switches"]["s2"], json!({}));
+
+        // without a topology block, the annotated switches get a host each
+        let topo = topology(&format!("{} @ bmv2 : s2; snippet foo() {{ transient b : bit<32>; b = 2; }}", snippet));
+        assert_eq!(topo["hosts"], json!(["h1", "h2"]));
+        assert_eq!(topo["snippet_loc"], json!({ "s1" : "fun", "s2" : "foo" }));
+        assert_eq!(topo["links"], json!([["h1", "s1"], ["h2", "s2"], ["s1", "s2"]]));
+
+        let topo = topology(r"@ bmv2 : ;
+                              snippet fun() {
+                                transient a : bit<32>;
+                                a = 1;
+                              }");
+        assert_eq!(topo["hosts"], json!(["h1"]));
+        assert_eq!(topo["snippet_loc"], json!({ "s1" : "fun" }));
+    }
+
+    #[test]
+    fn test_lower_packet_variables() {
+        let input = r"packet pac : ethernet(etherType : 1234) {
+                        seq : bit<32>;
+                      }
+                      @ bmv2 : s1;
+                      snippet fun() {
+                        input x : pac;
+                        output y : pac;
+                        transient a : bit<32>;
+                        a = x.seq;
+                        y.seq = a + 1;
+                      }";
+        let tokens = &mut get_tokens(input);
+        let token_iter = &mut tokens.iter().peekable();
+        let parse_tree = parse_prog(token_iter);
+        let my_snippet = &lower_packet_variables(&parse_tree.snippets).snippet_vector[0];
+        assert_eq!(my_snippet.variable_decls.decl_vector.len(), 1);
+        let statements = &my_snippet.ifblocks.ifblock_vector[0].statements.stmt_vector;
+        assert_eq!(statements[0].expr.op1, Operand::LValue(LValue::Field(Identifier { id_name : "pac" }, Identifier { id_name : "seq" })));
+        assert_eq!(statements[1].lvalue, LValue::Field(Identifier { id_name : "pac" }, Identifier { id_name : "seq" }));
+
+        let output = compile(input, &CompileOptions::new()).unwrap();
+        assert!(output.programs[0].p4_code.contains("add(pac.seq, mdata.a, 1);"), "{}", output.programs[0].p4_code);
+    }
  }
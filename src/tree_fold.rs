@@ -10,7 +10,9 @@ pub trait TreeFold<'a> {
   fn visit_prog(&mut self, tree : &'a Prog) {
     self.visit_globals(&tree.globals);
     self.visit_packets(&tree.packets);
+    self.visit_topology(&tree.topology);
     self.visit_snippets(&tree.snippets);
+    self.visit_pipelines(&tree.pipelines);
     self.visit_connections(&tree.connections);
   }
 
@@ -36,6 +38,18 @@ pub trait TreeFold<'a> {
     self.visit_var_type(&tree.var_type);
   }
 
+  fn visit_topology(&mut self, tree : &'a Topology) {
+    for host in &tree.host_vector { self.visit_identifier(host); }
+    for switch in &tree.switch_vector {
+      self.visit_identifier(&switch.switch_id);
+      self.visit_identifier(&switch.device_type);
+    }
+    for link in &tree.link_vector {
+      self.visit_identifier(&link.from_node);
+      self.visit_identifier(&link.to_node);
+    }
+  }
+
   fn visit_snippets(&mut self, tree : &'a Snippets) {
     for snippet in &tree.snippet_vector { self.visit_snippet(snippet); }
   }
@@ -44,6 +58,22 @@ pub trait TreeFold<'a> {
     self.visit_identifier(&tree.snippet_id);
     self.visit_variable_decls(&tree.variable_decls);
     self.visit_ifblocks(&tree.ifblocks);
+    // invariants are checked once the snippet ran
+    for invariant in &tree.invariants { self.visit_invariant(invariant); }
+  }
+
+  fn visit_invariant(&mut self, tree : &'a Invariant) {
+    self.visit_identifier(&tree.identifier);
+    self.visit_condition(&tree.condition);
+  }
+
+  fn visit_pipelines(&mut self, tree : &'a Pipelines) {
+    for pipeline in &tree.pipeline_vector { self.visit_pipeline(pipeline); }
+  }
+
+  fn visit_pipeline(&mut self, tree : &'a Pipeline) {
+    self.visit_identifier(&tree.device_id);
+    for snippet in &tree.snippet_vector { self.visit_identifier(snippet); }
   }
 
   fn visit_connections(&mut self, tree : &'a Connections) {
@@ -85,7 +115,12 @@ pub trait TreeFold<'a> {
   }
 
   fn visit_statements(&mut self, tree : &'a Statements) {
-    for stmt in &tree.stmt_vector { self.visit_statement(stmt); }
+    // each assertion is visited after the statements that run before it
+    for (position, stmt) in tree.stmt_vector.iter().enumerate() {
+      for assertion in tree.assert_vector.iter().filter(|a| a.position == position) { self.visit_condition(&assertion.condition); }
+      self.visit_statement(stmt);
+    }
+    for assertion in tree.assert_vector.iter().filter(|a| a.position == tree.stmt_vector.len()) { self.visit_condition(&assertion.condition); }
   }
 
   fn visit_statement(&mut self, tree : &'a Statement) {
@@ -0,0 +1,44 @@
+// How much the compiler prints while it runs. Messages are printed through the
+// macros below, which check the level of the current thread, so that compilations
+// running on different threads of an embedding service don't share it.
+use std::cell::Cell;
+
+pub const QUIET   : usize = 0;
+pub const NORMAL  : usize = 1;
+pub const VERBOSE : usize = 2;
+pub const DEBUG   : usize = 3;
+
+thread_local! {
+    static LEVEL : Cell<usize> = const { Cell::new(NORMAL) };
+}
+
+pub fn set_level(level : usize) {
+    LEVEL.with(|l| l.set(level));
+}
+
+// Runs f at the given level, and restores the level of the current thread afterwards
+pub fn with_level<T, F : FnOnce() -> T>(level : usize, f : F) -> T {
+    let previous = LEVEL.with(|l| l.replace(level));
+    let result = f();
+    LEVEL.with(|l| l.set(previous));
+    result
+}
+
+pub fn enabled(level : usize) -> bool {
+    LEVEL.with(|l| l.get() >= level)
+}
+
+// Progress and warnings, hidden by --quiet
+macro_rules! note {
+    ($($arg:tt)*) => (if ::verbosity::enabled(::verbosity::NORMAL) { println!($($arg)*); })
+}
+
+// Intermediate results of the passes, shown with -v
+macro_rules! verbose {
+    ($($arg:tt)*) => (if ::verbosity::enabled(::verbosity::VERBOSE) { println!($($arg)*); })
+}
+
+// Traces of the code generators, shown with -vv
+macro_rules! debug {
+    ($($arg:tt)*) => (if ::verbosity::enabled(::verbosity::DEBUG) { println!($($arg)*); })
+}
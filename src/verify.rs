@@ -0,0 +1,310 @@
+// Bounded exhaustive verification of snippets. verify_snippet runs the interpreter on every
+// combination of the inputs, packet fields and register elements of a snippet, and checks that
+// its assertions and invariants hold, that it doesn't fail at runtime, and that its outputs are
+// written on every path. Variables of at most width_bound bits are enumerated, so the properties
+// are proved for them. Wider variables take the values below 2^width_bound, and the values
+// around the sign bit and the maximum where overflows happen, so the result is only bounded.
+// The dags of branch removal and SSA agree with the interpreter, see differential.rs.
+use grammar::*;
+use interpreter::*;
+use differential::snippet_fields;
+use compile::*;
+use imports::{ImportArena, resolve_imports};
+use lexer;
+use parser;
+use std::collections::BTreeSet;
+use std::collections::HashSet;
+
+pub const DEFAULT_WIDTH_BOUND : u64 = 4;
+// widths above this would make the enumerated domains too large to build
+pub const MAX_WIDTH_BOUND : u64 = 20;
+// number of packets and register states checked at most for one snippet
+pub const MAX_CASES : u64 = 1 << 20;
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Counterexample {
+    // inputs and packet fields, keyed p.f
+    pub packet    : FieldMap,
+    // register state before the packet
+    pub registers : RegisterMap,
+    // property that doesn't hold on the packet
+    pub message   : String,
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Verification {
+    pub snippet_name   : String,
+    // packets and register states checked
+    pub cases          : u64,
+    // every value of every variable was checked, so the properties hold on all packets
+    pub complete       : bool,
+    pub counterexample : Option<Counterexample>,
+}
+
+// Values a variable of the given width takes
+fn domain(bit_width : u64, width_bound : u64) -> Vec<u64> {
+    if bit_width <= width_bound {
+        return (0..1u64 << bit_width).collect();
+    }
+    let max = truncate(u64::MAX, bit_width);
+    let sign = 1u64 << (bit_width - 1);
+    let mut values : Vec<u64> = (0..1u64 << width_bound).collect();
+    for value in [sign - 1, sign, max - 1, max] {
+        if !values.contains(&value) {
+            values.push(value);
+        }
+    }
+    values
+}
+
+// Variables and fields that must be written on every path: the scalar outputs,
+// and the fields of the output packets that the snippet writes somewhere
+fn snippet_outputs<'a> (my_snippet : &Snippet<'a>) -> BTreeSet<String> {
+    let mut outputs = BTreeSet::new();
+    let mut output_packets = HashSet::new();
+    for my_decl in &my_snippet.variable_decls.decl_vector {
+        match (&my_decl.var_type.var_info, my_decl.var_type.type_qualifier) {
+            (&VarInfo::BitArray(_, _), TypeQualifier::Output) => { outputs.insert(my_decl.identifier.id_name.to_string()); }
+            (&VarInfo::Packet(_), TypeQualifier::Output) => { output_packets.insert(my_decl.identifier.id_name); }
+            _ => {}
+        }
+    }
+    for ifblock in &my_snippet.ifblocks.ifblock_vector {
+        for my_statement in &ifblock.statements.stmt_vector {
+            match my_statement.lvalue {
+                LValue::Field(ref id, ref field) if output_packets.contains(id.id_name) => {
+                    outputs.insert(format!("{}.{}", id.id_name, field.id_name));
+                }
+                _ => {}
+            }
+        }
+    }
+    outputs
+}
+
+// Checks the properties of a snippet on every case, and returns the first counterexample
+pub fn verify_snippet<'a> (my_snippet : &Snippet<'a>, my_globals : &Globals<'a>, my_packets : &Packets<'a>,
+                           pkt_tree : &Packets<'a>, width_bound : u64) -> Verification {
+    if width_bound > MAX_WIDTH_BOUND {
+        panic!("Width bound {} is larger than {}.", width_bound, MAX_WIDTH_BOUND);
+    }
+    let snippet_name = my_snippet.snippet_id.id_name;
+    let mut interpreter = Interpreter::new(my_snippet, my_globals, my_packets);
+    interpreter.add_headers(pkt_tree);
+
+    // name, register index and values of every variable, packet inputs first
+    let mut variables : Vec<(String, Option<usize>, Vec<u64>)> = Vec::new();
+    let mut complete = true;
+    for my_decl in &my_snippet.variable_decls.decl_vector {
+        if let (&VarInfo::BitArray(bit_width, _), TypeQualifier::Input) = (&my_decl.var_type.var_info, my_decl.var_type.type_qualifier) {
+            complete &= bit_width <= width_bound;
+            variables.push((my_decl.identifier.id_name.to_string(), None, domain(bit_width, width_bound)));
+        }
+    }
+    for field in snippet_fields(my_snippet) {
+        let bit_width = interpreter.field_width(&field);
+        complete &= bit_width <= width_bound;
+        variables.push((field, None, domain(bit_width, width_bound)));
+    }
+    for my_decl in &my_snippet.variable_decls.decl_vector {
+        if let (&VarInfo::BitArray(bit_width, var_size), TypeQualifier::Persistent) = (&my_decl.var_type.var_info, my_decl.var_type.type_qualifier) {
+            complete &= bit_width <= width_bound;
+            for index in 0..var_size as usize {
+                variables.push((my_decl.identifier.id_name.to_string(), Some(index), domain(bit_width, width_bound)));
+            }
+        }
+    }
+
+    let cases = variables.iter().try_fold(1u64, |cases, (_, _, values)| cases.checked_mul(values.len() as u64));
+    let cases = match cases {
+        Some(cases) if cases <= MAX_CASES => cases,
+        _ => panic!("Snippet {} has more than {} cases to check, lower the width bound.", snippet_name, MAX_CASES),
+    };
+    let outputs = snippet_outputs(my_snippet);
+
+    // digits[i] is the index of the value of variable i in the current case
+    let mut digits = vec![0; variables.len()];
+    for _ in 0..cases {
+        let mut packet = FieldMap::new();
+        let mut registers = RegisterMap::new();
+        for (&(ref name, index, ref values), &digit) in variables.iter().zip(digits.iter()) {
+            match index {
+                Some(index) => {
+                    interpreter.set_register(name, index, values[digit]);
+                    registers.entry(name.clone()).or_default().push(values[digit]);
+                }
+                None => { packet.insert(name.clone(), values[digit]); }
+            }
+        }
+
+        let message = match catch_errors(|| interpreter.process(&packet)) {
+            Err(errors) => Some(errors[0].message.clone()),
+            Ok(output) => output.violations.first().cloned().or_else(|| {
+                outputs.iter().find(|o| !interpreter.written().contains(*o))
+                    .map(|o| format!("Output {} of snippet {} is not defined on every path.", o, snippet_name))
+            }),
+        };
+        if let Some(message) = message {
+            return Verification { snippet_name : snippet_name.to_string(), cases, complete,
+                                  counterexample : Some(Counterexample { packet, registers, message }) };
+        }
+
+        for (digit, (_, _, values)) in digits.iter_mut().zip(variables.iter()) {
+            *digit += 1;
+            if *digit < values.len() {
+                break;
+            }
+            *digit = 0;
+        }
+    }
+    Verification { snippet_name : snippet_name.to_string(), cases, complete, counterexample : None }
+}
+
+// Verifies every snippet of a program
+pub fn verify_program(source : &str, width_bound : u64, options : &CompileOptions) -> Result<Vec<Verification>, Vec<CompileError>> {
+    catch_errors(|| {
+        let tokens = &mut lexer::get_tokens(source);
+        let token_iter = &mut tokens.iter().peekable();
+        let parse_tree = parser::parse_prog(token_iter);
+        assert!(token_iter.peek().is_none(), "Token iterator is not empty.");
+        let mut import_arena = ImportArena::new();
+        let parse_tree = resolve_imports(parse_tree, options.source_path.as_deref(), &options.include_dirs, &mut import_arena);
+
+        let contents = read_include_file(&options.include_dirs, "packet.np");
+        let pkt_tokens = &mut lexer::get_tokens(&contents);
+        let pkt_iter = &mut pkt_tokens.iter().peekable();
+        let pkt_tree = parser::parse_import_packets(pkt_iter);
+
+        parse_tree.snippets.snippet_vector.iter()
+            .map(|my_snippet| verify_snippet(my_snippet, &parse_tree.globals, &parse_tree.packets, &pkt_tree, width_bound))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn verify(input : &str) -> Verification {
+        return verify_program(input, DEFAULT_WIDTH_BOUND, &CompileOptions::new()).unwrap().remove(0);
+    }
+
+    #[test]
+    fn test_verify_proved() {
+        let input = r"snippet fun() {
+                        input a : bit<3>;
+                        output b : bit<3>;
+                        persistent cnt : bit<2> = 0;
+                        if (a > 2) {
+                          b = a - 1;
+                          assert(b >= 2);
+                        }
+                        else {
+                          b = 0;
+                        }
+                        cnt = cnt + 1;
+                      }";
+        let verification = verify(input);
+        assert_eq!(verification.counterexample, None);
+        // 8 values of a, 4 of cnt
+        assert_eq!(verification.cases, 32);
+        assert!(verification.complete);
+    }
+
+    #[test]
+    fn test_verify_assertion() {
+        let input = r"snippet fun() {
+                        input a : bit<4>;
+                        transient x : bit<4>;
+                        x = a + 1;
+                        assert(x != 0);
+                      }";
+        let counterexample = verify(input).counterexample.unwrap();
+        assert_eq!(counterexample.packet["a"], 15);
+        assert_eq!(counterexample.message, "Assertion x != 0 failed in snippet fun.");
+    }
+
+    #[test]
+    fn test_verify_output_undefined() {
+        let input = r"snippet fun() {
+                        input a : bit<2>;
+                        output b : bit<2>;
+                        if (a > 1) {
+                          b = 1;
+                        }
+                      }";
+        let counterexample = verify(input).counterexample.unwrap();
+        assert_eq!(counterexample.packet["a"], 0);
+        assert_eq!(counterexample.message, "Output b of snippet fun is not defined on every path.");
+    }
+
+    #[test]
+    fn test_verify_invariant_register() {
+        let input = r"snippet fun() {
+                        input a : bit<1>;
+                        persistent cnt : bit<3>[2] = {0, 0,};
+                        invariant bounded : cnt[1] < 6;
+                        cnt[a] = cnt[a] + 1;
+                      }";
+        let counterexample = verify(input).counterexample.unwrap();
+        assert_eq!(counterexample.packet["a"], 1);
+        assert_eq!(counterexample.registers["cnt"], vec![0, 5]);
+        assert_eq!(counterexample.message, "Invariant bounded of snippet fun does not hold.");
+    }
+
+    #[test]
+    fn test_verify_runtime_error() {
+        let input = r"snippet fun() {
+                        input i : bit<2>;
+                        persistent r : bit<1>[3] = {0, 0, 0,};
+                        r[i] = 1;
+                      }";
+        let counterexample = verify(input).counterexample.unwrap();
+        assert_eq!(counterexample.packet["i"], 3);
+        assert_eq!(counterexample.message, "Index 3 is out of bounds for r of size 3.");
+    }
+
+    #[test]
+    fn test_verify_wide_fields() {
+        let input = r"packet p : ethernet(etherType : 1234) {
+                        seq : bit<32>;
+                      }
+                      snippet fun() {
+                        transient next : bit<32>;
+                        next = p.seq + 1;
+                        assert(next > p.seq);
+                      }";
+        let verification = verify(input);
+        assert!(!verification.complete);
+        // the overflow of the maximum is among the sampled values
+        assert_eq!(verification.counterexample.unwrap().packet["p.seq"], 0xffffffff);
+
+        let input = r"snippet fun() {
+                        input a : bit<32>;
+                        output b : bit<32>;
+                        b = a;
+                      }";
+        let verification = verify(input);
+        assert_eq!(verification.counterexample, None);
+        assert!(!verification.complete);
+        assert_eq!(verification.cases, 20);
+    }
+
+    #[test]
+    fn test_verify_too_many_cases() {
+        let input = r"snippet fun() {
+                        input a : bit<8>;
+                        input b : bit<8>;
+                        input c : bit<8>;
+                        output d : bit<8>;
+                        d = a + b;
+                        d = d + c;
+                      }";
+        let errors = verify_program(input, 8, &CompileOptions::new()).unwrap_err();
+        assert_eq!(errors[0].message, "Snippet fun has more than 1048576 cases to check, lower the width bound.");
+    }
+}